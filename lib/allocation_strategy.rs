@@ -1,4 +1,4 @@
-use std::ptr;
+use std::{ffi::c_void, ops::Range, ptr, ptr::NonNull};
 
 use ash::vk;
 use ash::vk::{BufferCreateFlags, BufferCreateInfo, BufferUsageFlags, SharingMode, StructureType};
@@ -14,23 +14,109 @@ use ndarray::prelude::*;
 
 use crate::AllocatorLogConfig;
 
+use super::gpu_task::GPUTask;
+use super::host_staging::{self, HostImport, HostStagingHints};
+use super::readback_transform::ReadbackTransform;
+use super::resource_tracker::{LiveResourceKind, TrackedResource};
+use super::staging::StagingError;
+
 use super::ComputeManager;
 use super::{device::DeviceInfo, instance::InstanceInfo};
 
 pub struct Allocator {
     pub(super) vulkan_allocator: VulkanAllocator,
+    memory_policy: DeviceMemoryPolicy,
+    memory_stats: DeviceMemoryStats,
 }
 
 pub struct Buffer {
     pub(super) buffer: vk::Buffer,
     pub(super) allocation: Allocation,
+    pub(super) tracking: Option<TrackedResource>,
+    pub(super) placement: Option<TensorPlacement>,
+    // Host pages backing the buffer in place of `allocation`, for pinned or NUMA placed staging
+    pub(super) host_import: Option<HostImport>,
+}
+
+impl Buffer {
+    pub(super) fn mapped_ptr(&self) -> Option<NonNull<c_void>> {
+        match self.host_import.as_ref() {
+            Some(host_import) => Some(host_import.mapped_ptr()),
+            None => self.mapped_ptr(),
+        }
+    }
+}
+
+// Where a tensor's device buffer ended up, so freeing it updates the memory stats
+#[derive(Debug, Clone, Copy)]
+pub(super) enum TensorPlacement {
+    Device(u64),
+    Spilled(u64),
+}
+
+/// A soft cap on the device memory tensors take up, and what happens to tensors that don't fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceMemoryPolicy {
+    /// Bytes of device-local memory the buffers backing tensors may hold at once. `None`, the
+    /// default, leaves it to the driver.
+    pub limit: Option<u64>,
+    /// Places tensors past the limit, or that the device is out of memory for, in host-visible
+    /// memory the device reads over the bus instead of failing the task. Slower, but jobs near
+    /// the memory capacity keep running.
+    pub spill_to_host: bool,
+}
+
+/// Memory held by the buffers backing tensors in recorded tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceMemoryStats {
+    pub device_bytes: u64,
+    /// Buffers currently spilled to host memory
+    pub spilled_buffers: usize,
+    pub spilled_bytes: u64,
+    /// Buffers spilled since the manager was created, including freed ones
+    pub total_spills: u64,
+}
+
+/// Host memory for the buffers that move a tensor's data between host and device. Upload through
+/// `CpuToGpu` and read back through `HostCached`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostMemoryLocation {
+    /// For uploads. Write-combined and often device-local. Fast for the host to write, very slow
+    /// to read.
+    CpuToGpu,
+    /// For readback. The allocator's `GpuToCpu` location, which is host-cached where the device
+    /// has it, so the host reads it at full speed.
+    HostCached,
+}
+
+impl HostMemoryLocation {
+    pub(super) fn memory_location(self) -> MemoryLocation {
+        match self {
+            HostMemoryLocation::CpuToGpu => MemoryLocation::CpuToGpu,
+            HostMemoryLocation::HostCached => MemoryLocation::GpuToCpu,
+        }
+    }
+}
+
+// Where a tensor carved out of an arena lives in the arena's buffer
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ArenaSlot {
+    pub(super) arena_id: u32,
+    pub(super) offset: u64,
+    pub(super) arena_size: u64,
 }
 
 pub struct Tensor {
     pub(super) id: u32,
     pub(super) readback_enabled: bool,
+    pub(super) staging_location: HostMemoryLocation,
+    pub(super) readback_location: HostMemoryLocation,
+    pub(super) staging_hints: HostStagingHints,
+    pub(super) arena: Option<ArenaSlot>,
+    pub(super) readback_transform: Option<ReadbackTransform>,
 
     local_data: Array<f32, Ix1>,
+    _tracking: Option<TrackedResource>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -39,19 +125,116 @@ pub enum AllocationError {
     BufferCreationFailure,
     MemoryAllocationError,
     MemoryBindFailure,
+    DeviceMemoryLimitExceeded,
+    // Staging alignments must be powers of two
+    InvalidStagingAlignment,
 }
 
 impl ComputeManager {
     pub fn create_tensor(&self, data: Array<f32, Ix1>, enable_readback: bool) -> Tensor {
+        self.new_tensor(data, enable_readback, None)
+    }
+
+    pub(super) fn next_tensor_id(&self) -> u32 {
+        self.current_tensor_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(super) fn new_tensor(
+        &self,
+        data: Array<f32, Ix1>,
+        enable_readback: bool,
+        arena: Option<ArenaSlot>,
+    ) -> Tensor {
+        let id = self.next_tensor_id();
+        let len = data.len();
+
         Tensor {
-            id: self.current_tensor_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            id,
             readback_enabled: enable_readback,
+            staging_location: HostMemoryLocation::CpuToGpu,
+            readback_location: HostMemoryLocation::HostCached,
+            staging_hints: HostStagingHints::default(),
+            arena,
+            readback_transform: None,
             local_data: data,
+            _tracking: self.track_resource(LiveResourceKind::Tensor, || {
+                format!("tensor{{id={}, len={}}}", id, len)
+            }),
+        }
+    }
+
+    /// Applies to buffers allocated for tasks recorded from now on. Tasks already recorded keep
+    /// their buffers where they are, even when they're over a lowered limit.
+    pub fn set_device_memory_policy(&self, policy: DeviceMemoryPolicy) {
+        match self.allocator.write() {
+            Ok(mut allocator) => allocator.memory_policy = policy,
+            Err(e) => log::error!("Failed to acquire allocator! Error: {e}"),
+        }
+    }
+
+    pub fn device_memory_policy(&self) -> DeviceMemoryPolicy {
+        match self.allocator.read() {
+            Ok(allocator) => allocator.memory_policy,
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                DeviceMemoryPolicy::default()
+            }
+        }
+    }
+
+    pub fn device_memory_stats(&self) -> DeviceMemoryStats {
+        match self.allocator.read() {
+            Ok(allocator) => allocator.memory_stats,
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                DeviceMemoryStats::default()
+            }
         }
     }
 }
 
 impl Tensor {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Memory the tensor is uploaded from. Defaults to `CpuToGpu`.
+    pub fn with_staging_location(mut self, location: HostMemoryLocation) -> Self {
+        self.staging_location = location;
+        self
+    }
+
+    /// Memory the tensor is read back through. Defaults to `HostCached`.
+    pub fn with_readback_location(mut self, location: HostMemoryLocation) -> Self {
+        self.readback_location = location;
+        self
+    }
+
+    /// Alignment, pinning and NUMA placement of the memory the tensor is uploaded from. No hints
+    /// are applied by default.
+    pub fn with_staging_hints(mut self, hints: HostStagingHints) -> Self {
+        self.staging_hints = hints;
+        self
+    }
+
+    /// Byte offset of the tensor in its arena's buffer, for tensors carved out of an arena
+    pub fn arena_offset(&self) -> Option<u64> {
+        self.arena.map(|a| a.offset)
+    }
+
+    pub fn staging_location(&self) -> HostMemoryLocation {
+        self.staging_location
+    }
+
+    pub fn readback_location(&self) -> HostMemoryLocation {
+        self.readback_location
+    }
+
+    pub fn staging_hints(&self) -> HostStagingHints {
+        self.staging_hints
+    }
+
     pub fn data(&self) -> &Array<f32, Ix1> {
         &self.local_data
     }
@@ -59,6 +242,14 @@ impl Tensor {
     pub fn data_mut(&mut self) -> &mut Array<f32, Ix1> {
         &mut self.local_data
     }
+
+    /// Reads elements `range` of the tensor's readback in `task` straight from the mapped
+    /// buffer, leaving `data` untouched. Only the window is made host-visible and copied, so
+    /// huge results can be spot-checked cheaply. The task must not be running, e.g. it has been
+    /// awaited or a frame loop is between submissions.
+    pub fn peek(&self, task: &GPUTask, range: Range<usize>) -> Result<Vec<f32>, StagingError> {
+        task.peek_readback(self.id, range)
+    }
 }
 
 impl Allocator {
@@ -83,7 +274,7 @@ impl Allocator {
             } else {
                 AllocatorDebugSettings::default()
             },
-            buffer_device_address: false,
+            buffer_device_address: device_info.descriptor_buffer.is_some(),
         }) {
             Ok(a) => a,
             Err(e) => {
@@ -92,7 +283,97 @@ impl Allocator {
             }
         };
 
-        Ok(Allocator { vulkan_allocator })
+        Ok(Allocator {
+            vulkan_allocator,
+            memory_policy: DeviceMemoryPolicy::default(),
+            memory_stats: DeviceMemoryStats::default(),
+        })
+    }
+
+    // Allocates the device buffer backing a tensor, spilling it to host-visible memory when the
+    // policy allows and it's over the limit or device memory runs out
+    pub(super) fn allocate_tensor_buffer(
+        &mut self,
+        device_info: &DeviceInfo,
+        size: u64,
+        usage: BufferUsageFlags,
+        name: &str,
+        queue_family: u32,
+    ) -> Result<Buffer, AllocationError> {
+        let over_limit = self
+            .memory_policy
+            .limit
+            .is_some_and(|limit| self.memory_stats.device_bytes + size > limit);
+
+        if !over_limit {
+            match self.allocate_buffer(
+                device_info,
+                size,
+                usage,
+                MemoryLocation::GpuOnly,
+                name,
+                queue_family,
+            ) {
+                Ok(mut buffer) => {
+                    buffer.placement = Some(TensorPlacement::Device(size));
+                    self.memory_stats.device_bytes += size;
+                    return Ok(buffer);
+                }
+                Err(AllocationError::MemoryAllocationError) if self.memory_policy.spill_to_host => {
+                    log::warn!(
+                        "Out of device memory for {} bytes of \"{}\", spilling it to host memory",
+                        size,
+                        name
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        } else if self.memory_policy.spill_to_host {
+            log::warn!(
+                "{} bytes of \"{}\" would exceed the device memory limit, spilling it to host memory",
+                size,
+                name
+            );
+        } else {
+            log::error!(
+                "{} bytes of \"{}\" would exceed the device memory limit of {} bytes, with {} bytes in use!",
+                size,
+                name,
+                self.memory_policy.limit.unwrap_or(0),
+                self.memory_stats.device_bytes
+            );
+            return Err(AllocationError::DeviceMemoryLimitExceeded);
+        }
+
+        let mut buffer = self.allocate_buffer(
+            device_info,
+            size,
+            usage,
+            MemoryLocation::GpuToCpu,
+            name,
+            queue_family,
+        )?;
+        buffer.placement = Some(TensorPlacement::Spilled(size));
+        self.memory_stats.spilled_buffers += 1;
+        self.memory_stats.spilled_bytes += size;
+        self.memory_stats.total_spills += 1;
+
+        Ok(buffer)
+    }
+
+    pub(super) fn release_placement(&mut self, placement: Option<TensorPlacement>) {
+        match placement {
+            Some(TensorPlacement::Device(size)) => {
+                self.memory_stats.device_bytes = self.memory_stats.device_bytes.saturating_sub(size)
+            }
+            Some(TensorPlacement::Spilled(size)) => {
+                self.memory_stats.spilled_buffers =
+                    self.memory_stats.spilled_buffers.saturating_sub(1);
+                self.memory_stats.spilled_bytes =
+                    self.memory_stats.spilled_bytes.saturating_sub(size);
+            }
+            None => (),
+        }
     }
 
     pub fn allocate_buffer(
@@ -104,6 +385,64 @@ impl Allocator {
         name: &str,
         queue_family: u32,
     ) -> Result<Buffer, AllocationError> {
+        self.allocate_buffer_with_hints(
+            device_info,
+            size,
+            usage,
+            location,
+            HostStagingHints::default(),
+            name,
+            queue_family,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn allocate_buffer_with_hints(
+        &mut self,
+        device_info: &DeviceInfo,
+        size: u64,
+        usage: BufferUsageFlags,
+        location: MemoryLocation,
+        hints: HostStagingHints,
+        name: &str,
+        queue_family: u32,
+    ) -> Result<Buffer, AllocationError> {
+        if !hints.alignment_valid() {
+            log::error!(
+                "Staging alignment {} is not a power of two!",
+                hints.alignment
+            );
+            return Err(AllocationError::InvalidStagingAlignment);
+        }
+        if hints.needs_host_import() && location != MemoryLocation::GpuOnly {
+            match device_info.external_memory_host.as_ref() {
+                Some(support) => match host_staging::import_buffer(
+                    device_info,
+                    support,
+                    size,
+                    usage,
+                    hints,
+                    queue_family,
+                ) {
+                    Ok((buffer, host_import)) => {
+                        return Ok(Buffer {
+                            buffer,
+                            allocation: Allocation::default(),
+                            tracking: None,
+                            placement: None,
+                            host_import: Some(host_import),
+                        })
+                    }
+                    Err(_) => log::warn!(
+                        "Failed to import pinned or NUMA placed staging memory for \"{}\", allocating it without the hints",
+                        name
+                    ),
+                },
+                None => log::warn!(
+                    "Pinning and NUMA placement of staging memory need VK_EXT_external_memory_host on Linux, ignoring them"
+                ),
+            }
+        }
         let queue_families = [queue_family];
 
         let buffer_create_info = BufferCreateInfo {
@@ -127,11 +466,13 @@ impl Allocator {
             }
         };
 
-        let buffer_memory_requirements = unsafe {
+        let mut buffer_memory_requirements = unsafe {
             device_info
                 .device
                 .get_buffer_memory_requirements(buffer)
         };
+        buffer_memory_requirements.alignment =
+            buffer_memory_requirements.alignment.max(hints.alignment);
 
         let buffer_allocation = match self.vulkan_allocator.allocate(&AllocationCreateDesc {
             name,
@@ -143,6 +484,7 @@ impl Allocator {
             Ok(a) => a,
             Err(e) => {
                 log::error!("Failed to allocate backing memory for buffer! Error: {}", e);
+                unsafe { device_info.device.destroy_buffer(buffer, None) };
                 return Err(AllocationError::MemoryAllocationError);
             }
         };
@@ -156,6 +498,8 @@ impl Allocator {
                 Ok(_) => (),
                 Err(e) => {
                     log::error!("Failed to bind buffer memory! Error: {}", e);
+                    let _ = self.vulkan_allocator.free(buffer_allocation);
+                    device_info.device.destroy_buffer(buffer, None);
                     return Err(AllocationError::MemoryBindFailure);
                 }
             };
@@ -164,6 +508,9 @@ impl Allocator {
         Ok(Buffer {
             buffer,
             allocation: buffer_allocation,
+            tracking: None,
+            placement: None,
+            host_import: None,
         })
     }
 }
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use ndarray::{Array, Ix1};
+
+use super::{allocation_strategy::ArenaSlot, ComputeManager, Tensor};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArenaError {
+    ArenaFull {
+        requested_bytes: u64,
+        available_bytes: u64,
+    },
+}
+
+/// One device buffer that tensors are carved out of at fixed offsets. A task binding several
+/// tensors of the same arena allocates the buffer once and binds each tensor at its offset.
+/// Tensors share the arena's readback setting, and its staging and readback memory follow the
+/// first of them bound in a task.
+pub struct TensorArena {
+    id: u32,
+    capacity_bytes: u64,
+    next_offset: u64,
+    enable_readback: bool,
+
+    parent: Arc<ComputeManager>,
+}
+
+impl ComputeManager {
+    pub fn create_arena(
+        self: Arc<Self>,
+        capacity_elements: usize,
+        enable_readback: bool,
+    ) -> TensorArena {
+        TensorArena {
+            id: self.next_tensor_id(),
+            capacity_bytes: capacity_elements as u64 * 4,
+            next_offset: 0,
+            enable_readback,
+            parent: self,
+        }
+    }
+}
+
+impl TensorArena {
+    /// Places `data` at the next offset that satisfies the device's storage buffer alignment
+    pub fn carve(&mut self, data: Array<f32, Ix1>) -> Result<Tensor, ArenaError> {
+        let alignment = self
+            .parent
+            .device_info
+            .limits
+            .min_storage_buffer_offset_alignment
+            .max(4);
+        let offset = self.next_offset.div_ceil(alignment) * alignment;
+        let size = data.len() as u64 * 4;
+        if offset + size > self.capacity_bytes {
+            return Err(ArenaError::ArenaFull {
+                requested_bytes: size,
+                available_bytes: self.capacity_bytes.saturating_sub(offset),
+            });
+        }
+        self.next_offset = offset + size;
+
+        Ok(self.parent.new_tensor(
+            data,
+            self.enable_readback,
+            Some(ArenaSlot {
+                arena_id: self.id,
+                offset,
+                arena_size: self.capacity_bytes,
+            }),
+        ))
+    }
+
+    pub fn capacity_bytes(&self) -> u64 {
+        self.capacity_bytes
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.next_offset
+    }
+}
@@ -0,0 +1,180 @@
+use std::ptr;
+
+use ash::vk::{
+    self, AccessFlags, AccessFlags2, BufferMemoryBarrier, BufferMemoryBarrier2, CommandBuffer,
+    DependencyFlags, DependencyInfo, MemoryBarrier, MemoryBarrier2, PipelineStageFlags,
+    PipelineStageFlags2, StructureType,
+};
+
+use super::device::DeviceInfo;
+
+// A dependency on either all memory or a single whole buffer. With synchronization2 every barrier
+// keeps its own stages; without it, the stages of a batch are merged into one pipeline barrier.
+#[derive(Clone, Copy)]
+pub(super) struct Barrier {
+    pub(super) src_stage: PipelineStageFlags,
+    pub(super) src_access: AccessFlags,
+    pub(super) dst_stage: PipelineStageFlags,
+    pub(super) dst_access: AccessFlags,
+    pub(super) buffer: Option<vk::Buffer>,
+    // Source and destination queue families when the barrier transfers ownership of `buffer`
+    pub(super) queue_families: Option<(u32, u32)>,
+}
+
+impl Barrier {
+    pub(super) fn buffer(
+        buffer: vk::Buffer,
+        src_stage: PipelineStageFlags,
+        src_access: AccessFlags,
+        dst_stage: PipelineStageFlags,
+        dst_access: AccessFlags,
+    ) -> Self {
+        Barrier {
+            src_stage,
+            src_access,
+            dst_stage,
+            dst_access,
+            buffer: Some(buffer),
+            queue_families: None,
+        }
+    }
+
+    // The same barrier is recorded on both queues: as the release on the source queue, where the
+    // destination stage is ignored, and as the acquire on the destination, where the source is
+    pub(super) fn ownership_transfer(
+        buffer: vk::Buffer,
+        src_queue_family: u32,
+        dst_queue_family: u32,
+        src_stage: PipelineStageFlags,
+        src_access: AccessFlags,
+        dst_stage: PipelineStageFlags,
+        dst_access: AccessFlags,
+    ) -> Self {
+        Barrier {
+            queue_families: Some((src_queue_family, dst_queue_family)),
+            ..Barrier::buffer(buffer, src_stage, src_access, dst_stage, dst_access)
+        }
+    }
+
+    fn queue_family_indices(&self) -> (u32, u32) {
+        self.queue_families
+            .unwrap_or((vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED))
+    }
+}
+
+// The legacy stage and access bits share their values with the synchronization2 flags
+fn stage2(stage: PipelineStageFlags) -> PipelineStageFlags2 {
+    PipelineStageFlags2::from_raw(stage.as_raw() as u64)
+}
+
+fn access2(access: AccessFlags) -> AccessFlags2 {
+    AccessFlags2::from_raw(access.as_raw() as u64)
+}
+
+pub(super) fn cmd_barriers(
+    device_info: &DeviceInfo,
+    command_buffer: CommandBuffer,
+    barriers: &[Barrier],
+) {
+    if barriers.is_empty() {
+        return;
+    }
+
+    match device_info.synchronization2.as_ref() {
+        Some(synchronization2) => {
+            let memory_barriers: Vec<MemoryBarrier2> = barriers
+                .iter()
+                .filter(|b| b.buffer.is_none())
+                .map(|b| MemoryBarrier2 {
+                    s_type: StructureType::MEMORY_BARRIER_2,
+                    p_next: ptr::null(),
+                    src_stage_mask: stage2(b.src_stage),
+                    src_access_mask: access2(b.src_access),
+                    dst_stage_mask: stage2(b.dst_stage),
+                    dst_access_mask: access2(b.dst_access),
+                })
+                .collect();
+            let buffer_barriers: Vec<BufferMemoryBarrier2> = barriers
+                .iter()
+                .filter_map(|b| {
+                    let (src_queue_family_index, dst_queue_family_index) = b.queue_family_indices();
+                    b.buffer.map(|buffer| BufferMemoryBarrier2 {
+                        s_type: StructureType::BUFFER_MEMORY_BARRIER_2,
+                        p_next: ptr::null(),
+                        src_stage_mask: stage2(b.src_stage),
+                        src_access_mask: access2(b.src_access),
+                        dst_stage_mask: stage2(b.dst_stage),
+                        dst_access_mask: access2(b.dst_access),
+                        src_queue_family_index,
+                        dst_queue_family_index,
+                        buffer,
+                        offset: 0,
+                        size: vk::WHOLE_SIZE,
+                    })
+                })
+                .collect();
+
+            let dependency_info = DependencyInfo {
+                s_type: StructureType::DEPENDENCY_INFO,
+                p_next: ptr::null(),
+                dependency_flags: DependencyFlags::empty(),
+                memory_barrier_count: memory_barriers.len() as u32,
+                p_memory_barriers: memory_barriers.as_ptr(),
+                buffer_memory_barrier_count: buffer_barriers.len() as u32,
+                p_buffer_memory_barriers: buffer_barriers.as_ptr(),
+                image_memory_barrier_count: 0,
+                p_image_memory_barriers: ptr::null(),
+            };
+
+            unsafe { synchronization2.cmd_pipeline_barrier2(command_buffer, &dependency_info) };
+        }
+        None => {
+            let src_stage = barriers
+                .iter()
+                .fold(PipelineStageFlags::empty(), |s, b| s | b.src_stage);
+            let dst_stage = barriers
+                .iter()
+                .fold(PipelineStageFlags::empty(), |s, b| s | b.dst_stage);
+
+            let memory_barriers: Vec<MemoryBarrier> = barriers
+                .iter()
+                .filter(|b| b.buffer.is_none())
+                .map(|b| MemoryBarrier {
+                    s_type: StructureType::MEMORY_BARRIER,
+                    p_next: ptr::null(),
+                    src_access_mask: b.src_access,
+                    dst_access_mask: b.dst_access,
+                })
+                .collect();
+            let buffer_barriers: Vec<BufferMemoryBarrier> = barriers
+                .iter()
+                .filter_map(|b| {
+                    let (src_queue_family_index, dst_queue_family_index) = b.queue_family_indices();
+                    b.buffer.map(|buffer| BufferMemoryBarrier {
+                        s_type: StructureType::BUFFER_MEMORY_BARRIER,
+                        p_next: ptr::null(),
+                        src_access_mask: b.src_access,
+                        dst_access_mask: b.dst_access,
+                        src_queue_family_index,
+                        dst_queue_family_index,
+                        buffer,
+                        offset: 0,
+                        size: vk::WHOLE_SIZE,
+                    })
+                })
+                .collect();
+
+            unsafe {
+                device_info.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    src_stage,
+                    dst_stage,
+                    DependencyFlags::empty(),
+                    &memory_barriers,
+                    &buffer_barriers,
+                    &[],
+                );
+            }
+        }
+    }
+}
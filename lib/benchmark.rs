@@ -0,0 +1,219 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ndarray::Array1;
+
+use super::{
+    gpu_task::GPUTaskRecordingDiagnostic, kernel_assert::KernelAssertionFailed, pipeline::Pipeline,
+    ComputeManager, Tensor, WorkGroupSize,
+};
+
+#[derive(Debug, Clone)]
+pub enum BenchmarkError {
+    TaskRecordingFailure(Vec<GPUTaskRecordingDiagnostic>),
+    TaskSubmissionFailure,
+    OutputCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    OutputLengthMismatch {
+        output: usize,
+        expected: usize,
+        actual: usize,
+    },
+    KernelAssertionFailed(KernelAssertionFailed),
+}
+
+/// All GPU timings are host wall-clock averages per iteration and include recording and submission.
+#[derive(Debug, Clone, Copy)]
+pub struct ComparisonReport {
+    pub iterations: u32,
+    pub cpu_time: Duration,
+    pub gpu_time: Duration,
+    pub task_overhead_time: Duration,
+    pub upload_time: Duration,
+    pub dispatch_time: Duration,
+    pub readback_time: Duration,
+    pub speedup: f64,
+    pub max_abs_error: f32,
+    pub max_rel_error: f32,
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum Phase {
+    Empty,
+    Upload,
+    Readback,
+    Full,
+}
+
+impl ComputeManager {
+    /// Bindings are `inputs` followed by `outputs`, in order. Output tensors must have readback enabled.
+    #[allow(clippy::too_many_arguments)]
+    pub fn compare_with_cpu<F>(
+        self: Arc<Self>,
+        pipeline: &Arc<Pipeline>,
+        inputs: Vec<&Tensor>,
+        mut outputs: Vec<&mut Tensor>,
+        work_group: WorkGroupSize,
+        iterations: u32,
+        mut cpu_reference: F,
+    ) -> Result<ComparisonReport, BenchmarkError>
+    where
+        F: FnMut(&[&Array1<f32>]) -> Vec<Array1<f32>>,
+    {
+        let iterations = iterations.max(1);
+
+        let input_data: Vec<&Array1<f32>> = inputs.iter().map(|t| t.data()).collect();
+        let mut expected = Vec::new();
+        let cpu_start = Instant::now();
+        for _ in 0..iterations {
+            expected = cpu_reference(&input_data);
+        }
+        let cpu_time = cpu_start.elapsed() / iterations;
+
+        if expected.len() != outputs.len() {
+            return Err(BenchmarkError::OutputCountMismatch {
+                expected: outputs.len(),
+                actual: expected.len(),
+            });
+        }
+
+        // Warm up once so one-time driver costs don't land in the first phase
+        self.clone()
+            .time_phase(Phase::Full, pipeline, &inputs, &mut outputs, work_group, 1)?;
+
+        let empty_time = self.clone().time_phase(
+            Phase::Empty,
+            pipeline,
+            &inputs,
+            &mut outputs,
+            work_group,
+            iterations,
+        )?;
+        let upload_total = self.clone().time_phase(
+            Phase::Upload,
+            pipeline,
+            &inputs,
+            &mut outputs,
+            work_group,
+            iterations,
+        )?;
+        let readback_total = self.clone().time_phase(
+            Phase::Readback,
+            pipeline,
+            &inputs,
+            &mut outputs,
+            work_group,
+            iterations,
+        )?;
+        let gpu_time = self.clone().time_phase(
+            Phase::Full,
+            pipeline,
+            &inputs,
+            &mut outputs,
+            work_group,
+            iterations,
+        )?;
+
+        let upload_time = upload_total.saturating_sub(empty_time);
+        let readback_time = readback_total.saturating_sub(empty_time);
+        let dispatch_time = gpu_time
+            .saturating_sub(empty_time)
+            .saturating_sub(upload_time)
+            .saturating_sub(readback_time);
+
+        let mut max_abs_error = 0.0_f32;
+        let mut max_rel_error = 0.0_f32;
+        for (i, (output, reference)) in outputs.iter().zip(expected.iter()).enumerate() {
+            if output.data().len() != reference.len() {
+                return Err(BenchmarkError::OutputLengthMismatch {
+                    output: i,
+                    expected: output.data().len(),
+                    actual: reference.len(),
+                });
+            }
+
+            output
+                .data()
+                .iter()
+                .zip(reference.iter())
+                .for_each(|(gpu, cpu)| {
+                    let abs_error = (gpu - cpu).abs();
+                    max_abs_error = max_abs_error.max(abs_error);
+                    max_rel_error = max_rel_error.max(abs_error / cpu.abs().max(f32::EPSILON));
+                });
+        }
+
+        Ok(ComparisonReport {
+            iterations,
+            cpu_time,
+            gpu_time,
+            task_overhead_time: empty_time,
+            upload_time,
+            dispatch_time,
+            readback_time,
+            speedup: cpu_time.as_secs_f64() / gpu_time.as_secs_f64().max(f64::EPSILON),
+            max_abs_error,
+            max_rel_error,
+        })
+    }
+
+    pub(super) fn time_phase(
+        self: Arc<Self>,
+        phase: Phase,
+        pipeline: &Arc<Pipeline>,
+        inputs: &[&Tensor],
+        outputs: &mut [&mut Tensor],
+        work_group: WorkGroupSize,
+        iterations: u32,
+    ) -> Result<Duration, BenchmarkError> {
+        let mut elapsed = Duration::ZERO;
+
+        for _ in 0..iterations {
+            let start = Instant::now();
+
+            let bindings: Vec<&Tensor> = inputs
+                .iter()
+                .copied()
+                .chain(outputs.iter().map(|t| &**t))
+                .collect();
+            let output_refs: Vec<&Tensor> = outputs.iter().map(|t| &**t).collect();
+
+            let mut recording = self.clone().new_task(pipeline, bindings);
+            recording = match phase {
+                Phase::Empty => recording,
+                Phase::Upload => recording.op_local_sync_device(inputs.to_vec()),
+                Phase::Readback => recording.op_device_sync_local(output_refs),
+                Phase::Full => recording
+                    .op_local_sync_device(inputs.to_vec())
+                    .op_pipeline_dispatch(work_group)
+                    .op_device_sync_local(output_refs),
+            };
+
+            let task = recording
+                .finalize()
+                .map_err(BenchmarkError::TaskRecordingFailure)?;
+
+            let running_task = match self.exec_task(&task) {
+                Some(r) => r,
+                None => return Err(BenchmarkError::TaskSubmissionFailure),
+            };
+
+            match phase {
+                Phase::Readback | Phase::Full => self.await_task(
+                    &running_task,
+                    outputs.iter_mut().map(|t| &mut **t).collect(),
+                ),
+                Phase::Empty | Phase::Upload => self.await_task(&running_task, vec![]),
+            }
+            .map_err(BenchmarkError::KernelAssertionFailed)?;
+
+            elapsed += start.elapsed();
+        }
+
+        Ok(elapsed / iterations)
+    }
+}
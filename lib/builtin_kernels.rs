@@ -0,0 +1,550 @@
+use std::{sync::Arc, time::Instant};
+
+use ndarray::Array1;
+
+use super::{
+    gpu_task::GPUTaskRecordingDiagnostic,
+    kernel_assert::KernelAssertionFailed,
+    kernel_selection::KernelSelectionError,
+    pipeline::{Pipeline, PipelineCreateError, ShaderSource},
+    ComputeManager, Tensor, WorkGroupSize,
+};
+
+const BUILTIN_LOCAL_SIZE: u32 = 64;
+const MAX_WORK_GROUP_COUNT: u32 = 65535;
+// Each reduction invocation combines this many consecutive elements before the group's tree
+const REDUCE_ELEMENTS_PER_INVOCATION: u64 = 16;
+// Runs per candidate when picking the faster reduction order
+const REDUCE_SELECTION_ITERATIONS: u32 = 3;
+
+/// Element type conversions. Tensors hold 32-bit words, so f16 data is packed two halves per word
+/// with the first in the low bits, and i32 data is stored as bit patterns, e.g. from
+/// `f32::from_bits(value as u32)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Conversion {
+    F32ToF16,
+    F16ToF32,
+    I32ToF32,
+    F32ToI32,
+}
+
+/// Copies `count` elements, reading every `src_stride`th element from `src_offset` and writing
+/// every `dst_stride`th from `dst_offset`. Elements of the destination in between are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StridedCopy {
+    pub src_offset: u32,
+    pub src_stride: u32,
+    pub dst_offset: u32,
+    pub dst_stride: u32,
+    pub count: u32,
+}
+
+/// Reductions of a whole tensor to one element
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reduction {
+    Sum,
+    Max,
+    Min,
+}
+
+/// How per-group partial results are combined. Only sums are affected, max and min are exact in
+/// any order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReductionOrder {
+    /// Partials are added atomically as groups finish, so the last bits of a sum can differ
+    /// between runs
+    Unordered,
+    /// Elements are combined in fixed chunks and fixed pairwise trees, and the partials in group
+    /// order by the last group to finish. Sums of the same input are bit-identical across runs
+    /// and devices, at the cost of a scratch buffer and a serial final step.
+    Deterministic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuiltinKernel {
+    Convert(Conversion),
+    StridedCopy,
+    Reduce(Reduction, ReductionOrder),
+}
+
+#[derive(Debug, Clone)]
+pub enum BuiltinKernelError {
+    PipelineCreationFailure(PipelineCreateError),
+    OutputLengthMismatch { expected: usize, actual: usize },
+    CopyOutOfBounds,
+    TaskRecordingFailure(Vec<GPUTaskRecordingDiagnostic>),
+    TaskSubmissionFailure,
+    KernelAssertionFailed(KernelAssertionFailed),
+    KernelSelectionFailure(KernelSelectionError),
+}
+
+impl From<KernelSelectionError> for BuiltinKernelError {
+    fn from(e: KernelSelectionError) -> Self {
+        BuiltinKernelError::KernelSelectionFailure(e)
+    }
+}
+
+impl Conversion {
+    // Output elements for an input of `len` words
+    fn output_len(&self, len: usize) -> usize {
+        match self {
+            Conversion::F32ToF16 => len.div_ceil(2),
+            Conversion::F16ToF32 => len * 2,
+            Conversion::I32ToF32 | Conversion::F32ToI32 => len,
+        }
+    }
+}
+
+impl Reduction {
+    fn identity(&self) -> f32 {
+        match self {
+            Reduction::Sum => 0.0,
+            Reduction::Max => f32::NEG_INFINITY,
+            Reduction::Min => f32::INFINITY,
+        }
+    }
+
+    fn defines(&self) -> &'static str {
+        match self {
+            Reduction::Sum => REDUCE_SUM_DEFINES,
+            Reduction::Max => REDUCE_MAX_DEFINES,
+            Reduction::Min => REDUCE_MIN_DEFINES,
+        }
+    }
+}
+
+impl BuiltinKernel {
+    // Helpers shared by kernels of a family, placed between the header and the body
+    fn prelude(&self) -> String {
+        match self {
+            BuiltinKernel::Reduce(reduction, _) => {
+                format!("{}{}", reduction.defines(), REDUCE_COMMON)
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn body(&self) -> &'static str {
+        match self {
+            BuiltinKernel::Convert(Conversion::F32ToF16) => CONVERT_F32_TO_F16,
+            BuiltinKernel::Convert(Conversion::F16ToF32) => CONVERT_F16_TO_F32,
+            BuiltinKernel::Convert(Conversion::I32ToF32) => CONVERT_I32_TO_F32,
+            BuiltinKernel::Convert(Conversion::F32ToI32) => CONVERT_F32_TO_I32,
+            BuiltinKernel::StridedCopy => STRIDED_COPY,
+            BuiltinKernel::Reduce(_, ReductionOrder::Unordered) => REDUCE_UNORDERED,
+            BuiltinKernel::Reduce(_, ReductionOrder::Deterministic) => REDUCE_DETERMINISTIC,
+        }
+    }
+
+    // Source and destination, plus the copy parameters for strided copies or the scratch buffer
+    // of reductions
+    fn binding_count(&self) -> u32 {
+        match self {
+            BuiltinKernel::Convert(_) => 2,
+            BuiltinKernel::StridedCopy | BuiltinKernel::Reduce(..) => 3,
+        }
+    }
+
+    // Whether the kernel keeps or accumulates into what the destination already holds
+    fn uploads_output(&self) -> bool {
+        matches!(
+            self,
+            BuiltinKernel::StridedCopy | BuiltinKernel::Reduce(_, ReductionOrder::Unordered)
+        )
+    }
+}
+
+// Work groups of the built-in local size for `invocations`, spilling into y past the x limit
+fn dispatch_size(invocations: u64) -> WorkGroupSize {
+    let group_count = invocations.div_ceil(BUILTIN_LOCAL_SIZE as u64).max(1);
+    let groups_x = group_count.min(MAX_WORK_GROUP_COUNT as u64) as u32;
+    WorkGroupSize {
+        x: groups_x,
+        y: group_count.div_ceil(groups_x as u64) as u32,
+        z: 1,
+    }
+}
+
+impl ComputeManager {
+    /// The pipeline behind a built-in kernel, for recording it into tasks directly. Binding 0 is
+    /// the source and binding 1 the destination. Strided copies read their parameters from
+    /// binding 2, as the five words of `StridedCopy` in field order, and reductions use it as
+    /// scratch. Pipelines are cached like `get_or_build_pipeline`'s.
+    pub fn builtin_pipeline(
+        self: Arc<Self>,
+        kernel: BuiltinKernel,
+    ) -> Result<Arc<Pipeline>, PipelineCreateError> {
+        let source = format!("{}{}{}", BUILTIN_HEADER, kernel.prelude(), kernel.body());
+        self.get_or_build_pipeline(
+            ShaderSource::Glsl(&source),
+            &format!("builtin_{:?}", kernel),
+            kernel.binding_count(),
+        )
+    }
+
+    /// Converts every element of `input` into `output`, which must hold exactly the converted
+    /// elements and have readback enabled. F32 to I32 truncates towards zero.
+    pub fn convert(
+        self: Arc<Self>,
+        conversion: Conversion,
+        input: &Tensor,
+        output: &mut Tensor,
+    ) -> Result<(), BuiltinKernelError> {
+        let expected = conversion.output_len(input.data().len());
+        if output.data().len() != expected {
+            return Err(BuiltinKernelError::OutputLengthMismatch {
+                expected,
+                actual: output.data().len(),
+            });
+        }
+
+        self.run_builtin(
+            BuiltinKernel::Convert(conversion),
+            input,
+            None,
+            output,
+            expected as u64,
+        )
+    }
+
+    /// Copies elements of `input` into `output` as described by `copy`. `output` must have
+    /// readback enabled.
+    pub fn strided_copy(
+        self: Arc<Self>,
+        copy: StridedCopy,
+        input: &Tensor,
+        output: &mut Tensor,
+    ) -> Result<(), BuiltinKernelError> {
+        let last = |offset: u32, stride: u32| {
+            offset as u64 + (copy.count.saturating_sub(1) as u64) * stride as u64
+        };
+        if copy.count > 0
+            && (last(copy.src_offset, copy.src_stride) >= input.data().len() as u64
+                || last(copy.dst_offset, copy.dst_stride) >= output.data().len() as u64)
+        {
+            return Err(BuiltinKernelError::CopyOutOfBounds);
+        }
+
+        let params = self.create_tensor(
+            Array1::from_iter(
+                [
+                    copy.src_offset,
+                    copy.src_stride,
+                    copy.dst_offset,
+                    copy.dst_stride,
+                    copy.count,
+                ]
+                .map(f32::from_bits),
+            ),
+            false,
+        );
+        self.run_builtin(
+            BuiltinKernel::StridedCopy,
+            input,
+            Some(&params),
+            output,
+            copy.count as u64,
+        )
+    }
+
+    /// Reduces every element of `input` into the single element of `output`, which must have
+    /// readback enabled. Empty inputs reduce to the identity, e.g. negative infinity for max.
+    pub fn reduce(
+        self: Arc<Self>,
+        reduction: Reduction,
+        order: ReductionOrder,
+        input: &Tensor,
+        output: &mut Tensor,
+    ) -> Result<(), BuiltinKernelError> {
+        if output.data().len() != 1 {
+            return Err(BuiltinKernelError::OutputLengthMismatch {
+                expected: 1,
+                actual: output.data().len(),
+            });
+        }
+
+        let invocations = (input.data().len() as u64).div_ceil(REDUCE_ELEMENTS_PER_INVOCATION);
+        let scratch_len = match order {
+            ReductionOrder::Unordered => {
+                output.data_mut()[0] = reduction.identity();
+                1
+            }
+            // The finished-group counter, then a partial per group
+            ReductionOrder::Deterministic => {
+                let work_group = dispatch_size(invocations);
+                1 + (work_group.x * work_group.y) as usize
+            }
+        };
+        let scratch = self.create_tensor(Array1::zeros(scratch_len), false);
+
+        self.run_builtin(
+            BuiltinKernel::Reduce(reduction, order),
+            input,
+            Some(&scratch),
+            output,
+            invocations,
+        )
+    }
+
+    /// Like `reduce`, in whichever order is faster on this device for inputs of about this size.
+    /// The first call per device and size benchmarks both on `input`, and the winner is cached
+    /// like `select_kernel`'s. Sums may differ in the last bits between runs, as with `Unordered`.
+    /// Returns the order used.
+    pub fn reduce_fastest(
+        self: Arc<Self>,
+        reduction: Reduction,
+        input: &Tensor,
+        output: &mut Tensor,
+    ) -> Result<ReductionOrder, BuiltinKernelError> {
+        const ORDERS: [ReductionOrder; 2] =
+            [ReductionOrder::Unordered, ReductionOrder::Deterministic];
+
+        let op = format!(
+            "builtin_reduce_{:?}_{}",
+            reduction,
+            input.data().len().next_power_of_two()
+        );
+        let mut scratch_output = self.create_tensor(Array1::zeros(1), true);
+        let winner = self.select_candidate(
+            &op,
+            &["Unordered", "Deterministic"],
+            REDUCE_SELECTION_ITERATIONS,
+            |index, iterations| {
+                let start = Instant::now();
+                for _ in 0..iterations {
+                    self.clone()
+                        .reduce(reduction, ORDERS[index], input, &mut scratch_output)?;
+                }
+                Ok::<_, BuiltinKernelError>(start.elapsed() / iterations)
+            },
+        )?;
+
+        self.reduce(reduction, ORDERS[winner], input, output)?;
+        Ok(ORDERS[winner])
+    }
+
+    fn run_builtin(
+        self: Arc<Self>,
+        kernel: BuiltinKernel,
+        input: &Tensor,
+        params: Option<&Tensor>,
+        output: &mut Tensor,
+        invocations: u64,
+    ) -> Result<(), BuiltinKernelError> {
+        let pipeline = self
+            .clone()
+            .builtin_pipeline(kernel)
+            .map_err(BuiltinKernelError::PipelineCreationFailure)?;
+
+        let mut uploads = vec![input];
+        uploads.extend(params);
+        if kernel.uploads_output() {
+            uploads.push(output);
+        }
+        let mut bindings = vec![input, &*output];
+        bindings.extend(params);
+
+        let task = self
+            .clone()
+            .new_task(&pipeline, bindings)
+            .op_local_sync_device(uploads)
+            .op_pipeline_dispatch(dispatch_size(invocations))
+            .op_device_sync_local(vec![output])
+            .finalize()
+            .map_err(BuiltinKernelError::TaskRecordingFailure)?;
+
+        let running_task = match self.exec_task(&task) {
+            Some(r) => r,
+            None => return Err(BuiltinKernelError::TaskSubmissionFailure),
+        };
+        self.await_task(&running_task, vec![output])
+            .map_err(BuiltinKernelError::KernelAssertionFailed)
+    }
+}
+
+const BUILTIN_HEADER: &str = "
+#version 450
+
+layout (local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+layout(set = 0, binding = 0) readonly buffer buf_src { uint src[]; };
+layout(set = 0, binding = 1) buffer buf_dst { uint dst[]; };
+
+uint element_index() {
+    return gl_GlobalInvocationID.x + gl_GlobalInvocationID.y * gl_NumWorkGroups.x * gl_WorkGroupSize.x;
+}
+";
+
+const CONVERT_F32_TO_F16: &str = "
+void main() {
+    uint index = element_index();
+    if (index >= uint(dst.length())) {
+        return;
+    }
+
+    uint first = index * 2u;
+    float low = uintBitsToFloat(src[first]);
+    float high = first + 1u < uint(src.length()) ? uintBitsToFloat(src[first + 1u]) : 0.0;
+    dst[index] = packHalf2x16(vec2(low, high));
+}
+";
+
+const CONVERT_F16_TO_F32: &str = "
+void main() {
+    uint index = element_index();
+    if (index >= uint(dst.length()) || index / 2u >= uint(src.length())) {
+        return;
+    }
+
+    vec2 halves = unpackHalf2x16(src[index / 2u]);
+    dst[index] = floatBitsToUint((index & 1u) == 0u ? halves.x : halves.y);
+}
+";
+
+const CONVERT_I32_TO_F32: &str = "
+void main() {
+    uint index = element_index();
+    if (index >= uint(dst.length()) || index >= uint(src.length())) {
+        return;
+    }
+
+    dst[index] = floatBitsToUint(float(int(src[index])));
+}
+";
+
+const CONVERT_F32_TO_I32: &str = "
+void main() {
+    uint index = element_index();
+    if (index >= uint(dst.length()) || index >= uint(src.length())) {
+        return;
+    }
+
+    dst[index] = uint(int(uintBitsToFloat(src[index])));
+}
+";
+
+const STRIDED_COPY: &str = "
+layout(set = 0, binding = 2) readonly buffer buf_params {
+    uint src_offset;
+    uint src_stride;
+    uint dst_offset;
+    uint dst_stride;
+    uint count;
+};
+
+void main() {
+    uint index = element_index();
+    if (index >= count) {
+        return;
+    }
+
+    uint from = src_offset + index * src_stride;
+    uint to = dst_offset + index * dst_stride;
+    if (from < uint(src.length()) && to < uint(dst.length())) {
+        dst[to] = src[from];
+    }
+}
+";
+
+const REDUCE_SUM_DEFINES: &str = "
+#define COMBINE(a, b) ((a) + (b))
+#define IDENTITY 0.0
+";
+
+const REDUCE_MAX_DEFINES: &str = "
+#define COMBINE(a, b) max(a, b)
+#define IDENTITY uintBitsToFloat(0xff800000u)
+";
+
+const REDUCE_MIN_DEFINES: &str = "
+#define COMBINE(a, b) min(a, b)
+#define IDENTITY uintBitsToFloat(0x7f800000u)
+";
+
+const REDUCE_COMMON: &str = "
+const uint ELEMENTS_PER_INVOCATION = 16u;
+
+shared float group_values[64];
+
+// Combines the group's values in a fixed pairwise tree
+float reduce_group(float value) {
+    uint invocation = gl_LocalInvocationID.x;
+    group_values[invocation] = value;
+    barrier();
+    for (uint width = 32u; width > 0u; width /= 2u) {
+        if (invocation < width) {
+            group_values[invocation] = COMBINE(group_values[invocation], group_values[invocation + width]);
+        }
+        barrier();
+    }
+    return group_values[0];
+}
+
+// Each invocation combines a fixed run of consecutive elements before the group's tree
+float reduce_chunk() {
+    uint first = element_index() * ELEMENTS_PER_INVOCATION;
+    uint last = min(first + ELEMENTS_PER_INVOCATION, uint(src.length()));
+    float value = IDENTITY;
+    for (uint i = first; i < last; i++) {
+        value = COMBINE(value, uintBitsToFloat(src[i]));
+    }
+    return reduce_group(value);
+}
+";
+
+const REDUCE_UNORDERED: &str = "
+void main() {
+    float partial = reduce_chunk();
+    if (gl_LocalInvocationID.x != 0u) {
+        return;
+    }
+
+    uint expected = dst[0];
+    while (true) {
+        uint combined = floatBitsToUint(COMBINE(uintBitsToFloat(expected), partial));
+        uint found = atomicCompSwap(dst[0], expected, combined);
+        if (found == expected) {
+            break;
+        }
+        expected = found;
+    }
+}
+";
+
+const REDUCE_DETERMINISTIC: &str = "
+layout(set = 0, binding = 2) coherent buffer buf_scratch {
+    uint finished_groups;
+    uint partials[];
+};
+
+shared bool last_group;
+
+void main() {
+    float partial = reduce_chunk();
+    uint group = gl_WorkGroupID.x + gl_WorkGroupID.y * gl_NumWorkGroups.x;
+    uint group_count = gl_NumWorkGroups.x * gl_NumWorkGroups.y;
+    if (gl_LocalInvocationID.x == 0u) {
+        partials[group] = floatBitsToUint(partial);
+        memoryBarrierBuffer();
+        last_group = atomicAdd(finished_groups, 1u) == group_count - 1u;
+    }
+    barrier();
+    if (!last_group) {
+        return;
+    }
+    memoryBarrierBuffer();
+
+    // Partials are combined in group order, whichever group finished last
+    uint per_invocation = (group_count + 63u) / 64u;
+    uint first = gl_LocalInvocationID.x * per_invocation;
+    uint last = min(first + per_invocation, group_count);
+    float value = IDENTITY;
+    for (uint i = first; i < last; i++) {
+        value = COMBINE(value, uintBitsToFloat(partials[i]));
+    }
+    float total = reduce_group(value);
+    if (gl_LocalInvocationID.x == 0u) {
+        dst[0] = floatBitsToUint(total);
+    }
+}
+";
@@ -0,0 +1,313 @@
+use ash::vk::{
+    self, AccessFlags, BufferCopy, BufferUsageFlags, CommandBuffer, CommandBufferUsageFlags, Fence,
+    PipelineStageFlags,
+};
+
+use super::{
+    allocation_strategy::Buffer,
+    barrier::{self, Barrier},
+    command_buffer_util,
+    gpu_task::{free_buffer, GPUTask},
+    resource_state::ResourceStates,
+    ComputeManager, Tensor,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChunkedReadbackError {
+    TensorNotBound(u32),
+    BufferAllocationFailure,
+    CommandBufferRecordingFailure,
+    TaskSubmissionFailure,
+    TaskExecutionFailure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadbackProgress {
+    pub bytes_copied: u64,
+    pub total_bytes: u64,
+    pub chunks_completed: u32,
+    pub chunk_count: u32,
+}
+
+// Two slots let the device fill one chunk while the host copies out the other
+const SLOT_COUNT: usize = 2;
+
+struct ChunkSlot {
+    buffer: Buffer,
+    command_buffer: CommandBuffer,
+    in_flight: Option<(Fence, u64, u64)>,
+}
+
+impl ComputeManager {
+    /// Reads the device contents of `tensor` through bounded host-visible chunks instead of a
+    /// full-size readback buffer. The task must not be running.
+    pub fn readback_chunked<F>(
+        &self,
+        task: &GPUTask,
+        tensor: &mut Tensor,
+        chunk_bytes: u64,
+        mut on_progress: F,
+    ) -> Result<(), ChunkedReadbackError>
+    where
+        F: FnMut(ReadbackProgress),
+    {
+        let ((source, source_offset), total_bytes) = match (
+            task.device_range(tensor.id()),
+            task.buffer_size(tensor.id()),
+        ) {
+            (Some(b), Some(s)) => (b, s),
+            _ => return Err(ChunkedReadbackError::TensorNotBound(tensor.id())),
+        };
+        if total_bytes == 0 {
+            return Ok(());
+        }
+
+        // Keep chunks whole floats
+        let chunk_bytes = (chunk_bytes.max(4) & !3).min(total_bytes);
+        let chunk_count = total_bytes.div_ceil(chunk_bytes) as u32;
+
+        let mut slots = Vec::with_capacity(SLOT_COUNT);
+        let mut result = self.allocate_chunk_slots(&mut slots, chunk_bytes, chunk_count);
+        if result.is_ok() {
+            result = self.copy_chunks(
+                &mut slots,
+                (source, source_offset),
+                tensor,
+                total_bytes,
+                chunk_bytes,
+                chunk_count,
+                &mut on_progress,
+            );
+        }
+
+        self.free_chunk_slots(slots);
+        if result.is_ok() {
+            tensor.apply_readback_transform();
+        }
+
+        result
+    }
+
+    fn allocate_chunk_slots(
+        &self,
+        slots: &mut Vec<ChunkSlot>,
+        chunk_bytes: u64,
+        chunk_count: u32,
+    ) -> Result<(), ChunkedReadbackError> {
+        let mut allocator = match self.allocator.write() {
+            Ok(a) => a,
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                return Err(ChunkedReadbackError::BufferAllocationFailure);
+            }
+        };
+
+        for _ in 0..SLOT_COUNT.min(chunk_count as usize) {
+            let buffer = match allocator.allocate_buffer(
+                &self.device_info,
+                chunk_bytes,
+                BufferUsageFlags::TRANSFER_DST,
+                gpu_allocator::MemoryLocation::GpuToCpu,
+                "chunked_readback_alloc",
+                self.device_info.queue_indices.compute_queue.unwrap(),
+            ) {
+                Ok(b) => b,
+                Err(e) => {
+                    log::error!("Failed to allocate buffer! Error: {:?}", e);
+                    return Err(ChunkedReadbackError::BufferAllocationFailure);
+                }
+            };
+
+            let command_buffer =
+                match command_buffer_util::allocate_command_buffer(&self.device_info) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log::error!("Failed to allocate command buffer! Error: {}", e);
+                        free_buffer(&self.device_info, &mut allocator, buffer);
+                        return Err(ChunkedReadbackError::CommandBufferRecordingFailure);
+                    }
+                };
+
+            slots.push(ChunkSlot {
+                buffer,
+                command_buffer,
+                in_flight: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn copy_chunks<F>(
+        &self,
+        slots: &mut [ChunkSlot],
+        // The device buffer and the tensor's offset in it
+        source: (vk::Buffer, u64),
+        tensor: &mut Tensor,
+        total_bytes: u64,
+        chunk_bytes: u64,
+        chunk_count: u32,
+        on_progress: &mut F,
+    ) -> Result<(), ChunkedReadbackError>
+    where
+        F: FnMut(ReadbackProgress),
+    {
+        let mut progress = ReadbackProgress {
+            bytes_copied: 0,
+            total_bytes,
+            chunks_completed: 0,
+            chunk_count,
+        };
+
+        for chunk in 0..chunk_count {
+            let slot = &mut slots[chunk as usize % slots.len()];
+            self.finish_chunk(slot, tensor, &mut progress, on_progress)?;
+
+            let offset = chunk as u64 * chunk_bytes;
+            let size = chunk_bytes.min(total_bytes - offset);
+            let fence = self.submit_chunk(slot, source.0, source.1 + offset, size)?;
+            slot.in_flight = Some((fence, offset, size));
+        }
+
+        // Drain the remaining chunks in submission order
+        for chunk in chunk_count..chunk_count + slots.len() as u32 {
+            let slot = &mut slots[chunk as usize % slots.len()];
+            self.finish_chunk(slot, tensor, &mut progress, on_progress)?;
+        }
+
+        Ok(())
+    }
+
+    fn submit_chunk(
+        &self,
+        slot: &ChunkSlot,
+        source: vk::Buffer,
+        offset: u64,
+        size: u64,
+    ) -> Result<Fence, ChunkedReadbackError> {
+        let device = &self.device_info.device;
+
+        if let Err(e) = command_buffer_util::begin_command_buffer_recording(
+            device,
+            slot.command_buffer,
+            CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        ) {
+            log::error!("Failed to begin command buffer recording! Error: {}", e);
+            return Err(ChunkedReadbackError::CommandBufferRecordingFailure);
+        }
+
+        let mut states = ResourceStates::new();
+        let barriers: Vec<Barrier> = states
+            .access(
+                source,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_READ,
+            )
+            .into_iter()
+            .chain(states.access(
+                slot.buffer.buffer,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_WRITE,
+            ))
+            .collect();
+        barrier::cmd_barriers(&self.device_info, slot.command_buffer, &barriers);
+
+        unsafe {
+            device.cmd_copy_buffer(
+                slot.command_buffer,
+                source,
+                slot.buffer.buffer,
+                &[BufferCopy {
+                    src_offset: offset,
+                    dst_offset: 0,
+                    size,
+                }],
+            );
+        }
+
+        let host_barriers: Vec<Barrier> = states
+            .access(
+                slot.buffer.buffer,
+                PipelineStageFlags::HOST,
+                AccessFlags::HOST_READ,
+            )
+            .into_iter()
+            .collect();
+        barrier::cmd_barriers(&self.device_info, slot.command_buffer, &host_barriers);
+
+        if let Err(e) = unsafe { device.end_command_buffer(slot.command_buffer) } {
+            log::error!("Failed to end command buffer recording! Error: {}", e);
+            return Err(ChunkedReadbackError::CommandBufferRecordingFailure);
+        }
+
+        match self.submission_thread.submit(&[slot.command_buffer]) {
+            Ok(f) => Ok(f),
+            Err(e) => {
+                log::error!("Failed to submit command buffer! Error: {}", e);
+                Err(ChunkedReadbackError::TaskSubmissionFailure)
+            }
+        }
+    }
+
+    fn finish_chunk<F>(
+        &self,
+        slot: &mut ChunkSlot,
+        tensor: &mut Tensor,
+        progress: &mut ReadbackProgress,
+        on_progress: &mut F,
+    ) -> Result<(), ChunkedReadbackError>
+    where
+        F: FnMut(ReadbackProgress),
+    {
+        let (fence, offset, size) = match slot.in_flight.take() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        let result = unsafe {
+            let result = self
+                .device_info
+                .device
+                .wait_for_fences(&[fence], true, u64::MAX);
+            self.submission_thread.destroy_fence(fence);
+            result
+        };
+        if let Err(e) = result {
+            log::error!("Failed to wait for readback chunk! Error: {}", e);
+            return Err(ChunkedReadbackError::TaskExecutionFailure);
+        }
+
+        unsafe {
+            let mapped_ptr = slot.buffer.mapped_ptr().unwrap().as_ptr() as *const u8;
+            (tensor.data_mut().as_mut_ptr() as *mut u8)
+                .add(offset as usize)
+                .copy_from(mapped_ptr, size as usize);
+        }
+
+        progress.bytes_copied += size;
+        progress.chunks_completed += 1;
+        on_progress(*progress);
+
+        Ok(())
+    }
+
+    fn free_chunk_slots(&self, slots: Vec<ChunkSlot>) {
+        let device = &self.device_info.device;
+
+        slots.iter().for_each(|slot| unsafe {
+            if let Some((fence, _, _)) = slot.in_flight {
+                let _ = device.wait_for_fences(&[fence], true, u64::MAX);
+                self.submission_thread.destroy_fence(fence);
+            }
+            command_buffer_util::free_command_buffers(&self.device_info, &[slot.command_buffer]);
+        });
+
+        match self.allocator.write() {
+            Ok(mut allocator) => slots.into_iter().for_each(|slot| {
+                free_buffer(&self.device_info, &mut allocator, slot.buffer);
+            }),
+            Err(e) => log::error!("Failed to acquire allocator! Error: {e}"),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use ash::vk::DescriptorType;
+
+use super::{
+    kernel_assert::KERNEL_ASSERT_BINDING, progress::KERNEL_PROGRESS_BINDING, spirv_reflect,
+};
+
+// Names GLSL allows that Rust doesn't as identifiers
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof", "unsafe",
+    "unsized", "use", "virtual", "where",
+];
+
+#[derive(Debug, Clone)]
+pub enum CodegenError {
+    Io(String),
+    CompilationFailure(String),
+    /// Pipelines have no push constant ranges, so shaders declaring them can't be bound
+    PushConstantsUnsupported,
+    /// Only storage buffers in set 0 can be bound to tensors
+    UnsupportedDescriptor {
+        set: u32,
+        binding: u32,
+    },
+    /// Tensors are bound at 0 up to the binding count, so the shader can't skip one
+    MissingBinding(u32),
+}
+
+/// Generates Rust source for a struct named `struct_name` whose constructor takes one tensor per
+/// storage buffer of the GLSL compute shader `glsl`, named after the buffer and in binding
+/// order, with a `bind_*` method to replace each. Tasks built from it can't bind too few or too
+/// many tensors, and its `pipeline` is built from the same source, so a shader change that moves
+/// bindings fails to compile downstream instead of reading the wrong buffers.
+pub fn kernel_interface(glsl: &str, struct_name: &str) -> Result<String, CodegenError> {
+    let compiler = shaderc::Compiler::new().unwrap();
+    let spirv = match compiler.compile_into_spirv(
+        glsl,
+        shaderc::ShaderKind::Compute,
+        struct_name,
+        "main",
+        None,
+    ) {
+        Ok(r) => r,
+        Err(e) => return Err(CodegenError::CompilationFailure(e.to_string())),
+    };
+
+    interface_from_spirv(spirv.as_binary(), glsl, struct_name)
+}
+
+fn interface_from_spirv(
+    spirv: &[u32],
+    glsl: &str,
+    struct_name: &str,
+) -> Result<String, CodegenError> {
+    let reflection = spirv_reflect::reflect(spirv);
+    if reflection.push_constants {
+        return Err(CodegenError::PushConstantsUnsupported);
+    }
+
+    // Counters and asserts are bound by the crate itself
+    let tensor_bindings: Vec<_> = reflection
+        .descriptor_bindings
+        .iter()
+        .filter(|b| {
+            b.set != 0 || ![KERNEL_ASSERT_BINDING, KERNEL_PROGRESS_BINDING].contains(&b.binding)
+        })
+        .collect();
+    for (index, b) in tensor_bindings.iter().enumerate() {
+        if b.set != 0 || b.descriptor_type != DescriptorType::STORAGE_BUFFER {
+            return Err(CodegenError::UnsupportedDescriptor {
+                set: b.set,
+                binding: b.binding,
+            });
+        }
+        if b.binding != index as u32 {
+            return Err(CodegenError::MissingBinding(index as u32));
+        }
+    }
+
+    let mut used = HashSet::new();
+    let fields: Vec<(u32, String, String)> = tensor_bindings
+        .iter()
+        .map(|b| {
+            let glsl_name = reflection
+                .binding_names
+                .get(&(0, b.binding))
+                .cloned()
+                .unwrap_or_default();
+            let mut field = rust_identifier(&glsl_name);
+            if field.is_empty() || !used.insert(field.clone()) {
+                field = format!("binding_{}", b.binding);
+                used.insert(field.clone());
+            }
+            (b.binding, field, glsl_name)
+        })
+        .collect();
+
+    Ok(render(glsl, struct_name, &fields))
+}
+
+/// For build scripts: generates the interface of the shader at `glsl_path` into `out_path`, to be
+/// pulled in with `include!`, and has cargo rerun the script when the shader changes
+pub fn write_kernel_interface(
+    glsl_path: &Path,
+    struct_name: &str,
+    out_path: &Path,
+) -> Result<(), CodegenError> {
+    println!("cargo:rerun-if-changed={}", glsl_path.display());
+
+    let glsl = fs::read_to_string(glsl_path).map_err(|e| CodegenError::Io(e.to_string()))?;
+    let interface = kernel_interface(&glsl, struct_name)?;
+    fs::write(out_path, interface).map_err(|e| CodegenError::Io(e.to_string()))
+}
+
+// Lowercases the name and replaces anything that isn't valid in a Rust identifier
+fn rust_identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    }
+    if RUST_KEYWORDS.contains(&identifier.as_str()) {
+        identifier.push('_');
+    }
+    identifier
+}
+
+fn render(glsl: &str, struct_name: &str, fields: &[(u32, String, String)]) -> String {
+    let mut code = String::new();
+    let mut line = |text: String| {
+        code.push_str(&text);
+        code.push('\n');
+    };
+
+    line("// Generated by gauss::codegen::kernel_interface. Don't edit.".to_string());
+    line(String::new());
+    line(format!("/// Tensor bindings of the `{struct_name}` kernel"));
+    line(format!("pub struct {struct_name}<'a> {{"));
+    fields.iter().for_each(|(_, field, _)| {
+        line(format!("    {field}: &'a gauss::Tensor,"));
+    });
+    if fields.is_empty() {
+        line("    _tensors: std::marker::PhantomData<&'a gauss::Tensor>,".to_string());
+    }
+    line("}".to_string());
+    line(String::new());
+
+    line(format!("impl<'a> {struct_name}<'a> {{"));
+    line(format!("    pub const SOURCE: &'static str = {glsl:?};"));
+    line(format!(
+        "    pub const BINDING_COUNT: u32 = {};",
+        fields.len()
+    ));
+    line(String::new());
+
+    let parameters: Vec<String> = fields
+        .iter()
+        .map(|(_, field, _)| format!("{field}: &'a gauss::Tensor"))
+        .collect();
+    line(format!(
+        "    pub fn new({}) -> Self {{",
+        parameters.join(", ")
+    ));
+    if fields.is_empty() {
+        line("        Self { _tensors: std::marker::PhantomData }".to_string());
+    } else {
+        let names: Vec<&str> = fields.iter().map(|(_, field, _)| field.as_str()).collect();
+        line(format!("        Self {{ {} }}", names.join(", ")));
+    }
+    line("    }".to_string());
+
+    fields.iter().for_each(|(binding, field, glsl_name)| {
+        line(String::new());
+        line(format!(
+            "    /// Binding {binding}, `{glsl_name}` in the shader"
+        ));
+        line(format!(
+            "    pub fn bind_{}(mut self, tensor: &'a gauss::Tensor) -> Self {{",
+            field.trim_end_matches('_')
+        ));
+        line(format!("        self.{field} = tensor;"));
+        line("        self".to_string());
+        line("    }".to_string());
+    });
+
+    line(String::new());
+    line("    /// The tensors in binding order, for `ComputeManager::new_task`".to_string());
+    line("    pub fn bindings(&self) -> Vec<&'a gauss::Tensor> {".to_string());
+    let names: Vec<String> = fields
+        .iter()
+        .map(|(_, field, _)| format!("self.{field}"))
+        .collect();
+    line(format!("        vec![{}]", names.join(", ")));
+    line("    }".to_string());
+    line(String::new());
+
+    line("    pub fn pipeline(".to_string());
+    line("        gpu: std::sync::Arc<gauss::ComputeManager>,".to_string());
+    line(
+        "    ) -> Result<std::sync::Arc<gauss::Pipeline>, gauss::PipelineCreateError> {"
+            .to_string(),
+    );
+    line(format!(
+        "        gpu.get_or_build_pipeline(gauss::ShaderSource::Glsl(Self::SOURCE), {struct_name:?}, Self::BINDING_COUNT)"
+    ));
+    line("    }".to_string());
+    line("}".to_string());
+
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the SPIR-V spec
+    const OP_NAME: u32 = 5;
+    const OP_TYPE_FLOAT: u32 = 22;
+    const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+    const OP_TYPE_STRUCT: u32 = 30;
+    const OP_TYPE_POINTER: u32 = 32;
+    const OP_VARIABLE: u32 = 59;
+    const OP_DECORATE: u32 = 71;
+    const DECORATION_BINDING: u32 = 33;
+    const DECORATION_DESCRIPTOR_SET: u32 = 34;
+    const STORAGE_CLASS_UNIFORM: u32 = 2;
+    const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+    const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+    fn op(opcode: u32, operands: &[u32]) -> Vec<u32> {
+        let mut words = vec![((operands.len() as u32 + 1) << 16) | opcode];
+        words.extend(operands);
+        words
+    }
+
+    fn name(id: u32, name: &str) -> Vec<u32> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(bytes.len() / 4 * 4 + 4, 0);
+        let mut operands = vec![id];
+        operands.extend(
+            bytes
+                .chunks(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])),
+        );
+        op(OP_NAME, &operands)
+    }
+
+    // Storage buffer variables with the given (id, binding, name), a float array block each
+    fn module(buffers: &[(u32, u32, &str)], extra: &[Vec<u32>]) -> Vec<u32> {
+        let mut words = vec![0x0723_0203, 0x0001_0300, 0, 64, 0];
+        let mut instructions = vec![
+            op(OP_TYPE_FLOAT, &[2, 32]),
+            op(OP_TYPE_RUNTIME_ARRAY, &[3, 2]),
+            op(OP_TYPE_STRUCT, &[4, 3]),
+            op(OP_TYPE_POINTER, &[5, STORAGE_CLASS_STORAGE_BUFFER, 4]),
+        ];
+        buffers.iter().for_each(|(id, binding, buffer_name)| {
+            instructions.push(name(*id, buffer_name));
+            instructions.push(op(OP_DECORATE, &[*id, DECORATION_BINDING, *binding]));
+            instructions.push(op(OP_VARIABLE, &[5, *id, STORAGE_CLASS_STORAGE_BUFFER]));
+        });
+        instructions.extend(extra.iter().cloned());
+        instructions.iter().for_each(|i| words.extend(i));
+        words
+    }
+
+    #[test]
+    fn generates_fields_in_binding_order() {
+        let spirv = module(
+            &[
+                (11, 1, "type"),
+                (10, 0, "Input"),
+                (12, KERNEL_ASSERT_BINDING, "asserts"),
+            ],
+            &[],
+        );
+        let code = interface_from_spirv(&spirv, "void main() {}", "Kernel").unwrap();
+
+        assert!(code.contains("pub struct Kernel<'a> {"));
+        assert!(code.contains("pub const BINDING_COUNT: u32 = 2;"));
+        assert!(code.contains("pub fn new(input: &'a gauss::Tensor, type_: &'a gauss::Tensor)"));
+        assert!(code.contains("pub fn bind_type(mut self"));
+        assert!(code.contains("vec![self.input, self.type_]"));
+        assert!(!code.contains("asserts"));
+    }
+
+    #[test]
+    fn names_clashing_and_unnamed_bindings_by_number() {
+        let spirv = module(&[(10, 0, "data"), (11, 1, "data"), (12, 2, "")], &[]);
+        let code = interface_from_spirv(&spirv, "", "Kernel").unwrap();
+        assert!(code.contains("vec![self.data, self.binding_1, self.binding_2]"));
+    }
+
+    #[test]
+    fn rejects_unbindable_shaders() {
+        let push_constants = module(
+            &[(10, 0, "data")],
+            &[
+                op(OP_TYPE_POINTER, &[6, STORAGE_CLASS_PUSH_CONSTANT, 4]),
+                op(OP_VARIABLE, &[6, 20, STORAGE_CLASS_PUSH_CONSTANT]),
+            ],
+        );
+        assert!(matches!(
+            interface_from_spirv(&push_constants, "", "Kernel"),
+            Err(CodegenError::PushConstantsUnsupported)
+        ));
+
+        let other_set = module(
+            &[(10, 0, "data")],
+            &[op(OP_DECORATE, &[10, DECORATION_DESCRIPTOR_SET, 1])],
+        );
+        assert!(matches!(
+            interface_from_spirv(&other_set, "", "Kernel"),
+            Err(CodegenError::UnsupportedDescriptor { set: 1, binding: 0 })
+        ));
+
+        let uniform = module(
+            &[],
+            &[
+                op(OP_TYPE_POINTER, &[6, STORAGE_CLASS_UNIFORM, 4]),
+                op(OP_DECORATE, &[20, DECORATION_BINDING, 0]),
+                op(OP_VARIABLE, &[6, 20, STORAGE_CLASS_UNIFORM]),
+            ],
+        );
+        assert!(matches!(
+            interface_from_spirv(&uniform, "", "Kernel"),
+            Err(CodegenError::UnsupportedDescriptor { set: 0, binding: 0 })
+        ));
+
+        let gap = module(&[(10, 0, "a"), (11, 2, "b")], &[]);
+        assert!(matches!(
+            interface_from_spirv(&gap, "", "Kernel"),
+            Err(CodegenError::MissingBinding(1))
+        ));
+    }
+
+    #[test]
+    fn handles_truncated_and_corrupt_spirv() {
+        let spirv = module(&[(10, 0, "a"), (11, 1, "b")], &[]);
+
+        // Whatever survives the cut is still a valid interface, or a gap is reported
+        for len in 0..spirv.len() {
+            match interface_from_spirv(&spirv[..len], "", "Kernel") {
+                Ok(code) => assert!(code.contains("pub struct Kernel<'a> {")),
+                Err(e) => assert!(matches!(e, CodegenError::MissingBinding(_)), "{:?}", e),
+            }
+        }
+
+        let mut wrong_magic = spirv.clone();
+        wrong_magic[0] = 0;
+        let code = interface_from_spirv(&wrong_magic, "", "Kernel").unwrap();
+        assert!(code.contains("pub const BINDING_COUNT: u32 = 0;"));
+    }
+
+    #[test]
+    fn escapes_rust_identifiers() {
+        assert_eq!(rust_identifier("inputData"), "inputdata");
+        assert_eq!(rust_identifier("buf-out.x"), "buf_out_x");
+        assert_eq!(rust_identifier("2d"), "_2d");
+        assert_eq!(rust_identifier("match"), "match_");
+        assert_eq!(rust_identifier(""), "");
+    }
+}
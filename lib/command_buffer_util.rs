@@ -3,82 +3,229 @@ use std::ptr;
 use ash::{
     prelude::VkResult,
     vk::{
-        CommandBuffer, CommandBufferAllocateInfo, CommandBufferBeginInfo, CommandBufferLevel,
-        CommandBufferUsageFlags, CommandPool, Fence, FenceCreateFlags, FenceCreateInfo, Queue,
-        StructureType, SubmitInfo,
+        self, CommandBuffer, CommandBufferAllocateInfo, CommandBufferBeginInfo, CommandBufferLevel,
+        CommandBufferSubmitInfo, CommandBufferUsageFlags, CommandPoolCreateFlags,
+        CommandPoolCreateInfo, Fence, FenceCreateFlags, FenceCreateInfo, PipelineStageFlags2,
+        Queue, Semaphore, SemaphoreSubmitInfo, StructureType, SubmitFlags, SubmitInfo, SubmitInfo2,
     },
     Device,
 };
 
-pub fn allocate_command_buffer(device: &Device, pool: CommandPool) -> VkResult<CommandBuffer> {
+use super::{device::DeviceInfo, object_budget::VulkanObjectKind};
+
+// Every command buffer gets a pool of its own. Recording into a command buffer needs its pool
+// synchronized as much as allocating and freeing does, and tasks are recorded, submitted and
+// dropped on any thread, so a shared pool would need a lock around every recording. Free with
+// `free_command_buffers` so the pool goes with it and the buffer is uncounted.
+pub fn allocate_command_buffer(device_info: &DeviceInfo) -> VkResult<CommandBuffer> {
+    let counts = &device_info.object_counts;
+    counts.reserve(VulkanObjectKind::CommandBuffer, 1)?;
+    let command_buffer = unsafe { allocate_pooled_command_buffer(device_info) };
+    if command_buffer.is_err() {
+        counts.release(VulkanObjectKind::CommandBuffer, 1);
+    }
+
+    command_buffer
+}
+
+unsafe fn allocate_pooled_command_buffer(device_info: &DeviceInfo) -> VkResult<CommandBuffer> {
+    let device = &device_info.device;
+    let command_pool_create_info = CommandPoolCreateInfo {
+        s_type: StructureType::COMMAND_POOL_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: CommandPoolCreateFlags::empty(),
+        queue_family_index: device_info.queue_indices.compute_queue.unwrap(),
+    };
+    let command_pool = device.create_command_pool(&command_pool_create_info, None)?;
+
     let command_buffer_allocation_info = CommandBufferAllocateInfo {
         s_type: StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
         p_next: ptr::null(),
-        command_pool: pool,
+        command_pool,
         level: CommandBufferLevel::PRIMARY,
         command_buffer_count: 1,
     };
+    let command_buffer = match device.allocate_command_buffers(&command_buffer_allocation_info) {
+        Ok(c) => c[0],
+        Err(e) => {
+            device.destroy_command_pool(command_pool, None);
+            return Err(e);
+        }
+    };
 
-    unsafe {
-        match device.allocate_command_buffers(&command_buffer_allocation_info) {
-            Ok(c) => Ok(c[0]),
-            Err(e) => Err(e),
+    match device_info.command_pools.lock() {
+        Ok(mut command_pools) => {
+            command_pools.insert(command_buffer, command_pool);
+            Ok(command_buffer)
+        }
+        Err(e) => {
+            log::error!("Failed to acquire command pools! Error: {e}");
+            device.destroy_command_pool(command_pool, None);
+            Err(vk::Result::ERROR_UNKNOWN)
         }
     }
 }
 
+pub fn free_command_buffers(device_info: &DeviceInfo, command_buffers: &[CommandBuffer]) {
+    // Destroying the pool frees the command buffer along with it
+    match device_info.command_pools.lock() {
+        Ok(mut command_pools) => command_buffers
+            .iter()
+            .filter_map(|c| command_pools.remove(c))
+            .for_each(|p| unsafe { device_info.device.destroy_command_pool(p, None) }),
+        Err(e) => log::error!("Failed to acquire command pools! Error: {e}"),
+    }
+    device_info
+        .object_counts
+        .release(VulkanObjectKind::CommandBuffer, command_buffers.len());
+}
+
+// For the pools of command buffers still around once the device is idle
+pub fn destroy_command_pools(device_info: &DeviceInfo) {
+    match device_info.command_pools.lock() {
+        Ok(mut command_pools) => command_pools
+            .drain()
+            .for_each(|(_, p)| unsafe { device_info.device.destroy_command_pool(p, None) }),
+        Err(e) => log::error!("Failed to acquire command pools! Error: {e}"),
+    }
+}
+
+// For fences created by `create_fence` or `submit_command_buffers`
+pub fn destroy_fence(device_info: &DeviceInfo, fence: Fence) {
+    unsafe { device_info.device.destroy_fence(fence, None) };
+    device_info
+        .object_counts
+        .release(VulkanObjectKind::Fence, 1);
+}
+
 pub fn begin_command_buffer_recording(
     device: &Device,
     command_buffer: CommandBuffer,
-    single_use: bool,
+    usage: CommandBufferUsageFlags,
 ) -> VkResult<()> {
     let begin_info = CommandBufferBeginInfo {
         s_type: StructureType::COMMAND_BUFFER_BEGIN_INFO,
         p_next: ptr::null(),
-        flags: if single_use {
-            CommandBufferUsageFlags::ONE_TIME_SUBMIT
-        } else {
-            CommandBufferUsageFlags::empty()
-        },
+        flags: usage,
         p_inheritance_info: ptr::null(),
     };
 
     unsafe { device.begin_command_buffer(command_buffer, &begin_info) }
 }
 
-pub fn end_and_submit_command_buffer(
-    device: &Device,
-    command_buffer: CommandBuffer,
-    dst_queue: Queue,
+pub fn create_fence(device_info: &DeviceInfo) -> VkResult<Fence> {
+    let fence_create_info = FenceCreateInfo {
+        s_type: StructureType::FENCE_CREATE_INFO,
+        p_next: ptr::null(),
+        flags: FenceCreateFlags::empty(),
+    };
+
+    device_info
+        .object_counts
+        .reserve(VulkanObjectKind::Fence, 1)?;
+    match unsafe { device_info.device.create_fence(&fence_create_info, None) } {
+        Ok(f) => Ok(f),
+        Err(e) => {
+            device_info
+                .object_counts
+                .release(VulkanObjectKind::Fence, 1);
+            Err(e)
+        }
+    }
+}
+
+// Submits through vkQueueSubmit2 when synchronization2 is enabled. Every batch of command buffers
+// signals its own semaphores, while the fence signals once all batches have finished.
+pub fn submit_command_buffers(
+    device_info: &DeviceInfo,
+    queue: Queue,
+    batches: &[(&[CommandBuffer], &[Semaphore])],
 ) -> VkResult<Fence> {
+    let fence = create_fence(device_info)?;
+    match submit_command_buffers_with_fence(device_info, queue, batches, fence) {
+        Ok(_) => Ok(fence),
+        Err(e) => {
+            destroy_fence(device_info, fence);
+            Err(e)
+        }
+    }
+}
+
+// Like `submit_command_buffers`, with a fence created beforehand
+pub fn submit_command_buffers_with_fence(
+    device_info: &DeviceInfo,
+    queue: Queue,
+    batches: &[(&[CommandBuffer], &[Semaphore])],
+    fence: Fence,
+) -> VkResult<()> {
+    let device = &device_info.device;
+
     unsafe {
-        device.end_command_buffer(command_buffer)?;
-
-        let submit_info = SubmitInfo {
-            s_type: StructureType::SUBMIT_INFO,
-            p_next: ptr::null(),
-            wait_semaphore_count: 0,
-            p_wait_semaphores: ptr::null(),
-            p_wait_dst_stage_mask: ptr::null(),
-            command_buffer_count: 1,
-            p_command_buffers: &command_buffer,
-            signal_semaphore_count: 0,
-            p_signal_semaphores: ptr::null(),
-        };
-
-        let fence_create_info = FenceCreateInfo {
-            s_type: StructureType::FENCE_CREATE_INFO,
-            p_next: ptr::null(),
-            flags: FenceCreateFlags::empty(),
-        };
-
-        let fence = device.create_fence(&fence_create_info, None)?;
-
-        match device.queue_submit(dst_queue, &[submit_info], fence) {
-            Ok(_) => Ok(fence),
-            Err(e) => {
-                device.destroy_fence(fence, None);
-                Err(e)
+        match device_info.synchronization2.as_ref() {
+            Some(synchronization2) => {
+                let command_buffer_infos: Vec<Vec<CommandBufferSubmitInfo>> = batches
+                    .iter()
+                    .map(|(command_buffers, _)| {
+                        command_buffers
+                            .iter()
+                            .map(|c| CommandBufferSubmitInfo {
+                                s_type: StructureType::COMMAND_BUFFER_SUBMIT_INFO,
+                                p_next: ptr::null(),
+                                command_buffer: *c,
+                                device_mask: 0,
+                            })
+                            .collect()
+                    })
+                    .collect();
+                let signal_semaphore_infos: Vec<Vec<SemaphoreSubmitInfo>> = batches
+                    .iter()
+                    .map(|(_, signal_semaphores)| {
+                        signal_semaphores
+                            .iter()
+                            .map(|s| SemaphoreSubmitInfo {
+                                semaphore: *s,
+                                stage_mask: PipelineStageFlags2::ALL_COMMANDS,
+                                ..Default::default()
+                            })
+                            .collect()
+                    })
+                    .collect();
+
+                let submit_infos: Vec<SubmitInfo2> = command_buffer_infos
+                    .iter()
+                    .zip(&signal_semaphore_infos)
+                    .map(|(command_buffers, signal_semaphores)| SubmitInfo2 {
+                        s_type: StructureType::SUBMIT_INFO_2,
+                        p_next: ptr::null(),
+                        flags: SubmitFlags::empty(),
+                        wait_semaphore_info_count: 0,
+                        p_wait_semaphore_infos: ptr::null(),
+                        command_buffer_info_count: command_buffers.len() as u32,
+                        p_command_buffer_infos: command_buffers.as_ptr(),
+                        signal_semaphore_info_count: signal_semaphores.len() as u32,
+                        p_signal_semaphore_infos: signal_semaphores.as_ptr(),
+                    })
+                    .collect();
+
+                synchronization2.queue_submit2(queue, &submit_infos, fence)
+            }
+            None => {
+                let submit_infos: Vec<SubmitInfo> = batches
+                    .iter()
+                    .map(|(command_buffers, signal_semaphores)| SubmitInfo {
+                        s_type: StructureType::SUBMIT_INFO,
+                        p_next: ptr::null(),
+                        wait_semaphore_count: 0,
+                        p_wait_semaphores: ptr::null(),
+                        p_wait_dst_stage_mask: ptr::null(),
+                        command_buffer_count: command_buffers.len() as u32,
+                        p_command_buffers: command_buffers.as_ptr(),
+                        signal_semaphore_count: signal_semaphores.len() as u32,
+                        p_signal_semaphores: signal_semaphores.as_ptr(),
+                    })
+                    .collect();
+
+                device.queue_submit(queue, &submit_infos, fence)
             }
         }
     }
@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    mem::ManuallyDrop,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use ash::vk::{CommandBuffer, Fence};
+
+use super::{
+    gpu_task::{GPUTask, GPUTaskInProcess, GPUTaskRecordingDiagnostic},
+    kernel_assert::KernelAssertionFailed,
+    ComputeManager, Tensor,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextQuota {
+    /// Device, staging and readback memory of all tasks the context holds. `None` is unbounded.
+    pub memory_bytes: Option<u64>,
+    pub in_flight_tasks: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ContextError {
+    MemoryQuotaExceeded {
+        requested_bytes: u64,
+        available_bytes: u64,
+    },
+    InFlightQuotaExceeded,
+    UnknownTask(ContextTaskHandle),
+    // The task's command buffer can't be resubmitted until its outstanding run is awaited or dropped
+    TaskAlreadyRunning(ContextTaskHandle),
+    RecordingFailure(Vec<GPUTaskRecordingDiagnostic>),
+    TaskSubmissionFailure,
+    ContextLockFailure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContextTaskHandle(u64);
+
+// A task of a context. Its memory is returned to the context's quota once the last owner, be it
+// the context or an outstanding run, drops it.
+pub struct ContextTask {
+    // Freed before the memory is refunded
+    task: ManuallyDrop<GPUTask>,
+    memory_bytes: u64,
+    context_memory_bytes: Arc<AtomicU64>,
+    // Set from submission until the run is awaited or dropped
+    running: AtomicBool,
+}
+
+// Groups the tasks of one tenant so their footprint can be bounded and released together. Tasks
+// are owned by the context and referred to through handles.
+pub struct ComputeContext {
+    quota: ContextQuota,
+    tasks: Mutex<HashMap<u64, Arc<ContextTask>>>,
+    next_handle: AtomicU64,
+    // Shared with the tasks, which refund their memory when dropped
+    memory_bytes: Arc<AtomicU64>,
+    // Shared with completion callbacks on the submission thread
+    in_flight: Arc<AtomicU32>,
+
+    parent: Arc<ComputeManager>,
+}
+
+// A submitted context task. Dropping it waits for the task to finish.
+pub struct ContextRun {
+    fence: Fence,
+    prologues: Vec<CommandBuffer>,
+    task: Arc<ContextTask>,
+
+    parent: Arc<ComputeManager>,
+}
+
+impl ComputeManager {
+    pub fn create_context(self: Arc<Self>, quota: ContextQuota) -> ComputeContext {
+        ComputeContext {
+            quota,
+            tasks: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(0),
+            memory_bytes: Arc::new(AtomicU64::new(0)),
+            in_flight: Arc::new(AtomicU32::new(0)),
+            parent: self,
+        }
+    }
+}
+
+impl ComputeContext {
+    /// The memory quota is checked against the task's estimate before anything is allocated
+    pub fn add_task(&self, task: GPUTaskInProcess) -> Result<ContextTaskHandle, ContextError> {
+        let estimate = task.estimate();
+        let requested_bytes = estimate.device_memory_bytes
+            + estimate.staging_memory_bytes
+            + estimate.readback_memory_bytes;
+
+        let limit = self.quota.memory_bytes.unwrap_or(u64::MAX);
+        if self
+            .memory_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(requested_bytes).filter(|b| *b <= limit)
+            })
+            .is_err()
+        {
+            return Err(ContextError::MemoryQuotaExceeded {
+                requested_bytes,
+                available_bytes: limit.saturating_sub(self.memory_bytes()),
+            });
+        }
+
+        let task = match task.finalize() {
+            Ok(t) => t,
+            Err(diagnostics) => {
+                self.memory_bytes
+                    .fetch_sub(requested_bytes, Ordering::SeqCst);
+                return Err(ContextError::RecordingFailure(diagnostics));
+            }
+        };
+
+        let handle = self.next_handle.fetch_add(1, Ordering::SeqCst);
+        match self.tasks.lock() {
+            Ok(mut tasks) => {
+                tasks.insert(
+                    handle,
+                    Arc::new(ContextTask {
+                        task: ManuallyDrop::new(task),
+                        memory_bytes: requested_bytes,
+                        context_memory_bytes: self.memory_bytes.clone(),
+                        running: AtomicBool::new(false),
+                    }),
+                );
+                Ok(ContextTaskHandle(handle))
+            }
+            Err(e) => {
+                log::error!("Failed to acquire context tasks! Error: {e}");
+                self.memory_bytes
+                    .fetch_sub(requested_bytes, Ordering::SeqCst);
+                Err(ContextError::ContextLockFailure)
+            }
+        }
+    }
+
+    pub fn exec(&self, handle: ContextTaskHandle) -> Result<ContextRun, ContextError> {
+        let task = match self.tasks.lock() {
+            Ok(tasks) => match tasks.get(&handle.0) {
+                Some(t) => t.clone(),
+                None => return Err(ContextError::UnknownTask(handle)),
+            },
+            Err(e) => {
+                log::error!("Failed to acquire context tasks! Error: {e}");
+                return Err(ContextError::ContextLockFailure);
+            }
+        };
+
+        if task.running.swap(true, Ordering::SeqCst) {
+            return Err(ContextError::TaskAlreadyRunning(handle));
+        }
+
+        let limit = self.quota.in_flight_tasks.unwrap_or(u32::MAX);
+        if self
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < limit).then_some(n + 1)
+            })
+            .is_err()
+        {
+            task.running.store(false, Ordering::SeqCst);
+            return Err(ContextError::InFlightQuotaExceeded);
+        }
+
+        // The slot is released on the submission thread as soon as the task finishes. Submissions
+        // that fail before reaching the thread never run the callback, so either side may release.
+        let released = Arc::new(AtomicBool::new(false));
+        let release = {
+            let in_flight = self.in_flight.clone();
+            let released = released.clone();
+            move || {
+                if !released.swap(true, Ordering::SeqCst) {
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        };
+        let on_complete = {
+            let release = release.clone();
+            Box::new(move |_| release())
+        };
+
+        match self
+            .parent
+            .submit_tracked(&[&task.task], &[], Some(on_complete))
+        {
+            Ok(submission) => Ok(ContextRun {
+                fence: submission.fence,
+                prologues: submission.prologues,
+                task,
+                parent: self.parent.clone(),
+            }),
+            Err(e) => {
+                log::error!("Failed to submit context task! Error: {}", e);
+                release();
+                task.running.store(false, Ordering::SeqCst);
+                Err(ContextError::TaskSubmissionFailure)
+            }
+        }
+    }
+
+    /// The task's memory is returned to the quota once no run of it is outstanding
+    pub fn remove_task(&self, handle: ContextTaskHandle) -> Result<(), ContextError> {
+        let removed = match self.tasks.lock() {
+            Ok(mut tasks) => tasks.remove(&handle.0),
+            Err(e) => {
+                log::error!("Failed to acquire context tasks! Error: {e}");
+                return Err(ContextError::ContextLockFailure);
+            }
+        };
+
+        match removed {
+            Some(_) => Ok(()),
+            None => Err(ContextError::UnknownTask(handle)),
+        }
+    }
+
+    /// Releases every task the context created. Outstanding runs keep their task alive until
+    /// they are awaited or dropped.
+    pub fn release_all(&self) {
+        match self.tasks.lock() {
+            Ok(mut tasks) => tasks.clear(),
+            Err(e) => log::error!("Failed to acquire context tasks! Error: {e}"),
+        }
+    }
+
+    /// The task stays counted against the quota while the returned reference is held
+    pub fn task(&self, handle: ContextTaskHandle) -> Option<Arc<ContextTask>> {
+        match self.tasks.lock() {
+            Ok(tasks) => tasks.get(&handle.0).cloned(),
+            Err(_) => None,
+        }
+    }
+
+    pub fn task_count(&self) -> usize {
+        self.tasks.lock().map(|t| t.len()).unwrap_or(0)
+    }
+
+    pub fn memory_bytes(&self) -> u64 {
+        self.memory_bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight_tasks(&self) -> u32 {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn quota(&self) -> ContextQuota {
+        self.quota
+    }
+}
+
+impl ContextRun {
+    fn wait(&self) {
+        unsafe {
+            let _ = self
+                .parent
+                .device_info
+                .device
+                .wait_for_fences(&[self.fence], true, u64::MAX);
+        }
+    }
+
+    /// Fails if a kernel assert failed during the run; the tensors are read back either way
+    pub fn await_run(self, sync_tensors: Vec<&mut Tensor>) -> Result<(), KernelAssertionFailed> {
+        self.wait();
+        // Failures are logged per tensor, and the rest are still read back
+        let _ = self.task.task.copy_readback(sync_tensors);
+        self.task.task.check_kernel_asserts()
+    }
+
+    pub fn task(&self) -> &GPUTask {
+        &self.task.task
+    }
+}
+
+impl ContextTask {
+    pub fn task(&self) -> &GPUTask {
+        &self.task
+    }
+}
+
+impl Drop for ContextTask {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.task) };
+        self.context_memory_bytes
+            .fetch_sub(self.memory_bytes, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ContextRun {
+    fn drop(&mut self) {
+        self.wait();
+        self.parent.submission_thread.destroy_fence(self.fence);
+        self.parent.free_prologues(&self.prologues);
+        self.task.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ComputeContext {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use ndarray::Array1;
+
+use super::{
+    gpu_task::GPUTaskRecordingDiagnostic,
+    kernel_assert::KernelAssertionFailed,
+    pipeline::{Pipeline, PipelineCreateError, ShaderSource},
+    testing::{compare_floats, FloatComparison, Tolerance},
+    ComputeManager, Tensor, WorkGroupSize,
+};
+
+/// A user kernel the crate can build and run like its own. Bindings are the inputs followed by
+/// the outputs, so ops whose binding counts match can also be dispatched in each other's tasks
+/// with `op_pipeline_dispatch_with`.
+pub trait CustomOp: Send + Sync {
+    /// Used as the shader name and for caching its pipeline
+    fn name(&self) -> &str;
+
+    fn source(&self) -> ShaderSource<'_>;
+
+    fn input_count(&self) -> u32;
+
+    fn output_count(&self) -> u32;
+
+    /// Lengths of the outputs for inputs of `input_lens` elements
+    fn output_lens(&self, input_lens: &[usize]) -> Vec<usize>;
+
+    /// The dispatch size for inputs of `input_lens` elements
+    fn work_group(&self, input_lens: &[usize]) -> WorkGroupSize;
+
+    /// Computes the outputs on the host, for checking the kernel against
+    fn reference(&self, _inputs: &[&[f32]]) -> Option<Vec<Vec<f32>>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CustomOpError {
+    PipelineCreationFailure(PipelineCreateError),
+    BindingCountMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    OutputLengthMismatch {
+        output: usize,
+        expected: usize,
+        actual: usize,
+    },
+    TaskRecordingFailure(Vec<GPUTaskRecordingDiagnostic>),
+    TaskSubmissionFailure,
+    KernelAssertionFailed(KernelAssertionFailed),
+    NoReference,
+    ReferenceMismatch(Vec<FloatComparison>),
+}
+
+impl ComputeManager {
+    pub fn custom_op_pipeline(
+        self: Arc<Self>,
+        op: &dyn CustomOp,
+    ) -> Result<Arc<Pipeline>, PipelineCreateError> {
+        self.get_or_build_pipeline(op.source(), op.name(), op.input_count() + op.output_count())
+    }
+
+    /// Runs `op` on `inputs`, writing its results into `outputs`, which must have the lengths
+    /// `CustomOp::output_lens` asks for and readback enabled
+    pub fn run_custom_op(
+        self: Arc<Self>,
+        op: &dyn CustomOp,
+        inputs: Vec<&Tensor>,
+        mut outputs: Vec<&mut Tensor>,
+    ) -> Result<(), CustomOpError> {
+        if inputs.len() as u32 != op.input_count() {
+            return Err(CustomOpError::BindingCountMismatch {
+                expected: op.input_count(),
+                actual: inputs.len() as u32,
+            });
+        }
+        if outputs.len() as u32 != op.output_count() {
+            return Err(CustomOpError::BindingCountMismatch {
+                expected: op.output_count(),
+                actual: outputs.len() as u32,
+            });
+        }
+
+        let input_lens: Vec<usize> = inputs.iter().map(|t| t.data().len()).collect();
+        let mismatch = op
+            .output_lens(&input_lens)
+            .into_iter()
+            .zip(outputs.iter())
+            .enumerate()
+            .find(|(_, (expected, t))| *expected != t.data().len());
+        if let Some((output, (expected, t))) = mismatch {
+            return Err(CustomOpError::OutputLengthMismatch {
+                output,
+                expected,
+                actual: t.data().len(),
+            });
+        }
+
+        let pipeline = self
+            .clone()
+            .custom_op_pipeline(op)
+            .map_err(CustomOpError::PipelineCreationFailure)?;
+
+        let bindings: Vec<&Tensor> = inputs
+            .iter()
+            .copied()
+            .chain(outputs.iter().map(|t| &**t))
+            .collect();
+        let task = self
+            .clone()
+            .new_task(&pipeline, bindings)
+            .op_local_sync_device(inputs.clone())
+            .op_pipeline_dispatch(op.work_group(&input_lens))
+            .op_device_sync_local(outputs.iter().map(|t| &**t).collect())
+            .finalize()
+            .map_err(CustomOpError::TaskRecordingFailure)?;
+
+        let running_task = match self.exec_task(&task) {
+            Some(r) => r,
+            None => return Err(CustomOpError::TaskSubmissionFailure),
+        };
+        self.await_task(
+            &running_task,
+            outputs.iter_mut().map(|t| &mut **t).collect(),
+        )
+        .map_err(CustomOpError::KernelAssertionFailed)
+    }
+
+    /// Runs `op` on `inputs` and compares its outputs against `CustomOp::reference`
+    pub fn check_custom_op(
+        self: Arc<Self>,
+        op: &dyn CustomOp,
+        inputs: Vec<Vec<f32>>,
+        tolerance: Tolerance,
+    ) -> Result<Vec<FloatComparison>, CustomOpError> {
+        let input_slices: Vec<&[f32]> = inputs.iter().map(|i| i.as_slice()).collect();
+        let expected = match op.reference(&input_slices) {
+            Some(e) => e,
+            None => return Err(CustomOpError::NoReference),
+        };
+
+        let input_lens: Vec<usize> = inputs.iter().map(|i| i.len()).collect();
+        let input_tensors: Vec<Tensor> = inputs
+            .into_iter()
+            .map(|data| self.create_tensor(Array1::from(data), false))
+            .collect();
+        let mut output_tensors: Vec<Tensor> = op
+            .output_lens(&input_lens)
+            .into_iter()
+            .map(|len| self.create_tensor(Array1::zeros(len), true))
+            .collect();
+
+        self.clone().run_custom_op(
+            op,
+            input_tensors.iter().collect(),
+            output_tensors.iter_mut().collect(),
+        )?;
+
+        let comparisons: Vec<FloatComparison> = expected
+            .iter()
+            .zip(output_tensors.iter())
+            .enumerate()
+            .map(|(i, (e, t))| compare_floats(i, e, &t.data().to_vec(), tolerance))
+            .collect();
+        if comparisons.iter().any(|c| c.mismatched > 0) {
+            log::error!("Custom op \"{}\" doesn't match its reference!", op.name());
+            return Err(CustomOpError::ReferenceMismatch(comparisons));
+        }
+
+        Ok(comparisons)
+    }
+}
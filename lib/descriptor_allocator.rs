@@ -0,0 +1,223 @@
+use std::{collections::HashMap, ptr};
+
+use ash::{
+    prelude::VkResult,
+    vk::{
+        self, DescriptorPool, DescriptorPoolCreateFlags, DescriptorPoolCreateInfo,
+        DescriptorPoolSize, DescriptorSet, DescriptorSetAllocateInfo, DescriptorSetLayout,
+        DescriptorType, StructureType,
+    },
+    Device,
+};
+
+use super::{
+    device::DeviceInfo,
+    object_budget::VulkanObjectKind,
+    resource_tracker::{LiveResourceKind, TrackedResource},
+    ComputeManager,
+};
+
+const INITIAL_POOL_SETS: u32 = 16;
+const MAX_POOL_SETS: u32 = 1024;
+
+struct PoolBlock {
+    pool: DescriptorPool,
+    free_sets: u32,
+    free_descriptors: HashMap<DescriptorType, u32>,
+    live_sets: u32,
+    _tracking: Option<TrackedResource>,
+}
+
+// Hands out descriptor sets from a chain of pools. A new, larger pool is appended whenever none of
+// the existing pools can fit a set, and pools are destroyed once their last set is freed.
+pub(super) struct DescriptorAllocator {
+    pools: Vec<PoolBlock>,
+    next_pool_sets: u32,
+}
+
+impl PoolBlock {
+    fn fits(&self, set_sizes: &[DescriptorPoolSize]) -> bool {
+        self.free_sets > 0
+            && set_sizes.iter().all(|size| {
+                self.free_descriptors.get(&size.ty).copied().unwrap_or(0) >= size.descriptor_count
+            })
+    }
+
+    fn take(&mut self, set_sizes: &[DescriptorPoolSize]) {
+        self.free_sets -= 1;
+        self.live_sets += 1;
+        set_sizes.iter().for_each(|size| {
+            *self.free_descriptors.entry(size.ty).or_insert(0) -= size.descriptor_count;
+        });
+    }
+
+    fn give_back(&mut self, set_sizes: &[DescriptorPoolSize]) {
+        self.free_sets += 1;
+        self.live_sets -= 1;
+        set_sizes.iter().for_each(|size| {
+            *self.free_descriptors.entry(size.ty).or_insert(0) += size.descriptor_count;
+        });
+    }
+}
+
+impl DescriptorAllocator {
+    pub(super) fn new() -> Self {
+        DescriptorAllocator {
+            pools: Vec::new(),
+            next_pool_sets: INITIAL_POOL_SETS,
+        }
+    }
+
+    pub(super) fn allocate(
+        &mut self,
+        manager: &ComputeManager,
+        layout: DescriptorSetLayout,
+        set_sizes: &[DescriptorPoolSize],
+    ) -> VkResult<(DescriptorSet, DescriptorPool)> {
+        let device = &manager.device_info.device;
+
+        for block in self.pools.iter_mut().rev() {
+            if !block.fits(set_sizes) {
+                continue;
+            }
+
+            match allocate_set(device, block.pool, layout) {
+                Ok(set) => {
+                    block.take(set_sizes);
+                    return Ok((set, block.pool));
+                }
+                Err(vk::Result::ERROR_FRAGMENTED_POOL | vk::Result::ERROR_OUT_OF_POOL_MEMORY) => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let sets = self.next_pool_sets;
+        let mut free_descriptors = HashMap::new();
+        set_sizes.iter().for_each(|size| {
+            *free_descriptors.entry(size.ty).or_insert(0) += size.descriptor_count * sets;
+        });
+
+        let mut pool_sizes: Vec<DescriptorPoolSize> = free_descriptors
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(ty, count)| DescriptorPoolSize {
+                ty: *ty,
+                descriptor_count: *count,
+            })
+            .collect();
+        if pool_sizes.is_empty() {
+            pool_sizes.push(DescriptorPoolSize {
+                ty: DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 1,
+            });
+        }
+
+        let create_info = DescriptorPoolCreateInfo {
+            s_type: StructureType::DESCRIPTOR_POOL_CREATE_INFO,
+            p_next: ptr::null(),
+            flags: DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
+            max_sets: sets,
+            pool_size_count: pool_sizes.len() as u32,
+            p_pool_sizes: pool_sizes.as_ptr(),
+        };
+
+        let counts = &manager.device_info.object_counts;
+        counts.reserve(VulkanObjectKind::DescriptorPool, 1)?;
+        let pool = match unsafe { device.create_descriptor_pool(&create_info, None) } {
+            Ok(p) => p,
+            Err(e) => {
+                counts.release(VulkanObjectKind::DescriptorPool, 1);
+                return Err(e);
+            }
+        };
+        let set = match allocate_set(device, pool, layout) {
+            Ok(s) => s,
+            Err(e) => {
+                destroy_pool(&manager.device_info, pool);
+                return Err(e);
+            }
+        };
+
+        log::trace!("Created descriptor pool with capacity for {} sets", sets);
+
+        let mut block = PoolBlock {
+            pool,
+            free_sets: sets,
+            free_descriptors,
+            live_sets: 0,
+            _tracking: manager.track_resource(LiveResourceKind::DescriptorPool, || {
+                format!("descriptor_pool{{max_sets={}}}", sets)
+            }),
+        };
+        block.take(set_sizes);
+        self.pools.push(block);
+
+        self.next_pool_sets = (sets * 2).min(MAX_POOL_SETS);
+
+        Ok((set, pool))
+    }
+
+    pub(super) fn free(
+        &mut self,
+        device_info: &DeviceInfo,
+        set: DescriptorSet,
+        pool: DescriptorPool,
+        set_sizes: &[DescriptorPoolSize],
+    ) {
+        let index = match self.pools.iter().position(|b| b.pool == pool) {
+            Some(i) => i,
+            None => {
+                log::error!(
+                    "Descriptor set was freed to an unknown pool! This is an internal issue!"
+                );
+                return;
+            }
+        };
+
+        unsafe {
+            let _ = device_info.device.free_descriptor_sets(pool, &[set]);
+        }
+
+        let block = &mut self.pools[index];
+        block.give_back(set_sizes);
+        if block.live_sets == 0 {
+            destroy_pool(device_info, pool);
+            self.pools.remove(index);
+        }
+    }
+
+    pub(super) fn destroy(&mut self, device_info: &DeviceInfo) {
+        self.pools.drain(..).for_each(|block| {
+            destroy_pool(device_info, block.pool);
+        });
+    }
+}
+
+fn destroy_pool(device_info: &DeviceInfo, pool: DescriptorPool) {
+    unsafe { device_info.device.destroy_descriptor_pool(pool, None) };
+    device_info
+        .object_counts
+        .release(VulkanObjectKind::DescriptorPool, 1);
+}
+
+fn allocate_set(
+    device: &Device,
+    pool: DescriptorPool,
+    layout: DescriptorSetLayout,
+) -> VkResult<DescriptorSet> {
+    let allocate_info = DescriptorSetAllocateInfo {
+        s_type: StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
+        p_next: ptr::null(),
+        descriptor_pool: pool,
+        descriptor_set_count: 1,
+        p_set_layouts: &layout,
+    };
+
+    unsafe {
+        device
+            .allocate_descriptor_sets(&allocate_info)
+            .map(|s| s[0])
+    }
+}
@@ -0,0 +1,196 @@
+use std::{collections::HashMap, ffi::CStr, ptr};
+
+use ash::{
+    extensions::ext::DescriptorBuffer,
+    vk::{
+        self, BufferDeviceAddressInfo, CommandBuffer, DescriptorAddressInfoEXT,
+        DescriptorBufferBindingInfoEXT, DescriptorDataEXT, DescriptorGetInfoEXT,
+        DescriptorSetLayout, DescriptorType, Format, PhysicalDevice,
+        PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceDescriptorBufferFeaturesEXT,
+        PhysicalDeviceDescriptorBufferPropertiesEXT, PhysicalDeviceFeatures2,
+        PhysicalDeviceProperties2, PipelineBindPoint, PipelineLayout, StructureType,
+    },
+    Device,
+};
+
+use super::{allocation_strategy::Buffer, device::DeviceInfo, instance::InstanceInfo};
+
+#[derive(Clone)]
+pub struct DescriptorBufferSupport {
+    pub loader: DescriptorBuffer,
+    pub offset_alignment: u64,
+    pub storage_buffer_descriptor_size: usize,
+}
+
+// Where each binding's descriptor lives inside a descriptor buffer for one set layout
+pub(super) struct DescriptorBufferLayout {
+    pub(super) size: u64,
+    pub(super) binding_offsets: HashMap<u32, u64>,
+}
+
+// Descriptor buffers need buffer device addresses, which are only core from 1.2 on
+pub(super) fn supports_descriptor_buffer(
+    instance_info: &InstanceInfo,
+    physical_device: PhysicalDevice,
+) -> bool {
+    let properties2_loader = match instance_info.physical_device_properties2_loader.as_ref() {
+        Some(l) => l,
+        None => return false,
+    };
+
+    let required_version = vk::make_api_version(0, 1, 2, 0);
+    unsafe {
+        let device_version = instance_info
+            .instance
+            .get_physical_device_properties(physical_device)
+            .api_version;
+        if instance_info.api_version < required_version || device_version < required_version {
+            return false;
+        }
+
+        let extension_available = instance_info
+            .instance
+            .enumerate_device_extension_properties(physical_device)
+            .unwrap_or_default()
+            .iter()
+            .any(|e| CStr::from_ptr(e.extension_name.as_ptr()) == DescriptorBuffer::name());
+        if !extension_available {
+            return false;
+        }
+
+        let mut descriptor_buffer_features = PhysicalDeviceDescriptorBufferFeaturesEXT::default();
+        let mut buffer_device_address_features =
+            PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let mut features2 = PhysicalDeviceFeatures2::builder()
+            .push_next(&mut descriptor_buffer_features)
+            .push_next(&mut buffer_device_address_features)
+            .build();
+        properties2_loader.get_physical_device_features2(physical_device, &mut features2);
+
+        descriptor_buffer_features.descriptor_buffer == vk::TRUE
+            && buffer_device_address_features.buffer_device_address == vk::TRUE
+    }
+}
+
+pub(super) fn load_descriptor_buffer_support(
+    instance_info: &InstanceInfo,
+    device: &Device,
+    physical_device: PhysicalDevice,
+) -> DescriptorBufferSupport {
+    let mut properties = PhysicalDeviceDescriptorBufferPropertiesEXT::default();
+    let mut properties2 = PhysicalDeviceProperties2::builder()
+        .push_next(&mut properties)
+        .build();
+    if let Some(loader) = instance_info.physical_device_properties2_loader.as_ref() {
+        unsafe { loader.get_physical_device_properties2(physical_device, &mut properties2) };
+    }
+
+    DescriptorBufferSupport {
+        loader: DescriptorBuffer::new(&instance_info.instance, device),
+        offset_alignment: properties.descriptor_buffer_offset_alignment.max(1),
+        storage_buffer_descriptor_size: properties.storage_buffer_descriptor_size,
+    }
+}
+
+impl DescriptorBufferSupport {
+    pub(super) fn layout(
+        &self,
+        descriptor_set_layout: DescriptorSetLayout,
+        bindings: impl Iterator<Item = u32>,
+    ) -> DescriptorBufferLayout {
+        unsafe {
+            let size = self
+                .loader
+                .get_descriptor_set_layout_size(descriptor_set_layout)
+                .max(1);
+
+            DescriptorBufferLayout {
+                size: size.div_ceil(self.offset_alignment) * self.offset_alignment,
+                binding_offsets: bindings
+                    .map(|binding| {
+                        let offset = self.loader.get_descriptor_set_layout_binding_offset(
+                            descriptor_set_layout,
+                            binding,
+                        );
+                        (binding, offset)
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    // `bindings` holds the binding index, buffer and range of each storage buffer
+    pub(super) fn write_storage_buffers(
+        &self,
+        device: &Device,
+        layout: &DescriptorBufferLayout,
+        descriptor_buffer: &Buffer,
+        bindings: &[(u32, vk::Buffer, u64, u64)],
+    ) {
+        let mapped_ptr = descriptor_buffer.mapped_ptr().unwrap().as_ptr() as *mut u8;
+
+        bindings
+            .iter()
+            .for_each(|(binding, buffer, offset, range)| unsafe {
+                let address_info = DescriptorAddressInfoEXT {
+                    s_type: StructureType::DESCRIPTOR_ADDRESS_INFO_EXT,
+                    p_next: ptr::null_mut(),
+                    address: buffer_address(device, *buffer) + offset,
+                    range: *range,
+                    format: Format::UNDEFINED,
+                };
+                let get_info = DescriptorGetInfoEXT {
+                    s_type: StructureType::DESCRIPTOR_GET_INFO_EXT,
+                    p_next: ptr::null(),
+                    ty: DescriptorType::STORAGE_BUFFER,
+                    data: DescriptorDataEXT {
+                        p_storage_buffer: &address_info,
+                    },
+                };
+
+                let descriptor = std::slice::from_raw_parts_mut(
+                    mapped_ptr.add(layout.binding_offsets[binding] as usize),
+                    self.storage_buffer_descriptor_size,
+                );
+                self.loader.get_descriptor(&get_info, descriptor);
+            });
+    }
+
+    pub(super) fn cmd_bind(
+        &self,
+        device_info: &DeviceInfo,
+        command_buffer: CommandBuffer,
+        pipeline_layout: PipelineLayout,
+        descriptor_buffer: &Buffer,
+    ) {
+        unsafe {
+            self.loader.cmd_bind_descriptor_buffers(
+                command_buffer,
+                &[DescriptorBufferBindingInfoEXT {
+                    s_type: StructureType::DESCRIPTOR_BUFFER_BINDING_INFO_EXT,
+                    p_next: ptr::null_mut(),
+                    address: buffer_address(&device_info.device, descriptor_buffer.buffer),
+                    usage: vk::BufferUsageFlags::RESOURCE_DESCRIPTOR_BUFFER_EXT,
+                }],
+            );
+            self.loader.cmd_set_descriptor_buffer_offsets(
+                command_buffer,
+                PipelineBindPoint::COMPUTE,
+                pipeline_layout,
+                0,
+                &[0],
+                &[0],
+            );
+        }
+    }
+}
+
+fn buffer_address(device: &Device, buffer: vk::Buffer) -> vk::DeviceAddress {
+    unsafe {
+        device.get_buffer_device_address(&BufferDeviceAddressInfo {
+            s_type: StructureType::BUFFER_DEVICE_ADDRESS_INFO,
+            p_next: ptr::null(),
+            buffer,
+        })
+    }
+}
@@ -1,25 +1,58 @@
-use std::{cmp::Ordering, ffi::CStr, ptr};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    ffi::{c_void, CStr},
+    ptr,
+    sync::{Arc, Mutex},
+};
 
 use ash::{
+    extensions::{ext::DescriptorBuffer, khr::Synchronization2},
     vk::{
-        self, CommandPool, CommandPoolCreateFlags, CommandPoolCreateInfo, DeviceCreateFlags,
+        self, CommandBuffer, CommandPool, DeviceCreateFlags,
         DeviceCreateInfo, DeviceQueueCreateFlags, DeviceQueueCreateInfo, PhysicalDevice,
-        PhysicalDeviceFeatures, PhysicalDeviceType, Queue, QueueFamilyProperties, QueueFlags,
-        StructureType 
+        PhysicalDeviceBufferDeviceAddressFeatures, PhysicalDeviceDescriptorBufferFeaturesEXT,
+        PhysicalDeviceFeatures, PhysicalDeviceFeatures2, PhysicalDeviceLimits, PhysicalDeviceSynchronization2Features,
+        PhysicalDeviceType, Queue, QueueFamilyProperties, QueueFlags, StructureType,
     },
     Device, Instance,
 };
 
-use super::{init_error::InitError, instance::InstanceInfo};
+use super::{
+    descriptor_buffer::{self, DescriptorBufferSupport},
+    external_semaphore::{self, ExternalSemaphoreSupport},
+    host_staging::{self, ExternalMemoryHostSupport},
+    init_error::InitError,
+    instance::InstanceInfo,
+    object_budget::ObjectCounts,
+    ComputeManager,
+};
 
 #[derive(Clone)]
 pub struct DeviceInfo {
     pub device: Device,
-    pub compute_queue: Queue,
+    // Every queue of the compute family. The first one has the highest priority.
+    pub compute_queues: Vec<Queue>,
     pub physical_device: PhysicalDevice,
     pub queue_indices: QueueFamilyInfo,
 
-    pub compute_pool: CommandPool,
+    // The pool of every command buffer, each has its own
+    pub command_pools: Arc<Mutex<HashMap<CommandBuffer, CommandPool>>>,
+    pub limits: PhysicalDeviceLimits,
+
+    // Present when VK_KHR_synchronization2 is supported and enabled
+    pub synchronization2: Option<Synchronization2>,
+    // Present when VK_EXT_descriptor_buffer is supported and enabled
+    pub descriptor_buffer: Option<DescriptorBufferSupport>,
+    // Present when semaphores can be exported as opaque FD or win32 handles
+    pub external_semaphore: Option<ExternalSemaphoreSupport>,
+    // Present when host pages can be imported as memory, for pinned and NUMA placed staging
+    pub external_memory_host: Option<ExternalMemoryHostSupport>,
+    // Set when vkCmdDispatchBase is available, for splitting dispatches over the work group count
+    // limit
+    pub dispatch_base: bool,
+    // Shared by every clone, so objects are counted wherever they're created or destroyed
+    pub object_counts: Arc<ObjectCounts>,
 }
 
 fn score_device(instance: &Instance, physical_device: PhysicalDevice) -> Option<u32> {
@@ -56,9 +89,53 @@ fn score_device(instance: &Instance, physical_device: PhysicalDevice) -> Option<
     Some(score)
 }
 
+/// A physical device visible to the instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSummary {
+    pub name: String,
+    pub device_type: PhysicalDeviceType,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub api_version: (u32, u32, u32),
+    /// `None` when the device has no compute queue
+    pub score: Option<u32>,
+    /// Whether this is the device the manager runs on
+    pub selected: bool,
+}
+
+impl ComputeManager {
+    pub fn devices(&self) -> Vec<DeviceSummary> {
+        let instance = &self.instance_info.instance;
+        let physical_devices = unsafe { instance.enumerate_physical_devices() }.unwrap_or_default();
+
+        physical_devices
+            .iter()
+            .map(|physical_device| unsafe {
+                let properties = instance.get_physical_device_properties(*physical_device);
+                DeviceSummary {
+                    name: CStr::from_ptr(properties.device_name.as_ptr())
+                        .to_string_lossy()
+                        .into_owned(),
+                    device_type: properties.device_type,
+                    vendor_id: properties.vendor_id,
+                    device_id: properties.device_id,
+                    api_version: (
+                        vk::api_version_major(properties.api_version),
+                        vk::api_version_minor(properties.api_version),
+                        vk::api_version_patch(properties.api_version),
+                    ),
+                    score: score_device(instance, *physical_device),
+                    selected: *physical_device == self.device_info.physical_device,
+                }
+            })
+            .collect()
+    }
+}
+
 #[derive(Clone)]
 pub struct QueueFamilyInfo {
     pub compute_queue: Option<u32>,
+    pub compute_queue_count: u32,
 }
 
 impl QueueFamilyInfo {
@@ -93,27 +170,39 @@ fn load_queue_family_info(instance: &Instance, physical_device: PhysicalDevice)
             });
 
         let compute_queue = best_queue.map(|(queue, _)| queue as u32);
+        let compute_queue_count = best_queue.map(|(_, info)| info.queue_count).unwrap_or(0);
 
-        QueueFamilyInfo { compute_queue }
+        QueueFamilyInfo {
+            compute_queue,
+            compute_queue_count,
+        }
     }
 }
 
-fn create_compute_pool(device: &Device, queue_index: u32) -> Result<CommandPool, InitError> {
-    let create_info = CommandPoolCreateInfo {
-        s_type: StructureType::COMMAND_POOL_CREATE_INFO,
-        p_next: ptr::null(),
-        flags: CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
-        queue_family_index: queue_index,
+fn supports_synchronization2(instance_info: &InstanceInfo, physical_device: PhysicalDevice) -> bool {
+    let properties2_loader = match instance_info.physical_device_properties2_loader.as_ref() {
+        Some(l) => l,
+        None => return false,
     };
 
     unsafe {
-        Ok(match device.create_command_pool(&create_info, None) {
-            Ok(p) => p,
-            Err(e) => {
-                log::error!("Failed to create command pool! Error: {}", e);
-                return Err(InitError::ComputePoolCreationFailure);
-            }
-        })
+        let extension_available = instance_info
+            .instance
+            .enumerate_device_extension_properties(physical_device)
+            .unwrap_or_default()
+            .iter()
+            .any(|e| CStr::from_ptr(e.extension_name.as_ptr()) == Synchronization2::name());
+        if !extension_available {
+            return false;
+        }
+
+        let mut synchronization2_features = PhysicalDeviceSynchronization2Features::default();
+        let mut features2 = PhysicalDeviceFeatures2::builder()
+            .push_next(&mut synchronization2_features)
+            .build();
+        properties2_loader.get_physical_device_features2(physical_device, &mut features2);
+
+        synchronization2_features.synchronization2 == vk::TRUE
     }
 }
 
@@ -187,7 +276,10 @@ pub fn initialize_device(
             return Err(InitError::NoComputeQueue);
         }
 
-        let queue_prior = [1.0_f32];
+        // The first queue takes high priority work; any others run at a lower priority
+        let queue_prior: Vec<f32> = (0..queue_family_info.compute_queue_count.max(1))
+            .map(|i| if i == 0 { 1.0 } else { 0.5 })
+            .collect();
 
         #[allow(unused_mut)]
         let mut queue_create_infos = vec![ 
@@ -196,7 +288,7 @@ pub fn initialize_device(
             p_next: ptr::null(),
             flags: DeviceQueueCreateFlags::empty(),
             queue_family_index: queue_family_info.compute_queue.unwrap(),
-            queue_count: 1,
+            queue_count: queue_prior.len() as u32,
             p_queue_priorities: queue_prior.as_ptr(),
         }];
 
@@ -212,12 +304,67 @@ pub fn initialize_device(
                 .push(CStr::from_bytes_with_nul_unchecked(b"VK_KHR_portability_subset\0").as_ptr());
         }
 
+        let synchronization2_supported =
+            supports_synchronization2(instance_info, *physical_device);
+        // Descriptor buffers depend on synchronization2
+        let descriptor_buffer_supported = synchronization2_supported
+            && descriptor_buffer::supports_descriptor_buffer(instance_info, *physical_device);
+        let external_semaphore_supported =
+            external_semaphore::supports_external_semaphore(instance_info, *physical_device);
+        let external_memory_host_supported =
+            host_staging::supports_external_memory_host(instance_info, *physical_device);
+        // vkCmdDispatchBase is core from 1.1 on
+        let required_version = vk::make_api_version(0, 1, 1, 0);
+        let dispatch_base_supported = instance_info.api_version >= required_version
+            && instance_info
+                .instance
+                .get_physical_device_properties(*physical_device)
+                .api_version
+                >= required_version;
+
+        let mut synchronization2_features = PhysicalDeviceSynchronization2Features {
+            synchronization2: vk::TRUE,
+            ..Default::default()
+        };
+        let mut buffer_device_address_features = PhysicalDeviceBufferDeviceAddressFeatures {
+            buffer_device_address: vk::TRUE,
+            ..Default::default()
+        };
+        let mut descriptor_buffer_features = PhysicalDeviceDescriptorBufferFeaturesEXT {
+            descriptor_buffer: vk::TRUE,
+            ..Default::default()
+        };
+
+        let mut features_chain: *mut c_void = ptr::null_mut();
+        if descriptor_buffer_supported {
+            device_extensions.push(DescriptorBuffer::name().as_ptr());
+            descriptor_buffer_features.p_next = features_chain;
+            buffer_device_address_features.p_next =
+                &mut descriptor_buffer_features as *mut _ as *mut c_void;
+            features_chain = &mut buffer_device_address_features as *mut _ as *mut c_void;
+        }
+        if synchronization2_supported {
+            device_extensions.push(Synchronization2::name().as_ptr());
+            synchronization2_features.p_next = features_chain;
+            features_chain = &mut synchronization2_features as *mut _ as *mut c_void;
+        }
+
+        if let (true, Some(name)) = (
+            external_semaphore_supported,
+            external_semaphore::extension_name(),
+        ) {
+            device_extensions.push(name.as_ptr());
+        }
+        if external_memory_host_supported {
+            device_extensions.push(vk::ExtExternalMemoryHostFn::name().as_ptr());
+        }
+
         let layer_names =
             [CStr::from_bytes_with_nul_unchecked(b"VK_LAYER_KHRONOS_validation\0").as_ptr()];
 
         let device_create_info = DeviceCreateInfo {
             s_type: StructureType::DEVICE_CREATE_INFO,
-            p_next: ptr::null(),
+            p_next: features_chain as *const c_void,
             flags: DeviceCreateFlags::default(),
             queue_create_info_count: queue_create_infos.len() as u32,
             p_queue_create_infos: queue_create_infos.as_ptr(),
@@ -245,15 +392,69 @@ pub fn initialize_device(
         };
 
         log_device_info(&instance_info.instance, &device, *physical_device);
+        if synchronization2_supported {
+            log::info!("\tSYNCHRONIZATION2: enabled");
+        }
+        if descriptor_buffer_supported {
+            log::info!("\tDESCRIPTOR_BUFFER: enabled");
+        }
+        if external_semaphore_supported {
+            log::info!("\tEXTERNAL_SEMAPHORE: enabled");
+        }
+        if external_memory_host_supported {
+            log::info!("\tEXTERNAL_MEMORY_HOST: enabled");
+        }
+        if dispatch_base_supported {
+            log::info!("\tDISPATCH_BASE: enabled");
+        }
 
-        let compute_queue = device.get_device_queue(queue_family_info.compute_queue.unwrap(), 0);
+        let compute_queues: Vec<Queue> = (0..queue_prior.len() as u32)
+            .map(|i| device.get_device_queue(queue_family_info.compute_queue.unwrap(), i))
+            .collect();
 
         Ok(DeviceInfo {
             device: device.clone(),
-            compute_queue,
+            compute_queues,
             physical_device: *physical_device,
             queue_indices: load_queue_family_info(&instance_info.instance, *physical_device),
-            compute_pool: create_compute_pool(&device, queue_family_info.compute_queue.unwrap())?,
+            command_pools: Arc::new(Mutex::new(HashMap::new())),
+            limits: instance_info
+                .instance
+                .get_physical_device_properties(*physical_device)
+                .limits,
+            synchronization2: if synchronization2_supported {
+                Some(Synchronization2::new(&instance_info.instance, &device))
+            } else {
+                None
+            },
+            descriptor_buffer: if descriptor_buffer_supported {
+                Some(descriptor_buffer::load_descriptor_buffer_support(
+                    instance_info,
+                    &device,
+                    *physical_device,
+                ))
+            } else {
+                None
+            },
+            external_semaphore: if external_semaphore_supported {
+                Some(external_semaphore::load_external_semaphore_support(
+                    instance_info,
+                    &device,
+                ))
+            } else {
+                None
+            },
+            external_memory_host: if external_memory_host_supported {
+                Some(host_staging::load_external_memory_host_support(
+                    instance_info,
+                    &device,
+                    *physical_device,
+                ))
+            } else {
+                None
+            },
+            dispatch_base: dispatch_base_supported,
+            object_counts: Arc::new(ObjectCounts::new()),
         })
     }
 }
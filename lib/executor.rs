@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+
+use ash::vk::Fence;
+
+use super::{gpu_task::GPUTask, ComputeManager};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExecutorError {
+    TaskListedTwice,
+    // Forwarding device contents across queues isn't tracked, see `HazardTracker`
+    TaskReadsDeviceTensors,
+    HazardTrackerLockFailure,
+    TaskSubmissionFailure,
+    TaskExecutionFailure,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutorReport {
+    /// Tasks run on each compute queue, starting with the high priority queue
+    pub tasks_per_queue: Vec<u32>,
+}
+
+// Keeps a queue busy while the host is busy handing out its next task
+const IN_FLIGHT_PER_QUEUE: usize = 2;
+
+// Task command buffers come from the compute family's pool, so queues of other families would need
+// tasks recorded against their own pools and aren't used.
+
+impl ComputeManager {
+    pub fn compute_queue_count(&self) -> usize {
+        self.device_info.compute_queues.len()
+    }
+
+    /// Runs independent tasks across every queue of the compute family and returns once all of
+    /// them have finished. Tasks must not depend on each other's results, and none of them may be
+    /// running elsewhere, or read on the device tensors that earlier tasks left there.
+    pub fn execute_parallel(&self, tasks: &[&GPUTask]) -> Result<ExecutorReport, ExecutorError> {
+        for (i, task) in tasks.iter().enumerate() {
+            if tasks[..i].iter().any(|t| std::ptr::eq(*t, *task)) {
+                return Err(ExecutorError::TaskListedTwice);
+            }
+        }
+
+        // Only tensors the tracker holds contents for would need forwarding. The others, outputs
+        // included, are whatever the task's own buffers start out with.
+        match self.hazard_tracker.lock() {
+            Ok(hazard_tracker) => {
+                if tasks.iter().any(|task| {
+                    task.device_inputs()
+                        .iter()
+                        .any(|id| hazard_tracker.device_location(*id).is_some())
+                }) {
+                    return Err(ExecutorError::TaskReadsDeviceTensors);
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to acquire hazard tracker! Error: {e}");
+                return Err(ExecutorError::HazardTrackerLockFailure);
+            }
+        }
+
+        // Stable, so tasks of equal priority keep their order
+        let mut sorted = tasks.to_vec();
+        sorted.sort_by_key(|t| std::cmp::Reverse(t.priority()));
+        let mut pending: VecDeque<&GPUTask> = sorted.into();
+
+        let queue_count = self.compute_queue_count();
+        let mut in_flight: Vec<Vec<Fence>> = vec![Vec::new(); queue_count];
+        let mut report = ExecutorReport {
+            tasks_per_queue: vec![0; queue_count],
+        };
+        let mut result = Ok(());
+
+        loop {
+            if result.is_ok() {
+                result = self.fill_queues(&mut pending, &mut in_flight, &mut report);
+            }
+
+            let fences: Vec<Fence> = in_flight.iter().flatten().copied().collect();
+            if fences.is_empty() {
+                break;
+            }
+
+            let device = &self.device_info.device;
+            if let Err(e) = unsafe { device.wait_for_fences(&fences, false, u64::MAX) } {
+                log::error!("Failed to wait for executor tasks! Error: {}", e);
+                let _ = unsafe { device.wait_for_fences(&fences, true, u64::MAX) };
+                fences
+                    .iter()
+                    .for_each(|f| self.submission_thread.destroy_fence(*f));
+                return Err(ExecutorError::TaskExecutionFailure);
+            }
+
+            // Retiring fences frees up their queues for the next round of stealing
+            in_flight.iter_mut().for_each(|queue_fences| {
+                queue_fences.retain(|fence| match unsafe { device.get_fence_status(*fence) } {
+                    Ok(false) => true,
+                    Ok(true) => {
+                        self.submission_thread.destroy_fence(*fence);
+                        false
+                    }
+                    Err(e) => {
+                        log::error!("Failed to query executor task fence! Error: {}", e);
+                        result = Err(ExecutorError::TaskExecutionFailure);
+                        self.submission_thread.destroy_fence(*fence);
+                        false
+                    }
+                })
+            });
+        }
+
+        result?;
+        self.note_task_writes(tasks);
+
+        Ok(report)
+    }
+
+    // Idle queues take the next pending task. The high priority queue takes from the front of the
+    // priority order and the others from the back, so urgent work lands on the faster queue.
+    fn fill_queues(
+        &self,
+        pending: &mut VecDeque<&GPUTask>,
+        in_flight: &mut [Vec<Fence>],
+        report: &mut ExecutorReport,
+    ) -> Result<(), ExecutorError> {
+        for (queue_index, queue_fences) in in_flight.iter_mut().enumerate() {
+            while queue_fences.len() < IN_FLIGHT_PER_QUEUE {
+                let next = if queue_index == 0 {
+                    pending.pop_front()
+                } else {
+                    pending.pop_back()
+                };
+                let task = match next {
+                    Some(t) => t,
+                    None => return Ok(()),
+                };
+
+                // Turns are shared by every queue, so priorities hold across the executor too
+                let turn = self.submission_thread.wait_turn(task.priority());
+                task.begin_submission();
+                let submitted =
+                    self.submission_thread
+                        .submit_to(queue_index, &[task.command_buffer], None);
+                drop(turn);
+
+                match submitted {
+                    Ok(f) => {
+                        queue_fences.push(f);
+                        report.tasks_per_queue[queue_index] += 1;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to submit executor task! Error: {}", e);
+                        return Err(ExecutorError::TaskSubmissionFailure);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
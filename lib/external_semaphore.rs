@@ -0,0 +1,201 @@
+use std::ffi::CStr;
+
+use ash::{
+    vk::{
+        self, ExportSemaphoreCreateInfo, ExternalSemaphoreFeatureFlags,
+        ExternalSemaphoreHandleTypeFlags, ExternalSemaphoreProperties, PhysicalDevice,
+        PhysicalDeviceExternalSemaphoreInfo, Semaphore, SemaphoreCreateInfo,
+    },
+    Device,
+};
+
+#[cfg(unix)]
+use ash::extensions::khr::ExternalSemaphoreFd;
+#[cfg(windows)]
+use ash::extensions::khr::ExternalSemaphoreWin32;
+
+use super::{
+    gpu_task::{GPUSyncPrimitive, GPUTask},
+    instance::InstanceInfo,
+    ComputeManager,
+};
+
+#[cfg(unix)]
+const EXPORT_HANDLE_TYPE: ExternalSemaphoreHandleTypeFlags =
+    ExternalSemaphoreHandleTypeFlags::OPAQUE_FD;
+#[cfg(windows)]
+const EXPORT_HANDLE_TYPE: ExternalSemaphoreHandleTypeFlags =
+    ExternalSemaphoreHandleTypeFlags::OPAQUE_WIN32;
+
+#[derive(Clone)]
+pub struct ExternalSemaphoreSupport {
+    #[cfg(unix)]
+    pub loader: ExternalSemaphoreFd,
+    #[cfg(windows)]
+    pub loader: ExternalSemaphoreWin32,
+}
+
+/// A handle to a binary semaphore that is signalled once an exported task finishes. Ownership of
+/// the handle passes to the caller, and importing it into another Vulkan or GL context hands it on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExternalSemaphoreHandle {
+    OpaqueFd(i32),
+    OpaqueWin32(vk::HANDLE),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SemaphoreExportError {
+    Unsupported,
+    SemaphoreCreationFailure,
+    TaskSubmissionFailure,
+    HandleExportFailure,
+}
+
+pub(super) fn extension_name() -> Option<&'static CStr> {
+    #[cfg(unix)]
+    return Some(ExternalSemaphoreFd::name());
+    #[cfg(windows)]
+    return Some(ExternalSemaphoreWin32::name());
+    #[allow(unreachable_code)]
+    None
+}
+
+// External semaphores are core from 1.1 on, so only the handle type extension is needed there
+pub(super) fn supports_external_semaphore(
+    instance_info: &InstanceInfo,
+    physical_device: PhysicalDevice,
+) -> bool {
+    let name = match extension_name() {
+        Some(n) => n,
+        None => return false,
+    };
+
+    let required_version = vk::make_api_version(0, 1, 1, 0);
+    unsafe {
+        let device_version = instance_info
+            .instance
+            .get_physical_device_properties(physical_device)
+            .api_version;
+        if instance_info.api_version < required_version || device_version < required_version {
+            return false;
+        }
+
+        let extension_available = instance_info
+            .instance
+            .enumerate_device_extension_properties(physical_device)
+            .unwrap_or_default()
+            .iter()
+            .any(|e| CStr::from_ptr(e.extension_name.as_ptr()) == name);
+        if !extension_available {
+            return false;
+        }
+
+        let info = PhysicalDeviceExternalSemaphoreInfo {
+            handle_type: EXPORT_HANDLE_TYPE,
+            ..Default::default()
+        };
+        let mut properties = ExternalSemaphoreProperties::default();
+        instance_info
+            .instance
+            .get_physical_device_external_semaphore_properties(
+                physical_device,
+                &info,
+                &mut properties,
+            );
+
+        properties
+            .external_semaphore_features
+            .contains(ExternalSemaphoreFeatureFlags::EXPORTABLE)
+    }
+}
+
+#[allow(unused_variables)]
+pub(super) fn load_external_semaphore_support(
+    instance_info: &InstanceInfo,
+    device: &Device,
+) -> ExternalSemaphoreSupport {
+    ExternalSemaphoreSupport {
+        #[cfg(unix)]
+        loader: ExternalSemaphoreFd::new(&instance_info.instance, device),
+        #[cfg(windows)]
+        loader: ExternalSemaphoreWin32::new(&instance_info.instance, device),
+    }
+}
+
+impl ExternalSemaphoreSupport {
+    #[allow(unused_variables)]
+    fn export(&self, semaphore: Semaphore) -> ash::prelude::VkResult<ExternalSemaphoreHandle> {
+        #[cfg(unix)]
+        return unsafe {
+            self.loader
+                .get_semaphore_fd(&vk::SemaphoreGetFdInfoKHR {
+                    semaphore,
+                    handle_type: EXPORT_HANDLE_TYPE,
+                    ..Default::default()
+                })
+                .map(ExternalSemaphoreHandle::OpaqueFd)
+        };
+        #[cfg(windows)]
+        return unsafe {
+            self.loader
+                .get_semaphore_win32_handle(&vk::SemaphoreGetWin32HandleInfoKHR {
+                    semaphore,
+                    handle_type: EXPORT_HANDLE_TYPE,
+                    ..Default::default()
+                })
+                .map(ExternalSemaphoreHandle::OpaqueWin32)
+        };
+        #[allow(unreachable_code)]
+        Err(vk::Result::ERROR_EXTENSION_NOT_PRESENT)
+    }
+}
+
+impl ComputeManager {
+    pub fn semaphore_export_supported(&self) -> bool {
+        self.device_info.external_semaphore.is_some()
+    }
+
+    /// Like `exec_task`, but also signals a semaphore that other contexts can wait on GPU-side.
+    /// The task must still be awaited here, which also releases gauss's side of the semaphore.
+    pub fn exec_task_exported<'a>(
+        &self,
+        task: &'a GPUTask,
+    ) -> Result<(GPUSyncPrimitive<'a>, ExternalSemaphoreHandle), SemaphoreExportError> {
+        let support = match self.device_info.external_semaphore.as_ref() {
+            Some(s) => s,
+            None => return Err(SemaphoreExportError::Unsupported),
+        };
+        let device = &self.device_info.device;
+
+        let mut export_info = ExportSemaphoreCreateInfo {
+            handle_types: EXPORT_HANDLE_TYPE,
+            ..Default::default()
+        };
+        let create_info = SemaphoreCreateInfo::builder().push_next(&mut export_info);
+        let semaphore = match unsafe { device.create_semaphore(&create_info, None) } {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Failed to create exportable semaphore! Error: {}", e);
+                return Err(SemaphoreExportError::SemaphoreCreationFailure);
+            }
+        };
+
+        let sync = match self.submit_task(task, Some(semaphore), None) {
+            Some(s) => s,
+            None => {
+                unsafe { device.destroy_semaphore(semaphore, None) };
+                return Err(SemaphoreExportError::TaskSubmissionFailure);
+            }
+        };
+
+        // Exported after submission so a failed submission doesn't leave a handle to close
+        match support.export(semaphore) {
+            Ok(handle) => Ok((sync, handle)),
+            Err(e) => {
+                log::error!("Failed to export task semaphore! Error: {}", e);
+                let _ = self.await_task(&sync, Vec::new());
+                Err(SemaphoreExportError::HandleExportFailure)
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use ash::vk::{CommandBuffer, Fence};
+
+use super::{
+    gpu_task::{GPUTask, GPUTaskInProcess, GPUTaskRecordingDiagnostic},
+    kernel_assert::KernelAssertionFailed,
+    staging::StagingError,
+    ComputeManager, Tensor,
+};
+
+#[derive(Debug, Clone)]
+pub enum FrameError {
+    TaskRecordingFailure(Vec<GPUTaskRecordingDiagnostic>),
+    TaskSubmissionFailure,
+    UnknownTask(FrameTaskId),
+    FrameRecycled(u64),
+    ReadbackFailure(StagingError),
+    KernelAssertionFailed(KernelAssertionFailed),
+}
+
+/// A task submitted in a frame, valid until that frame is recycled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTaskId {
+    pub frame: u64,
+    index: usize,
+}
+
+// Everything a frame owns until its work retires
+struct FrameResources {
+    value: u64,
+    tasks: Vec<GPUTask>,
+    submissions: Vec<(Fence, Vec<CommandBuffer>)>,
+}
+
+/// Drives repeated compute one frame at a time. Each frame gets the next timeline value, and its
+/// tasks are transient: they are freed at the first `begin_frame` after the frame's work retires.
+pub struct FrameTimeline {
+    max_frames_in_flight: usize,
+    next_value: u64,
+    // Ended frames that haven't been recycled yet, oldest first
+    pending: VecDeque<FrameResources>,
+
+    parent: Arc<ComputeManager>,
+}
+
+pub struct Frame<'a> {
+    resources: Option<FrameResources>,
+    timeline: &'a mut FrameTimeline,
+}
+
+impl ComputeManager {
+    pub fn create_frame_timeline(self: Arc<Self>, max_frames_in_flight: u32) -> FrameTimeline {
+        FrameTimeline {
+            max_frames_in_flight: max_frames_in_flight.max(1) as usize,
+            next_value: 1,
+            pending: VecDeque::new(),
+            parent: self,
+        }
+    }
+}
+
+impl FrameTimeline {
+    /// Recycles retired frames, then blocks until fewer than the cap of frames are in flight
+    pub fn begin_frame(&mut self) -> Frame<'_> {
+        while let Some(oldest) = self.pending.front() {
+            if self.pending.len() < self.max_frames_in_flight && !self.has_retired(oldest) {
+                break;
+            }
+
+            let oldest = self.pending.pop_front().unwrap();
+            self.recycle(oldest);
+        }
+
+        let value = self.next_value;
+        self.next_value += 1;
+
+        Frame {
+            resources: Some(FrameResources {
+                value,
+                tasks: Vec::new(),
+                submissions: Vec::new(),
+            }),
+            timeline: self,
+        }
+    }
+
+    /// The highest frame value whose work has finished on the device. Frames retire in order.
+    pub fn retired_value(&self) -> u64 {
+        match self.pending.iter().find(|f| !self.has_retired(f)) {
+            Some(f) => f.value - 1,
+            None => self.next_value - 1,
+        }
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.pending.iter().filter(|f| !self.has_retired(f)).count()
+    }
+
+    /// Waits for the task's frame to retire and copies its readback into `sync_tensors`, then
+    /// checks the task's kernel asserts like `await_task`. Only frames that haven't been recycled
+    /// by a later `begin_frame` can be read.
+    pub fn readback(
+        &self,
+        id: FrameTaskId,
+        sync_tensors: Vec<&mut Tensor>,
+    ) -> Result<(), FrameError> {
+        let frame = match self.pending.iter().find(|f| f.value == id.frame) {
+            Some(f) => f,
+            None if id.frame < self.next_value => return Err(FrameError::FrameRecycled(id.frame)),
+            None => return Err(FrameError::UnknownTask(id)),
+        };
+        let task = match frame.tasks.get(id.index) {
+            Some(t) => t,
+            None => return Err(FrameError::UnknownTask(id)),
+        };
+
+        self.wait(frame);
+        let readback = task.copy_readback(sync_tensors);
+        task.check_kernel_asserts()
+            .map_err(FrameError::KernelAssertionFailed)?;
+        readback.map_err(FrameError::ReadbackFailure)
+    }
+
+    fn has_retired(&self, frame: &FrameResources) -> bool {
+        let device = &self.parent.device_info.device;
+        frame
+            .submissions
+            .iter()
+            .all(|(fence, _)| unsafe { device.get_fence_status(*fence) }.unwrap_or(true))
+    }
+
+    fn wait(&self, frame: &FrameResources) {
+        let fences: Vec<Fence> = frame.submissions.iter().map(|(f, _)| *f).collect();
+        if fences.is_empty() {
+            return;
+        }
+
+        unsafe {
+            let _ = self
+                .parent
+                .device_info
+                .device
+                .wait_for_fences(&fences, true, u64::MAX);
+        }
+    }
+
+    fn recycle(&self, frame: FrameResources) {
+        self.wait(&frame);
+        frame.submissions.iter().for_each(|(fence, prologues)| {
+            self.parent.submission_thread.destroy_fence(*fence);
+            self.parent.free_prologues(prologues);
+        });
+        drop(frame.tasks);
+    }
+}
+
+impl Drop for FrameTimeline {
+    fn drop(&mut self) {
+        while let Some(frame) = self.pending.pop_front() {
+            self.recycle(frame);
+        }
+    }
+}
+
+impl Frame<'_> {
+    pub fn value(&self) -> u64 {
+        self.resources.as_ref().unwrap().value
+    }
+
+    /// Finalizes and submits `task`. The task belongs to the frame and is freed with it.
+    pub fn exec(&mut self, task: GPUTaskInProcess) -> Result<FrameTaskId, FrameError> {
+        let task = task.finalize().map_err(FrameError::TaskRecordingFailure)?;
+        let submission = match self.timeline.parent.submit_tracked(&[&task], &[], None) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Failed to submit frame task! Error: {}", e);
+                return Err(FrameError::TaskSubmissionFailure);
+            }
+        };
+
+        let resources = self.resources.as_mut().unwrap();
+        resources.tasks.push(task);
+        resources
+            .submissions
+            .push((submission.fence, submission.prologues));
+
+        Ok(FrameTaskId {
+            frame: resources.value,
+            index: resources.tasks.len() - 1,
+        })
+    }
+
+    /// Returns the frame's timeline value. Dropping the frame ends it too.
+    pub fn end(self) -> u64 {
+        self.value()
+    }
+}
+
+impl Drop for Frame<'_> {
+    fn drop(&mut self) {
+        if let Some(resources) = self.resources.take() {
+            self.timeline.pending.push_back(resources);
+        }
+    }
+}
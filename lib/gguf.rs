@@ -0,0 +1,860 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use ndarray::Array1;
+
+use super::{gpu_task::GPUTask, pipeline::Pipeline, ComputeManager, Tensor, WorkGroupSize};
+
+const GGUF_MAGIC: u32 = 0x4655_4747;
+const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+const DEQUANT_LOCAL_SIZE: u32 = 64;
+// Raw tensor data is read and uploaded this many bytes at a time, so neither the host nor staging
+// ever holds more than a chunk of it
+const UPLOAD_CHUNK_SIZE: u64 = 64 << 20;
+
+#[derive(Debug, Clone)]
+pub enum GgufError {
+    Io(String),
+    InvalidMagic(u32),
+    UnsupportedVersion(u32),
+    InvalidMetadata(String),
+    TensorNotFound(String),
+    UnsupportedType(GgmlType),
+    TensorTooLarge(String),
+    PipelineCreationFailure,
+    UploadFailure,
+    TaskRecordingFailure,
+    TaskExecutionFailure,
+}
+
+impl From<std::io::Error> for GgufError {
+    fn from(e: std::io::Error) -> Self {
+        GgufError::Io(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GgmlType {
+    F32,
+    F16,
+    Q4_0,
+    Q4_1,
+    Q8_0,
+    Other(u32),
+}
+
+#[derive(Debug, Clone)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufTensorInfo {
+    pub name: String,
+    pub dimensions: Vec<u64>,
+    pub ggml_type: GgmlType,
+    pub offset: u64,
+}
+
+pub struct GgufFile {
+    path: PathBuf,
+    version: u32,
+    metadata: HashMap<String, GgufValue>,
+    tensors: Vec<GgufTensorInfo>,
+    data_offset: u64,
+}
+
+// Keeps track of how much of the file is left, so counts read from it can be checked before
+// anything is allocated for them
+struct BoundedReader<R> {
+    inner: R,
+    position: u64,
+    len: u64,
+}
+
+/// A tensor loaded from a GGUF file. Its values stay on the device, in the buffers of the task that
+/// dequantized them, so tasks that bind `tensor()` read them from there for as long as this lives.
+/// The task also keeps the raw tensor data on the device.
+pub struct GgufTensor {
+    tensor: Tensor,
+    task: GPUTask,
+}
+
+pub struct GgufLoader {
+    file: GgufFile,
+    dequant_pipelines: HashMap<GgmlType, Arc<Pipeline>>,
+
+    parent: Arc<ComputeManager>,
+}
+
+impl GgmlType {
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            0 => GgmlType::F32,
+            1 => GgmlType::F16,
+            2 => GgmlType::Q4_0,
+            3 => GgmlType::Q4_1,
+            8 => GgmlType::Q8_0,
+            other => GgmlType::Other(other),
+        }
+    }
+
+    // (elements per block, bytes per block)
+    fn block_layout(&self) -> Option<(u64, u64)> {
+        match self {
+            GgmlType::F32 => Some((1, 4)),
+            GgmlType::F16 => Some((1, 2)),
+            GgmlType::Q4_0 => Some((32, 18)),
+            GgmlType::Q4_1 => Some((32, 20)),
+            GgmlType::Q8_0 => Some((32, 34)),
+            GgmlType::Other(_) => None,
+        }
+    }
+
+    fn dequant_body(&self) -> Option<&'static str> {
+        match self {
+            GgmlType::F32 => Some(DEQUANT_F32),
+            GgmlType::F16 => Some(DEQUANT_F16),
+            GgmlType::Q4_0 => Some(DEQUANT_Q4_0),
+            GgmlType::Q4_1 => Some(DEQUANT_Q4_1),
+            GgmlType::Q8_0 => Some(DEQUANT_Q8_0),
+            GgmlType::Other(_) => None,
+        }
+    }
+}
+
+impl GgufTensorInfo {
+    /// `None` if the dimensions overflow
+    pub fn element_count(&self) -> Option<u64> {
+        self.dimensions
+            .iter()
+            .try_fold(1_u64, |count, d| count.checked_mul(*d))
+    }
+
+    /// `None` if the type is unsupported or the size overflows
+    pub fn byte_size(&self) -> Option<u64> {
+        let (elements, bytes) = self.ggml_type.block_layout()?;
+        (self.element_count()? / elements).checked_mul(bytes)
+    }
+}
+
+impl<R: Read> Read for BoundedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R> BoundedReader<R> {
+    // Fails if `count` entries of at least `entry_size` bytes can't fit in the rest of the file
+    fn check_count(&self, count: u64, entry_size: u64, what: &str) -> Result<(), GgufError> {
+        let remaining = self.len.saturating_sub(self.position);
+        match count.checked_mul(entry_size) {
+            Some(size) if size <= remaining => Ok(()),
+            _ => Err(GgufError::InvalidMetadata(format!(
+                "{} of {} entries runs past the end of the file",
+                what, count
+            ))),
+        }
+    }
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, GgufError> {
+    let mut bytes = [0_u8; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, GgufError> {
+    let mut bytes = [0_u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, GgufError> {
+    let mut bytes = [0_u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, GgufError> {
+    let mut bytes = [0_u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_string<R: Read>(reader: &mut BoundedReader<R>) -> Result<String, GgufError> {
+    let len = read_u64(reader)?;
+    reader.check_count(len, 1, "String")?;
+    let mut bytes = vec![0_u8; len as usize];
+    reader.read_exact(&mut bytes)?;
+
+    String::from_utf8(bytes)
+        .map_err(|e| GgufError::InvalidMetadata(format!("Invalid UTF-8 string: {}", e)))
+}
+
+// The fewest bytes a value of the type takes up in the file
+fn min_value_size(value_type: u32) -> u64 {
+    match value_type {
+        2 | 3 => 2,
+        4..=6 => 4,
+        // Strings start with their length, arrays with their element type and length
+        8 | 10..=12 => 8,
+        9 => 12,
+        _ => 1,
+    }
+}
+
+fn read_value<R: Read>(
+    reader: &mut BoundedReader<R>,
+    value_type: u32,
+) -> Result<GgufValue, GgufError> {
+    Ok(match value_type {
+        0 => GgufValue::U8(read_u8(reader)?),
+        1 => GgufValue::I8(read_u8(reader)? as i8),
+        2 => GgufValue::U16(read_u16(reader)?),
+        3 => GgufValue::I16(read_u16(reader)? as i16),
+        4 => GgufValue::U32(read_u32(reader)?),
+        5 => GgufValue::I32(read_u32(reader)? as i32),
+        6 => GgufValue::F32(f32::from_bits(read_u32(reader)?)),
+        7 => GgufValue::Bool(read_u8(reader)? != 0),
+        8 => GgufValue::String(read_string(reader)?),
+        9 => {
+            let element_type = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            reader.check_count(len, min_value_size(element_type), "Array")?;
+            let mut values = Vec::with_capacity(len as usize);
+            for _ in 0..len {
+                values.push(read_value(reader, element_type)?);
+            }
+            GgufValue::Array(values)
+        }
+        10 => GgufValue::U64(read_u64(reader)?),
+        11 => GgufValue::I64(read_u64(reader)? as i64),
+        12 => GgufValue::F64(f64::from_bits(read_u64(reader)?)),
+        other => {
+            return Err(GgufError::InvalidMetadata(format!(
+                "Unknown metadata value type {}",
+                other
+            )))
+        }
+    })
+}
+
+// Fails if the tensor's data can't lie within a file of `len` bytes whose data starts at
+// `data_offset`. Only the offset is checked for unsupported types, since their size is unknown.
+fn check_tensor_bounds(info: &GgufTensorInfo, data_offset: u64, len: u64) -> Result<(), GgufError> {
+    let element_count = info.element_count();
+    let size = match info.ggml_type.block_layout() {
+        Some(_) => info.byte_size(),
+        None => Some(0),
+    };
+    let end = size.and_then(|size| data_offset.checked_add(info.offset)?.checked_add(size));
+
+    match (element_count, end) {
+        (Some(_), Some(end)) if end <= len => Ok(()),
+        (None, _) => Err(GgufError::InvalidMetadata(format!(
+            "Tensor \"{}\" has too many elements",
+            info.name
+        ))),
+        _ => Err(GgufError::InvalidMetadata(format!(
+            "Tensor \"{}\" runs past the end of the file",
+            info.name
+        ))),
+    }
+}
+
+impl GgufFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, GgufError> {
+        let file = File::open(path.as_ref())?;
+        let len = file.metadata()?.len();
+        Self::parse(path.as_ref().to_path_buf(), BufReader::new(file), len)
+    }
+
+    // `len` is the size of the file `reader` reads from the start of
+    fn parse<R: Read>(path: PathBuf, reader: R, len: u64) -> Result<Self, GgufError> {
+        let mut reader = BoundedReader {
+            inner: reader,
+            position: 0,
+            len,
+        };
+
+        let magic = read_u32(&mut reader)?;
+        if magic != GGUF_MAGIC {
+            return Err(GgufError::InvalidMagic(magic));
+        }
+
+        let version = read_u32(&mut reader)?;
+        if version != 2 && version != 3 {
+            return Err(GgufError::UnsupportedVersion(version));
+        }
+
+        let tensor_count = read_u64(&mut reader)?;
+        let metadata_count = read_u64(&mut reader)?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..metadata_count {
+            let key = read_string(&mut reader)?;
+            let value_type = read_u32(&mut reader)?;
+            metadata.insert(key, read_value(&mut reader, value_type)?);
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..tensor_count {
+            let name = read_string(&mut reader)?;
+            let n_dims = read_u32(&mut reader)?;
+            reader.check_count(n_dims as u64, 8, "Tensor dimensions")?;
+            let mut dimensions = Vec::with_capacity(n_dims as usize);
+            for _ in 0..n_dims {
+                dimensions.push(read_u64(&mut reader)?);
+            }
+            let ggml_type = GgmlType::from_raw(read_u32(&mut reader)?);
+            let offset = read_u64(&mut reader)?;
+
+            tensors.push(GgufTensorInfo {
+                name,
+                dimensions,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = match metadata.get("general.alignment") {
+            Some(GgufValue::U32(a)) if *a > 0 => *a as u64,
+            Some(_) => {
+                return Err(GgufError::InvalidMetadata(
+                    "general.alignment must be a non-zero u32".to_string(),
+                ))
+            }
+            None => GGUF_DEFAULT_ALIGNMENT,
+        };
+
+        let data_offset = reader.position.div_ceil(alignment) * alignment;
+        for info in tensors.iter() {
+            check_tensor_bounds(info, data_offset, len)?;
+        }
+
+        Ok(GgufFile {
+            path,
+            version,
+            metadata,
+            tensors,
+            data_offset,
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, GgufValue> {
+        &self.metadata
+    }
+
+    pub fn tensors(&self) -> &[GgufTensorInfo] {
+        &self.tensors
+    }
+
+    pub fn tensor_info(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    fn open_tensor_data(&self, info: &GgufTensorInfo) -> Result<BufReader<File>, GgufError> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(self.data_offset + info.offset))?;
+        Ok(reader)
+    }
+}
+
+// Reads the next `len` raw tensor bytes into `chunk`, zero-padded to whole u32 words
+fn read_padded_chunk<R: Read>(
+    reader: &mut R,
+    chunk: &mut Vec<u8>,
+    len: usize,
+) -> Result<(), GgufError> {
+    chunk.clear();
+    chunk.resize(len.div_ceil(4) * 4, 0);
+    reader.read_exact(&mut chunk[..len])?;
+    Ok(())
+}
+
+impl GgufTensor {
+    pub fn tensor(&self) -> &Tensor {
+        &self.tensor
+    }
+
+    pub fn task(&self) -> &GPUTask {
+        &self.task
+    }
+}
+
+impl GgufLoader {
+    pub fn new<P: AsRef<Path>>(
+        compute_manager: Arc<ComputeManager>,
+        path: P,
+    ) -> Result<Self, GgufError> {
+        Ok(GgufLoader {
+            file: GgufFile::open(path)?,
+            dequant_pipelines: HashMap::new(),
+            parent: compute_manager,
+        })
+    }
+
+    pub fn file(&self) -> &GgufFile {
+        &self.file
+    }
+
+    /// Uploads and dequantizes the tensor on the device, where its values stay. With
+    /// `enable_readback` they're also read back into the host data of the returned tensor.
+    pub fn load_tensor(
+        &mut self,
+        name: &str,
+        enable_readback: bool,
+    ) -> Result<GgufTensor, GgufError> {
+        let info = match self.file.tensor_info(name) {
+            Some(i) => i.clone(),
+            None => return Err(GgufError::TensorNotFound(name.to_string())),
+        };
+
+        let (byte_size, element_count) = match (info.byte_size(), info.element_count()) {
+            (Some(b), Some(e)) => (b, e),
+            _ => return Err(GgufError::UnsupportedType(info.ggml_type)),
+        };
+        // The kernels address both buffers with 32-bit byte offsets
+        let max_range =
+            (self.parent.device_info.limits.max_storage_buffer_range as u64).min(u32::MAX as u64);
+        if byte_size > max_range || element_count.saturating_mul(4) > max_range {
+            return Err(GgufError::TensorTooLarge(name.to_string()));
+        }
+
+        self.prepare_dequant_pipeline(info.ggml_type)?;
+        let pipeline = self.dequant_pipelines.get(&info.ggml_type).unwrap();
+
+        let raw = self
+            .parent
+            .create_tensor(Array1::zeros(byte_size.div_ceil(4) as usize), false);
+        let mut tensor = self
+            .parent
+            .create_tensor(Array1::zeros(element_count as usize), enable_readback);
+
+        let (_, block_bytes) = info.ggml_type.block_layout().unwrap();
+        let max_groups_x = self.parent.device_info.limits.max_compute_work_group_count[0].max(1);
+        let group_count = (byte_size / block_bytes)
+            .div_ceil(DEQUANT_LOCAL_SIZE as u64)
+            .max(1);
+        let groups_x = group_count.min(max_groups_x as u64) as u32;
+        let groups_y = group_count.div_ceil(groups_x as u64) as u32;
+
+        // The raw data is uploaded straight into the task's buffer before it runs, never through
+        // the task's own staging
+        let task = self
+            .parent
+            .clone()
+            .new_task(pipeline, vec![&raw, &tensor])
+            .op_pipeline_dispatch(WorkGroupSize {
+                x: groups_x,
+                y: groups_y,
+                z: 1,
+            });
+        let task = match enable_readback {
+            true => task.op_device_sync_local(vec![&tensor]),
+            false => task,
+        };
+        let task = match task.finalize() {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!(
+                    "Failed to record dequantization task for \"{}\"! Error: {:?}",
+                    name,
+                    e
+                );
+                return Err(GgufError::TaskRecordingFailure);
+            }
+        };
+
+        let mut reader = self.file.open_tensor_data(&info)?;
+        let mut chunk = Vec::new();
+        let mut uploaded = 0;
+        while uploaded < byte_size {
+            let len = (byte_size - uploaded).min(UPLOAD_CHUNK_SIZE);
+            read_padded_chunk(&mut reader, &mut chunk, len as usize)?;
+            if let Err(e) = self
+                .parent
+                .upload_to_task(&task, raw.id(), uploaded, &chunk)
+            {
+                log::error!("Failed to upload raw data of \"{}\"! Error: {:?}", name, e);
+                return Err(GgufError::UploadFailure);
+            }
+            uploaded += len;
+        }
+
+        let running_task = match self.parent.exec_task(&task) {
+            Some(r) => r,
+            None => return Err(GgufError::TaskExecutionFailure),
+        };
+        let readback = match enable_readback {
+            true => vec![&mut tensor],
+            false => Vec::new(),
+        };
+        if self.parent.await_task(&running_task, readback).is_err() {
+            return Err(GgufError::TaskExecutionFailure);
+        }
+        drop(running_task);
+
+        Ok(GgufTensor { tensor, task })
+    }
+
+    fn prepare_dequant_pipeline(&mut self, ggml_type: GgmlType) -> Result<(), GgufError> {
+        if !self.dequant_pipelines.contains_key(&ggml_type) {
+            let body = match ggml_type.dequant_body() {
+                Some(b) => b,
+                None => return Err(GgufError::UnsupportedType(ggml_type)),
+            };
+
+            let source = format!("{}{}", DEQUANT_HEADER, body);
+            let program = match self.parent.compile_program(
+                &source,
+                &format!("gguf_dequant_{:?}", ggml_type),
+                true,
+            ) {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!("Failed to compile dequantization kernel! Error: {:?}", e);
+                    return Err(GgufError::PipelineCreationFailure);
+                }
+            };
+
+            let pipeline = match self.parent.clone().build_pipeline(program, 2) {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!("Failed to build dequantization pipeline! Error: {:?}", e);
+                    return Err(GgufError::PipelineCreationFailure);
+                }
+            };
+
+            self.dequant_pipelines.insert(ggml_type, Arc::new(pipeline));
+        }
+
+        Ok(())
+    }
+}
+
+const DEQUANT_HEADER: &str = "
+#version 450
+
+layout (local_size_x = 64, local_size_y = 1, local_size_z = 1) in;
+
+layout(set = 0, binding = 0) readonly buffer buf_raw { uint raw[]; };
+layout(set = 0, binding = 1) writeonly buffer buf_out { float values[]; };
+
+uint read_u8(uint byte_offset) {
+    return (raw[byte_offset >> 2] >> ((byte_offset & 3u) * 8u)) & 0xFFu;
+}
+
+float read_f16(uint byte_offset) {
+    return unpackHalf2x16(read_u8(byte_offset) | (read_u8(byte_offset + 1u) << 8u)).x;
+}
+
+uint block_index() {
+    return gl_GlobalInvocationID.x + gl_GlobalInvocationID.y * gl_NumWorkGroups.x * gl_WorkGroupSize.x;
+}
+";
+
+const DEQUANT_F32: &str = "
+void main() {
+    uint index = block_index();
+    if (index >= uint(values.length())) {
+        return;
+    }
+
+    values[index] = uintBitsToFloat(raw[index]);
+}
+";
+
+const DEQUANT_F16: &str = "
+void main() {
+    uint index = block_index();
+    if (index >= uint(values.length())) {
+        return;
+    }
+
+    values[index] = read_f16(index * 2u);
+}
+";
+
+const DEQUANT_Q4_0: &str = "
+void main() {
+    uint block = block_index();
+    if (block >= uint(values.length()) / 32u) {
+        return;
+    }
+
+    uint base = block * 18u;
+    float d = read_f16(base);
+    for (uint i = 0u; i < 16u; i++) {
+        uint q = read_u8(base + 2u + i);
+        values[block * 32u + i] = (float(q & 0xFu) - 8.0) * d;
+        values[block * 32u + i + 16u] = (float(q >> 4u) - 8.0) * d;
+    }
+}
+";
+
+const DEQUANT_Q4_1: &str = "
+void main() {
+    uint block = block_index();
+    if (block >= uint(values.length()) / 32u) {
+        return;
+    }
+
+    uint base = block * 20u;
+    float d = read_f16(base);
+    float m = read_f16(base + 2u);
+    for (uint i = 0u; i < 16u; i++) {
+        uint q = read_u8(base + 4u + i);
+        values[block * 32u + i] = float(q & 0xFu) * d + m;
+        values[block * 32u + i + 16u] = float(q >> 4u) * d + m;
+    }
+}
+";
+
+const DEQUANT_Q8_0: &str = "
+void main() {
+    uint block = block_index();
+    if (block >= uint(values.length()) / 32u) {
+        return;
+    }
+
+    uint base = block * 34u;
+    float d = read_f16(base);
+    for (uint i = 0u; i < 32u; i++) {
+        int q = int(read_u8(base + 2u + i) << 24u) >> 24;
+        values[block * 32u + i] = float(q) * d;
+    }
+}
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_string(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend((s.len() as u64).to_le_bytes());
+        bytes.extend(s.as_bytes());
+    }
+
+    fn header(tensor_count: u64, metadata_count: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(GGUF_MAGIC.to_le_bytes());
+        bytes.extend(3_u32.to_le_bytes());
+        bytes.extend(tensor_count.to_le_bytes());
+        bytes.extend(metadata_count.to_le_bytes());
+        bytes
+    }
+
+    // Three metadata entries and one Q8_0 tensor of two blocks, without the tensor data
+    fn well_formed_header() -> Vec<u8> {
+        let mut bytes = header(1, 3);
+
+        push_string(&mut bytes, "general.name");
+        bytes.extend(8_u32.to_le_bytes());
+        push_string(&mut bytes, "test");
+
+        push_string(&mut bytes, "general.alignment");
+        bytes.extend(4_u32.to_le_bytes());
+        bytes.extend(64_u32.to_le_bytes());
+
+        push_string(&mut bytes, "test.shape");
+        bytes.extend(9_u32.to_le_bytes());
+        bytes.extend(2_u32.to_le_bytes());
+        bytes.extend(3_u64.to_le_bytes());
+        [1_u16, 2, 3]
+            .iter()
+            .for_each(|v| bytes.extend(v.to_le_bytes()));
+
+        push_string(&mut bytes, "weights");
+        bytes.extend(2_u32.to_le_bytes());
+        bytes.extend(32_u64.to_le_bytes());
+        bytes.extend(2_u64.to_le_bytes());
+        bytes.extend(8_u32.to_le_bytes());
+        bytes.extend(0_u64.to_le_bytes());
+        bytes
+    }
+
+    fn well_formed() -> Vec<u8> {
+        let mut bytes = well_formed_header();
+        bytes.resize(bytes.len().div_ceil(64) * 64, 0);
+        bytes.extend([0_u8; 68]);
+        bytes
+    }
+
+    // A file with one tensor of the given dimensions and offset, followed by `data_len` bytes
+    fn single_tensor(dimensions: &[u64], offset: u64, data_len: usize) -> Vec<u8> {
+        let mut bytes = header(1, 0);
+        push_string(&mut bytes, "t");
+        bytes.extend((dimensions.len() as u32).to_le_bytes());
+        dimensions
+            .iter()
+            .for_each(|d| bytes.extend(d.to_le_bytes()));
+        bytes.extend(0_u32.to_le_bytes());
+        bytes.extend(offset.to_le_bytes());
+        bytes.resize(bytes.len().div_ceil(32) * 32 + data_len, 0);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> Result<GgufFile, GgufError> {
+        GgufFile::parse(PathBuf::new(), bytes, bytes.len() as u64)
+    }
+
+    #[test]
+    fn parses_well_formed_header() {
+        let bytes = well_formed();
+        let file = parse(&bytes).unwrap();
+
+        assert_eq!(file.version(), 3);
+        assert!(matches!(
+            file.metadata().get("general.name"),
+            Some(GgufValue::String(s)) if s == "test"
+        ));
+        match file.metadata().get("test.shape") {
+            Some(GgufValue::Array(values)) => {
+                assert_eq!(values.len(), 3);
+                assert!(matches!(values[2], GgufValue::U16(3)));
+            }
+            other => panic!("Unexpected test.shape: {:?}", other),
+        }
+
+        let info = file.tensor_info("weights").unwrap();
+        assert_eq!(info.dimensions, vec![32, 2]);
+        assert_eq!(info.ggml_type, GgmlType::Q8_0);
+        assert_eq!(info.element_count(), Some(64));
+        assert_eq!(info.byte_size(), Some(68));
+        let header_len = well_formed_header().len() as u64;
+        assert_eq!(file.data_offset, header_len.div_ceil(64) * 64);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = well_formed();
+        for len in 0..bytes.len() {
+            assert!(parse(&bytes[..len]).is_err(), "Accepted {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_magic_and_version() {
+        let mut bytes = well_formed();
+        bytes[0] ^= 0xFF;
+        assert!(matches!(parse(&bytes), Err(GgufError::InvalidMagic(_))));
+
+        let mut bytes = well_formed();
+        bytes[4..8].copy_from_slice(&7_u32.to_le_bytes());
+        assert!(matches!(
+            parse(&bytes),
+            Err(GgufError::UnsupportedVersion(7))
+        ));
+    }
+
+    #[test]
+    fn rejects_counts_past_the_end_of_the_file() {
+        let mut string = header(0, 1);
+        string.extend(u64::MAX.to_le_bytes());
+        assert!(matches!(parse(&string), Err(GgufError::InvalidMetadata(_))));
+
+        let mut array = header(0, 1);
+        push_string(&mut array, "huge");
+        array.extend(9_u32.to_le_bytes());
+        array.extend(12_u32.to_le_bytes());
+        array.extend((u64::MAX / 4).to_le_bytes());
+        assert!(matches!(parse(&array), Err(GgufError::InvalidMetadata(_))));
+
+        let mut dimensions = header(1, 0);
+        push_string(&mut dimensions, "huge");
+        dimensions.extend(u32::MAX.to_le_bytes());
+        assert!(matches!(
+            parse(&dimensions),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_metadata() {
+        let mut unknown_type = header(0, 1);
+        push_string(&mut unknown_type, "key");
+        unknown_type.extend(99_u32.to_le_bytes());
+        unknown_type.extend(0_u64.to_le_bytes());
+        assert!(matches!(
+            parse(&unknown_type),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+
+        let mut zero_alignment = header(0, 1);
+        push_string(&mut zero_alignment, "general.alignment");
+        zero_alignment.extend(4_u32.to_le_bytes());
+        zero_alignment.extend(0_u32.to_le_bytes());
+        assert!(matches!(
+            parse(&zero_alignment),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+
+        let mut invalid_utf8 = header(0, 1);
+        invalid_utf8.extend(2_u64.to_le_bytes());
+        invalid_utf8.extend([0xC3, 0x28]);
+        assert!(matches!(
+            parse(&invalid_utf8),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_tensors_outside_the_file() {
+        assert!(parse(&single_tensor(&[4, 2], 0, 32)).is_ok());
+        assert!(matches!(
+            parse(&single_tensor(&[4, 2], 0, 31)),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+        assert!(matches!(
+            parse(&single_tensor(&[4, 2], 4, 32)),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+        assert!(matches!(
+            parse(&single_tensor(&[4, 2], u64::MAX, 32)),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+        assert!(matches!(
+            parse(&single_tensor(&[u64::MAX, 2], 0, 32)),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+        assert!(matches!(
+            parse(&single_tensor(&[u64::MAX / 2], 0, 32)),
+            Err(GgufError::InvalidMetadata(_))
+        ));
+    }
+
+    #[test]
+    fn reads_padded_chunks() {
+        let bytes = [1_u8, 0, 0, 0, 2, 3];
+        let mut chunk = vec![0xFF_u8; 16];
+        read_padded_chunk(&mut &bytes[..], &mut chunk, 6).unwrap();
+        assert_eq!(chunk, vec![1, 0, 0, 0, 2, 3, 0, 0]);
+
+        assert!(read_padded_chunk(&mut &bytes[..], &mut chunk, 8).is_err());
+    }
+}
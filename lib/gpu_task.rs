@@ -1,46 +1,126 @@
 use std::{
     collections::HashMap,
-    ffi::c_void,
+    ops::Range,
     ptr,
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use ash::vk::{
-    AccessFlags, BufferCopy, BufferUsageFlags, CommandBuffer, DependencyFlags,
-    DescriptorBufferInfo, DescriptorPool, DescriptorPoolCreateFlags, DescriptorPoolCreateInfo,
-    DescriptorPoolSize, DescriptorSet, DescriptorSetAllocateInfo, DescriptorType, Fence,
-    MemoryBarrier, PipelineBindPoint, PipelineStageFlags, StructureType, WriteDescriptorSet, DescriptorPoolResetFlags,
+    self, AccessFlags, BufferCopy, BufferUsageFlags, CommandBuffer, CommandBufferUsageFlags,
+    DescriptorBufferInfo, DescriptorPool, DescriptorSet, DescriptorType, Fence, PipelineBindPoint,
+    PipelineStageFlags, Semaphore, StructureType, WriteDescriptorSet,
 };
 
 use super::{
-    allocation_strategy::Allocator, allocation_strategy::Buffer, command_buffer_util,
-    device::DeviceInfo, pipeline::Pipeline, ComputeManager, Tensor,
+    allocation_strategy::Allocator,
+    allocation_strategy::Buffer,
+    barrier::{self, Barrier},
+    command_buffer_util,
+    device::DeviceInfo,
+    kernel_assert::{
+        AssertBuffer, KernelAssertionFailed, ASSERT_BUFFER_BYTES, KERNEL_ASSERT_BINDING,
+    },
+    pipeline::{LayoutMismatch, Pipeline},
+    progress::{ProgressBuffer, KERNEL_PROGRESS_BINDING, PROGRESS_BUFFER_BYTES},
+    queue_ownership::{self, OwnershipTransfer, QueueRole},
+    resource_state::ResourceStates,
+    resource_tracker::{LiveResourceKind, TrackedResource},
+    staging::{self, StagingError, StagingGenerations},
+    submission::CompletionCallback,
+    timing_budget::OpTimers,
+    ComputeManager, Tensor,
 };
 
+// Small tensors have no staging buffer and are uploaded with `vkCmdUpdateBuffer`. If they need
+// readback, their GPU buffer is host-visible and read directly instead of through a readback buffer.
 struct TensorBufferBacking {
     pub(super) gpu_buffer: Buffer,
-    pub(super) staging_buffer: Buffer,
+    pub(super) staging_buffer: Option<Buffer>,
 
     pub(super) readback_buffer: Option<Buffer>,
+    pub(super) generations: StagingGenerations,
+}
+
+/// Tensors at or below this size take the low-latency path with no staging or readback copies.
+pub const SMALL_TENSOR_MAX_BYTES: u64 = 4096;
+
+fn is_small_tensor(size_bytes: u64) -> bool {
+    size_bytes > 0 && size_bytes <= SMALL_TENSOR_MAX_BYTES
 }
 
 pub struct GPUTask {
-    command_buffer: CommandBuffer,
+    pub(super) command_buffer: CommandBuffer,
     device_info: DeviceInfo,
+    // Keyed by tensor, or by arena for tensors carved out of one
     buffers: HashMap<u32, TensorBufferBacking>,
+    // The backing key of every bound tensor and its byte offset in the backing buffers
+    slots: HashMap<u32, (u32, u64)>,
     descriptor_set: DescriptorSet,
     parent_descriptor_pool: DescriptorPool,
+    descriptor_buffer: Option<Buffer>,
     allocator: Arc<RwLock<Allocator>>,
+    bindings: Vec<TaskBinding>,
+    ops: Vec<RecordedOp>,
+    // Tensors a dispatch reads before this task uploads them
+    device_inputs: Vec<u32>,
+    // The stage and access of the last device write to each tensor
+    device_writes: Vec<(u32, PipelineStageFlags, AccessFlags)>,
+    priority: TaskPriority,
+    op_timers: Option<OpTimers>,
+    assert_buffer: Option<AssertBuffer>,
+    progress_buffer: Option<ProgressBuffer>,
+    // Host data of small tensors, kept so the command buffer can be recorded again
+    inline_uploads: HashMap<u32, Vec<u8>>,
+    // The pipeline generation the command buffer was recorded against
+    pipeline_generation: u64,
+    _tracking: Option<TrackedResource>,
+    pub(super) pipeline: Arc<Pipeline>,
+    // Pipelines dispatched with the task's descriptor set besides its own
+    secondary_pipelines: Vec<Arc<Pipeline>>,
+
+    parent: Arc<ComputeManager>,
+}
+
+// Ops are only validated while recording; buffers, descriptors and the command buffer are created
+// in `finalize`.
+pub struct GPUTaskInProcess<'a> {
+    diagnostics: Vec<GPUTaskRecordingDiagnostic>,
+    pipeline: Arc<Pipeline>,
+    secondary_pipelines: Vec<Arc<Pipeline>>,
+    bindings: Vec<(u32, &'a Tensor)>,
+    // Tensors no dispatch of the task writes
+    read_only: Vec<u32>,
+    ops: Vec<PendingOp<'a>>,
+    priority: TaskPriority,
+    persistent_staging: bool,
+    // Expected durations of budgeted dispatches by op index
+    budgets: Vec<(usize, Duration)>,
 
-    _parent: Arc<ComputeManager>,
+    parent: Arc<ComputeManager>,
 }
 
-pub struct GPUTaskInProcess {
-    errno: Option<GPUTaskRecordingError>,
-    task: Option<GPUTask>,
+enum PendingOp<'a> {
+    LocalSyncDevice(Vec<&'a Tensor>),
+    // The pipeline is indexed like `RecordedOp::PipelineDispatch`'s
+    PipelineDispatch(WorkGroupSize, usize),
+    DeviceSyncLocal(Vec<&'a Tensor>),
+    Ownership(Vec<&'a Tensor>, OwnershipTransfer),
 }
 
-#[derive(Debug, Clone, Copy)]
+impl RecordedOp {
+    fn kind(&self) -> GPUTaskOpKind {
+        match self {
+            RecordedOp::LocalSyncDevice { .. } => GPUTaskOpKind::LocalSyncDevice,
+            RecordedOp::PipelineDispatch { .. } => GPUTaskOpKind::PipelineDispatch,
+            RecordedOp::DeviceSyncLocal { .. } => GPUTaskOpKind::DeviceSyncLocal,
+            RecordedOp::ReleaseOwnership { .. } => GPUTaskOpKind::ReleaseOwnership,
+            RecordedOp::AcquireOwnership { .. } => GPUTaskOpKind::AcquireOwnership,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct WorkGroupSize {
     pub x: u32,
     pub y: u32,
@@ -49,7 +129,11 @@ pub struct WorkGroupSize {
 
 pub struct GPUSyncPrimitive<'a> {
     pub(super) fence: Fence,
+    prologues: Vec<CommandBuffer>,
+    // Exported to another context, which may still be waiting on it
+    signal_semaphore: Option<Semaphore>,
 
+    _pipeline: Arc<Pipeline>,
     parent: &'a GPUTask,
 }
 
@@ -57,262 +141,175 @@ pub struct GPUSyncPrimitive<'a> {
 pub enum GPUTaskRecordingError {
     CommandBufferAllocationFailure,
     CommandBufferRecordingStartFailure,
+    CommandBufferRecordingEndFailure,
     BufferAllocationFailure,
     DescriptorSetAllocationFailure,
+    TensorNotBound,
+    ReadbackNotEnabled,
+    DuplicateBinding(u32),
+    MissingBinding(u32),
+    BindingOutOfRange(u32),
+    TensorBoundTwice,
+    WorkGroupCountExceeded(DispatchAxis),
+    WorkGroupSizeExceeded(DispatchAxis),
+    WorkGroupInvocationsExceeded,
+    IncompatiblePipelineLayout(LayoutMismatch),
+    /// A tensor marked read-only is bound where a shader doesn't declare its buffer `readonly`
+    BindingNotReadOnly(u32),
+    /// Read-only tensors aren't written on the device, so there is nothing to read back
+    ReadOnlyTensor,
     UnknownError,
 }
 
-impl ComputeManager {
-    pub fn new_task(
-        self: Arc<Self>,
-        pipeline: &Pipeline,
-        bindings: Vec<&Tensor>,
-    ) -> GPUTaskInProcess {
-        let mut buffer_backing = HashMap::<u32, TensorBufferBacking>::with_capacity(bindings.len());
-
-        // Allocate buffers
-        for (_i, binding) in bindings.iter().enumerate() {
-            let mut allocator_actual = match self.allocator.write() {
-                Ok(a) => a,
-                Err(e) => {
-                    log::error!("Failed to acquire allocator! Error: {e}");
-                    return GPUTaskInProcess {
-                        errno: Some(GPUTaskRecordingError::BufferAllocationFailure),
-                        task: None,
-                    };
-                }
-            };
-
-            let gpu_buffer = match allocator_actual.allocate_buffer(
-                &self.device_info,
-                (binding.data().len() * 4) as u64,
-                BufferUsageFlags::STORAGE_BUFFER
-                    | BufferUsageFlags::TRANSFER_SRC
-                    | BufferUsageFlags::TRANSFER_DST,
-                gpu_allocator::MemoryLocation::GpuOnly,
-                format!("gpu_only_alloc{{id={}}}", binding.id).as_str(),
-                self.device_info.queue_indices.compute_queue.unwrap(),
-            ) {
-                Ok(b) => b,
-                Err(e) => {
-                    log::error!("Failed to allocate buffer! Error: {:?}", e);
-                    return GPUTaskInProcess {
-                        errno: Some(GPUTaskRecordingError::BufferAllocationFailure),
-                        task: None,
-                    };
-                }
-            };
-
-            let staging_buffer = match allocator_actual.allocate_buffer(
-                &self.device_info,
-                (binding.data().len() * 4) as u64,
-                BufferUsageFlags::TRANSFER_SRC,
-                gpu_allocator::MemoryLocation::CpuToGpu,
-                format!("gpu_staging_only_alloc{{id={}}}", binding.id).as_str(),
-                self.device_info.queue_indices.compute_queue.unwrap(),
-            ) {
-                Ok(b) => b,
-                Err(e) => {
-                    log::error!("Failed to allocate buffer! Error: {:?}", e);
-                    return GPUTaskInProcess {
-                        errno: Some(GPUTaskRecordingError::BufferAllocationFailure),
-                        task: None,
-                    };
-                }
-            };
-
-            let readback_buffer = if binding.readback_enabled {
-                Some(
-                    match allocator_actual.allocate_buffer(
-                        &self.device_info,
-                        (binding.data().len() * 4) as u64,
-                        BufferUsageFlags::TRANSFER_DST,
-                        gpu_allocator::MemoryLocation::CpuToGpu,
-                        format!("gpu_staging_only_alloc{{id={}}}", binding.id).as_str(),
-                        self.device_info.queue_indices.compute_queue.unwrap(),
-                    ) {
-                        Ok(b) => b,
-                        Err(e) => {
-                            log::error!("Failed to allocate buffer! Error: {:?}", e);
-                            return GPUTaskInProcess {
-                                errno: Some(GPUTaskRecordingError::BufferAllocationFailure),
-                                task: None,
-                            };
-                        }
-                    },
-                )
-            } else {
-                None
-            };
-
-            let backing = TensorBufferBacking {
-                gpu_buffer,
-                staging_buffer,
-                readback_buffer,
-            };
-
-            buffer_backing.insert(binding.id, backing);
-        }
+/// Task submissions waiting at the same time reach the queue in priority order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TaskPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
 
-        let pool_size = DescriptorPoolSize {
-            ty: DescriptorType::STORAGE_BUFFER,
-            descriptor_count: bindings.len() as u32,
-        };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchAxis {
+    X,
+    Y,
+    Z,
+}
 
-        let descriptor_pool_create_info = DescriptorPoolCreateInfo {
-            s_type: StructureType::DESCRIPTOR_POOL_CREATE_INFO,
-            p_next: ptr::null(),
-            flags: DescriptorPoolCreateFlags::empty(),
-            max_sets: 10,
-            pool_size_count: 1,
-            p_pool_sizes: &pool_size,
-        };
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordedOp {
+    LocalSyncDevice {
+        tensor_ids: Vec<u32>,
+    },
+    PipelineDispatch {
+        work_group: WorkGroupSize,
+        /// 0 for the task's own pipeline, otherwise one more than its index among the pipelines
+        /// added with `op_pipeline_dispatch_with`
+        pipeline: usize,
+    },
+    DeviceSyncLocal {
+        tensor_ids: Vec<u32>,
+    },
+    ReleaseOwnership {
+        tensor_ids: Vec<u32>,
+        to: QueueRole,
+    },
+    AcquireOwnership {
+        tensor_ids: Vec<u32>,
+        from: QueueRole,
+    },
+}
 
-        let descriptor_pool = unsafe {
-            match self
-                .device_info
-                .device
-                .create_descriptor_pool(&descriptor_pool_create_info, None)
-            {
-                Ok(p) => p,
-                Err(e) => {
-                    log::error!("Failed to create descriptor pool! Error: {}", e);
-                    return GPUTaskInProcess {
-                        errno: Some(GPUTaskRecordingError::DescriptorSetAllocationFailure),
-                        task: None,
-                    };
-                }
-            }
-        };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskBinding {
+    pub binding: u32,
+    pub tensor_id: u32,
+    pub size_bytes: u64,
+    /// Always `false` for read-only tensors, which get no readback buffer
+    pub readback_enabled: bool,
+    pub read_only: bool,
+}
 
-        let descriptor_set_alloc_info = DescriptorSetAllocateInfo {
-            s_type: StructureType::DESCRIPTOR_SET_ALLOCATE_INFO,
-            p_next: ptr::null(),
-            descriptor_pool,
-            descriptor_set_count: 1,
-            p_set_layouts: &pipeline.descriptor_set_layout,
-        };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GPUTaskOpKind {
+    NewTask,
+    LocalSyncDevice,
+    PipelineDispatch,
+    DeviceSyncLocal,
+    ReleaseOwnership,
+    AcquireOwnership,
+}
 
-        let descriptor_set = unsafe {
-            match self
-                .device_info
-                .device
-                .allocate_descriptor_sets(&descriptor_set_alloc_info)
-            {
-                Ok(s) => s,
-                Err(e) => {
-                    log::error!("Failed to allocate descriptor set! Error: {}", e);
-                    return GPUTaskInProcess {
-                        errno: Some(GPUTaskRecordingError::DescriptorSetAllocationFailure),
-                        task: None,
-                    };
-                }
-            }
-        };
+#[derive(Debug, Clone)]
+pub struct GPUTaskRecordingDiagnostic {
+    /// `None` for failures while creating the task itself
+    pub op_index: Option<usize>,
+    pub op_kind: GPUTaskOpKind,
+    pub tensor_ids: Vec<u32>,
+    pub error: GPUTaskRecordingError,
+}
 
-        {
-            let mut descriptor_writes = Vec::<WriteDescriptorSet>::with_capacity(bindings.len());
-            let mut descriptor_write_buffer_infos =
-                Vec::<DescriptorBufferInfo>::with_capacity(bindings.len());
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GPUTaskResourceEstimate {
+    pub device_memory_bytes: u64,
+    /// Staging buffers, plus the descriptor buffer on devices that use descriptor buffers
+    pub staging_memory_bytes: u64,
+    /// Readback buffers, plus the kernel assert and progress buffers
+    pub readback_memory_bytes: u64,
+    pub buffer_count: u32,
+    pub descriptor_set_count: u32,
+    pub descriptor_count: u32,
+}
 
-            bindings.iter().enumerate().for_each(|(i, binding)| {
-                descriptor_write_buffer_infos.push(DescriptorBufferInfo {
-                    buffer: buffer_backing
-                        .get(&binding.id)
-                        .unwrap()
-                        .gpu_buffer
-                        .buffer,
-                    offset: 0,
-                    range: (binding.data().len() * 4) as u64,
-                });
-                descriptor_writes.push(WriteDescriptorSet {
-                    s_type: StructureType::WRITE_DESCRIPTOR_SET,
-                    p_next: ptr::null(),
-                    dst_set: descriptor_set[0],
-                    dst_binding: i as u32,
-                    dst_array_element: 0,
-                    descriptor_count: 1,
-                    descriptor_type: DescriptorType::STORAGE_BUFFER,
-                    p_image_info: ptr::null(),
-                    p_buffer_info: &descriptor_write_buffer_infos[i],
-                    p_texel_buffer_view: ptr::null(),
-                });
-            });
+impl ComputeManager {
+    pub fn new_task<'a>(
+        self: Arc<Self>,
+        pipeline: &Arc<Pipeline>,
+        bindings: Vec<&'a Tensor>,
+    ) -> GPUTaskInProcess<'a> {
+        self.new_task_with_bindings(
+            pipeline,
+            bindings
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| (i as u32, t))
+                .collect(),
+        )
+    }
 
-            unsafe {
-                self.device_info
-                    .device
-                    .update_descriptor_sets(descriptor_writes.as_slice(), &[]);
-            }
-        }
+    pub fn new_task_with_bindings<'a>(
+        self: Arc<Self>,
+        pipeline: &Arc<Pipeline>,
+        mut bindings: Vec<(u32, &'a Tensor)>,
+    ) -> GPUTaskInProcess<'a> {
+        bindings.sort_by_key(|(index, _)| *index);
 
-        let command_buffer = match command_buffer_util::allocate_command_buffer(
-            &self.device_info.device,
-            self.device_info.compute_pool,
-        ) {
-            Ok(b) => b,
-            Err(e) => {
-                log::error!("Failed to allocate command buffer! Error: {}", e);
-                return GPUTaskInProcess {
-                    errno: Some(GPUTaskRecordingError::CommandBufferAllocationFailure),
-                    task: None,
-                };
-            }
+        let mut task = GPUTaskInProcess {
+            diagnostics: Vec::new(),
+            pipeline: pipeline.clone(),
+            secondary_pipelines: Vec::new(),
+            bindings,
+            read_only: Vec::new(),
+            ops: Vec::new(),
+            priority: TaskPriority::Normal,
+            persistent_staging: false,
+            budgets: Vec::new(),
+            parent: self,
         };
+        task.validate_bindings();
+        task
+    }
 
-        match command_buffer_util::begin_command_buffer_recording(
-            &self.device_info.device,
-            command_buffer,
-            false,
-        ) {
-            Ok(_) => (),
-            Err(e) => {
-                log::error!("Failed to begin command buffer recording! Error: {}", e);
-                return GPUTaskInProcess {
-                    errno: Some(GPUTaskRecordingError::CommandBufferRecordingStartFailure),
-                    task: None,
-                };
-            }
-        }
-
-        unsafe {
-            self.device_info.device.cmd_bind_pipeline(
-                command_buffer,
-                PipelineBindPoint::COMPUTE,
-                pipeline.pipeline,
-            );
-
-            self.device_info.device.cmd_bind_descriptor_sets(
-                command_buffer,
-                PipelineBindPoint::COMPUTE,
-                pipeline.pipeline_layout,
-                0,
-                &[descriptor_set[0]],
-                &[],
-            );
-        }
+    pub fn exec_task<'a>(&self, task: &'a GPUTask) -> Option<GPUSyncPrimitive<'a>> {
+        self.submit_task(task, None, None)
+    }
 
-        GPUTaskInProcess {
-            task: Some(GPUTask {
-                command_buffer,
-                device_info: self.device_info.clone(),
-                buffers: buffer_backing,
-                descriptor_set: descriptor_set[0],
-                parent_descriptor_pool: descriptor_pool,
-                allocator: self.allocator.clone(),
-                _parent: self.clone(),
-            }),
-            errno: None,
-        }
+    /// `on_complete` runs on the submission thread once the task finishes, with `false` if the
+    /// task failed. The task must still be awaited to read back its results.
+    pub fn exec_task_with_callback<'a, F>(
+        &self,
+        task: &'a GPUTask,
+        on_complete: F,
+    ) -> Option<GPUSyncPrimitive<'a>>
+    where
+        F: FnOnce(bool) + Send + 'static,
+    {
+        self.submit_task(task, None, Some(Box::new(on_complete)))
     }
 
-    pub fn exec_task<'a>(&self, task: &'a GPUTask) -> Option<GPUSyncPrimitive<'a>> {
-        let fence = match command_buffer_util::end_and_submit_command_buffer(
-            &self.device_info.device,
-            task.command_buffer,
-            self.device_info.compute_queue,
+    pub(super) fn submit_task<'a>(
+        &self,
+        task: &'a GPUTask,
+        signal_semaphore: Option<Semaphore>,
+        on_complete: Option<CompletionCallback>,
+    ) -> Option<GPUSyncPrimitive<'a>> {
+        let submission = match self.submit_tracked(
+            &[task],
+            signal_semaphore.as_slice(),
+            on_complete,
         ) {
-            Ok(f) => f,
+            Ok(s) => s,
             Err(e) => {
                 log::error!("Failed to submit command buffer! Error: {}", e);
                 return None;
@@ -320,250 +317,1758 @@ impl ComputeManager {
         };
 
         Some(GPUSyncPrimitive {
-            fence,
+            fence: submission.fence,
+            prologues: submission.prologues,
+            signal_semaphore,
+            _pipeline: task.pipeline.clone(),
             parent: task,
         })
     }
 
-    pub fn await_task(&self, sync: &GPUSyncPrimitive, sync_tensors: Vec<&mut Tensor>) {
+    /// Waits for the task and reads back `sync_tensors`. Fails if a kernel assert failed during the
+    /// submission; the tensors are read back either way.
+    pub fn await_task(
+        &self,
+        sync: &GPUSyncPrimitive,
+        sync_tensors: Vec<&mut Tensor>,
+    ) -> Result<(), KernelAssertionFailed> {
         unsafe {
             let _ = self
                 .device_info
                 .device
                 .wait_for_fences(&[sync.fence], true, u64::MAX);
+        }
+        self.submission_thread.destroy_fence(sync.fence);
+        self.free_prologues(&sync.prologues);
+        // The signal has completed, so the importer holds its own reference to the payload
+        if let Some(semaphore) = sync.signal_semaphore {
+            unsafe {
+                self.device_info.device.destroy_semaphore(semaphore, None);
+            }
+        }
+
+        // Failures are logged per tensor, and the rest are still read back
+        let _ = sync.parent.copy_readback(sync_tensors);
+        sync.parent.check_kernel_asserts()
+    }
 
-            self.device_info.device.destroy_fence(sync.fence, None);
+    /// Blocks until at least one of `syncs` has finished and returns its index, the lowest if
+    /// several have. Nothing is read back: awaiting the winner with `await_task` returns straight
+    /// away with its results, while the others keep running and still need awaiting. Returns
+    /// `None` if `syncs` is empty or the wait fails.
+    pub fn await_any(&self, syncs: &[&GPUSyncPrimitive]) -> Option<usize> {
+        if syncs.is_empty() {
+            return None;
         }
 
-        sync_tensors.into_iter().for_each(|tensor| unsafe {
-            let backing = match sync.parent.buffers.get(&tensor.id) {
-                Some(b) => b,
-                None => {
-                    log::error!(
-                        "Failed to find backing buffer for tensor! This is an internal issue!"
-                    );
-                    return;
-                }
-            };
+        let device = &self.device_info.device;
+        let fences: Vec<Fence> = syncs.iter().map(|s| s.fence).collect();
+        if let Err(e) = unsafe { device.wait_for_fences(&fences, false, u64::MAX) } {
+            log::error!("Failed to wait for any task! Error: {}", e);
+            return None;
+        }
 
-            let mapped_ptr = backing
-                .readback_buffer
-                .as_ref()
-                .unwrap()
-                .allocation
-                .mapped_ptr()
-                .unwrap()
-                .as_ptr() as *mut f32;
-
-            tensor
-                .data_mut()
-                .as_mut_ptr()
-                .copy_from(mapped_ptr as *const f32, tensor.data().len());
-        });
+        // A fence that can't be queried belongs to a failed task, which `await_task` reports
+        fences
+            .iter()
+            .position(|f| !matches!(unsafe { device.get_fence_status(*f) }, Ok(false)))
     }
 }
 
-impl GPUTaskInProcess {
-    pub fn op_local_sync_device(self, tensors: Vec<&Tensor>) -> Self {
-        if self.task.is_none() || self.errno.is_some() {
-            return self;
-        }
+impl<'a> GPUTaskInProcess<'a> {
+    fn push_binding_diagnostic(&mut self, error: GPUTaskRecordingError, tensor_ids: Vec<u32>) {
+        self.diagnostics.push(GPUTaskRecordingDiagnostic {
+            op_index: None,
+            op_kind: GPUTaskOpKind::NewTask,
+            tensor_ids,
+            error,
+        });
+    }
 
-        tensors.iter().for_each(|tensor| unsafe {
-            let backing = match self.task.as_ref().unwrap().buffers.get(&tensor.id) {
-                Some(b) => b,
-                None => {
-                    log::error!(
-                        "Failed to find backing buffer for tensor! This is an internal issue!"
-                    );
-                    return;
-                }
-            };
+    fn validate_bindings(&mut self) {
+        let binding_count = self.pipeline.binding_count();
+        let bindings = self.bindings.clone();
 
-            backing
-                .staging_buffer
-                .allocation
-                .mapped_ptr()
-                .unwrap()
-                .as_ptr()
-                .copy_from(
-                    tensor.data().as_ptr() as *const c_void,
-                    tensor.data().len() * 4_usize,
+        for pair in bindings.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                self.push_binding_diagnostic(
+                    GPUTaskRecordingError::DuplicateBinding(pair[0].0),
+                    vec![pair[0].1.id, pair[1].1.id],
                 );
+            }
+        }
 
-            self.task
-                .as_ref()
-                .unwrap()
-                .device_info
-                .device
-                .cmd_copy_buffer(
-                    self.task.as_ref().unwrap().command_buffer,
-                    backing.staging_buffer.buffer,
-                    backing.gpu_buffer.buffer,
-                    &[BufferCopy {
-                        src_offset: 0,
-                        dst_offset: 0,
-                        size: (tensor.data().len() * 4) as u64,
-                    }],
+        for (i, (index, tensor)) in bindings.iter().enumerate() {
+            if *index >= binding_count {
+                self.push_binding_diagnostic(
+                    GPUTaskRecordingError::BindingOutOfRange(*index),
+                    vec![tensor.id],
                 );
-        });
+            }
 
-        unsafe {
-            self.task
-                .as_ref()
-                .unwrap()
-                .device_info
-                .device
-                .cmd_pipeline_barrier(
-                    self.task.as_ref().unwrap().command_buffer,
-                    PipelineStageFlags::TRANSFER,
-                    PipelineStageFlags::COMPUTE_SHADER,
-                    DependencyFlags::empty(),
-                    &[MemoryBarrier {
-                        s_type: StructureType::MEMORY_BARRIER,
-                        p_next: ptr::null(),
-                        src_access_mask: AccessFlags::MEMORY_WRITE,
-                        dst_access_mask: AccessFlags::MEMORY_WRITE | AccessFlags::MEMORY_READ,
-                    }],
-                    &[],
-                    &[],
+            if bindings[..i].iter().any(|(_, t)| t.id == tensor.id) {
+                self.push_binding_diagnostic(
+                    GPUTaskRecordingError::TensorBoundTwice,
+                    vec![tensor.id],
                 );
+            }
         }
 
-        self
+        for index in 0..binding_count {
+            if !bindings.iter().any(|(i, _)| *i == index) {
+                self.push_binding_diagnostic(
+                    GPUTaskRecordingError::MissingBinding(index),
+                    Vec::new(),
+                );
+            }
+        }
     }
 
-    pub fn op_pipeline_dispatch(self, work_group: WorkGroupSize) -> Self {
-        if self.task.is_none() || self.errno.is_some() {
-            return self;
+    // Validates an op's tensors against the task bindings and returns the op's index. Ops keep
+    // being validated after a failure so `finalize` can report every problem at once.
+    fn validate_op(&mut self, op_kind: GPUTaskOpKind, tensors: &[&Tensor]) -> usize {
+        let op_index = self.ops.len();
+
+        let unbound: Vec<u32> = tensors
+            .iter()
+            .filter(|t| !self.bindings.iter().any(|(_, b)| b.id == t.id))
+            .map(|t| t.id)
+            .collect();
+        if !unbound.is_empty() {
+            self.diagnostics.push(GPUTaskRecordingDiagnostic {
+                op_index: Some(op_index),
+                op_kind,
+                tensor_ids: unbound,
+                error: GPUTaskRecordingError::TensorNotBound,
+            });
         }
 
-        unsafe {
-            self.task.as_ref().unwrap().device_info.device.cmd_dispatch(
-                self.task.as_ref().unwrap().command_buffer,
-                work_group.x,
-                work_group.y,
-                work_group.z,
-            );
+        if op_kind == GPUTaskOpKind::DeviceSyncLocal {
+            let no_readback: Vec<u32> = tensors
+                .iter()
+                .filter(|t| {
+                    self.bindings
+                        .iter()
+                        .any(|(_, b)| b.id == t.id && !b.readback_enabled)
+                })
+                .map(|t| t.id)
+                .collect();
+            if !no_readback.is_empty() {
+                self.diagnostics.push(GPUTaskRecordingDiagnostic {
+                    op_index: Some(op_index),
+                    op_kind,
+                    tensor_ids: no_readback,
+                    error: GPUTaskRecordingError::ReadbackNotEnabled,
+                });
+            }
         }
 
-        self
+        op_index
     }
 
-    pub fn op_device_sync_local(self, tensors: Vec<&Tensor>) -> Self {
-        if self.task.is_none() || self.errno.is_some() {
-            return self;
-        }
+    // Checked once all ops are known, since tensors can be marked read-only after the ops using
+    // them and pipelines dispatched after the marking
+    fn validate_read_only(&mut self) {
+        let writable: Vec<(u32, u32)> = self
+            .bindings
+            .iter()
+            .filter(|(index, tensor)| {
+                self.read_only.contains(&tensor.id)
+                    && !std::iter::once(&self.pipeline)
+                        .chain(self.secondary_pipelines.iter())
+                        .all(|p| p.binding_read_only(*index))
+            })
+            .map(|(index, tensor)| (*index, tensor.id))
+            .collect();
+        writable.into_iter().for_each(|(index, tensor_id)| {
+            self.push_binding_diagnostic(
+                GPUTaskRecordingError::BindingNotReadOnly(index),
+                vec![tensor_id],
+            )
+        });
 
-        unsafe {
-            self.task
-                .as_ref()
-                .unwrap()
-                .device_info
-                .device
-                .cmd_pipeline_barrier(
-                    self.task.as_ref().unwrap().command_buffer,
-                    PipelineStageFlags::COMPUTE_SHADER,
-                    PipelineStageFlags::TRANSFER,
-                    DependencyFlags::empty(),
-                    &[MemoryBarrier {
-                        s_type: StructureType::MEMORY_BARRIER,
-                        p_next: ptr::null(),
-                        src_access_mask: AccessFlags::MEMORY_WRITE,
-                        dst_access_mask: AccessFlags::MEMORY_READ,
-                    }],
-                    &[],
-                    &[],
-                )
+        let synced: Vec<(usize, Vec<u32>)> = self
+            .ops
+            .iter()
+            .enumerate()
+            .filter_map(|(op_index, op)| match op {
+                PendingOp::DeviceSyncLocal(tensors) => Some((
+                    op_index,
+                    tensors
+                        .iter()
+                        .map(|t| t.id)
+                        .filter(|id| self.read_only.contains(id))
+                        .collect::<Vec<u32>>(),
+                )),
+                _ => None,
+            })
+            .filter(|(_, tensor_ids)| !tensor_ids.is_empty())
+            .collect();
+        synced.into_iter().for_each(|(op_index, tensor_ids)| {
+            self.diagnostics.push(GPUTaskRecordingDiagnostic {
+                op_index: Some(op_index),
+                op_kind: GPUTaskOpKind::DeviceSyncLocal,
+                tensor_ids,
+                error: GPUTaskRecordingError::ReadOnlyTensor,
+            })
+        });
+    }
+
+    // Dispatches outside the device's limits are undefined behavior, so they are caught here
+    fn validate_dispatch(
+        &mut self,
+        op_index: usize,
+        work_group: WorkGroupSize,
+        pipeline: &Pipeline,
+    ) {
+        let limits = self.parent.device_info.limits;
+        let axes = [DispatchAxis::X, DispatchAxis::Y, DispatchAxis::Z];
+        let mut errors = Vec::new();
+
+        // Oversized dispatches are split when recorded if the device can offset the parts
+        if !self.parent.device_info.dispatch_base {
+            [work_group.x, work_group.y, work_group.z]
+                .iter()
+                .zip(limits.max_compute_work_group_count)
+                .zip(axes)
+                .filter(|((count, limit), _)| **count > *limit)
+                .for_each(|(_, axis)| {
+                    errors.push(GPUTaskRecordingError::WorkGroupCountExceeded(axis))
+                });
         }
 
-        tensors.iter().for_each(|tensor| unsafe {
-            let backing = match self.task.as_ref().unwrap().buffers.get(&tensor.id) {
-                Some(b) => b,
-                None => {
-                    log::error!(
-                        "Failed to find backing buffer for tensor! This is an internal issue!"
-                    );
-                    return;
-                }
-            };
+        if let Some(local_size) = pipeline.local_size() {
+            local_size
+                .iter()
+                .zip(limits.max_compute_work_group_size)
+                .zip(axes)
+                .filter(|((size, limit), _)| **size > *limit)
+                .for_each(|(_, axis)| {
+                    errors.push(GPUTaskRecordingError::WorkGroupSizeExceeded(axis))
+                });
+        }
 
-            if backing.readback_buffer.is_none() {
-                log::error!("Tensor has no readback buffer! Did you enable readback on creation?");
-                return;
+        if let Some(invocations) = pipeline.local_invocations() {
+            if invocations > limits.max_compute_work_group_invocations as u64 {
+                errors.push(GPUTaskRecordingError::WorkGroupInvocationsExceeded);
             }
+        }
 
-            self.task
-                .as_ref()
-                .unwrap()
-                .device_info
-                .device
-                .cmd_copy_buffer(
-                    self.task.as_ref().unwrap().command_buffer,
-                    backing.gpu_buffer.buffer,
-                    backing.readback_buffer.as_ref().unwrap().buffer,
-                    &[BufferCopy {
-                        src_offset: 0,
-                        dst_offset: 0,
-                        size: (tensor.data().len() * 4) as u64,
-                    }],
-                )
+        errors.into_iter().for_each(|error| {
+            self.diagnostics.push(GPUTaskRecordingDiagnostic {
+                op_index: Some(op_index),
+                op_kind: GPUTaskOpKind::PipelineDispatch,
+                tensor_ids: Vec::new(),
+                error,
+            })
         });
+    }
 
+    pub fn with_priority(mut self, priority: TaskPriority) -> Self {
+        self.priority = priority;
         self
     }
 
-    pub fn finalize(self) -> Result<GPUTask, GPUTaskRecordingError> {
-        if self.errno.is_some() {
-            Err(self.errno.unwrap())
-        } else if self.task.is_some() {
-            return Ok(self.task.unwrap());
-        } else {
-            log::error!("This is an GPU task recording API error! Either you have done something really wrong or the API has a mistake in it that we haven't caught!");
-            return Err(GPUTaskRecordingError::UnknownError);
+    /// Gives every bound tensor a mapped staging buffer, so its data can be rewritten with
+    /// `GPUTask::write_staging` between submissions instead of re-recording the task
+    pub fn with_persistent_staging(mut self) -> Self {
+        self.persistent_staging = true;
+        self
+    }
+
+    /// Marks bound `tensors` as never written by the task's dispatches. Every pipeline the task
+    /// dispatches must declare their buffers `readonly`, and they can't be read back. Barriers
+    /// before dispatches then only wait for earlier writes to them, and they get no readback
+    /// buffer.
+    pub fn with_read_only(mut self, tensors: Vec<&'a Tensor>) -> Self {
+        let unbound: Vec<u32> = tensors
+            .iter()
+            .filter(|t| !self.bindings.iter().any(|(_, b)| b.id == t.id))
+            .map(|t| t.id)
+            .collect();
+        if !unbound.is_empty() {
+            self.push_binding_diagnostic(GPUTaskRecordingError::TensorNotBound, unbound);
         }
+
+        self.read_only.extend(tensors.iter().map(|t| t.id));
+        self
     }
-}
 
-impl Drop for GPUTask {
-    fn drop(&mut self) {
-        unsafe {
-            self.device_info.device.free_command_buffers(
-                self.device_info.compute_pool,
-                &[self.command_buffer],
-            );
+    pub fn op_local_sync_device(mut self, tensors: Vec<&'a Tensor>) -> Self {
+        self.validate_op(GPUTaskOpKind::LocalSyncDevice, &tensors);
+        self.ops.push(PendingOp::LocalSyncDevice(tensors));
+        self
+    }
 
-            let _ = self.device_info.device.reset_descriptor_pool(self.parent_descriptor_pool, DescriptorPoolResetFlags::empty());
-            self.device_info.device.destroy_descriptor_pool(self.parent_descriptor_pool, None);
-
-            // Free backing buffers
-            self.buffers.iter_mut().for_each(|(_, buffer)| {
-                let gpu_alloc = std::mem::take(&mut buffer.gpu_buffer.allocation);
-                if let Ok(mut allocator_actual) = self.allocator.write() {
-                    let _ = allocator_actual.vulkan_allocator.free(gpu_alloc);
-                    self.device_info
-                        .device
-                        .destroy_buffer(buffer.gpu_buffer.buffer, None);
-
-                    let stage_alloc = std::mem::take(&mut buffer.staging_buffer.allocation);
-                    let _ = allocator_actual.vulkan_allocator.free(stage_alloc);
-                    self.device_info
-                        .device
-                        .destroy_buffer(buffer.staging_buffer.buffer, None);
-
-                    if buffer.readback_buffer.is_some() {
-                        let readback_alloc = std::mem::take(
-                            &mut buffer.readback_buffer.as_mut().unwrap().allocation,
-                        );
-                        let _ = allocator_actual.vulkan_allocator.free(readback_alloc);
-                        self.device_info
-                            .device
-                            .destroy_buffer(buffer.readback_buffer.as_mut().unwrap().buffer, None);
-                    }
-                } else {
-                    log::error!("Failed to acquire allocator for GPU task!");
-                }
+    /// Work group counts over `maxComputeWorkGroupCount` are split into several dispatches with
+    /// base offsets on devices with Vulkan 1.1. `gl_WorkGroupID` and `gl_GlobalInvocationID` are
+    /// the same as for one dispatch, while `gl_NumWorkGroups` holds the size of each part.
+    pub fn op_pipeline_dispatch(mut self, work_group: WorkGroupSize) -> Self {
+        let op_index = self.validate_op(GPUTaskOpKind::PipelineDispatch, &[]);
+        let pipeline = self.pipeline.clone();
+        self.validate_dispatch(op_index, work_group, &pipeline);
+        self.ops.push(PendingOp::PipelineDispatch(work_group, 0));
+        self
+    }
+
+    /// Dispatches `pipeline` instead of the task's own, with the task's bindings. Its layout must
+    /// match the task pipeline's binding for binding, since the same descriptor set is bound for
+    /// both.
+    pub fn op_pipeline_dispatch_with(
+        mut self,
+        pipeline: &Arc<Pipeline>,
+        work_group: WorkGroupSize,
+    ) -> Self {
+        let op_index = self.validate_op(GPUTaskOpKind::PipelineDispatch, &[]);
+        if let Err(mismatch) = self.pipeline.check_layout_compatible(pipeline) {
+            self.diagnostics.push(GPUTaskRecordingDiagnostic {
+                op_index: Some(op_index),
+                op_kind: GPUTaskOpKind::PipelineDispatch,
+                tensor_ids: Vec::new(),
+                error: GPUTaskRecordingError::IncompatiblePipelineLayout(mismatch),
             });
         }
+        self.validate_dispatch(op_index, work_group, pipeline);
+
+        let index = if Arc::ptr_eq(pipeline, &self.pipeline) {
+            0
+        } else {
+            match self
+                .secondary_pipelines
+                .iter()
+                .position(|p| Arc::ptr_eq(p, pipeline))
+            {
+                Some(i) => i + 1,
+                None => {
+                    self.secondary_pipelines.push(pipeline.clone());
+                    self.secondary_pipelines.len()
+                }
+            }
+        };
+        self.ops
+            .push(PendingOp::PipelineDispatch(work_group, index));
+        self
+    }
+
+    /// Like `op_pipeline_dispatch`, but times the dispatch on the device and reports it through
+    /// `ComputeManager::take_budget_warnings` when it runs over `expected` by more than the budget
+    /// factor. Checked whenever the task's results are read.
+    pub fn op_pipeline_dispatch_with_budget(
+        mut self,
+        work_group: WorkGroupSize,
+        expected: Duration,
+    ) -> Self {
+        self.budgets.push((self.ops.len(), expected));
+        self.op_pipeline_dispatch(work_group)
+    }
+
+    pub fn op_device_sync_local(mut self, tensors: Vec<&'a Tensor>) -> Self {
+        self.validate_op(GPUTaskOpKind::DeviceSyncLocal, &tensors);
+        self.ops.push(PendingOp::DeviceSyncLocal(tensors));
+        self
+    }
+
+    /// Hands `tensors` over to the queue of `to`. The queue that uses them next must acquire them
+    /// with `op_acquire_ownership`. Nothing is recorded while both queues share a family.
+    pub fn op_release_ownership(mut self, tensors: Vec<&'a Tensor>, to: QueueRole) -> Self {
+        self.validate_op(GPUTaskOpKind::ReleaseOwnership, &tensors);
+        self.ops.push(PendingOp::Ownership(
+            tensors,
+            OwnershipTransfer::Release { to },
+        ));
+        self
+    }
+
+    /// Takes `tensors` over from the queue of `from`, after it released them
+    pub fn op_acquire_ownership(mut self, tensors: Vec<&'a Tensor>, from: QueueRole) -> Self {
+        self.validate_op(GPUTaskOpKind::AcquireOwnership, &tensors);
+        self.ops.push(PendingOp::Ownership(
+            tensors,
+            OwnershipTransfer::Acquire { from },
+        ));
+        self
+    }
+
+    pub fn estimate(&self) -> GPUTaskResourceEstimate {
+        let mut estimate = GPUTaskResourceEstimate {
+            descriptor_count: self.bindings.len() as u32,
+            ..Default::default()
+        };
+        match self.pipeline.descriptor_buffer_layout.as_ref() {
+            Some(layout) => {
+                estimate.staging_memory_bytes += layout.size;
+                estimate.buffer_count += 1;
+            }
+            None => estimate.descriptor_set_count = 1,
+        }
+        [
+            (self.pipeline.assert_binding, ASSERT_BUFFER_BYTES),
+            (self.pipeline.progress_binding, PROGRESS_BUFFER_BYTES),
+        ]
+        .iter()
+        .filter(|(bound, _)| *bound)
+        .for_each(|(_, size)| {
+            estimate.readback_memory_bytes += size;
+            estimate.buffer_count += 1;
+            estimate.descriptor_count += 1;
+        });
+
+        let uploaded = self.uploaded_tensors();
+        let mut arenas = Vec::new();
+        self.bindings.iter().for_each(|(_, binding)| {
+            let (key, size) = match binding.arena {
+                Some(arena) if arenas.contains(&arena.arena_id) => return,
+                Some(arena) => {
+                    arenas.push(arena.arena_id);
+                    (arena.arena_id, arena.arena_size)
+                }
+                None => (binding.id, (binding.data().len() * 4) as u64),
+            };
+            let arena_tensors: Vec<&Tensor> = self
+                .bindings
+                .iter()
+                .map(|(_, t)| *t)
+                .filter(|t| t.arena.map_or(t.id, |a| a.arena_id) == key)
+                .collect();
+
+            estimate.device_memory_bytes += size;
+            estimate.buffer_count += 1;
+            let uploads = arena_tensors.iter().any(|t| uploaded.contains(&t.id));
+            if self.persistent_staging || (uploads && !is_small_tensor(size)) {
+                estimate.staging_memory_bytes += size;
+                estimate.buffer_count += 1;
+            }
+
+            let readback_enabled = arena_tensors
+                .iter()
+                .any(|t| t.readback_enabled && !self.read_only.contains(&t.id));
+            if readback_enabled && !is_small_tensor(size) {
+                estimate.readback_memory_bytes += size;
+                estimate.buffer_count += 1;
+            }
+        });
+
+        estimate
+    }
+
+    // Tensors an op uploads, the only ones that need staging unless it's persistent
+    fn uploaded_tensors(&self) -> Vec<u32> {
+        self.ops
+            .iter()
+            .flat_map(|op| match op {
+                PendingOp::LocalSyncDevice(tensors) => tensors.iter().map(|t| t.id).collect(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    pub fn finalize(mut self) -> Result<GPUTask, Vec<GPUTaskRecordingDiagnostic>> {
+        self.validate_read_only();
+        if !self.diagnostics.is_empty() {
+            for diagnostic in &self.diagnostics {
+                log::error!("GPU task recording failed: {:?}", diagnostic);
+            }
+            return Err(self.diagnostics);
+        }
+
+        match self.record() {
+            Ok(task) => Ok(task),
+            Err(error) => {
+                let diagnostic = GPUTaskRecordingDiagnostic {
+                    op_index: None,
+                    op_kind: GPUTaskOpKind::NewTask,
+                    tensor_ids: self.bindings.iter().map(|(_, b)| b.id).collect(),
+                    error,
+                };
+                log::error!("GPU task recording failed: {:?}", diagnostic);
+                Err(vec![diagnostic])
+            }
+        }
+    }
+
+    // Anything created before a failure is released by `GPUTask`'s Drop
+    fn record(&self) -> Result<GPUTask, GPUTaskRecordingError> {
+        let mut task = GPUTask {
+            command_buffer: CommandBuffer::null(),
+            device_info: self.parent.device_info.clone(),
+            buffers: HashMap::with_capacity(self.bindings.len()),
+            slots: HashMap::with_capacity(self.bindings.len()),
+            descriptor_set: DescriptorSet::null(),
+            parent_descriptor_pool: DescriptorPool::null(),
+            descriptor_buffer: None,
+            allocator: self.parent.allocator.clone(),
+            bindings: self
+                .bindings
+                .iter()
+                .map(|(index, b)| {
+                    let read_only = self.read_only.contains(&b.id);
+                    TaskBinding {
+                        binding: *index,
+                        tensor_id: b.id,
+                        size_bytes: (b.data().len() * 4) as u64,
+                        readback_enabled: b.readback_enabled && !read_only,
+                        read_only,
+                    }
+                })
+                .collect(),
+            ops: Vec::with_capacity(self.ops.len()),
+            device_inputs: Vec::new(),
+            device_writes: Vec::new(),
+            priority: self.priority,
+            op_timers: None,
+            assert_buffer: None,
+            progress_buffer: None,
+            inline_uploads: HashMap::new(),
+            pipeline_generation: 0,
+            _tracking: self.parent.track_resource(LiveResourceKind::Task, || {
+                format!(
+                    "task{{tensors={:?}}}",
+                    self.bindings
+                        .iter()
+                        .map(|(_, b)| b.id)
+                        .collect::<Vec<u32>>()
+                )
+            }),
+            pipeline: self.pipeline.clone(),
+            secondary_pipelines: self.secondary_pipelines.clone(),
+            parent: self.parent.clone(),
+        };
+
+        task.allocate_buffers(
+            &self.bindings,
+            &self.uploaded_tensors(),
+            self.persistent_staging,
+        )?;
+        if self.pipeline.assert_binding {
+            task.assert_buffer = AssertBuffer::new(&task.device_info, &task.allocator);
+            if task.assert_buffer.is_none() {
+                return Err(GPUTaskRecordingError::BufferAllocationFailure);
+            }
+        }
+        if self.pipeline.progress_binding {
+            task.progress_buffer = ProgressBuffer::new(&task.device_info, &task.allocator);
+            if task.progress_buffer.is_none() {
+                return Err(GPUTaskRecordingError::BufferAllocationFailure);
+            }
+        }
+        task.allocate_descriptor_set(&self.bindings)?;
+        task.op_timers = match OpTimers::new(&task.device_info, &self.budgets) {
+            Ok(t) => t,
+            Err(e) => {
+                log::warn!("Failed to create op timers, budgets are ignored! Error: {}", e);
+                None
+            }
+        };
+
+        for op in self.ops.iter() {
+            task.note_device_access(op, &self.bindings);
+            let recorded = match op {
+                PendingOp::LocalSyncDevice(tensors) => {
+                    tensors.iter().for_each(|tensor| task.stage_upload(tensor));
+                    RecordedOp::LocalSyncDevice {
+                        tensor_ids: tensors.iter().map(|t| t.id).collect(),
+                    }
+                }
+                PendingOp::PipelineDispatch(work_group, pipeline) => RecordedOp::PipelineDispatch {
+                    work_group: *work_group,
+                    pipeline: *pipeline,
+                },
+                PendingOp::DeviceSyncLocal(tensors) => RecordedOp::DeviceSyncLocal {
+                    tensor_ids: tensors.iter().map(|t| t.id).collect(),
+                },
+                PendingOp::Ownership(tensors, transfer) => {
+                    let tensor_ids: Vec<u32> = tensors.iter().map(|t| t.id).collect();
+                    match *transfer {
+                        OwnershipTransfer::Release { to } => {
+                            RecordedOp::ReleaseOwnership { tensor_ids, to }
+                        }
+                        OwnershipTransfer::Acquire { from } => {
+                            RecordedOp::AcquireOwnership { tensor_ids, from }
+                        }
+                    }
+                }
+            };
+            task.ops.push(recorded);
+        }
+
+        task.pipeline_generation = task.pipelines_generation();
+        task.command_buffer = task.record_command_buffer()?;
+
+        Ok(task)
+    }
+}
+
+impl GPUTask {
+    fn allocate_buffers(
+        &mut self,
+        bindings: &[(u32, &Tensor)],
+        uploaded: &[u32],
+        persistent_staging: bool,
+    ) -> Result<(), GPUTaskRecordingError> {
+        let mut allocator_actual = match self.allocator.write() {
+            Ok(a) => a,
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                return Err(GPUTaskRecordingError::BufferAllocationFailure);
+            }
+        };
+
+        for (_, binding) in bindings {
+            // Tensors of one arena share the buffers allocated for the first of them
+            let (key, offset, size) = match binding.arena {
+                Some(arena) => (arena.arena_id, arena.offset, arena.arena_size),
+                None => (binding.id, 0, (binding.data().len() * 4) as u64),
+            };
+            self.slots.insert(binding.id, (key, offset));
+            if self.buffers.contains_key(&key) {
+                continue;
+            }
+            let small = is_small_tensor(size);
+            // An arena is read back if any of its tensors is, and likewise for uploads
+            let arena_tensors: Vec<&Tensor> = bindings
+                .iter()
+                .map(|(_, t)| *t)
+                .filter(|t| t.arena.map_or(t.id, |a| a.arena_id) == key)
+                .collect();
+            let readback_enabled = arena_tensors
+                .iter()
+                .any(|t| t.readback_enabled && !self.read_only(t.id));
+            let uploaded = arena_tensors.iter().any(|t| uploaded.contains(&t.id));
+
+            let mut gpu_usage = BufferUsageFlags::STORAGE_BUFFER
+                | BufferUsageFlags::TRANSFER_SRC
+                | BufferUsageFlags::TRANSFER_DST;
+            if self.device_info.descriptor_buffer.is_some() {
+                gpu_usage |= BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+            }
+
+            let gpu_buffer_name = format!("gpu_only_alloc{{id={}}}", key);
+            let gpu_buffer = if small && readback_enabled {
+                allocator_actual.allocate_buffer(
+                    &self.device_info,
+                    size,
+                    gpu_usage,
+                    gpu_allocator::MemoryLocation::GpuToCpu,
+                    &gpu_buffer_name,
+                    self.device_info.queue_indices.compute_queue.unwrap(),
+                )
+            } else {
+                allocator_actual.allocate_tensor_buffer(
+                    &self.device_info,
+                    size,
+                    gpu_usage,
+                    &gpu_buffer_name,
+                    self.device_info.queue_indices.compute_queue.unwrap(),
+                )
+            };
+            let gpu_buffer = match gpu_buffer {
+                Ok(b) => b,
+                Err(e) => {
+                    log::error!("Failed to allocate buffer! Error: {:?}", e);
+                    return Err(GPUTaskRecordingError::BufferAllocationFailure);
+                }
+            };
+
+            // Only tensors the task uploads need staging, small ones go inline
+            let staging_buffer = if !persistent_staging && (small || !uploaded) {
+                None
+            } else {
+                Some(
+                    match allocator_actual.allocate_buffer_with_hints(
+                        &self.device_info,
+                        size,
+                        BufferUsageFlags::TRANSFER_SRC,
+                        binding.staging_location.memory_location(),
+                        binding.staging_hints,
+                        format!("gpu_staging_only_alloc{{id={}}}", key).as_str(),
+                        self.device_info.queue_indices.compute_queue.unwrap(),
+                    ) {
+                        Ok(b) => b,
+                        Err(e) => {
+                            log::error!("Failed to allocate buffer! Error: {:?}", e);
+                            free_buffer(&self.device_info, &mut allocator_actual, gpu_buffer);
+                            return Err(GPUTaskRecordingError::BufferAllocationFailure);
+                        }
+                    },
+                )
+            };
+
+            let readback_buffer = if readback_enabled && !small {
+                Some(
+                    match allocator_actual.allocate_buffer(
+                        &self.device_info,
+                        size,
+                        BufferUsageFlags::TRANSFER_DST,
+                        binding.readback_location.memory_location(),
+                        format!("gpu_staging_only_alloc{{id={}}}", key).as_str(),
+                        self.device_info.queue_indices.compute_queue.unwrap(),
+                    ) {
+                        Ok(b) => b,
+                        Err(e) => {
+                            log::error!("Failed to allocate buffer! Error: {:?}", e);
+                            free_buffer(&self.device_info, &mut allocator_actual, gpu_buffer);
+                            if let Some(staging_buffer) = staging_buffer {
+                                free_buffer(
+                                    &self.device_info,
+                                    &mut allocator_actual,
+                                    staging_buffer,
+                                );
+                            }
+                            return Err(GPUTaskRecordingError::BufferAllocationFailure);
+                        }
+                    },
+                )
+            } else {
+                None
+            };
+
+            let mut backing = TensorBufferBacking {
+                gpu_buffer,
+                staging_buffer,
+                readback_buffer,
+                generations: StagingGenerations::default(),
+            };
+            backing.gpu_buffer.tracking = self.track_buffer("gpu_only_alloc", key);
+            if let Some(staging_buffer) = backing.staging_buffer.as_mut() {
+                staging_buffer.tracking = self.track_buffer("gpu_staging_alloc", key);
+            }
+            if let Some(readback_buffer) = backing.readback_buffer.as_mut() {
+                readback_buffer.tracking = self.track_buffer("gpu_readback_alloc", key);
+            }
+
+            self.buffers.insert(key, backing);
+        }
+
+        Ok(())
+    }
+
+    fn track_buffer(&self, name: &str, tensor_id: u32) -> Option<TrackedResource> {
+        self.parent.track_resource(LiveResourceKind::Buffer, || {
+            format!("{}{{id={}}}", name, tensor_id)
+        })
+    }
+
+    fn allocate_descriptor_set(
+        &mut self,
+        bindings: &[(u32, &Tensor)],
+    ) -> Result<(), GPUTaskRecordingError> {
+        if self.pipeline.descriptor_buffer_layout.is_some() {
+            return self.allocate_descriptor_buffer(bindings);
+        }
+
+        let allocation = match self.parent.descriptor_allocator.lock() {
+            Ok(mut descriptor_allocator) => descriptor_allocator.allocate(
+                &self.parent,
+                self.pipeline.descriptor_set_layout,
+                &self.pipeline.descriptor_pool_sizes,
+            ),
+            Err(e) => {
+                log::error!("Failed to acquire descriptor allocator! Error: {e}");
+                return Err(GPUTaskRecordingError::DescriptorSetAllocationFailure);
+            }
+        };
+
+        (self.descriptor_set, self.parent_descriptor_pool) = match allocation {
+            Ok(a) => a,
+            Err(e) => {
+                log::error!("Failed to allocate descriptor set! Error: {}", e);
+                return Err(GPUTaskRecordingError::DescriptorSetAllocationFailure);
+            }
+        };
+
+        let storage_buffers = self.storage_buffers(bindings);
+        let mut descriptor_writes = Vec::<WriteDescriptorSet>::with_capacity(storage_buffers.len());
+        let mut descriptor_write_buffer_infos =
+            Vec::<DescriptorBufferInfo>::with_capacity(storage_buffers.len());
+
+        storage_buffers
+            .iter()
+            .enumerate()
+            .for_each(|(i, (index, buffer, offset, range))| {
+                descriptor_write_buffer_infos.push(DescriptorBufferInfo {
+                    buffer: *buffer,
+                    offset: *offset,
+                    range: *range,
+                });
+                descriptor_writes.push(WriteDescriptorSet {
+                    s_type: StructureType::WRITE_DESCRIPTOR_SET,
+                    p_next: ptr::null(),
+                    dst_set: self.descriptor_set,
+                    dst_binding: *index,
+                    dst_array_element: 0,
+                    descriptor_count: 1,
+                    descriptor_type: DescriptorType::STORAGE_BUFFER,
+                    p_image_info: ptr::null(),
+                    p_buffer_info: &descriptor_write_buffer_infos[i],
+                    p_texel_buffer_view: ptr::null(),
+                });
+            });
+
+        unsafe {
+            self.device_info
+                .device
+                .update_descriptor_sets(descriptor_writes.as_slice(), &[]);
+        }
+
+        Ok(())
+    }
+
+    fn allocate_descriptor_buffer(
+        &mut self,
+        bindings: &[(u32, &Tensor)],
+    ) -> Result<(), GPUTaskRecordingError> {
+        let (support, layout) = match (
+            self.device_info.descriptor_buffer.as_ref(),
+            self.pipeline.descriptor_buffer_layout.as_ref(),
+        ) {
+            (Some(s), Some(l)) => (s, l),
+            _ => return Err(GPUTaskRecordingError::DescriptorSetAllocationFailure),
+        };
+
+        let mut descriptor_buffer = match self.allocator.write() {
+            Ok(mut allocator_actual) => match allocator_actual.allocate_buffer(
+                &self.device_info,
+                layout.size,
+                BufferUsageFlags::RESOURCE_DESCRIPTOR_BUFFER_EXT
+                    | BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                gpu_allocator::MemoryLocation::CpuToGpu,
+                "descriptor_buffer_alloc",
+                self.device_info.queue_indices.compute_queue.unwrap(),
+            ) {
+                Ok(b) => b,
+                Err(e) => {
+                    log::error!("Failed to allocate descriptor buffer! Error: {:?}", e);
+                    return Err(GPUTaskRecordingError::DescriptorSetAllocationFailure);
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                return Err(GPUTaskRecordingError::DescriptorSetAllocationFailure);
+            }
+        };
+
+        let storage_buffers = self.storage_buffers(bindings);
+        support.write_storage_buffers(
+            &self.device_info.device,
+            layout,
+            &descriptor_buffer,
+            &storage_buffers,
+        );
+
+        descriptor_buffer.tracking = self
+            .parent
+            .track_resource(LiveResourceKind::Buffer, || "descriptor_buffer".to_string());
+        self.descriptor_buffer = Some(descriptor_buffer);
+
+        Ok(())
+    }
+
+    // Binding, buffer, offset and range of every storage buffer descriptor of the task
+    fn storage_buffers(&self, bindings: &[(u32, &Tensor)]) -> Vec<(u32, vk::Buffer, u64, u64)> {
+        let mut storage_buffers: Vec<(u32, vk::Buffer, u64, u64)> = bindings
+            .iter()
+            .map(|(index, binding)| {
+                let (buffer, offset) = self.device_range(binding.id).unwrap();
+                (*index, buffer, offset, (binding.data().len() * 4) as u64)
+            })
+            .collect();
+        if let Some(assert_buffer) = self.assert_buffer.as_ref() {
+            storage_buffers.push((
+                KERNEL_ASSERT_BINDING,
+                assert_buffer.buffer(),
+                0,
+                assert_buffer.range(),
+            ));
+        }
+        if let Some(progress_buffer) = self.progress_buffer.as_ref() {
+            storage_buffers.push((
+                KERNEL_PROGRESS_BINDING,
+                progress_buffer.buffer(),
+                0,
+                progress_buffer.range(),
+            ));
+        }
+        storage_buffers
+    }
+
+    fn note_device_access(&mut self, op: &PendingOp, bindings: &[(u32, &Tensor)]) {
+        // (tensor, whether the op may read what's on the device)
+        let (tensors, stage, access): (Vec<(u32, bool)>, _, _) = match op {
+            PendingOp::LocalSyncDevice(tensors) => (
+                tensors.iter().map(|t| (t.id, false)).collect(),
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_WRITE,
+            ),
+            PendingOp::PipelineDispatch(_, pipeline) => {
+                let pipeline = self.dispatch_pipeline(*pipeline).clone();
+                (
+                    bindings
+                        .iter()
+                        .map(|(binding, t)| (t.id, !pipeline.binding_write_only(*binding)))
+                        .collect(),
+                    PipelineStageFlags::COMPUTE_SHADER,
+                    AccessFlags::SHADER_WRITE,
+                )
+            }
+            PendingOp::DeviceSyncLocal(_) | PendingOp::Ownership(..) => return,
+        };
+
+        tensors.into_iter().for_each(|(tensor_id, reads)| {
+            // A dispatch reads whatever is on the device for tensors nothing has written yet,
+            // unless its shader only writes them
+            let written = self.device_writes.iter().any(|(id, _, _)| *id == tensor_id);
+            let dispatch = stage == PipelineStageFlags::COMPUTE_SHADER;
+            if !written && reads && !self.device_inputs.contains(&tensor_id) {
+                self.device_inputs.push(tensor_id);
+            }
+            if dispatch && self.read_only(tensor_id) {
+                return;
+            }
+
+            self.device_writes.retain(|(id, _, _)| *id != tensor_id);
+            self.device_writes.push((tensor_id, stage, access));
+        });
+    }
+
+    // Host data goes to the staging buffer now, or into the command buffer for small tensors
+    fn stage_upload(&mut self, tensor: &Tensor) {
+        let (backing, offset) = match self.slot(tensor.id) {
+            Some(s) => s,
+            None => {
+                log::error!("Failed to find backing buffer for tensor! This is an internal issue!");
+                return;
+            }
+        };
+        let data = unsafe {
+            std::slice::from_raw_parts(
+                tensor.data().as_ptr() as *const u8,
+                tensor.data().len() * 4_usize,
+            )
+        };
+
+        match backing.staging_buffer.as_ref() {
+            Some(staging_buffer) => unsafe {
+                (staging_buffer.mapped_ptr().unwrap().as_ptr() as *mut u8)
+                    .add(offset as usize)
+                    .copy_from(data.as_ptr(), data.len());
+            },
+            None => {
+                self.inline_uploads.insert(tensor.id, data.to_vec());
+            }
+        }
+    }
+
+    // Records the task's ops against the pipeline's current shader
+    fn record_command_buffer(&self) -> Result<CommandBuffer, GPUTaskRecordingError> {
+        let command_buffer = self.begin_command_buffer(CommandBufferUsageFlags::empty())?;
+        self.parent.name_object(command_buffer, self.pipeline.shader_name());
+        if let Some(op_timers) = self.op_timers.as_ref() {
+            op_timers.cmd_reset(&self.device_info, command_buffer);
+        }
+        if let Some(assert_buffer) = self.assert_buffer.as_ref() {
+            assert_buffer.cmd_reset(&self.device_info, command_buffer);
+        }
+        if let Some(progress_buffer) = self.progress_buffer.as_ref() {
+            progress_buffer.cmd_reset(&self.device_info, command_buffer);
+        }
+
+        let mut states = ResourceStates::new();
+
+        for (op_index, op) in self.ops.iter().enumerate() {
+            self.parent.begin_op_label(command_buffer, op_index, op.kind());
+            match op {
+                RecordedOp::LocalSyncDevice { tensor_ids } => {
+                    self.record_local_sync_device(command_buffer, tensor_ids, &mut states);
+                }
+                RecordedOp::PipelineDispatch {
+                    work_group,
+                    pipeline,
+                } => {
+                    self.cmd_op_timestamp(command_buffer, op_index, false);
+                    self.record_pipeline_dispatch(
+                        command_buffer,
+                        *work_group,
+                        *pipeline,
+                        &mut states,
+                    );
+                    self.cmd_op_timestamp(command_buffer, op_index, true);
+                }
+                RecordedOp::DeviceSyncLocal { tensor_ids } => {
+                    self.record_device_sync_local(command_buffer, tensor_ids, &mut states);
+                }
+                RecordedOp::ReleaseOwnership { tensor_ids, to } => self.record_ownership_transfer(
+                    command_buffer,
+                    tensor_ids,
+                    OwnershipTransfer::Release { to: *to },
+                ),
+                RecordedOp::AcquireOwnership { tensor_ids, from } => self
+                    .record_ownership_transfer(
+                        command_buffer,
+                        tensor_ids,
+                        OwnershipTransfer::Acquire { from: *from },
+                    ),
+            }
+            self.parent.end_op_label(command_buffer);
+        }
+
+        if let Some(assert_buffer) = self.assert_buffer.as_ref() {
+            assert_buffer.cmd_make_host_visible(&self.device_info, command_buffer);
+        }
+
+        if let Err(e) = self.end_command_buffer(command_buffer) {
+            command_buffer_util::free_command_buffers(&self.device_info, &[command_buffer]);
+            return Err(e);
+        }
+
+        Ok(command_buffer)
+    }
+
+    /// Re-records the task if the shader of its pipeline, or of one it dispatches, was swapped
+    /// since it was recorded, and returns whether it did. The task must not be in flight.
+    pub fn refresh_pipeline(&mut self) -> Result<bool, GPUTaskRecordingError> {
+        let generation = self.pipelines_generation();
+        if generation == self.pipeline_generation {
+            return Ok(false);
+        }
+
+        let command_buffer = self.record_command_buffer()?;
+        command_buffer_util::free_command_buffers(&self.device_info, &[self.command_buffer]);
+        self.command_buffer = command_buffer;
+        self.pipeline_generation = generation;
+
+        Ok(true)
+    }
+
+    // Generations only grow, so their sum changes whenever any of the pipelines is swapped
+    fn pipelines_generation(&self) -> u64 {
+        std::iter::once(&self.pipeline)
+            .chain(&self.secondary_pipelines)
+            .map(|p| p.generation())
+            .sum()
+    }
+
+    // Index 0 is the task's own pipeline, as in `RecordedOp::PipelineDispatch`
+    pub(super) fn dispatch_pipeline(&self, index: usize) -> &Arc<Pipeline> {
+        match index {
+            0 => &self.pipeline,
+            i => &self.secondary_pipelines[i - 1],
+        }
+    }
+
+    // Binds the task's pipeline and its descriptor set or descriptor buffer
+    pub(super) fn begin_command_buffer(
+        &self,
+        usage: CommandBufferUsageFlags,
+    ) -> Result<CommandBuffer, GPUTaskRecordingError> {
+        let command_buffer = match command_buffer_util::allocate_command_buffer(&self.device_info) {
+            Ok(b) => b,
+            Err(e) => {
+                log::error!("Failed to allocate command buffer! Error: {}", e);
+                return Err(GPUTaskRecordingError::CommandBufferAllocationFailure);
+            }
+        };
+
+        match command_buffer_util::begin_command_buffer_recording(
+            &self.device_info.device,
+            command_buffer,
+            usage,
+        ) {
+            Ok(_) => (),
+            Err(e) => {
+                log::error!("Failed to begin command buffer recording! Error: {}", e);
+                command_buffer_util::free_command_buffers(&self.device_info, &[command_buffer]);
+                return Err(GPUTaskRecordingError::CommandBufferRecordingStartFailure);
+            }
+        }
+
+        unsafe {
+            self.device_info.device.cmd_bind_pipeline(
+                command_buffer,
+                PipelineBindPoint::COMPUTE,
+                self.pipeline.handle(),
+            );
+
+            match (
+                self.device_info.descriptor_buffer.as_ref(),
+                self.descriptor_buffer.as_ref(),
+            ) {
+                (Some(support), Some(descriptor_buffer)) => support.cmd_bind(
+                    &self.device_info,
+                    command_buffer,
+                    self.pipeline.pipeline_layout,
+                    descriptor_buffer,
+                ),
+                _ => self.device_info.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    PipelineBindPoint::COMPUTE,
+                    self.pipeline.pipeline_layout,
+                    0,
+                    &[self.descriptor_set],
+                    &[],
+                ),
+            }
+        }
+
+        Ok(command_buffer)
+    }
+
+    pub(super) fn end_command_buffer(
+        &self,
+        command_buffer: CommandBuffer,
+    ) -> Result<(), GPUTaskRecordingError> {
+        match unsafe { self.device_info.device.end_command_buffer(command_buffer) } {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!("Failed to end command buffer recording! Error: {}", e);
+                Err(GPUTaskRecordingError::CommandBufferRecordingEndFailure)
+            }
+        }
+    }
+
+    fn record_local_sync_device(
+        &self,
+        command_buffer: CommandBuffer,
+        tensor_ids: &[u32],
+        states: &mut ResourceStates,
+    ) {
+        let backings: Vec<(u32, &TensorBufferBacking, u64, u64)> = tensor_ids
+            .iter()
+            .filter_map(
+                |tensor_id| match (self.slot(*tensor_id), self.buffer_size(*tensor_id)) {
+                    (Some((b, offset)), Some(size)) => Some((*tensor_id, b, offset, size)),
+                    _ => {
+                        log::error!(
+                            "Failed to find backing buffer for tensor! This is an internal issue!"
+                        );
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        let barriers: Vec<Barrier> = backings
+            .iter()
+            .filter_map(|(_, backing, _, _)| {
+                states.access(
+                    backing.gpu_buffer.buffer,
+                    PipelineStageFlags::TRANSFER,
+                    AccessFlags::TRANSFER_WRITE,
+                )
+            })
+            .collect();
+        barrier::cmd_barriers(&self.device_info, command_buffer, &barriers);
+
+        backings
+            .iter()
+            .for_each(|(tensor_id, backing, offset, size)| unsafe {
+                match backing.staging_buffer.as_ref() {
+                    Some(staging_buffer) => self.device_info.device.cmd_copy_buffer(
+                        command_buffer,
+                        staging_buffer.buffer,
+                        backing.gpu_buffer.buffer,
+                        &[BufferCopy {
+                            src_offset: *offset,
+                            dst_offset: *offset,
+                            size: *size,
+                        }],
+                    ),
+                    // The data is copied into the command buffer at record time
+                    None => match self.inline_uploads.get(tensor_id) {
+                        Some(data) => self.device_info.device.cmd_update_buffer(
+                            command_buffer,
+                            backing.gpu_buffer.buffer,
+                            *offset,
+                            data,
+                        ),
+                        None => log::error!(
+                            "Failed to find upload data for tensor! This is an internal issue!"
+                        ),
+                    },
+                }
+            });
+    }
+
+    // The shader may read or write any bound tensor not marked read-only. Secondary pipelines are
+    // bound only for their dispatch; their layouts are compatible, so the descriptor set stays
+    // bound.
+    pub(super) fn record_pipeline_dispatch(
+        &self,
+        command_buffer: CommandBuffer,
+        work_group: WorkGroupSize,
+        pipeline: usize,
+        states: &mut ResourceStates,
+    ) {
+        let barriers: Vec<Barrier> = self
+            .buffers
+            .iter()
+            .filter_map(|(key, backing)| {
+                // Buffers shared by an arena are written if any of its tensors is
+                let written = self.bindings.iter().any(|b| {
+                    !b.read_only && self.slots.get(&b.tensor_id).map(|(k, _)| k) == Some(key)
+                });
+                states.access(
+                    backing.gpu_buffer.buffer,
+                    PipelineStageFlags::COMPUTE_SHADER,
+                    if written {
+                        AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE
+                    } else {
+                        AccessFlags::SHADER_READ
+                    },
+                )
+            })
+            .collect();
+        barrier::cmd_barriers(&self.device_info, command_buffer, &barriers);
+
+        let device = &self.device_info.device;
+        if pipeline != 0 {
+            unsafe {
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    PipelineBindPoint::COMPUTE,
+                    self.dispatch_pipeline(pipeline).handle(),
+                );
+            }
+        }
+        self.cmd_dispatch_split(command_buffer, work_group);
+        if pipeline != 0 {
+            unsafe {
+                device.cmd_bind_pipeline(
+                    command_buffer,
+                    PipelineBindPoint::COMPUTE,
+                    self.pipeline.handle(),
+                );
+            }
+        }
+    }
+
+    // Dispatches over the work group count limit are recorded as a grid of parts with base work
+    // groups, which only recording checks allowed
+    fn cmd_dispatch_split(&self, command_buffer: CommandBuffer, work_group: WorkGroupSize) {
+        let device = &self.device_info.device;
+        let [limit_x, limit_y, limit_z] = self.device_info.limits.max_compute_work_group_count;
+        if work_group.x <= limit_x && work_group.y <= limit_y && work_group.z <= limit_z {
+            unsafe {
+                device.cmd_dispatch(command_buffer, work_group.x, work_group.y, work_group.z);
+            }
+            return;
+        }
+
+        for base_z in (0..work_group.z).step_by(limit_z.max(1) as usize) {
+            for base_y in (0..work_group.y).step_by(limit_y.max(1) as usize) {
+                for base_x in (0..work_group.x).step_by(limit_x.max(1) as usize) {
+                    unsafe {
+                        device.cmd_dispatch_base(
+                            command_buffer,
+                            base_x,
+                            base_y,
+                            base_z,
+                            (work_group.x - base_x).min(limit_x),
+                            (work_group.y - base_y).min(limit_y),
+                            (work_group.z - base_z).min(limit_z),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn cmd_op_timestamp(&self, command_buffer: CommandBuffer, op_index: usize, end: bool) {
+        if let Some(op_timers) = self.op_timers.as_ref() {
+            op_timers.cmd_timestamp(&self.device_info, command_buffer, op_index, end);
+        }
+    }
+
+    fn record_ownership_transfer(
+        &self,
+        command_buffer: CommandBuffer,
+        tensor_ids: &[u32],
+        transfer: OwnershipTransfer,
+    ) {
+        let mut buffers: Vec<vk::Buffer> = tensor_ids
+            .iter()
+            .filter_map(|tensor_id| self.device_range(*tensor_id).map(|(b, _)| b))
+            .collect();
+        // Tensors of one arena share a buffer
+        buffers.sort();
+        buffers.dedup();
+        let barriers = queue_ownership::ownership_barriers(&self.device_info, transfer, &buffers);
+        barrier::cmd_barriers(&self.device_info, command_buffer, &barriers);
+    }
+
+    pub(super) fn record_device_sync_local(
+        &self,
+        command_buffer: CommandBuffer,
+        tensor_ids: &[u32],
+        states: &mut ResourceStates,
+    ) {
+        let mut copies = Vec::with_capacity(tensor_ids.len());
+        let mut barriers = Vec::with_capacity(tensor_ids.len() * 2);
+
+        tensor_ids.iter().for_each(|tensor_id| {
+            let ((backing, offset), size) =
+                match (self.slot(*tensor_id), self.buffer_size(*tensor_id)) {
+                    (Some(b), Some(s)) => (b, s),
+                    _ => {
+                        log::error!(
+                            "Failed to find backing buffer for tensor! This is an internal issue!"
+                        );
+                        return;
+                    }
+                };
+
+            match backing.readback_buffer.as_ref() {
+                Some(readback_buffer) => {
+                    barriers.extend(states.access(
+                        backing.gpu_buffer.buffer,
+                        PipelineStageFlags::TRANSFER,
+                        AccessFlags::TRANSFER_READ,
+                    ));
+                    barriers.extend(states.access(
+                        readback_buffer.buffer,
+                        PipelineStageFlags::TRANSFER,
+                        AccessFlags::TRANSFER_WRITE,
+                    ));
+                    copies.push((
+                        backing.gpu_buffer.buffer,
+                        readback_buffer.buffer,
+                        offset,
+                        size,
+                    ));
+                }
+                // Host-visible GPU buffers are read by the host directly
+                None if backing.gpu_buffer.mapped_ptr().is_some() => {
+                    barriers.extend(states.access(
+                        backing.gpu_buffer.buffer,
+                        PipelineStageFlags::HOST,
+                        AccessFlags::HOST_READ,
+                    ));
+                }
+                None => {
+                    log::error!(
+                        "Tensor has no readback buffer! Did you enable readback on creation?"
+                    );
+                }
+            }
+        });
+
+        barrier::cmd_barriers(&self.device_info, command_buffer, &barriers);
+
+        copies.iter().for_each(|(src, dst, offset, size)| unsafe {
+            self.device_info.device.cmd_copy_buffer(
+                command_buffer,
+                *src,
+                *dst,
+                &[BufferCopy {
+                    src_offset: *offset,
+                    dst_offset: *offset,
+                    size: *size,
+                }],
+            )
+        });
+
+        let host_barriers: Vec<Barrier> = copies
+            .iter()
+            .filter_map(|(_, dst, _, _)| {
+                states.access(*dst, PipelineStageFlags::HOST, AccessFlags::HOST_READ)
+            })
+            .collect();
+        barrier::cmd_barriers(&self.device_info, command_buffer, &host_barriers);
+    }
+
+    // Copies whatever the last readback left in the mapped readback buffers into the tensors.
+    // Tensors that can't be read back are skipped, and the first of them is reported.
+    pub(super) fn copy_readback(&self, tensors: Vec<&mut Tensor>) -> Result<(), StagingError> {
+        if let Some(op_timers) = self.op_timers.as_ref() {
+            op_timers.check(&self.parent, self.pipeline.shader_name());
+        }
+
+        let mut result = Ok(());
+        tensors.into_iter().for_each(|tensor| {
+            let copied = self.backing(tensor.id).and_then(|(backing, offset)| {
+                let (readback_buffer, mapped_ptr) = self.readback_buffer(tensor.id, backing)?;
+                if !backing.generations.is_invalidated() {
+                    let generation = backing.generations.readback();
+                    match staging::invalidate_buffer(&self.device_info, readback_buffer) {
+                        Ok(_) => backing.generations.mark_invalidated(generation),
+                        Err(e) => log::error!("Failed to invalidate readback buffer! Error: {}", e),
+                    }
+                }
+
+                unsafe {
+                    let mapped_ptr = mapped_ptr.add(offset as usize) as *const f32;
+                    tensor
+                        .data_mut()
+                        .as_mut_ptr()
+                        .copy_from(mapped_ptr, tensor.data().len());
+                }
+                tensor.apply_readback_transform();
+                Ok(())
+            });
+            if let Err(e) = copied {
+                log::error!("Failed to read back tensor {}! Error: {:?}", tensor.id, e);
+                result = result.and(Err(e));
+            }
+        });
+
+        result
+    }
+
+    /// Copies `tensor` into its mapped staging buffer, to be uploaded by the task's next
+    /// submission. Requires `with_persistent_staging`. Returns the new staging generation; the
+    /// write isn't visible to the device until it's flushed.
+    pub fn write_staging(&self, tensor: &Tensor) -> Result<u64, StagingError> {
+        let (backing, offset) = self.backing(tensor.id)?;
+        let staging_buffer = match backing.staging_buffer.as_ref() {
+            Some(b) => b,
+            None => return Err(StagingError::NoStagingBuffer(tensor.id)),
+        };
+
+        let size = tensor.data().len() * 4;
+        if Some(size as u64) != self.buffer_size(tensor.id) {
+            return Err(StagingError::SizeMismatch(tensor.id));
+        }
+        unsafe {
+            (staging_buffer.mapped_ptr().unwrap().as_ptr() as *mut u8)
+                .add(offset as usize)
+                .copy_from(tensor.data().as_ptr() as *const u8, size);
+        }
+
+        Ok(backing.generations.note_staging_write())
+    }
+
+    /// Makes host writes to the tensor's staging buffer visible to the device
+    pub fn flush(&self, tensor_id: u32) -> Result<(), StagingError> {
+        let (backing, _) = self.backing(tensor_id)?;
+        match backing.staging_buffer.as_ref() {
+            Some(_) => self.flush_backing(backing),
+            None => Err(StagingError::NoStagingBuffer(tensor_id)),
+        }
+    }
+
+    fn flush_backing(&self, backing: &TensorBufferBacking) -> Result<(), StagingError> {
+        let staging_buffer = match backing.staging_buffer.as_ref() {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+
+        let generation = backing.generations.staging();
+        match staging::flush_buffer(&self.device_info, staging_buffer) {
+            Ok(_) => {
+                backing.generations.mark_flushed(generation);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Failed to flush staging buffer! Error: {}", e);
+                Err(StagingError::FlushFailure)
+            }
+        }
+    }
+
+    /// Makes device writes to the tensor's readback visible to the host. Call it after the task
+    /// has been awaited.
+    pub fn invalidate(&self, tensor_id: u32) -> Result<(), StagingError> {
+        let (backing, _) = self.backing(tensor_id)?;
+        let (readback_buffer, _) = self.readback_buffer(tensor_id, backing)?;
+
+        let generation = backing.generations.readback();
+        match staging::invalidate_buffer(&self.device_info, readback_buffer) {
+            Ok(_) => {
+                backing.generations.mark_invalidated(generation);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Failed to invalidate readback buffer! Error: {}", e);
+                Err(StagingError::InvalidateFailure)
+            }
+        }
+    }
+
+    /// Counts `write_staging` calls for the tensor
+    pub fn staging_generation(&self, tensor_id: u32) -> Result<u64, StagingError> {
+        Ok(self.backing(tensor_id)?.0.generations.staging())
+    }
+
+    /// Counts submissions that rewrote the tensor's readback
+    pub fn readback_generation(&self, tensor_id: u32) -> Result<u64, StagingError> {
+        Ok(self.backing(tensor_id)?.0.generations.readback())
+    }
+
+    /// Borrows the tensor's mapped readback without copying it. Fails if the task was submitted
+    /// again since the last `invalidate`.
+    pub fn mapped_readback(&self, tensor_id: u32) -> Result<&[f32], StagingError> {
+        let (backing, offset) = self.backing(tensor_id)?;
+        let binding = match self.bindings.iter().find(|b| b.tensor_id == tensor_id) {
+            Some(b) if b.readback_enabled => b,
+            _ => return Err(StagingError::NoReadbackBuffer(tensor_id)),
+        };
+        if !backing.generations.is_invalidated() {
+            return Err(StagingError::NotInvalidated(tensor_id));
+        }
+
+        let (_, mapped_ptr) = self.readback_buffer(tensor_id, backing)?;
+        Ok(unsafe {
+            let mapped_ptr = mapped_ptr.add(offset as usize) as *const f32;
+            std::slice::from_raw_parts(mapped_ptr, binding.size_bytes as usize / 4)
+        })
+    }
+
+    // Copies elements `range` of the tensor's readback without invalidating or copying the rest
+    pub(super) fn peek_readback(
+        &self,
+        tensor_id: u32,
+        range: Range<usize>,
+    ) -> Result<Vec<f32>, StagingError> {
+        let (backing, offset) = self.backing(tensor_id)?;
+        let binding = match self.bindings.iter().find(|b| b.tensor_id == tensor_id) {
+            Some(b) if b.readback_enabled => b,
+            _ => return Err(StagingError::NoReadbackBuffer(tensor_id)),
+        };
+        if range.start > range.end || range.end > binding.size_bytes as usize / 4 {
+            return Err(StagingError::RangeOutOfBounds(tensor_id));
+        }
+
+        let (readback_buffer, mapped_ptr) = self.readback_buffer(tensor_id, backing)?;
+        let window_offset = offset + range.start as u64 * 4;
+        if !backing.generations.is_invalidated() {
+            if let Err(e) = staging::invalidate_buffer_range(
+                &self.device_info,
+                readback_buffer,
+                window_offset,
+                range.len() as u64 * 4,
+            ) {
+                log::error!("Failed to invalidate readback buffer! Error: {}", e);
+                return Err(StagingError::InvalidateFailure);
+            }
+        }
+
+        Ok(unsafe {
+            let mapped_ptr = mapped_ptr.add(window_offset as usize) as *const f32;
+            std::slice::from_raw_parts(mapped_ptr, range.len()).to_vec()
+        })
+    }
+
+    // The mapped buffer the tensor is read back through, the device buffer itself when it's
+    // host-visible, and where it's mapped
+    fn readback_buffer<'b>(
+        &self,
+        tensor_id: u32,
+        backing: &'b TensorBufferBacking,
+    ) -> Result<(&'b Buffer, *const u8), StagingError> {
+        let readback_enabled = self
+            .bindings
+            .iter()
+            .any(|b| b.tensor_id == tensor_id && b.readback_enabled);
+        let readback_buffer = backing
+            .readback_buffer
+            .as_ref()
+            .unwrap_or(&backing.gpu_buffer);
+        match readback_buffer.mapped_ptr() {
+            Some(p) if readback_enabled => Ok((readback_buffer, p.as_ptr() as *const u8)),
+            _ => Err(StagingError::NoReadbackBuffer(tensor_id)),
+        }
+    }
+
+    fn backing(&self, tensor_id: u32) -> Result<(&TensorBufferBacking, u64), StagingError> {
+        self.slot(tensor_id)
+            .ok_or(StagingError::TensorNotBound(tensor_id))
+    }
+
+    // The buffers backing a tensor and its byte offset in them
+    fn slot(&self, tensor_id: u32) -> Option<(&TensorBufferBacking, u64)> {
+        let (key, offset) = self.slots.get(&tensor_id)?;
+        self.buffers.get(key).map(|b| (b, *offset))
+    }
+
+    // Whether the task was told the tensor is only read, so it's never read back
+    fn read_only(&self, tensor_id: u32) -> bool {
+        self.bindings
+            .iter()
+            .any(|b| b.tensor_id == tensor_id && b.read_only)
+    }
+
+    // Called as the task is submitted. Flushes staging writes the caller didn't, and moves every
+    // readback to a new generation that needs invalidating before it's read in place.
+    pub(super) fn begin_submission(&self) {
+        if let Some(op_timers) = self.op_timers.as_ref() {
+            op_timers.note_submission();
+        }
+        self.buffers.iter().for_each(|(key, backing)| {
+            if backing.generations.needs_flush() {
+                log::warn!(
+                    "Staging buffer {{id={}}} was written but not flushed before submission",
+                    key
+                );
+                let _ = self.flush_backing(backing);
+            }
+            if self.bindings.iter().any(|b| {
+                b.readback_enabled && self.slots.get(&b.tensor_id).map(|(k, _)| k) == Some(key)
+            }) {
+                backing.generations.note_readback_write();
+            }
+        });
+    }
+
+    // Only valid once the task's submission has finished
+    pub(super) fn check_kernel_asserts(&self) -> Result<(), KernelAssertionFailed> {
+        match self.assert_buffer.as_ref() {
+            Some(assert_buffer) => {
+                assert_buffer.check(&self.device_info, self.pipeline.shader_name())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The kernel's progress counter, which can be polled while the task runs. `None` if the
+    /// shader doesn't declare one at `KERNEL_PROGRESS_BINDING`.
+    pub fn progress(&self) -> Option<u32> {
+        self.progress_buffer
+            .as_ref()
+            .map(|progress_buffer| progress_buffer.read(&self.device_info))
+    }
+
+    pub fn priority(&self) -> TaskPriority {
+        self.priority
+    }
+
+    pub fn ops(&self) -> &[RecordedOp] {
+        &self.ops
+    }
+
+    pub fn bindings(&self) -> &[TaskBinding] {
+        &self.bindings
+    }
+
+    pub(super) fn device_inputs(&self) -> &[u32] {
+        &self.device_inputs
+    }
+
+    pub(super) fn device_writes(&self) -> &[(u32, PipelineStageFlags, AccessFlags)] {
+        &self.device_writes
+    }
+
+    pub fn bound_tensor_ids(&self) -> Vec<u32> {
+        self.bindings.iter().map(|b| b.tensor_id).collect()
+    }
+
+    // What the task's next submission uploads for a tensor, `None` for tensors it doesn't upload
+    pub(super) fn uploaded_bytes(&self, tensor_id: u32) -> Option<&[u8]> {
+        if let Some(data) = self.inline_uploads.get(&tensor_id) {
+            return Some(data);
+        }
+        let size = self.buffer_size(tensor_id)? as usize;
+        let (backing, offset) = self.slot(tensor_id)?;
+        let staging_buffer = backing.staging_buffer.as_ref()?;
+        unsafe {
+            Some(std::slice::from_raw_parts(
+                (staging_buffer.mapped_ptr()?.as_ptr() as *const u8).add(offset as usize),
+                size,
+            ))
+        }
+    }
+
+    // The device buffer holding a tensor and the tensor's byte offset in it
+    pub(super) fn device_range(&self, tensor_id: u32) -> Option<(vk::Buffer, u64)> {
+        self.slot(tensor_id)
+            .map(|(b, offset)| (b.gpu_buffer.buffer, offset))
+    }
+
+    pub fn buffer_size(&self, tensor_id: u32) -> Option<u64> {
+        self.bindings
+            .iter()
+            .find(|b| b.tensor_id == tensor_id)
+            .map(|b| b.size_bytes)
+    }
+
+    pub fn dispatches(&self) -> Vec<WorkGroupSize> {
+        self.ops
+            .iter()
+            .filter_map(|op| match op {
+                RecordedOp::PipelineDispatch { work_group, .. } => Some(*work_group),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub(super) fn free_buffer(device_info: &DeviceInfo, allocator: &mut Allocator, mut buffer: Buffer) {
+    let allocation = std::mem::take(&mut buffer.allocation);
+    let _ = allocator.vulkan_allocator.free(allocation);
+    allocator.release_placement(buffer.placement);
+    unsafe {
+        device_info.device.destroy_buffer(buffer.buffer, None);
+    }
+    if let Some(host_import) = buffer.host_import.take() {
+        host_import.free(device_info);
+    }
+}
+
+impl Drop for GPUTask {
+    fn drop(&mut self) {
+        if self.command_buffer != CommandBuffer::null() {
+            command_buffer_util::free_command_buffers(&self.device_info, &[self.command_buffer]);
+        }
+
+        if let Some(op_timers) = self.op_timers.take() {
+            op_timers.destroy(&self.device_info);
+        }
+
+        if let Some(assert_buffer) = self.assert_buffer.take() {
+            assert_buffer.free(&self.device_info, &self.allocator);
+        }
+
+        if let Some(progress_buffer) = self.progress_buffer.take() {
+            progress_buffer.free(&self.device_info, &self.allocator);
+        }
+
+        if self.parent_descriptor_pool != DescriptorPool::null() {
+            match self.parent.descriptor_allocator.lock() {
+                Ok(mut descriptor_allocator) => descriptor_allocator.free(
+                    &self.device_info,
+                    self.descriptor_set,
+                    self.parent_descriptor_pool,
+                    &self.pipeline.descriptor_pool_sizes,
+                ),
+                Err(e) => log::error!("Failed to acquire descriptor allocator! Error: {e}"),
+            }
+        }
+
+        let mut gpu_buffers = Vec::with_capacity(self.buffers.len());
+        let mut host_buffers = Vec::new();
+        self.buffers.drain().for_each(|(_, buffer)| {
+            gpu_buffers.push(buffer.gpu_buffer);
+            host_buffers.extend(buffer.staging_buffer);
+            host_buffers.extend(buffer.readback_buffer);
+        });
+
+        // Prologues of later submissions may still be copying out of the device buffers
+        let gpu_buffers = match self.parent.hazard_tracker.lock() {
+            Ok(mut hazard_tracker) => {
+                hazard_tracker.release_buffers(
+                    &gpu_buffers
+                        .iter()
+                        .map(|b| b.buffer)
+                        .collect::<Vec<vk::Buffer>>(),
+                );
+                hazard_tracker.defer_read_buffers(gpu_buffers)
+            }
+            Err(e) => {
+                log::error!("Failed to acquire hazard tracker! Error: {e}");
+                gpu_buffers
+            }
+        };
+
+        // Free backing buffers
+        if let Ok(mut allocator_actual) = self.allocator.write() {
+            if let Some(descriptor_buffer) = self.descriptor_buffer.take() {
+                free_buffer(&self.device_info, &mut allocator_actual, descriptor_buffer);
+            }
+
+            gpu_buffers
+                .into_iter()
+                .chain(host_buffers)
+                .for_each(|buffer| free_buffer(&self.device_info, &mut allocator_actual, buffer));
+        } else {
+            log::error!("Failed to acquire allocator for GPU task!");
+        }
     }
 }
@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use ash::{
+    prelude::VkResult,
+    vk::{
+        self, AccessFlags, BufferCopy, CommandBuffer, CommandBufferUsageFlags, Fence,
+        PipelineStageFlags, Semaphore,
+    },
+};
+
+use super::{
+    allocation_strategy::Buffer,
+    barrier, command_buffer_util,
+    device::DeviceInfo,
+    gpu_task::{self, GPUTask},
+    resource_state::ResourceStates,
+    submission::CompletionCallback,
+    ComputeManager,
+};
+
+// Where the latest device contents of a tensor live and which access last wrote them
+#[derive(Clone, Copy)]
+struct TensorDeviceState {
+    buffer: vk::Buffer,
+    // Tensors carved out of an arena share its buffer
+    offset: u64,
+    size: u64,
+    stage: PipelineStageFlags,
+    access: AccessFlags,
+    queue_family: u32,
+}
+
+// Every task owns its own device buffers, so a task that reads a tensor on the device without
+// uploading it first gets the tensor's latest contents copied over from the last task that wrote
+// it. Tracked submissions share one queue, so barriers in the copy prologue order it after the
+// writer, and later recordings treat every buffer as possibly in use by earlier submissions. Work
+// run on other queues is only recorded here once it has finished.
+pub(super) struct HazardTracker {
+    tensors: HashMap<u32, TensorDeviceState>,
+    // Buffers that submitted prologues copy from, with the number of prologues yet to finish
+    prologue_reads: Arc<Mutex<HashMap<vk::Buffer, usize>>>,
+    // Buffers whose task was dropped while a prologue still copied from them
+    deferred_frees: Vec<Buffer>,
+}
+
+// Held by a submission's completion callback, so its prologue reads end with it whether the
+// callback runs or is dropped
+struct PrologueReads {
+    prologue_reads: Arc<Mutex<HashMap<vk::Buffer, usize>>>,
+    buffers: Vec<vk::Buffer>,
+}
+
+impl Drop for PrologueReads {
+    fn drop(&mut self) {
+        match self.prologue_reads.lock() {
+            Ok(mut prologue_reads) => self.buffers.iter().for_each(|buffer| {
+                if let Some(count) = prologue_reads.get_mut(buffer) {
+                    *count -= 1;
+                    if *count == 0 {
+                        prologue_reads.remove(buffer);
+                    }
+                }
+            }),
+            Err(e) => log::error!("Failed to acquire prologue reads! Error: {e}"),
+        }
+    }
+}
+
+// What a tracked submission leaves behind for the caller to clean up
+pub(super) struct TrackedSubmission {
+    pub(super) fence: Fence,
+    pub(super) prologues: Vec<CommandBuffer>,
+}
+
+impl HazardTracker {
+    pub(super) fn new() -> Self {
+        HazardTracker {
+            tensors: HashMap::new(),
+            prologue_reads: Arc::new(Mutex::new(HashMap::new())),
+            deferred_frees: Vec::new(),
+        }
+    }
+
+    // The buffer holding a tensor's latest device contents, the tensor's offset in it and its size
+    pub(super) fn device_location(&self, tensor_id: u32) -> Option<(vk::Buffer, u64, u64)> {
+        self.tensors
+            .get(&tensor_id)
+            .map(|state| (state.buffer, state.offset, state.size))
+    }
+
+    pub(super) fn note_transfer_write(&mut self, tensor_id: u32) {
+        if let Some(state) = self.tensors.get_mut(&tensor_id) {
+            state.stage = PipelineStageFlags::TRANSFER;
+            state.access = AccessFlags::TRANSFER_WRITE;
+        }
+    }
+
+    // Forgets tensor contents held in buffers that are about to be freed
+    pub(super) fn release_buffers(&mut self, buffers: &[vk::Buffer]) {
+        self.tensors
+            .retain(|_, state| !buffers.contains(&state.buffer));
+    }
+
+    // Holds on to the buffers pending prologues still copy from, and hands back the rest together
+    // with earlier deferred buffers that are no longer read
+    pub(super) fn defer_read_buffers(&mut self, buffers: Vec<Buffer>) -> Vec<Buffer> {
+        let prologue_reads = match self.prologue_reads.lock() {
+            Ok(p) => p,
+            Err(e) => {
+                log::error!("Failed to acquire prologue reads! Error: {e}");
+                self.deferred_frees.extend(buffers);
+                return Vec::new();
+            }
+        };
+        let (read, unread): (Vec<Buffer>, Vec<Buffer>) = std::mem::take(&mut self.deferred_frees)
+            .into_iter()
+            .chain(buffers)
+            .partition(|b| prologue_reads.contains_key(&b.buffer));
+        drop(prologue_reads);
+
+        self.deferred_frees = read;
+        unread
+    }
+
+    // Every deferred buffer, for when the device is idle
+    pub(super) fn take_deferred_frees(&mut self) -> Vec<Buffer> {
+        std::mem::take(&mut self.deferred_frees)
+    }
+
+    fn note_prologue_reads(&self, buffers: Vec<vk::Buffer>) -> VkResult<PrologueReads> {
+        match self.prologue_reads.lock() {
+            Ok(mut prologue_reads) => buffers
+                .iter()
+                .for_each(|buffer| *prologue_reads.entry(*buffer).or_default() += 1),
+            Err(e) => {
+                log::error!("Failed to acquire prologue reads! Error: {e}");
+                return Err(vk::Result::ERROR_UNKNOWN);
+            }
+        }
+
+        Ok(PrologueReads {
+            prologue_reads: self.prologue_reads.clone(),
+            buffers,
+        })
+    }
+}
+
+impl ComputeManager {
+    // Submits the tasks in order, each preceded by a prologue that forwards tensors it reads on the
+    // device from the last task that wrote them. The highest task priority decides when the
+    // submission gets its turn. `signal_semaphores` are signalled once every task has finished.
+    // The fence may be shared with other batched submissions, so it's handed back through
+    // `SubmissionThread::destroy_fence`.
+    pub(super) fn submit_tracked(
+        &self,
+        tasks: &[&GPUTask],
+        signal_semaphores: &[Semaphore],
+        on_complete: Option<CompletionCallback>,
+    ) -> VkResult<TrackedSubmission> {
+        let turn = self
+            .submission_thread
+            .wait_turn(tasks.iter().map(|t| t.priority()).max().unwrap_or_default());
+        let mut hazard_tracker = match self.hazard_tracker.lock() {
+            Ok(h) => h,
+            Err(e) => {
+                log::error!("Failed to acquire hazard tracker! Error: {e}");
+                return Err(vk::Result::ERROR_UNKNOWN);
+            }
+        };
+
+        let mut prologues = Vec::new();
+        let mut read_buffers = Vec::new();
+        let mut command_buffers = Vec::with_capacity(tasks.len() * 2);
+        let mut updates = Vec::new();
+        for task in tasks {
+            match self.record_hazard_prologue(&hazard_tracker, &updates, task) {
+                Ok(Some((prologue, sources))) => {
+                    prologues.push(prologue);
+                    read_buffers.extend(sources);
+                    command_buffers.push(prologue);
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    self.free_prologues(&prologues);
+                    return Err(e);
+                }
+            }
+            task.begin_submission();
+            command_buffers.push(task.command_buffer);
+            updates.extend(task_writes(&self.device_info, task));
+        }
+
+        // The sources stay alive until the submission has finished, even if their tasks don't
+        let on_complete = match read_buffers.is_empty() {
+            true => on_complete,
+            false => {
+                let reads = match hazard_tracker.note_prologue_reads(read_buffers) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        self.free_prologues(&prologues);
+                        return Err(e);
+                    }
+                };
+                Some(Box::new(move |completed| {
+                    drop(reads);
+                    if let Some(on_complete) = on_complete {
+                        on_complete(completed);
+                    }
+                }) as CompletionCallback)
+            }
+        };
+
+        let pending = match self.submission_thread.submit_batchable(
+            &command_buffers,
+            signal_semaphores,
+            tasks.len(),
+            on_complete,
+        ) {
+            Ok(p) => p,
+            Err(e) => {
+                self.free_prologues(&prologues);
+                return Err(e);
+            }
+        };
+
+        // Noted before the submission is made, so submissions batched after this one forward from
+        // these tasks
+        let written: Vec<vk::Buffer> = updates.iter().map(|(_, s)| s.buffer).collect();
+        hazard_tracker.tensors.extend(updates);
+        let retired = hazard_tracker.defer_read_buffers(Vec::new());
+        drop(hazard_tracker);
+        drop(turn);
+        self.free_retired_buffers(retired);
+
+        match pending.wait() {
+            Ok(fence) => Ok(TrackedSubmission { fence, prologues }),
+            Err(e) => {
+                match self.hazard_tracker.lock() {
+                    Ok(mut hazard_tracker) => hazard_tracker.release_buffers(&written),
+                    Err(e) => log::error!("Failed to acquire hazard tracker! Error: {e}"),
+                }
+                self.free_prologues(&prologues);
+                Err(e)
+            }
+        }
+    }
+
+    // For tasks that ran outside `submit_tracked` and have finished
+    pub(super) fn note_task_writes(&self, tasks: &[&GPUTask]) {
+        match self.hazard_tracker.lock() {
+            Ok(mut hazard_tracker) => tasks.iter().for_each(|task| {
+                hazard_tracker
+                    .tensors
+                    .extend(task_writes(&self.device_info, task))
+            }),
+            Err(e) => log::error!("Failed to acquire hazard tracker! Error: {e}"),
+        }
+    }
+
+    // Frees buffers handed back by `HazardTracker::defer_read_buffers`
+    pub(super) fn free_retired_buffers(&self, buffers: Vec<Buffer>) {
+        if buffers.is_empty() {
+            return;
+        }
+
+        match self.allocator.write() {
+            Ok(mut allocator) => buffers
+                .into_iter()
+                .for_each(|b| gpu_task::free_buffer(&self.device_info, &mut allocator, b)),
+            Err(e) => log::error!("Failed to acquire allocator! Error: {e}"),
+        }
+    }
+
+    pub(super) fn free_prologues(&self, prologues: &[CommandBuffer]) {
+        if prologues.is_empty() {
+            return;
+        }
+
+        command_buffer_util::free_command_buffers(&self.device_info, prologues);
+    }
+
+    fn record_hazard_prologue(
+        &self,
+        hazard_tracker: &HazardTracker,
+        pending: &[(u32, TensorDeviceState)],
+        task: &GPUTask,
+    ) -> VkResult<Option<(CommandBuffer, Vec<vk::Buffer>)>> {
+        let mut states = ResourceStates::new();
+        let mut before = Vec::new();
+        let mut copies = Vec::new();
+
+        task.device_inputs().iter().for_each(|tensor_id| {
+            // Writes earlier in the same submission take precedence over tracked state
+            let source = match pending
+                .iter()
+                .rev()
+                .find(|(id, _)| id == tensor_id)
+                .map(|(_, s)| s)
+                .or_else(|| hazard_tracker.tensors.get(tensor_id))
+            {
+                Some(s) => *s,
+                None => return,
+            };
+            let ((destination, destination_offset), size) = match (
+                task.device_range(*tensor_id),
+                task.buffer_size(*tensor_id),
+            ) {
+                (Some(b), Some(s)) => (b, s),
+                _ => return,
+            };
+            if source.buffer == destination && source.offset == destination_offset {
+                return;
+            }
+            if source.size != size {
+                log::warn!(
+                    "Tensor {} is read on the device with a different size than it was last written with! Not forwarding its contents.",
+                    tensor_id
+                );
+                return;
+            }
+            if source.queue_family != self.device_info.queue_indices.compute_queue.unwrap() {
+                log::warn!(
+                    "Tensor {} was last written on another queue family! Not forwarding its contents.",
+                    tensor_id
+                );
+                return;
+            }
+
+            states.assume_written(source.buffer, source.stage, source.access);
+            before.extend(states.access(
+                source.buffer,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_READ,
+            ));
+            before.extend(states.access(
+                destination,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_WRITE,
+            ));
+            copies.push((
+                source.buffer,
+                destination,
+                BufferCopy {
+                    src_offset: source.offset,
+                    dst_offset: destination_offset,
+                    size,
+                },
+            ));
+        });
+
+        if copies.is_empty() {
+            return Ok(None);
+        }
+
+        let device = &self.device_info.device;
+        let command_buffer = command_buffer_util::allocate_command_buffer(&self.device_info)?;
+        if let Err(e) = command_buffer_util::begin_command_buffer_recording(
+            device,
+            command_buffer,
+            CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        ) {
+            self.free_prologues(&[command_buffer]);
+            return Err(e);
+        }
+
+        barrier::cmd_barriers(&self.device_info, command_buffer, &before);
+        copies.iter().for_each(|(src, dst, region)| unsafe {
+            device.cmd_copy_buffer(command_buffer, *src, *dst, &[*region]);
+        });
+
+        if let Err(e) = unsafe { device.end_command_buffer(command_buffer) } {
+            self.free_prologues(&[command_buffer]);
+            return Err(e);
+        }
+
+        Ok(Some((
+            command_buffer,
+            copies.iter().map(|(src, _, _)| *src).collect(),
+        )))
+    }
+}
+
+fn task_writes(device_info: &DeviceInfo, task: &GPUTask) -> Vec<(u32, TensorDeviceState)> {
+    task.device_writes()
+        .iter()
+        .filter_map(|(tensor_id, stage, access)| {
+            let (buffer, offset) = task.device_range(*tensor_id)?;
+            Some((
+                *tensor_id,
+                TensorDeviceState {
+                    buffer,
+                    offset,
+                    size: task.buffer_size(*tensor_id)?,
+                    stage: *stage,
+                    access: *access,
+                    queue_family: device_info.queue_indices.compute_queue.unwrap(),
+                },
+            ))
+        })
+        .collect()
+}
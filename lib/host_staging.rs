@@ -0,0 +1,376 @@
+use std::{
+    ffi::{c_void, CStr},
+    ptr::NonNull,
+};
+
+use ash::{
+    vk::{
+        self, BufferUsageFlags, ExtExternalMemoryHostFn, ExternalMemoryHandleTypeFlags,
+        MemoryPropertyFlags, PhysicalDevice, PhysicalDeviceExternalMemoryHostPropertiesEXT,
+        PhysicalDeviceMemoryProperties, PhysicalDeviceProperties2, SharingMode,
+    },
+    Device,
+};
+
+use super::{allocation_strategy::AllocationError, device::DeviceInfo, instance::InstanceInfo};
+
+/// Placement hints for the host memory a tensor is uploaded through. Multi-gigabyte uploads are
+/// bound by the host copy into staging and the device pulling it over PCIe, and both go faster
+/// from aligned, resident pages on the node closest to the device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostStagingHints {
+    /// Minimum alignment of the staging buffer in bytes, on top of what the device requires.
+    /// Must be a power of two, 0 keeps the device's alignment.
+    pub alignment: u64,
+    /// Locks the staging pages into RAM so they're never paged out mid-upload
+    pub pinned: bool,
+    /// NUMA node to place the staging pages on
+    pub numa_node: Option<u32>,
+}
+
+impl HostStagingHints {
+    pub(super) fn alignment_valid(&self) -> bool {
+        self.alignment == 0 || self.alignment.is_power_of_two()
+    }
+
+    // Pinning and node placement apply to whole pages the driver hands out on its own terms, so
+    // those buffers are backed by pages allocated here and imported instead. Only on Linux, with
+    // VK_EXT_external_memory_host.
+    pub(super) fn needs_host_import(&self) -> bool {
+        self.pinned || self.numa_node.is_some()
+    }
+}
+
+#[derive(Clone)]
+pub struct ExternalMemoryHostSupport {
+    pub loader: ExtExternalMemoryHostFn,
+    pub min_imported_host_pointer_alignment: u64,
+    pub memory_properties: PhysicalDeviceMemoryProperties,
+}
+
+// Host pages imported as the memory of a staging buffer. Freeing it unmaps the pages, which
+// drops the lock with them.
+pub(super) struct HostImport {
+    memory: vk::DeviceMemory,
+    mapping: NonNull<c_void>,
+    mapping_len: usize,
+    ptr: NonNull<c_void>,
+}
+
+// The pages are only reached through the buffer that owns them, like a mapped allocation
+unsafe impl Send for HostImport {}
+unsafe impl Sync for HostImport {}
+
+impl HostImport {
+    pub(super) fn mapped_ptr(&self) -> NonNull<c_void> {
+        self.ptr
+    }
+
+    pub(super) fn free(self, device_info: &DeviceInfo) {
+        unsafe { device_info.device.free_memory(self.memory, None) };
+        unmap(self.mapping, self.mapping_len);
+    }
+}
+
+// External memory is core from 1.1 on. Host pages are only allocated and placed here on Linux.
+pub(super) fn supports_external_memory_host(
+    instance_info: &InstanceInfo,
+    physical_device: PhysicalDevice,
+) -> bool {
+    if cfg!(not(target_os = "linux")) || instance_info.physical_device_properties2_loader.is_none()
+    {
+        return false;
+    }
+
+    let required_version = vk::make_api_version(0, 1, 1, 0);
+    unsafe {
+        let device_version = instance_info
+            .instance
+            .get_physical_device_properties(physical_device)
+            .api_version;
+        if instance_info.api_version < required_version || device_version < required_version {
+            return false;
+        }
+
+        instance_info
+            .instance
+            .enumerate_device_extension_properties(physical_device)
+            .unwrap_or_default()
+            .iter()
+            .any(|e| CStr::from_ptr(e.extension_name.as_ptr()) == ExtExternalMemoryHostFn::name())
+    }
+}
+
+pub(super) fn load_external_memory_host_support(
+    instance_info: &InstanceInfo,
+    device: &Device,
+    physical_device: PhysicalDevice,
+) -> ExternalMemoryHostSupport {
+    let mut properties = PhysicalDeviceExternalMemoryHostPropertiesEXT::default();
+    let mut properties2 = PhysicalDeviceProperties2::builder()
+        .push_next(&mut properties)
+        .build();
+    if let Some(loader) = instance_info.physical_device_properties2_loader.as_ref() {
+        unsafe { loader.get_physical_device_properties2(physical_device, &mut properties2) };
+    }
+
+    ExternalMemoryHostSupport {
+        loader: ExtExternalMemoryHostFn::load(|name| unsafe {
+            std::mem::transmute((instance_info.instance.fp_v1_0().get_device_proc_addr)(
+                device.handle(),
+                name.as_ptr(),
+            ))
+        }),
+        min_imported_host_pointer_alignment: properties.min_imported_host_pointer_alignment.max(1),
+        memory_properties: unsafe {
+            instance_info
+                .instance
+                .get_physical_device_memory_properties(physical_device)
+        },
+    }
+}
+
+// Creates a buffer backed by freshly mapped host pages, placed and locked as the hints ask
+pub(super) fn import_buffer(
+    device_info: &DeviceInfo,
+    support: &ExternalMemoryHostSupport,
+    size: u64,
+    usage: BufferUsageFlags,
+    hints: HostStagingHints,
+    queue_family: u32,
+) -> Result<(vk::Buffer, HostImport), AllocationError> {
+    // Imported pointers and sizes must both be multiples of the import alignment
+    let alignment = support
+        .min_imported_host_pointer_alignment
+        .max(hints.alignment);
+    let len = size.max(1).div_ceil(alignment) * alignment;
+    let (mapping, mapping_len, ptr) = match map_host_pages(len as usize, alignment as usize) {
+        Some(m) => m,
+        None => return Err(AllocationError::MemoryAllocationError),
+    };
+
+    if let Some(node) = hints.numa_node {
+        bind_to_node(ptr.as_ptr(), len as usize, node);
+    }
+    // Locking faults the pages in, so it comes after placement
+    if hints.pinned {
+        pin(ptr.as_ptr(), len as usize);
+    }
+
+    match unsafe { import_pages(device_info, support, ptr, size, len, usage, queue_family) } {
+        Ok((buffer, memory)) => Ok((
+            buffer,
+            HostImport {
+                memory,
+                mapping,
+                mapping_len,
+                ptr,
+            },
+        )),
+        Err(e) => {
+            unmap(mapping, mapping_len);
+            Err(e)
+        }
+    }
+}
+
+unsafe fn import_pages(
+    device_info: &DeviceInfo,
+    support: &ExternalMemoryHostSupport,
+    ptr: NonNull<c_void>,
+    size: u64,
+    len: u64,
+    usage: BufferUsageFlags,
+    queue_family: u32,
+) -> Result<(vk::Buffer, vk::DeviceMemory), AllocationError> {
+    let device = &device_info.device;
+    let handle_type = ExternalMemoryHandleTypeFlags::HOST_ALLOCATION_EXT;
+
+    let mut host_pointer_properties = vk::MemoryHostPointerPropertiesEXT::default();
+    let result = (support.loader.get_memory_host_pointer_properties_ext)(
+        device.handle(),
+        handle_type,
+        ptr.as_ptr(),
+        &mut host_pointer_properties,
+    );
+    if result != vk::Result::SUCCESS {
+        log::error!("Failed to query host pointer properties! Error: {}", result);
+        return Err(AllocationError::MemoryAllocationError);
+    }
+
+    let queue_families = [queue_family];
+    let mut external_memory_info =
+        vk::ExternalMemoryBufferCreateInfo::builder().handle_types(handle_type);
+    let buffer_create_info = vk::BufferCreateInfo::builder()
+        .size(size)
+        .usage(usage)
+        .sharing_mode(SharingMode::EXCLUSIVE)
+        .queue_family_indices(&queue_families)
+        .push_next(&mut external_memory_info);
+    let buffer = match device.create_buffer(&buffer_create_info, None) {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!("Failed to allocate buffer with error {}", e);
+            return Err(AllocationError::BufferCreationFailure);
+        }
+    };
+
+    // Staging is written through the host pointer without flushes, so the memory must be coherent
+    let requirements = device.get_buffer_memory_requirements(buffer);
+    let memory_type = host_coherent_memory_type(
+        &support.memory_properties,
+        requirements.memory_type_bits & host_pointer_properties.memory_type_bits,
+    );
+    let memory_type = match memory_type {
+        Some(t) if requirements.size <= len => t,
+        _ => {
+            log::error!("No host-coherent memory type can import the staging pages!");
+            device.destroy_buffer(buffer, None);
+            return Err(AllocationError::MemoryAllocationError);
+        }
+    };
+
+    let mut import_info = vk::ImportMemoryHostPointerInfoEXT::builder()
+        .handle_type(handle_type)
+        .host_pointer(ptr.as_ptr());
+    let allocate_info = vk::MemoryAllocateInfo::builder()
+        .allocation_size(len)
+        .memory_type_index(memory_type)
+        .push_next(&mut import_info);
+    let memory = match device.allocate_memory(&allocate_info, None) {
+        Ok(m) => m,
+        Err(e) => {
+            log::error!("Failed to import staging pages! Error: {}", e);
+            device.destroy_buffer(buffer, None);
+            return Err(AllocationError::MemoryAllocationError);
+        }
+    };
+
+    if let Err(e) = device.bind_buffer_memory(buffer, memory, 0) {
+        log::error!("Failed to bind buffer memory! Error: {}", e);
+        device.free_memory(memory, None);
+        device.destroy_buffer(buffer, None);
+        return Err(AllocationError::MemoryBindFailure);
+    }
+
+    Ok((buffer, memory))
+}
+
+fn host_coherent_memory_type(
+    memory_properties: &PhysicalDeviceMemoryProperties,
+    type_bits: u32,
+) -> Option<u32> {
+    let flags = MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT;
+    (0..memory_properties.memory_type_count).find(|i| {
+        type_bits & (1 << i) != 0
+            && memory_properties.memory_types[*i as usize]
+                .property_flags
+                .contains(flags)
+    })
+}
+
+// The mapping, its length and the aligned pointer into it
+#[cfg(target_os = "linux")]
+fn map_host_pages(
+    len: usize,
+    alignment: usize,
+) -> Option<(NonNull<c_void>, usize, NonNull<c_void>)> {
+    // mmap only aligns to pages, so larger alignments get the difference mapped on top
+    let mapping_len = len + alignment.saturating_sub(page_size());
+    let mapping = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            mapping_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if mapping == libc::MAP_FAILED {
+        log::error!(
+            "Failed to map staging pages! Error: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+
+    let ptr = (mapping as usize).div_ceil(alignment) * alignment;
+    Some((
+        NonNull::new(mapping)?,
+        mapping_len,
+        NonNull::new(ptr as *mut c_void)?,
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn unmap(mapping: NonNull<c_void>, mapping_len: usize) {
+    unsafe { libc::munmap(mapping.as_ptr(), mapping_len) };
+}
+
+#[cfg(target_os = "linux")]
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        p if p > 0 => p as usize,
+        _ => 4096,
+    }
+}
+
+// Best effort, the pages are still imported when the OS refuses a hint
+#[cfg(target_os = "linux")]
+fn bind_to_node(ptr: *mut c_void, len: usize, node: u32) {
+    // From linux/mempolicy.h, which libc doesn't export
+    const MPOL_PREFERRED: libc::c_long = 1;
+    const MPOL_MF_MOVE: libc::c_ulong = 1 << 1;
+
+    let bits = libc::c_ulong::BITS;
+    let mut node_mask = vec![0 as libc::c_ulong; node as usize / bits as usize + 1];
+    node_mask[node as usize / bits as usize] |= 1 << (node % bits);
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            len,
+            MPOL_PREFERRED,
+            node_mask.as_ptr(),
+            node_mask.len() as libc::c_ulong * bits as libc::c_ulong,
+            MPOL_MF_MOVE,
+        )
+    };
+    if result != 0 {
+        log::warn!(
+            "Failed to bind staging memory to NUMA node {}! Error: {}",
+            node,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin(ptr: *mut c_void, len: usize) {
+    if unsafe { libc::mlock(ptr, len) } != 0 {
+        log::warn!(
+            "Failed to pin staging memory! Error: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// Never reached, `supports_external_memory_host` is false off Linux
+#[cfg(not(target_os = "linux"))]
+fn map_host_pages(
+    _len: usize,
+    _alignment: usize,
+) -> Option<(NonNull<c_void>, usize, NonNull<c_void>)> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmap(_mapping: NonNull<c_void>, _mapping_len: usize) {}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_node(_ptr: *mut c_void, _len: usize, _node: u32) {}
+
+#[cfg(not(target_os = "linux"))]
+fn pin(_ptr: *mut c_void, _len: usize) {}
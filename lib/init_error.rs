@@ -11,4 +11,5 @@ pub enum InitError {
     PhysicalDeviceQueryFailed,
     ComputePoolCreationFailure,
     AllocatorCreationFailure,
+    SubmissionThreadCreationFailure,
 }
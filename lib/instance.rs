@@ -2,10 +2,11 @@ use std::{
     borrow::Cow,
     ffi::{c_char, c_void, CStr, CString},
     ptr,
+    sync::Arc,
 };
 
 use ash::{
-    extensions::ext::DebugUtils,
+    extensions::{ext::DebugUtils, khr::GetPhysicalDeviceProperties2},
     vk::{
         self, ApplicationInfo, DebugUtilsMessageSeverityFlagsEXT, DebugUtilsMessageTypeFlagsEXT,
         DebugUtilsMessengerCreateInfoEXT, DebugUtilsMessengerEXT, InstanceCreateFlags,
@@ -16,20 +17,28 @@ use ash::{
 
 use crate::log_config::ValidationLayerLogConfig;
 
-use super::init_error::InitError;
+use super::{
+    init_error::InitError,
+    validation::{self, ValidationMessage, ValidationQueue, ValidationSeverity},
+};
 
 // #[derive(Debug)]
 pub struct InstanceInfo {
     pub instance: Instance,
     pub debug_messenger: Option<DebugUtilsMessengerEXT>,
     pub debug_utils_loader: Option<DebugUtils>,
+    // Needed to query extension features such as synchronization2 on a 1.0 instance
+    pub physical_device_properties2_loader: Option<GetPhysicalDeviceProperties2>,
+    pub api_version: u32,
+    // Shared with the debug messenger through its user data
+    pub(super) validation_queue: Arc<ValidationQueue>,
 }
 
 unsafe extern "system" fn vulkan_debug_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     _message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _user_data: *mut std::os::raw::c_void,
+    user_data: *mut std::os::raw::c_void,
 ) -> vk::Bool32 {
     let callback_data = *p_callback_data;
     let message_id_number = callback_data.message_id_number;
@@ -46,7 +55,31 @@ unsafe extern "system" fn vulkan_debug_callback(
         CStr::from_ptr(callback_data.p_message).to_string_lossy()
     };
 
-    let message = format!("[VK_VALIDATION: {message_id_name} ({message_id_number})] : {message}");
+    let (shader, op_index) = validation::attribute(&callback_data);
+    let source = match (&shader, op_index) {
+        (Some(shader), Some(op_index)) => format!(" (shader \"{shader}\", op {op_index})"),
+        (Some(shader), None) => format!(" (shader \"{shader}\")"),
+        (None, Some(op_index)) => format!(" (op {op_index})"),
+        (None, None) => String::new(),
+    };
+
+    if let Some(queue) = (user_data as *const ValidationQueue).as_ref() {
+        queue.push(ValidationMessage {
+            severity: match message_severity {
+                DebugUtilsMessageSeverityFlagsEXT::ERROR => ValidationSeverity::Error,
+                DebugUtilsMessageSeverityFlagsEXT::WARNING => ValidationSeverity::Warning,
+                DebugUtilsMessageSeverityFlagsEXT::INFO => ValidationSeverity::Info,
+                _ => ValidationSeverity::Verbose,
+            },
+            message_id: message_id_name.to_string(),
+            message: message.to_string(),
+            shader,
+            op_index,
+        });
+    }
+
+    let message =
+        format!("[VK_VALIDATION: {message_id_name} ({message_id_number})]{source} : {message}");
     match message_severity {
         DebugUtilsMessageSeverityFlagsEXT::VERBOSE => {
             log::info!("{}", message);
@@ -69,6 +102,7 @@ unsafe extern "system" fn vulkan_debug_callback(
 
 fn get_debug_utils_messenger_info(
     log_config: Option<ValidationLayerLogConfig>,
+    validation_queue: &Arc<ValidationQueue>,
 ) -> DebugUtilsMessengerCreateInfoEXT {
     let message_severity = DebugUtilsMessageSeverityFlagsEXT::default()
         | if let Some(cfg) = log_config {
@@ -98,6 +132,7 @@ fn get_debug_utils_messenger_info(
         .pfn_user_callback(Some(vulkan_debug_callback))
         .message_severity(message_severity)
         .message_type(message_type)
+        .user_data(Arc::as_ptr(validation_queue) as *mut c_void)
         .build()
 }
 
@@ -108,6 +143,12 @@ pub fn create_instance(
     unsafe {
         let entry = Entry::linked();
 
+        // Ask for up to 1.2 so optional features like descriptor buffers can use core entry points
+        let api_version = match entry.try_enumerate_instance_version() {
+            Ok(Some(version)) => version.min(vk::make_api_version(0, 1, 2, 0)),
+            _ => vk::make_api_version(0, 1, 0, 0),
+        };
+
         let app_name = CString::new("ICompute_APP").unwrap();
         let engine_name = CString::new("ICompute_ENGINE").unwrap();
         let app_info = ApplicationInfo::builder()
@@ -115,7 +156,7 @@ pub fn create_instance(
             .application_version(vk::make_api_version(1, 0, 0, 0))
             .engine_name(&engine_name)
             .engine_version(vk::make_api_version(1, 0, 0, 0))
-            .api_version(vk::make_api_version(0, 1, 0, 0))
+            .api_version(api_version)
             .build();
 
         let mut extension_names = Vec::new();
@@ -129,6 +170,19 @@ pub fn create_instance(
             extension_names.push(DebugUtils::name());
         }
 
+        let physical_device_properties2_available = entry
+            .enumerate_instance_extension_properties(None)
+            .unwrap_or_default()
+            .iter()
+            .any(|e| {
+                CStr::from_ptr(e.extension_name.as_ptr()) == GetPhysicalDeviceProperties2::name()
+            });
+        if physical_device_properties2_available
+            && !extension_names.contains(&GetPhysicalDeviceProperties2::name())
+        {
+            extension_names.push(GetPhysicalDeviceProperties2::name());
+        }
+
         let layer_names = [CStr::from_bytes_with_nul_unchecked(
             b"VK_LAYER_KHRONOS_validation\0",
         )];
@@ -148,7 +202,8 @@ pub fn create_instance(
             .map(|item| (*item).as_ptr())
             .collect();
 
-        let debug_messenger_info = get_debug_utils_messenger_info(log_config);
+        let validation_queue = Arc::new(ValidationQueue::new());
+        let debug_messenger_info = get_debug_utils_messenger_info(log_config, &validation_queue);
 
         let instance_create_info = InstanceCreateInfo {
             s_type: StructureType::INSTANCE_CREATE_INFO,
@@ -196,7 +251,14 @@ pub fn create_instance(
         Ok(InstanceInfo {
             debug_messenger,
             debug_utils_loader: debug_utils_messenger_loader,
+            physical_device_properties2_loader: if physical_device_properties2_available {
+                Some(GetPhysicalDeviceProperties2::new(&entry, &instance))
+            } else {
+                None
+            },
+            api_version,
             instance,
+            validation_queue,
         })
     }
 }
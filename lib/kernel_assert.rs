@@ -0,0 +1,189 @@
+use std::sync::{Arc, RwLock};
+
+use ash::vk::{self, AccessFlags, BufferUsageFlags, CommandBuffer, PipelineStageFlags};
+
+use super::{
+    allocation_strategy::{Allocator, Buffer},
+    barrier::{self, Barrier},
+    device::DeviceInfo,
+    gpu_task::free_buffer,
+    staging, ComputeManager,
+};
+
+/// Binding of the assert buffer in set 0 while kernel asserts are enabled. Pipelines binding more
+/// tensors than this get no assert buffer.
+pub const KERNEL_ASSERT_BINDING: u32 = 31;
+
+/// GLSL helper for kernel asserts, to be placed after `#version`. `gauss_assert(cond, code,
+/// index)` records the first failing `code` and `index` of a submission along with its line.
+/// Asserts compile to nothing unless kernel asserts are enabled, which they are in debug builds.
+pub const KERNEL_ASSERT_GLSL: &str = "
+#ifdef GAUSS_KERNEL_ASSERTS
+layout(set = 0, binding = 31) buffer gauss_assert_buf {
+    uint gauss_assert_failures;
+    uint gauss_assert_code;
+    uint gauss_assert_index;
+    uint gauss_assert_line;
+};
+
+#define gauss_assert(cond, code, index) \\
+    if (!(cond)) { \\
+        if (atomicAdd(gauss_assert_failures, 1u) == 0u) { \\
+            gauss_assert_code = uint(code); \\
+            gauss_assert_index = uint(index); \\
+            gauss_assert_line = uint(__LINE__); \\
+        } \\
+    }
+#else
+#define gauss_assert(cond, code, index)
+#endif
+";
+
+// Defined for GLSL compiled while kernel asserts are enabled
+pub(super) const KERNEL_ASSERT_MACRO: &str = "GAUSS_KERNEL_ASSERTS";
+
+// Failure count, then code, index and line of the first failure
+pub(super) const ASSERT_BUFFER_BYTES: u64 = 16;
+
+/// The first assert that failed during a task's submission
+#[derive(Debug, Clone)]
+pub struct KernelAssertionFailed {
+    pub shader: String,
+    /// How many invocations failed an assert
+    pub failures: u32,
+    pub code: u32,
+    pub index: u32,
+    pub line: u32,
+}
+
+pub(super) struct AssertBuffer {
+    buffer: Buffer,
+}
+
+impl ComputeManager {
+    pub fn kernel_asserts_enabled(&self) -> bool {
+        cfg!(debug_assertions)
+    }
+}
+
+impl AssertBuffer {
+    pub(super) fn new(
+        device_info: &DeviceInfo,
+        allocator: &Arc<RwLock<Allocator>>,
+    ) -> Option<Self> {
+        let mut usage = BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::TRANSFER_DST;
+        if device_info.descriptor_buffer.is_some() {
+            usage |= BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        }
+
+        let buffer = match allocator.write() {
+            Ok(mut allocator_actual) => match allocator_actual.allocate_buffer(
+                device_info,
+                ASSERT_BUFFER_BYTES,
+                usage,
+                gpu_allocator::MemoryLocation::GpuToCpu,
+                "kernel_assert_alloc",
+                device_info.queue_indices.compute_queue.unwrap(),
+            ) {
+                Ok(b) => b,
+                Err(e) => {
+                    log::error!("Failed to allocate assert buffer! Error: {:?}", e);
+                    return None;
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                return None;
+            }
+        };
+
+        Some(AssertBuffer { buffer })
+    }
+
+    pub(super) fn buffer(&self) -> vk::Buffer {
+        self.buffer.buffer
+    }
+
+    pub(super) fn range(&self) -> u64 {
+        ASSERT_BUFFER_BYTES
+    }
+
+    // Clears failures from the previous submission before any dispatch runs
+    pub(super) fn cmd_reset(&self, device_info: &DeviceInfo, command_buffer: CommandBuffer) {
+        unsafe {
+            device_info.device.cmd_fill_buffer(
+                command_buffer,
+                self.buffer.buffer,
+                0,
+                ASSERT_BUFFER_BYTES,
+                0,
+            );
+        }
+        barrier::cmd_barriers(
+            device_info,
+            command_buffer,
+            &[Barrier::buffer(
+                self.buffer.buffer,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_WRITE,
+                PipelineStageFlags::COMPUTE_SHADER,
+                AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE,
+            )],
+        );
+    }
+
+    pub(super) fn cmd_make_host_visible(
+        &self,
+        device_info: &DeviceInfo,
+        command_buffer: CommandBuffer,
+    ) {
+        barrier::cmd_barriers(
+            device_info,
+            command_buffer,
+            &[Barrier::buffer(
+                self.buffer.buffer,
+                PipelineStageFlags::COMPUTE_SHADER,
+                AccessFlags::SHADER_WRITE,
+                PipelineStageFlags::HOST,
+                AccessFlags::HOST_READ,
+            )],
+        );
+    }
+
+    // Only valid once the submission has finished
+    pub(super) fn check(
+        &self,
+        device_info: &DeviceInfo,
+        shader: &str,
+    ) -> Result<(), KernelAssertionFailed> {
+        if let Err(e) = staging::invalidate_buffer(device_info, &self.buffer) {
+            log::error!("Failed to invalidate assert buffer! Error: {}", e);
+        }
+
+        let words = unsafe {
+            std::slice::from_raw_parts(self.buffer.mapped_ptr().unwrap().as_ptr() as *const u32, 4)
+        };
+        if words[0] == 0 {
+            return Ok(());
+        }
+
+        let failure = KernelAssertionFailed {
+            shader: shader.to_string(),
+            failures: words[0],
+            code: words[1],
+            index: words[2],
+            line: words[3],
+        };
+        log::error!("Kernel assertion failed: {:?}", failure);
+        Err(failure)
+    }
+
+    pub(super) fn free(self, device_info: &DeviceInfo, allocator: &Arc<RwLock<Allocator>>) {
+        match allocator.write() {
+            Ok(mut allocator_actual) => {
+                free_buffer(device_info, &mut allocator_actual, self.buffer)
+            }
+            Err(e) => log::error!("Failed to acquire allocator! Leaking buffer. Error: {e}"),
+        }
+    }
+}
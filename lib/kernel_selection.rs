@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{
+    benchmark::{BenchmarkError, Phase},
+    pipeline::Pipeline,
+    ComputeManager, Tensor, WorkGroupSize,
+};
+
+/// One implementation of an op. Candidates of the same op must bind the same tensors.
+#[derive(Clone)]
+pub struct KernelCandidate {
+    pub name: String,
+    pub pipeline: Arc<Pipeline>,
+    pub work_group: WorkGroupSize,
+}
+
+#[derive(Debug, Clone)]
+pub enum KernelSelectionError {
+    NoCandidates,
+    BenchmarkFailure(BenchmarkError),
+    CacheReadFailure,
+    CacheWriteFailure,
+    CacheLockFailure,
+}
+
+// Winning candidate names by device and op. Entries for other devices are kept so one cache file
+// can be shared between machines.
+pub(super) struct KernelSelectionCache {
+    path: Option<PathBuf>,
+    winners: HashMap<(String, String), String>,
+}
+
+impl KernelSelectionCache {
+    pub(super) fn new() -> Self {
+        KernelSelectionCache {
+            path: None,
+            winners: HashMap::new(),
+        }
+    }
+
+    // One `device<TAB>op<TAB>winner` entry per line
+    fn load(&mut self, path: &Path) -> std::io::Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        contents.lines().for_each(|line| {
+            let mut fields = line.split('\t');
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(device), Some(op), Some(winner), None) => {
+                    self.winners
+                        .insert((device.to_string(), op.to_string()), winner.to_string());
+                }
+                _ if line.is_empty() => (),
+                _ => log::warn!("Skipping malformed kernel cache entry \"{}\"", line),
+            }
+        });
+        self.path = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let path = match self.path.as_ref() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        let mut entries: Vec<String> = self
+            .winners
+            .iter()
+            .map(|((device, op), winner)| format!("{device}\t{op}\t{winner}\n"))
+            .collect();
+        entries.sort();
+
+        fs::write(path, entries.concat())
+    }
+}
+
+impl ComputeManager {
+    /// Identifies the device and driver that selections were measured on
+    pub fn device_key(&self) -> String {
+        let properties = unsafe {
+            self.instance_info
+                .instance
+                .get_physical_device_properties(self.device_info.physical_device)
+        };
+
+        format!(
+            "{:04x}:{:04x}:{:08x}",
+            properties.vendor_id, properties.device_id, properties.driver_version
+        )
+    }
+
+    /// Loads earlier selections from `path` and saves new ones back to it. A missing file is
+    /// created on the next selection.
+    pub fn load_kernel_cache(&self, path: &Path) -> Result<(), KernelSelectionError> {
+        let mut cache = match self.kernel_selection.lock() {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to acquire kernel selection cache! Error: {e}");
+                return Err(KernelSelectionError::CacheLockFailure);
+            }
+        };
+
+        match cache.load(path) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!(
+                    "Failed to read kernel cache {}! Error: {}",
+                    path.display(),
+                    e
+                );
+                Err(KernelSelectionError::CacheReadFailure)
+            }
+        }
+    }
+
+    /// Picks the fastest of `candidates` for `op` on this device. The first call per device
+    /// benchmarks every candidate on the given tensors, and later calls reuse the winner. Output
+    /// tensors must have readback enabled and are left holding the last candidate's results.
+    pub fn select_kernel<'a>(
+        self: Arc<Self>,
+        op: &str,
+        candidates: &'a [KernelCandidate],
+        inputs: Vec<&Tensor>,
+        mut outputs: Vec<&mut Tensor>,
+        iterations: u32,
+    ) -> Result<&'a KernelCandidate, KernelSelectionError> {
+        let names: Vec<&str> = candidates.iter().map(|c| c.name.as_str()).collect();
+        let winner = self.select_candidate(op, &names, iterations, |index, iterations| {
+            let candidate = &candidates[index];
+            self.clone()
+                .time_phase(
+                    Phase::Full,
+                    &candidate.pipeline,
+                    &inputs,
+                    &mut outputs,
+                    candidate.work_group,
+                    iterations,
+                )
+                .map_err(KernelSelectionError::BenchmarkFailure)
+        })?;
+
+        Ok(&candidates[winner])
+    }
+
+    // The index of the fastest of the candidates `names` for `op`, either cached or measured with
+    // `time`, which runs a candidate `iterations` times and returns the mean time of a run
+    pub(super) fn select_candidate<E, F>(
+        &self,
+        op: &str,
+        names: &[&str],
+        iterations: u32,
+        mut time: F,
+    ) -> Result<usize, E>
+    where
+        E: From<KernelSelectionError>,
+        F: FnMut(usize, u32) -> Result<Duration, E>,
+    {
+        if names.is_empty() {
+            return Err(KernelSelectionError::NoCandidates.into());
+        }
+
+        let key = (self.device_key(), op.to_string());
+        match self.kernel_selection.lock() {
+            Ok(cache) => {
+                if let Some(winner) = cache
+                    .winners
+                    .get(&key)
+                    .and_then(|w| names.iter().position(|n| n == w))
+                {
+                    return Ok(winner);
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to acquire kernel selection cache! Error: {e}");
+                return Err(KernelSelectionError::CacheLockFailure.into());
+            }
+        }
+
+        let iterations = iterations.max(1);
+        let mut best: Option<(usize, Duration)> = None;
+        for (index, name) in names.iter().enumerate() {
+            // Warm up once so one-time driver costs don't count against the first candidate
+            let mut elapsed = Duration::ZERO;
+            for iterations in [1, iterations] {
+                elapsed = time(index, iterations)?;
+            }
+
+            log::info!("Kernel candidate {}/{} took {:?}", op, name, elapsed);
+            let faster = match best {
+                Some((_, t)) => elapsed < t,
+                None => true,
+            };
+            if faster {
+                best = Some((index, elapsed));
+            }
+        }
+        let (winner, _) = best.unwrap();
+
+        let mut cache = match self.kernel_selection.lock() {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to acquire kernel selection cache! Error: {e}");
+                return Err(KernelSelectionError::CacheLockFailure.into());
+            }
+        };
+        cache.winners.insert(key, names[winner].to_string());
+        if let Err(e) = cache.save() {
+            log::error!("Failed to write kernel cache! Error: {}", e);
+            return Err(KernelSelectionError::CacheWriteFailure.into());
+        }
+
+        Ok(winner)
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
+    collections::HashMap,
     mem::MaybeUninit,
-    sync::{atomic::AtomicU32, Arc, RwLock},
+    sync::{atomic::AtomicU32, Arc, Mutex, RwLock, Weak},
 };
 
 use self::{
@@ -10,36 +11,153 @@ use self::{
 };
 
 use allocation_strategy::Allocator;
-pub use allocation_strategy::Tensor;
-pub use gpu_task::WorkGroupSize;
+use descriptor_allocator::DescriptorAllocator;
+use hazard_tracker::HazardTracker;
+use kernel_selection::KernelSelectionCache;
+use registry::Registry;
+use resource_tracker::ResourceTracker;
+use result_cache::ResultCache;
+use submission::SubmissionThread;
+use telemetry::Telemetry;
+use timing_budget::TimingBudgets;
+pub use allocation_strategy::{DeviceMemoryPolicy, DeviceMemoryStats, HostMemoryLocation, Tensor};
+pub use arena::{ArenaError, TensorArena};
+pub use benchmark::{BenchmarkError, ComparisonReport};
+pub use builtin_kernels::{
+    BuiltinKernel, BuiltinKernelError, Conversion, Reduction, ReductionOrder, StridedCopy,
+};
+pub use chunked_readback::{ChunkedReadbackError, ReadbackProgress};
+pub use context::{
+    ComputeContext, ContextError, ContextQuota, ContextRun, ContextTask, ContextTaskHandle,
+};
+pub use custom_op::{CustomOp, CustomOpError};
+pub use device::DeviceSummary;
+pub use executor::{ExecutorError, ExecutorReport};
+pub use external_semaphore::{ExternalSemaphoreHandle, SemaphoreExportError};
+pub use frame::{Frame, FrameError, FrameTaskId, FrameTimeline};
+pub use gguf::{GgmlType, GgufError, GgufFile, GgufLoader, GgufTensor, GgufTensorInfo, GgufValue};
+pub use gpu_task::{
+    DispatchAxis, GPUTaskOpKind, GPUTaskRecordingDiagnostic, GPUTaskRecordingError,
+    GPUTaskResourceEstimate, RecordedOp, TaskBinding, TaskPriority, WorkGroupSize,
+    SMALL_TENSOR_MAX_BYTES,
+};
+pub use host_staging::HostStagingHints;
+pub use kernel_assert::{KernelAssertionFailed, KERNEL_ASSERT_BINDING, KERNEL_ASSERT_GLSL};
+pub use kernel_selection::{KernelCandidate, KernelSelectionError};
 pub use log_config::AllocatorLogConfig;
 pub use log_config::LogConfig;
 pub use log_config::ValidationLayerLogConfig;
+pub use object_budget::{ObjectBudgets, VulkanObjectKind};
+pub use pipeline::{LayoutMismatch, Pipeline, PipelineCreateError, ShaderSource};
+pub use progress::{KERNEL_PROGRESS_BINDING, KERNEL_PROGRESS_GLSL};
+pub use queue_ownership::QueueRole;
+pub use resource_tracker::{LeakCheckGuard, LiveResource, LiveResourceKind};
+pub use result_cache::{CachedExecution, ResultCacheError};
+pub use shutdown::ShutdownReport;
+pub use staging::StagingError;
+pub use stepper::{Stepper, StepperError};
+pub use submission::{InFlightLimit, SubmissionBatching};
+pub use sweep::{SweepError, SweepGrid, SweepMeasurement, SweepPoint, SweepResult};
+pub use task_sequence::{
+    HostAction, SequenceContext, SequenceOutcome, TaskSequence, TaskSequenceError,
+};
+#[cfg(feature = "amdgpu-sysfs")]
+pub use telemetry::AmdGpuTelemetry;
+#[cfg(feature = "nvml")]
+pub use telemetry::NvmlTelemetry;
+pub use telemetry::{TaskProfile, TelemetryError, TelemetrySample, TelemetrySource};
+pub use timing_budget::BudgetWarning;
+pub use trace::{TaskTrace, TraceDifference, TraceError, TraceTolerance};
+pub use transfer::TransferError;
+pub use validation::{ValidationMessage, ValidationSeverity};
+
+/// Generates typed Rust bindings for GLSL kernels, for build scripts of downstream crates
+pub mod codegen;
+/// Runs kernels over small fixture tensors and checks their outputs against expected values or
+/// recorded golden files, for unit-testing shaders
+pub mod testing;
 
 mod allocation_strategy;
+mod arena;
+mod barrier;
+mod benchmark;
+mod builtin_kernels;
+mod chunked_readback;
 mod command_buffer_util;
+mod context;
+mod custom_op;
+mod descriptor_allocator;
+mod descriptor_buffer;
 mod device;
+mod executor;
+mod external_semaphore;
+mod frame;
+mod gguf;
 mod gpu_task;
+mod hazard_tracker;
+mod host_staging;
 mod init_error;
 mod instance;
+mod kernel_assert;
+mod kernel_selection;
 mod log_config;
+mod object_budget;
 mod pipeline;
+mod progress;
+mod queue_ownership;
+mod readback_transform;
+mod registry;
+mod resource_state;
+mod resource_tracker;
+mod result_cache;
+mod shutdown;
+mod spirv_reflect;
+mod staging;
+mod stepper;
+mod submission;
+mod sweep;
+mod task_sequence;
+mod telemetry;
+mod timing_budget;
+mod trace;
+mod transfer;
+mod validation;
 
 pub struct ComputeManager {
     instance_info: InstanceInfo,
     device_info: DeviceInfo,
     allocator: Arc<RwLock<allocation_strategy::Allocator>>,
     current_tensor_id: AtomicU32,
+    resource_tracker: Option<Arc<ResourceTracker>>,
+    pipeline_cache: Mutex<HashMap<u64, Weak<pipeline::Pipeline>>>,
+    descriptor_allocator: Mutex<DescriptorAllocator>,
+    submission_thread: SubmissionThread,
+    hazard_tracker: Mutex<HazardTracker>,
+    kernel_selection: Mutex<KernelSelectionCache>,
+    timing_budgets: TimingBudgets,
+    registry: Registry,
+    result_cache: Mutex<ResultCache>,
+    telemetry: Mutex<Option<Telemetry>>,
 }
 
 impl Drop for ComputeManager {
     fn drop(&mut self) {
+        // Runs outstanding completion callbacks and releases deferred fences
+        self.submission_thread.shutdown();
+
         unsafe {
             self.device_info.device.device_wait_idle().unwrap();
 
-            self.device_info
-                .device
-                .destroy_command_pool(self.device_info.compute_pool, None);
+            command_buffer_util::destroy_command_pools(&self.device_info);
+
+            if let Ok(mut descriptor_allocator) = self.descriptor_allocator.lock() {
+                descriptor_allocator.destroy(&self.device_info);
+            }
+
+            // Buffers kept alive for prologues that have all finished by now
+            if let Ok(mut hazard_tracker) = self.hazard_tracker.lock() {
+                self.free_retired_buffers(hazard_tracker.take_deferred_frees());
+            }
 
             // Free the VkMemory allocations made by the allocator
             if let Ok(mut allocator) = self.allocator.write() {
@@ -73,6 +191,7 @@ pub fn compute_init(log_config: LogConfig) -> Result<Arc<ComputeManager>, InitEr
 
     let instance_info = create_instance(log_config.validation_config)?;
     let device_info = initialize_device(&instance_info, true)?;
+    let submission_thread = SubmissionThread::spawn(device_info.clone())?;
     let allocator = match allocation_strategy::Allocator::new(
         &instance_info,
         &device_info,
@@ -90,5 +209,19 @@ pub fn compute_init(log_config: LogConfig) -> Result<Arc<ComputeManager>, InitEr
         device_info,
         allocator: Arc::new(RwLock::new(allocator)),
         current_tensor_id: AtomicU32::new(0),
+        resource_tracker: if log_config.track_live_resources {
+            Some(Arc::new(ResourceTracker::new()))
+        } else {
+            None
+        },
+        pipeline_cache: Mutex::new(HashMap::new()),
+        descriptor_allocator: Mutex::new(DescriptorAllocator::new()),
+        submission_thread,
+        hazard_tracker: Mutex::new(HazardTracker::new()),
+        kernel_selection: Mutex::new(KernelSelectionCache::new()),
+        timing_budgets: TimingBudgets::new(),
+        registry: Registry::new(),
+        result_cache: Mutex::new(ResultCache::new()),
+        telemetry: Mutex::new(None),
     }))
 }
@@ -19,4 +19,5 @@ pub struct AllocatorLogConfig {
 pub struct LogConfig {
     pub validation_config: Option<ValidationLayerLogConfig>,
     pub allocator_config: Option<AllocatorLogConfig>,
+    pub track_live_resources: bool,
 }
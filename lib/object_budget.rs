@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use ash::{prelude::VkResult, vk};
+
+use super::ComputeManager;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VulkanObjectKind {
+    Pipeline,
+    DescriptorPool,
+    CommandBuffer,
+    Fence,
+}
+
+const KINDS: [VulkanObjectKind; 4] = [
+    VulkanObjectKind::Pipeline,
+    VulkanObjectKind::DescriptorPool,
+    VulkanObjectKind::CommandBuffer,
+    VulkanObjectKind::Fence,
+];
+
+/// How many Vulkan objects of each kind may be alive before it's reported. Counts far above what
+/// a workload needs usually mean something is created per frame or leaked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectBudgets {
+    pub pipelines: usize,
+    pub descriptor_pools: usize,
+    pub command_buffers: usize,
+    pub fences: usize,
+    /// Fail creating objects over budget instead of warning about them
+    pub strict: bool,
+}
+
+impl Default for ObjectBudgets {
+    fn default() -> Self {
+        ObjectBudgets {
+            pipelines: 512,
+            descriptor_pools: 256,
+            command_buffers: 4096,
+            fences: 1024,
+            strict: false,
+        }
+    }
+}
+
+impl ObjectBudgets {
+    fn get(&self, kind: VulkanObjectKind) -> usize {
+        match kind {
+            VulkanObjectKind::Pipeline => self.pipelines,
+            VulkanObjectKind::DescriptorPool => self.descriptor_pools,
+            VulkanObjectKind::CommandBuffer => self.command_buffers,
+            VulkanObjectKind::Fence => self.fences,
+        }
+    }
+}
+
+// Live counts of the objects with budgets. Unlike the resource tracker these are always kept, so
+// they're cheap enough to update on every submission.
+pub struct ObjectCounts {
+    live: [AtomicUsize; 4],
+    // Set while a kind is over budget, so each overrun is only reported once
+    over_budget: [AtomicBool; 4],
+    budgets: Mutex<ObjectBudgets>,
+}
+
+impl ObjectCounts {
+    pub(super) fn new() -> Self {
+        ObjectCounts {
+            live: Default::default(),
+            over_budget: Default::default(),
+            budgets: Mutex::new(ObjectBudgets::default()),
+        }
+    }
+
+    fn budgets(&self) -> ObjectBudgets {
+        *self.budgets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Counts `n` new objects, or refuses them in strict mode when they'd exceed the budget
+    pub(super) fn reserve(&self, kind: VulkanObjectKind, n: usize) -> VkResult<()> {
+        let budgets = self.budgets();
+        let budget = budgets.get(kind);
+        let live = self.live[kind as usize].fetch_add(n, Ordering::Relaxed) + n;
+        if live <= budget {
+            return Ok(());
+        }
+
+        if budgets.strict {
+            self.live[kind as usize].fetch_sub(n, Ordering::Relaxed);
+            log::error!(
+                "Refusing to create {:?}, {} are alive and the budget is {}!",
+                kind,
+                live - n,
+                budget
+            );
+            return Err(vk::Result::ERROR_TOO_MANY_OBJECTS);
+        }
+        if !self.over_budget[kind as usize].swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "{} {:?} objects are alive, over the budget of {}. Are they created per frame or leaked?",
+                live,
+                kind,
+                budget
+            );
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn release(&self, kind: VulkanObjectKind, n: usize) {
+        let live = self.live[kind as usize]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |l| {
+                Some(l.saturating_sub(n))
+            })
+            .unwrap_or(0)
+            .saturating_sub(n);
+        if live <= self.budgets().get(kind) {
+            self.over_budget[kind as usize].store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+impl ComputeManager {
+    /// Objects alive over a new, lower budget are reported on the next creation
+    pub fn set_object_budgets(&self, budgets: ObjectBudgets) {
+        let counts = &self.device_info.object_counts;
+        *counts
+            .budgets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = budgets;
+        counts
+            .over_budget
+            .iter()
+            .for_each(|o| o.store(false, Ordering::Relaxed));
+    }
+
+    pub fn object_budgets(&self) -> ObjectBudgets {
+        self.device_info.object_counts.budgets()
+    }
+
+    pub fn live_object_counts(&self) -> HashMap<VulkanObjectKind, usize> {
+        KINDS
+            .iter()
+            .map(|kind| {
+                (
+                    *kind,
+                    self.device_info.object_counts.live[*kind as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
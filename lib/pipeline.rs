@@ -1,15 +1,31 @@
-use std::{ffi::CString, ptr, str::FromStr, sync::Arc};
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::{c_void, CString},
+    hash::{Hash, Hasher},
+    ptr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+};
 
 use ash::vk::{
-    self, ComputePipelineCreateInfo,
-    DescriptorSetLayoutBinding, DescriptorSetLayoutCreateFlags,
-    DescriptorSetLayoutCreateInfo, DescriptorType, PipelineCache, PipelineCreateFlags,
-    PipelineLayoutCreateFlags, PipelineLayoutCreateInfo, PipelineShaderStageCreateFlags,
-    PipelineShaderStageCreateInfo, ShaderModule, ShaderModuleCreateFlags, ShaderModuleCreateInfo,
-    ShaderStageFlags, StructureType,
+    self, ComputePipelineCreateInfo, DescriptorPoolSize, DescriptorSetLayoutBinding,
+    DescriptorSetLayoutCreateFlags, DescriptorSetLayoutCreateInfo, DescriptorType, PipelineCache,
+    PipelineCreateFlags, PipelineLayoutCreateFlags, PipelineLayoutCreateInfo,
+    PipelineShaderStageCreateFlags, PipelineShaderStageCreateInfo, ShaderModule,
+    ShaderModuleCreateFlags, ShaderModuleCreateInfo, ShaderStageFlags, SpecializationInfo,
+    SpecializationMapEntry, StructureType,
 };
 
-use super::ComputeManager;
+use super::{
+    descriptor_buffer::DescriptorBufferLayout,
+    kernel_assert::{KERNEL_ASSERT_BINDING, KERNEL_ASSERT_MACRO},
+    object_budget::VulkanObjectKind,
+    progress::KERNEL_PROGRESS_BINDING,
+    resource_tracker::{LiveResourceKind, TrackedResource},
+    result_cache::StableHasher,
+    spirv_reflect::{self, DescriptorBinding, ShaderReflection},
+    ComputeManager,
+};
 
 #[derive(Clone, Copy, Debug)]
 pub enum PipelineCreateError {
@@ -19,21 +35,70 @@ pub enum PipelineCreateError {
     PipelineCreationFailure,
     DescriptorPoolCreationFailure,
     DescriptorSetAllocationFailure,
+    SharedMemoryExceeded {
+        required_bytes: u64,
+        limit_bytes: u32,
+    },
+    // A swapped-in shader must keep the local size existing dispatches were validated against
+    LocalSizeMismatch {
+        expected: Option<[u32; 3]>,
+        found: Option<[u32; 3]>,
+    },
+    // The shader declares descriptors the pipeline layout doesn't provide
+    LayoutMismatch(LayoutMismatch),
+}
+
+/// How descriptors expected by a shader or pipeline differ from a pipeline's layout
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayoutMismatch {
+    /// Expected but absent from the layout
+    MissingBinding { set: u32, binding: u32 },
+    /// In the layout but not expected, which other pipelines can't share a descriptor set with
+    ExtraBinding { binding: u32 },
+    DescriptorTypeMismatch {
+        binding: u32,
+        expected: DescriptorType,
+        found: DescriptorType,
+    },
 }
 
 pub struct Pipeline {
-    pub(super) pipeline: vk::Pipeline,
+    shader: Mutex<PipelineShader>,
     pub(super) pipeline_layout: vk::PipelineLayout,
 
     pub(super) descriptor_set_layout: vk::DescriptorSetLayout,
-    // pub(super) descriptor_pool: vk::DescriptorPool,
+    // The bindings of set 0, the only set in the layout
+    layout_bindings: Vec<(u32, DescriptorType)>,
+    binding_count: u32,
+    // Whether set 0 also holds the assert buffer at `KERNEL_ASSERT_BINDING`
+    pub(super) assert_binding: bool,
+    // Whether set 0 also holds a progress counter at `KERNEL_PROGRESS_BINDING`
+    pub(super) progress_binding: bool,
+    pub(super) descriptor_pool_sizes: Vec<DescriptorPoolSize>,
+    // Set when descriptors are written to a descriptor buffer instead of a pooled set
+    pub(super) descriptor_buffer_layout: Option<DescriptorBufferLayout>,
+    specialization: Vec<(u32, u32)>,
+    shader_name: String,
+    _tracking: Option<TrackedResource>,
 
     parent: Arc<ComputeManager>,
 }
 
+// The shader currently behind a pipeline. Command buffers recorded before a swap still reference
+// the pipelines it replaced, so those are only destroyed with the `Pipeline`.
+struct PipelineShader {
+    pipeline: vk::Pipeline,
+    reflection: ShaderReflection,
+    generation: u64,
+    spirv_hash: u64,
+    retired: Vec<vk::Pipeline>,
+}
+
 pub struct Program {
     shader_module: ShaderModule,
     shader_name: String,
+    reflection: ShaderReflection,
+    spirv_hash: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -42,7 +107,17 @@ pub enum ProgramCompilationError {
     ModuleCreationError(String),
 }
 
+#[derive(Debug, Clone, Copy, Hash)]
+pub enum ShaderSource<'a> {
+    Glsl(&'a str),
+    SpirV(&'a [u32]),
+}
+
 impl ComputeManager {
+    pub fn max_shared_memory_bytes(&self) -> u32 {
+        self.device_info.limits.max_compute_shared_memory_size
+    }
+
     pub fn compile_program(
         &self,
         shader: &str,
@@ -54,6 +129,9 @@ impl ComputeManager {
         if !optimize {
             options.set_optimization_level(shaderc::OptimizationLevel::Performance);
         }
+        if self.kernel_asserts_enabled() {
+            options.add_macro_definition(KERNEL_ASSERT_MACRO, None);
+        }
 
         let result = match compiler.compile_into_spirv(
             shader,
@@ -71,12 +149,20 @@ impl ComputeManager {
             }
         };
 
+        self.load_program(result.as_binary(), name)
+    }
+
+    pub fn load_program(
+        &self,
+        spirv: &[u32],
+        name: &str,
+    ) -> Result<Program, ProgramCompilationError> {
         let shader_module_create_info = ShaderModuleCreateInfo {
             s_type: StructureType::SHADER_MODULE_CREATE_INFO,
             p_next: ptr::null(),
             flags: ShaderModuleCreateFlags::empty(),
-            code_size: result.as_binary().len() * 4,
-            p_code: result.as_binary().as_ptr(),
+            code_size: std::mem::size_of_val(spirv),
+            p_code: spirv.as_ptr(),
         };
 
         let shader_module = unsafe {
@@ -93,14 +179,87 @@ impl ComputeManager {
         Ok(Program {
             shader_module,
             shader_name: String::from_str(name).unwrap(),
+            reflection: spirv_reflect::reflect(spirv),
+            spirv_hash: StableHasher::hash_words(spirv),
         })
     }
 
+    /// Pipelines are cached weakly, so an entry only lives as long as someone holds the pipeline
+    pub fn get_or_build_pipeline(
+        self: Arc<Self>,
+        source: ShaderSource,
+        name: &str,
+        n_tensors: u32,
+    ) -> Result<Arc<Pipeline>, PipelineCreateError> {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        n_tensors.hash(&mut hasher);
+        // Asserts change both the compiled shader and the layout
+        self.kernel_asserts_enabled().hash(&mut hasher);
+        let key = hasher.finish();
+
+        let mut cache = match self.pipeline_cache.lock() {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to acquire pipeline cache! Error: {e}");
+                return Err(PipelineCreateError::PipelineCreationFailure);
+            }
+        };
+
+        if let Some(pipeline) = cache.get(&key).and_then(Weak::upgrade) {
+            return Ok(pipeline);
+        }
+
+        let program = self.program_from_source(source, name)?;
+        let pipeline = Arc::new(self.clone().build_pipeline(program, n_tensors)?);
+
+        cache.retain(|_, p| p.strong_count() > 0);
+        cache.insert(key, Arc::downgrade(&pipeline));
+
+        Ok(pipeline)
+    }
+
+    pub(super) fn program_from_source(
+        &self,
+        source: ShaderSource,
+        name: &str,
+    ) -> Result<Program, PipelineCreateError> {
+        let program = match source {
+            ShaderSource::Glsl(shader) => self.compile_program(shader, name, true),
+            ShaderSource::SpirV(spirv) => self.load_program(spirv, name),
+        };
+        match program {
+            Ok(p) => Ok(p),
+            Err(e) => {
+                log::error!("Failed to create program \"{}\"! Error: {:?}", name, e);
+                Err(PipelineCreateError::InvalidShader)
+            }
+        }
+    }
+
     pub fn build_pipeline(
         self: Arc<Self>,
         program: Program,
         n_tensors: u32,
     ) -> Result<Pipeline, PipelineCreateError> {
+        self.build_specialized_pipeline(program, n_tensors, &[])
+    }
+
+    /// `specialization` holds `(constant_id, value)` pairs, e.g. for spec-constant-sized shared arrays
+    pub fn build_specialized_pipeline(
+        self: Arc<Self>,
+        program: Program,
+        n_tensors: u32,
+        specialization: &[(u32, u32)],
+    ) -> Result<Pipeline, PipelineCreateError> {
+        let mut reflection = program.reflection.clone();
+        reflection.specialize(specialization);
+
+        if let Err(e) = self.check_shared_memory(&program, &reflection) {
+            self.destroy_program(&program);
+            return Err(e);
+        }
+
         let mut descriptor_set_bindings: Vec<DescriptorSetLayoutBinding> = Vec::new();
         for i in 0..n_tensors {
             descriptor_set_bindings.push(DescriptorSetLayoutBinding {
@@ -112,10 +271,72 @@ impl ComputeManager {
             });
         }
 
+        // Only shaders that declare the counter get one
+        let progress_binding = n_tensors <= KERNEL_PROGRESS_BINDING
+            && reflection
+                .descriptor_bindings
+                .iter()
+                .any(|b| b.set == 0 && b.binding == KERNEL_PROGRESS_BINDING);
+        if progress_binding {
+            descriptor_set_bindings.push(DescriptorSetLayoutBinding {
+                binding: KERNEL_PROGRESS_BINDING,
+                descriptor_type: DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 1,
+                stage_flags: ShaderStageFlags::COMPUTE,
+                p_immutable_samplers: ptr::null(),
+            });
+        }
+
+        let assert_binding = self.kernel_asserts_enabled() && n_tensors <= KERNEL_ASSERT_BINDING;
+        if assert_binding {
+            descriptor_set_bindings.push(DescriptorSetLayoutBinding {
+                binding: KERNEL_ASSERT_BINDING,
+                descriptor_type: DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 1,
+                stage_flags: ShaderStageFlags::COMPUTE,
+                p_immutable_samplers: ptr::null(),
+            });
+        } else if self.kernel_asserts_enabled() {
+            log::warn!(
+                "Shader \"{}\" binds {} tensors, which leaves no room for the assert buffer",
+                program.shader_name,
+                n_tensors
+            );
+        }
+
+        let layout_bindings: Vec<(u32, DescriptorType)> = descriptor_set_bindings
+            .iter()
+            .map(|b| (b.binding, b.descriptor_type))
+            .collect();
+        if let Err(e) =
+            self.check_shader_layout(&program.shader_name, &reflection, &layout_bindings)
+        {
+            self.destroy_program(&program);
+            return Err(e);
+        }
+
+        let mut descriptor_pool_sizes: Vec<DescriptorPoolSize> = Vec::new();
+        descriptor_set_bindings.iter().for_each(|b| {
+            match descriptor_pool_sizes
+                .iter_mut()
+                .find(|s| s.ty == b.descriptor_type)
+            {
+                Some(size) => size.descriptor_count += b.descriptor_count,
+                None => descriptor_pool_sizes.push(DescriptorPoolSize {
+                    ty: b.descriptor_type,
+                    descriptor_count: b.descriptor_count,
+                }),
+            }
+        });
+
         let create_info = DescriptorSetLayoutCreateInfo {
             s_type: StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
             p_next: ptr::null(),
-            flags: DescriptorSetLayoutCreateFlags::empty(),
+            flags: if self.device_info.descriptor_buffer.is_some() {
+                DescriptorSetLayoutCreateFlags::DESCRIPTOR_BUFFER_EXT
+            } else {
+                DescriptorSetLayoutCreateFlags::empty()
+            },
             binding_count: descriptor_set_bindings.len() as u32,
             p_bindings: descriptor_set_bindings.as_ptr(),
         };
@@ -158,6 +379,108 @@ impl ComputeManager {
             }
         };
 
+        let pipeline = self.create_compute_pipeline(&program, pipeline_layout, specialization);
+        self.destroy_program(&program);
+        let pipeline = pipeline?;
+
+        let _tracking = self.track_resource(LiveResourceKind::Pipeline, || {
+            format!("pipeline{{shader={}}}", program.shader_name)
+        });
+        self.name_object(pipeline, &program.shader_name);
+
+        Ok(Pipeline {
+            shader: Mutex::new(PipelineShader {
+                pipeline,
+                reflection,
+                generation: 0,
+                spirv_hash: program.spirv_hash,
+                retired: Vec::new(),
+            }),
+            pipeline_layout,
+            descriptor_set_layout,
+            layout_bindings,
+            binding_count: n_tensors,
+            assert_binding,
+            progress_binding,
+            descriptor_pool_sizes,
+            descriptor_buffer_layout: self.device_info.descriptor_buffer.as_ref().map(|d| {
+                d.layout(
+                    descriptor_set_layout,
+                    descriptor_set_bindings.iter().map(|b| b.binding),
+                )
+            }),
+            specialization: specialization.to_vec(),
+            shader_name: program.shader_name,
+            _tracking,
+            parent: self,
+        })
+    }
+    fn check_shared_memory(
+        &self,
+        program: &Program,
+        reflection: &ShaderReflection,
+    ) -> Result<(), PipelineCreateError> {
+        let required_bytes = reflection.shared_memory_bytes();
+        let limit_bytes = self.max_shared_memory_bytes();
+        if required_bytes > limit_bytes as u64 {
+            log::error!(
+                "Shader \"{}\" declares {} bytes of shared memory but the device allows at most {}!",
+                program.shader_name,
+                required_bytes,
+                limit_bytes
+            );
+            return Err(PipelineCreateError::SharedMemoryExceeded {
+                required_bytes,
+                limit_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    // Drivers read whatever is bound where the layout disagrees with the shader, so that's
+    // refused up front
+    fn check_shader_layout(
+        &self,
+        name: &str,
+        reflection: &ShaderReflection,
+        layout_bindings: &[(u32, DescriptorType)],
+    ) -> Result<(), PipelineCreateError> {
+        match layout_mismatch(&reflection.descriptor_bindings, layout_bindings) {
+            Some(mismatch) => {
+                log::error!(
+                    "Shader \"{}\" doesn't match its pipeline layout! Error: {:?}",
+                    name,
+                    mismatch
+                );
+                Err(PipelineCreateError::LayoutMismatch(mismatch))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn create_compute_pipeline(
+        &self,
+        program: &Program,
+        pipeline_layout: vk::PipelineLayout,
+        specialization: &[(u32, u32)],
+    ) -> Result<vk::Pipeline, PipelineCreateError> {
+        let specialization_entries: Vec<SpecializationMapEntry> = specialization
+            .iter()
+            .enumerate()
+            .map(|(i, (constant_id, _))| SpecializationMapEntry {
+                constant_id: *constant_id,
+                offset: (i * 4) as u32,
+                size: 4,
+            })
+            .collect();
+        let specialization_data: Vec<u32> = specialization.iter().map(|(_, v)| *v).collect();
+        let specialization_info = SpecializationInfo {
+            map_entry_count: specialization_entries.len() as u32,
+            p_map_entries: specialization_entries.as_ptr(),
+            data_size: specialization_data.len() * 4,
+            p_data: specialization_data.as_ptr() as *const c_void,
+        };
+
         let name_cstring = CString::new("main").unwrap();
         let shader_stage_create_info = PipelineShaderStageCreateInfo {
             s_type: StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
@@ -166,46 +489,229 @@ impl ComputeManager {
             stage: ShaderStageFlags::COMPUTE,
             module: program.shader_module,
             p_name: name_cstring.as_ptr(),
-            p_specialization_info: ptr::null(),
+            p_specialization_info: if specialization.is_empty() {
+                ptr::null()
+            } else {
+                &specialization_info
+            },
         };
 
+        let mut flags = PipelineCreateFlags::empty();
+        if self.device_info.descriptor_buffer.is_some() {
+            flags |= PipelineCreateFlags::DESCRIPTOR_BUFFER_EXT;
+        }
+        // Lets oversized dispatches be split into parts with a base work group
+        if self.device_info.dispatch_base {
+            flags |= PipelineCreateFlags::DISPATCH_BASE;
+        }
         let pipeline_create_info = ComputePipelineCreateInfo {
             s_type: StructureType::COMPUTE_PIPELINE_CREATE_INFO,
             p_next: std::ptr::null(),
-            flags: PipelineCreateFlags::empty(),
+            flags,
             stage: shader_stage_create_info,
             layout: pipeline_layout,
             base_pipeline_handle: vk::Pipeline::null(),
             base_pipeline_index: -1,
         };
 
-        let pipeline = unsafe {
+        let counts = &self.device_info.object_counts;
+        if counts.reserve(VulkanObjectKind::Pipeline, 1).is_err() {
+            return Err(PipelineCreateError::PipelineCreationFailure);
+        }
+        unsafe {
             match self.device_info.device.create_compute_pipelines(
                 PipelineCache::null(),
                 &[pipeline_create_info],
                 None,
             ) {
-                Ok(p) => p[0],
+                Ok(p) => Ok(p[0]),
                 Err((_, e)) => {
                     log::error!("Failed to create pipeline! Error {}", e);
-                    return Err(PipelineCreateError::PipelineCreationFailure);
+                    counts.release(VulkanObjectKind::Pipeline, 1);
+                    Err(PipelineCreateError::PipelineCreationFailure)
                 }
             }
-        };
+        }
+    }
 
+    fn destroy_program(&self, program: &Program) {
         unsafe {
             self.device_info
                 .device
                 .destroy_shader_module(program.shader_module, None)
         }
+    }
+}
 
-        Ok(Pipeline {
-            pipeline,
-            pipeline_layout,
-            descriptor_set_layout,
-            //descriptor_pool,
-            parent: self,
-        })
+// Finds the first descriptor the shader uses that the layout lacks or declares differently. Bindings
+// the shader doesn't use are fine.
+fn layout_mismatch(
+    shader_bindings: &[DescriptorBinding],
+    layout_bindings: &[(u32, DescriptorType)],
+) -> Option<LayoutMismatch> {
+    shader_bindings.iter().find_map(|b| {
+        match layout_bindings
+            .iter()
+            .find(|(binding, _)| *binding == b.binding)
+        {
+            Some((_, expected)) if b.set == 0 && *expected != b.descriptor_type => {
+                Some(LayoutMismatch::DescriptorTypeMismatch {
+                    binding: b.binding,
+                    expected: *expected,
+                    found: b.descriptor_type,
+                })
+            }
+            Some(_) if b.set == 0 => None,
+            _ => Some(LayoutMismatch::MissingBinding {
+                set: b.set,
+                binding: b.binding,
+            }),
+        }
+    })
+}
+
+impl Pipeline {
+    pub fn binding_count(&self) -> u32 {
+        self.binding_count
+    }
+
+    /// `None` if the shader's local size couldn't be reflected
+    pub fn local_size(&self) -> Option<[u32; 3]> {
+        self.shader().reflection.local_size
+    }
+
+    pub(super) fn local_invocations(&self) -> Option<u64> {
+        self.shader().reflection.local_invocations()
+    }
+
+    /// Whether the shader declares the storage buffer at `binding` `readonly`
+    pub fn binding_read_only(&self, binding: u32) -> bool {
+        self.shader()
+            .reflection
+            .read_only_bindings
+            .contains(&(0, binding))
+    }
+
+    /// Whether the shader declares the storage buffer at `binding` `writeonly`
+    pub fn binding_write_only(&self, binding: u32) -> bool {
+        self.shader()
+            .reflection
+            .write_only_bindings
+            .contains(&(0, binding))
+    }
+
+    /// Shared memory declared by the shader after specialization, without padding
+    pub fn shared_memory_bytes(&self) -> u64 {
+        self.shader().reflection.shared_memory_bytes()
+    }
+
+    /// How many times the shader has been swapped since the pipeline was built
+    pub fn generation(&self) -> u64 {
+        self.shader().generation
+    }
+
+    pub(super) fn handle(&self) -> vk::Pipeline {
+        self.shader().pipeline
+    }
+
+    // Identifies the current shader's code, stable between runs
+    pub(super) fn spirv_hash(&self) -> u64 {
+        self.shader().spirv_hash
+    }
+
+    pub(super) fn specialization(&self) -> &[(u32, u32)] {
+        &self.specialization
+    }
+
+    /// Checks that descriptor sets allocated for this pipeline can be bound for `other`, which
+    /// needs both layouts to have the same bindings with the same descriptor types
+    pub fn check_layout_compatible(&self, other: &Pipeline) -> Result<(), LayoutMismatch> {
+        let expected: Vec<DescriptorBinding> = other
+            .layout_bindings
+            .iter()
+            .map(|(binding, descriptor_type)| DescriptorBinding {
+                set: 0,
+                binding: *binding,
+                descriptor_type: *descriptor_type,
+            })
+            .collect();
+        if let Some(mismatch) = layout_mismatch(&expected, &self.layout_bindings) {
+            return Err(mismatch);
+        }
+
+        match self
+            .layout_bindings
+            .iter()
+            .find(|(binding, _)| !other.layout_bindings.iter().any(|(b, _)| b == binding))
+        {
+            Some((binding, _)) => Err(LayoutMismatch::ExtraBinding { binding: *binding }),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the shader behind this pipeline, keeping its layout, so descriptor sets and
+    /// buffers of existing tasks stay valid. The new shader is built with the pipeline's
+    /// specialization, must have the same local size and may only use descriptors the layout
+    /// provides. Tasks recorded after the swap use it straight away, existing ones once they're
+    /// re-recorded with `GPUTask::refresh_pipeline`.
+    pub fn swap_shader(&self, source: ShaderSource, name: &str) -> Result<(), PipelineCreateError> {
+        let parent = &self.parent;
+        let program = parent.program_from_source(source, name)?;
+        let mut reflection = program.reflection.clone();
+        reflection.specialize(&self.specialization);
+
+        let expected = self.local_size();
+        if reflection.local_size != expected {
+            log::error!(
+                "Shader \"{}\" has local size {:?} but pipeline \"{}\" was built with {:?}!",
+                name,
+                reflection.local_size,
+                self.shader_name,
+                expected
+            );
+            parent.destroy_program(&program);
+            return Err(PipelineCreateError::LocalSizeMismatch {
+                expected,
+                found: reflection.local_size,
+            });
+        }
+        if let Err(e) = parent
+            .check_shared_memory(&program, &reflection)
+            .and_then(|_| parent.check_shader_layout(name, &reflection, &self.layout_bindings))
+        {
+            parent.destroy_program(&program);
+            return Err(e);
+        }
+
+        let pipeline =
+            parent.create_compute_pipeline(&program, self.pipeline_layout, &self.specialization);
+        parent.destroy_program(&program);
+        let pipeline = pipeline?;
+        parent.name_object(pipeline, &self.shader_name);
+
+        // The cached source no longer describes this pipeline
+        match parent.pipeline_cache.lock() {
+            Ok(mut cache) => cache.retain(|_, p| !std::ptr::eq(p.as_ptr(), self)),
+            Err(e) => log::error!("Failed to acquire pipeline cache! Error: {e}"),
+        }
+
+        let mut shader = self.shader();
+        let replaced = std::mem::replace(&mut shader.pipeline, pipeline);
+        shader.retired.push(replaced);
+        shader.reflection = reflection;
+        shader.generation += 1;
+        shader.spirv_hash = program.spirv_hash;
+
+        Ok(())
+    }
+
+    // Nothing panics while holding the lock, so a poisoned one still holds a consistent shader
+    fn shader(&self) -> MutexGuard<'_, PipelineShader> {
+        self.shader.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn shader_name(&self) -> &str {
+        &self.shader_name
     }
 }
 
@@ -220,10 +726,19 @@ impl Drop for Pipeline {
                 .device_info
                 .device
                 .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            let shader = self.shader();
+            std::iter::once(shader.pipeline)
+                .chain(shader.retired.iter().copied())
+                .for_each(|pipeline| {
+                    self.parent
+                        .device_info
+                        .device
+                        .destroy_pipeline(pipeline, None)
+                });
             self.parent
                 .device_info
-                .device
-                .destroy_pipeline(self.pipeline, None);
+                .object_counts
+                .release(VulkanObjectKind::Pipeline, shader.retired.len() + 1);
         }
     }
 }
@@ -0,0 +1,117 @@
+use std::sync::{Arc, RwLock};
+
+use ash::vk::{self, AccessFlags, BufferUsageFlags, CommandBuffer, PipelineStageFlags};
+
+use super::{
+    allocation_strategy::{Allocator, Buffer},
+    barrier::{self, Barrier},
+    device::DeviceInfo,
+    gpu_task::free_buffer,
+    staging,
+};
+
+/// Binding of the progress counter in set 0. Pipelines get a counter when their shader declares
+/// this binding, e.g. through `KERNEL_PROGRESS_GLSL`.
+pub const KERNEL_PROGRESS_BINDING: u32 = 30;
+
+/// GLSL helper for progress counters, to be placed after `#version`. `gauss_progress_add(n)` adds
+/// `n` to the counter `GPUTask::progress` reads, which starts at 0 with every submission.
+pub const KERNEL_PROGRESS_GLSL: &str = "
+layout(set = 0, binding = 30) coherent buffer gauss_progress_buf {
+    uint gauss_progress;
+};
+
+#define gauss_progress_add(n) atomicAdd(gauss_progress, uint(n))
+";
+
+pub(super) const PROGRESS_BUFFER_BYTES: u64 = 4;
+
+pub(super) struct ProgressBuffer {
+    buffer: Buffer,
+}
+
+impl ProgressBuffer {
+    pub(super) fn new(
+        device_info: &DeviceInfo,
+        allocator: &Arc<RwLock<Allocator>>,
+    ) -> Option<Self> {
+        let mut usage = BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::TRANSFER_DST;
+        if device_info.descriptor_buffer.is_some() {
+            usage |= BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        }
+
+        let buffer = match allocator.write() {
+            Ok(mut allocator_actual) => match allocator_actual.allocate_buffer(
+                device_info,
+                PROGRESS_BUFFER_BYTES,
+                usage,
+                gpu_allocator::MemoryLocation::GpuToCpu,
+                "kernel_progress_alloc",
+                device_info.queue_indices.compute_queue.unwrap(),
+            ) {
+                Ok(b) => b,
+                Err(e) => {
+                    log::error!("Failed to allocate progress buffer! Error: {:?}", e);
+                    return None;
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                return None;
+            }
+        };
+
+        Some(ProgressBuffer { buffer })
+    }
+
+    pub(super) fn buffer(&self) -> vk::Buffer {
+        self.buffer.buffer
+    }
+
+    pub(super) fn range(&self) -> u64 {
+        PROGRESS_BUFFER_BYTES
+    }
+
+    pub(super) fn cmd_reset(&self, device_info: &DeviceInfo, command_buffer: CommandBuffer) {
+        unsafe {
+            device_info.device.cmd_fill_buffer(
+                command_buffer,
+                self.buffer.buffer,
+                0,
+                PROGRESS_BUFFER_BYTES,
+                0,
+            );
+        }
+        barrier::cmd_barriers(
+            device_info,
+            command_buffer,
+            &[Barrier::buffer(
+                self.buffer.buffer,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_WRITE,
+                PipelineStageFlags::COMPUTE_SHADER,
+                AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE,
+            )],
+        );
+    }
+
+    // Can be read while the submission runs. Vulkan only guarantees device writes reach the host
+    // once the submission has finished, but coherent atomics show up much earlier on the desktop
+    // drivers progress bars are needed for.
+    pub(super) fn read(&self, device_info: &DeviceInfo) -> u32 {
+        if let Err(e) = staging::invalidate_buffer(device_info, &self.buffer) {
+            log::error!("Failed to invalidate progress buffer! Error: {}", e);
+        }
+
+        unsafe { std::ptr::read_volatile(self.buffer.mapped_ptr().unwrap().as_ptr() as *const u32) }
+    }
+
+    pub(super) fn free(self, device_info: &DeviceInfo, allocator: &Arc<RwLock<Allocator>>) {
+        match allocator.write() {
+            Ok(mut allocator_actual) => {
+                free_buffer(device_info, &mut allocator_actual, self.buffer)
+            }
+            Err(e) => log::error!("Failed to acquire allocator! Leaking buffer. Error: {e}"),
+        }
+    }
+}
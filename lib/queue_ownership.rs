@@ -0,0 +1,76 @@
+use ash::vk::{self, AccessFlags, PipelineStageFlags};
+
+use super::{barrier::Barrier, device::DeviceInfo};
+
+/// The queues a tensor can move between. Buffers are exclusive to one queue family at a time, so
+/// a tensor crossing families has to be released by one queue and acquired by the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueRole {
+    Compute,
+    Transfer,
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum OwnershipTransfer {
+    Release { to: QueueRole },
+    Acquire { from: QueueRole },
+}
+
+const DEVICE_STAGES: PipelineStageFlags = PipelineStageFlags::from_raw(
+    PipelineStageFlags::COMPUTE_SHADER.as_raw() | PipelineStageFlags::TRANSFER.as_raw(),
+);
+
+impl DeviceInfo {
+    // Transfers run on the compute queue until a dedicated transfer queue is created, so for now
+    // every role maps to the compute family and no ownership changes hands
+    pub(super) fn queue_family(&self, role: QueueRole) -> u32 {
+        match role {
+            QueueRole::Compute | QueueRole::Transfer => self.queue_indices.compute_queue.unwrap(),
+        }
+    }
+}
+
+// Tasks record on the compute queue, so that's the side of the transfer they record. Nothing is
+// recorded when both roles share a queue family.
+pub(super) fn ownership_barriers(
+    device_info: &DeviceInfo,
+    transfer: OwnershipTransfer,
+    buffers: &[vk::Buffer],
+) -> Vec<Barrier> {
+    let own_family = device_info.queue_family(QueueRole::Compute);
+    let (src_family, dst_family) = match transfer {
+        OwnershipTransfer::Release { to } => (own_family, device_info.queue_family(to)),
+        OwnershipTransfer::Acquire { from } => (device_info.queue_family(from), own_family),
+    };
+    if src_family == dst_family {
+        return Vec::new();
+    }
+
+    buffers
+        .iter()
+        .map(|buffer| match transfer {
+            // Later work on this queue must not touch the buffer, so only the source half counts
+            OwnershipTransfer::Release { .. } => Barrier::ownership_transfer(
+                *buffer,
+                src_family,
+                dst_family,
+                DEVICE_STAGES,
+                AccessFlags::SHADER_WRITE | AccessFlags::TRANSFER_WRITE,
+                PipelineStageFlags::BOTTOM_OF_PIPE,
+                AccessFlags::empty(),
+            ),
+            OwnershipTransfer::Acquire { .. } => Barrier::ownership_transfer(
+                *buffer,
+                src_family,
+                dst_family,
+                PipelineStageFlags::TOP_OF_PIPE,
+                AccessFlags::empty(),
+                DEVICE_STAGES,
+                AccessFlags::SHADER_READ
+                    | AccessFlags::SHADER_WRITE
+                    | AccessFlags::TRANSFER_READ
+                    | AccessFlags::TRANSFER_WRITE,
+            ),
+        })
+        .collect()
+}
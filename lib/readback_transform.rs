@@ -0,0 +1,68 @@
+use std::any::Any;
+
+use super::Tensor;
+
+type TransformFn = dyn Fn(&[u8]) -> Box<dyn Any + Send> + Send + Sync;
+
+// Runs on a tensor's raw bytes every time its readback lands on the host
+pub(super) struct ReadbackTransform {
+    transform: Box<TransformFn>,
+    output: Option<Box<dyn Any + Send>>,
+}
+
+impl Tensor {
+    /// Converts the tensor's data whenever it's read back, e.g. to dequantize it or reinterpret its
+    /// bytes as a host struct. The f32 data is still read back as usual, and the latest output is
+    /// available from `transformed` or `take_transformed`.
+    pub fn with_readback_transform<T, F>(mut self, transform: F) -> Self
+    where
+        T: Any + Send,
+        F: Fn(&[u8]) -> T + Send + Sync + 'static,
+    {
+        self.readback_transform = Some(ReadbackTransform {
+            transform: Box::new(move |bytes| Box::new(transform(bytes))),
+            output: None,
+        });
+        self
+    }
+
+    /// The transform's output for the latest readback. `None` before the first readback or if `T`
+    /// isn't the transform's output type.
+    pub fn transformed<T: Any>(&self) -> Option<&T> {
+        self.readback_transform
+            .as_ref()
+            .and_then(|t| t.output.as_ref())
+            .and_then(|output| output.downcast_ref())
+    }
+
+    /// Like `transformed`, but moves the output out. It's produced again on the next readback.
+    pub fn take_transformed<T: Any>(&mut self) -> Option<T> {
+        let transform = self.readback_transform.as_mut()?;
+        match transform.output.take()?.downcast() {
+            Ok(output) => Some(*output),
+            Err(output) => {
+                transform.output = Some(output);
+                None
+            }
+        }
+    }
+
+    pub(super) fn apply_readback_transform(&mut self) {
+        let transform = match self.readback_transform.as_ref() {
+            Some(t) => t,
+            None => return,
+        };
+
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                self.data().as_ptr() as *const u8,
+                self.data().len() * 4_usize,
+            )
+        };
+        let output = (transform.transform)(bytes);
+
+        if let Some(transform) = self.readback_transform.as_mut() {
+            transform.output = Some(output);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use super::{gpu_task::GPUTask, pipeline::Pipeline, ComputeManager};
+
+// Pipelines and tasks registered under a name, so code that only holds the manager can look up
+// kernels defined elsewhere
+pub(super) struct Registry {
+    pipelines: Mutex<HashMap<String, Arc<Pipeline>>>,
+    tasks: Mutex<HashMap<String, Arc<Mutex<GPUTask>>>>,
+}
+
+impl Registry {
+    pub(super) fn new() -> Self {
+        Registry {
+            pipelines: Mutex::new(HashMap::new()),
+            tasks: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn insert<T>(entries: &Mutex<HashMap<String, T>>, kind: &str, name: &str, value: T) -> Option<T> {
+    let previous = entries
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(name.to_string(), value);
+    if previous.is_some() {
+        log::warn!("Replacing the {} registered as \"{}\"", kind, name);
+    }
+    previous
+}
+
+impl ComputeManager {
+    /// Registered pipelines and tasks hold the manager, so it's only dropped once they've been
+    /// unregistered or the registry has been cleared. Returns the pipeline previously registered
+    /// under `name`.
+    pub fn register_pipeline(&self, name: &str, pipeline: Arc<Pipeline>) -> Option<Arc<Pipeline>> {
+        insert(&self.registry.pipelines, "pipeline", name, pipeline)
+    }
+
+    pub fn registered_pipeline(&self, name: &str) -> Option<Arc<Pipeline>> {
+        self.registry
+            .pipelines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .cloned()
+    }
+
+    pub fn unregister_pipeline(&self, name: &str) -> Option<Arc<Pipeline>> {
+        self.registry
+            .pipelines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name)
+    }
+
+    /// Tasks are shared behind a mutex, which callers hold from `exec_task` until `await_task` so
+    /// two threads never submit the same command buffer at once. Like `register_pipeline`, the
+    /// task keeps the manager alive until it's unregistered.
+    pub fn register_task(
+        &self,
+        name: &str,
+        task: GPUTask,
+    ) -> (Arc<Mutex<GPUTask>>, Option<Arc<Mutex<GPUTask>>>) {
+        let task = Arc::new(Mutex::new(task));
+        let previous = insert(&self.registry.tasks, "task", name, task.clone());
+        (task, previous)
+    }
+
+    pub fn registered_task(&self, name: &str) -> Option<Arc<Mutex<GPUTask>>> {
+        self.registry
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .cloned()
+    }
+
+    pub fn unregister_task(&self, name: &str) -> Option<Arc<Mutex<GPUTask>>> {
+        self.registry
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name)
+    }
+
+    /// Names of registered pipelines and tasks, each sorted
+    pub fn registered_names(&self) -> (Vec<String>, Vec<String>) {
+        let mut pipelines: Vec<String> = self
+            .registry
+            .pipelines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect();
+        let mut tasks: Vec<String> = self
+            .registry
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect();
+        pipelines.sort();
+        tasks.sort();
+        (pipelines, tasks)
+    }
+
+    /// Drops every registration. Tasks are dropped before pipelines, and either is only destroyed
+    /// here if nothing else still holds it.
+    pub fn clear_registry(&self) {
+        let tasks = std::mem::take(
+            &mut *self
+                .registry
+                .tasks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        drop(tasks);
+
+        let pipelines = std::mem::take(
+            &mut *self
+                .registry
+                .pipelines
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        drop(pipelines);
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use ash::vk::{self, AccessFlags, PipelineStageFlags};
+
+use super::barrier::Barrier;
+
+const WRITE_ACCESS: AccessFlags = AccessFlags::from_raw(
+    AccessFlags::SHADER_WRITE.as_raw()
+        | AccessFlags::TRANSFER_WRITE.as_raw()
+        | AccessFlags::HOST_WRITE.as_raw()
+        | AccessFlags::MEMORY_WRITE.as_raw(),
+);
+
+// How a buffer was last accessed within the command buffer being recorded
+#[derive(Clone, Copy)]
+struct ResourceState {
+    write_stage: PipelineStageFlags,
+    write_access: AccessFlags,
+    // Stages that read the buffer since the last write
+    read_stages: PipelineStageFlags,
+    // Where the last write has already been made visible
+    visible_stages: PipelineStageFlags,
+    visible_access: AccessFlags,
+}
+
+impl ResourceState {
+    // Command buffers can be resubmitted, so a buffer first touched in a recording may have been
+    // read or written by any earlier submission. Host accesses are ordered by fences instead.
+    fn unknown() -> Self {
+        ResourceState {
+            write_stage: PipelineStageFlags::COMPUTE_SHADER | PipelineStageFlags::TRANSFER,
+            write_access: AccessFlags::SHADER_WRITE | AccessFlags::TRANSFER_WRITE,
+            read_stages: PipelineStageFlags::COMPUTE_SHADER | PipelineStageFlags::TRANSFER,
+            visible_stages: PipelineStageFlags::empty(),
+            visible_access: AccessFlags::empty(),
+        }
+    }
+
+    fn written(stage: PipelineStageFlags, access: AccessFlags) -> Self {
+        ResourceState {
+            write_stage: stage,
+            write_access: access,
+            read_stages: PipelineStageFlags::empty(),
+            visible_stages: PipelineStageFlags::empty(),
+            visible_access: AccessFlags::empty(),
+        }
+    }
+}
+
+// Per-buffer state for one recording. Every op reports its accesses here and records only the
+// barriers that the returned transitions call for.
+pub(super) struct ResourceStates {
+    buffers: HashMap<vk::Buffer, ResourceState>,
+}
+
+impl ResourceStates {
+    pub(super) fn new() -> Self {
+        ResourceStates {
+            buffers: HashMap::new(),
+        }
+    }
+
+    // For buffers whose last write is known from outside this recording
+    pub(super) fn assume_written(
+        &mut self,
+        buffer: vk::Buffer,
+        stage: PipelineStageFlags,
+        access: AccessFlags,
+    ) {
+        self.buffers
+            .insert(buffer, ResourceState::written(stage, access));
+    }
+
+    // Returns the barrier needed before `buffer` is accessed, if any
+    pub(super) fn access(
+        &mut self,
+        buffer: vk::Buffer,
+        stage: PipelineStageFlags,
+        access: AccessFlags,
+    ) -> Option<Barrier> {
+        let state = self
+            .buffers
+            .entry(buffer)
+            .or_insert_with(ResourceState::unknown);
+        let writes = access & WRITE_ACCESS;
+        let reads = access & !WRITE_ACCESS;
+
+        let mut src_stage = PipelineStageFlags::empty();
+        let mut src_access = AccessFlags::empty();
+        // Read-after-write and write-after-write both need the last write made available
+        let visible = state.visible_stages.contains(stage) && state.visible_access.contains(reads);
+        if !writes.is_empty() || !visible {
+            src_stage |= state.write_stage;
+            src_access |= state.write_access;
+        }
+        // Write-after-read only needs the reads to finish
+        if !writes.is_empty() {
+            src_stage |= state.read_stages;
+        }
+
+        if writes.is_empty() {
+            state.read_stages |= stage;
+            if !src_stage.is_empty() {
+                state.visible_stages |= stage;
+                state.visible_access |= reads;
+            }
+        } else {
+            *state = ResourceState::written(stage, writes);
+        }
+
+        if src_stage.is_empty() {
+            return None;
+        }
+
+        Some(Barrier::buffer(
+            buffer, src_stage, src_access, stage, access,
+        ))
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use super::ComputeManager;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LiveResourceKind {
+    Tensor,
+    Pipeline,
+    Task,
+    Buffer,
+    DescriptorPool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LiveResource {
+    pub id: u64,
+    pub kind: LiveResourceKind,
+    pub label: String,
+    pub created_at: Instant,
+    /// Only captured in debug builds
+    pub backtrace: Option<String>,
+}
+
+pub(super) struct ResourceTracker {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<u64, LiveResource>>,
+}
+
+// Removes its entry from the tracker when the owning resource is dropped
+pub(super) struct TrackedResource {
+    tracker: Arc<ResourceTracker>,
+    id: u64,
+}
+
+// Panics on drop if resources created during its lifetime are still alive
+pub struct LeakCheckGuard {
+    baseline: HashSet<u64>,
+
+    parent: Arc<ComputeManager>,
+}
+
+impl ResourceTracker {
+    pub(super) fn new() -> Self {
+        ResourceTracker {
+            next_id: AtomicU64::new(0),
+            live: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn snapshot(&self) -> Vec<LiveResource> {
+        match self.live.lock() {
+            Ok(live) => live.values().cloned().collect(),
+            Err(e) => {
+                log::error!("Failed to acquire resource tracker! Error: {e}");
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl Drop for TrackedResource {
+    fn drop(&mut self) {
+        if let Ok(mut live) = self.tracker.live.lock() {
+            live.remove(&self.id);
+        }
+    }
+}
+
+impl ComputeManager {
+    pub(super) fn track_resource<F>(
+        &self,
+        kind: LiveResourceKind,
+        label: F,
+    ) -> Option<TrackedResource>
+    where
+        F: FnOnce() -> String,
+    {
+        let tracker = self.resource_tracker.as_ref()?;
+
+        let backtrace = if cfg!(debug_assertions) {
+            Some(std::backtrace::Backtrace::force_capture().to_string())
+        } else {
+            None
+        };
+
+        let id = tracker.next_id.fetch_add(1, Ordering::Relaxed);
+        match tracker.live.lock() {
+            Ok(mut live) => {
+                live.insert(
+                    id,
+                    LiveResource {
+                        id,
+                        kind,
+                        label: label(),
+                        created_at: Instant::now(),
+                        backtrace,
+                    },
+                );
+            }
+            Err(e) => {
+                log::error!("Failed to acquire resource tracker! Error: {e}");
+                return None;
+            }
+        }
+
+        Some(TrackedResource {
+            tracker: tracker.clone(),
+            id,
+        })
+    }
+
+    pub fn resource_tracking_enabled(&self) -> bool {
+        self.resource_tracker.is_some()
+    }
+
+    pub fn live_resources(&self) -> Vec<LiveResource> {
+        match self.resource_tracker.as_ref() {
+            Some(tracker) => {
+                let mut resources = tracker.snapshot();
+                resources.sort_by_key(|r| r.created_at);
+                resources
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn assert_no_live_resources(&self) {
+        if let Err(report) = self.check_live_resources(&HashSet::new()) {
+            panic!("{}", report);
+        }
+    }
+
+    pub fn leak_check_guard(self: Arc<Self>) -> LeakCheckGuard {
+        LeakCheckGuard {
+            baseline: self.live_resources().iter().map(|r| r.id).collect(),
+            parent: self,
+        }
+    }
+
+    fn check_live_resources(&self, ignored: &HashSet<u64>) -> Result<(), String> {
+        if !self.resource_tracking_enabled() {
+            return Err(
+                "Leak checks require live resource tracking! Enable `track_live_resources` in LogConfig."
+                    .to_string(),
+            );
+        }
+
+        let leaked: Vec<LiveResource> = self
+            .live_resources()
+            .into_iter()
+            .filter(|r| !ignored.contains(&r.id))
+            .collect();
+        if leaked.is_empty() {
+            return Ok(());
+        }
+
+        let mut report = format!("{} resource(s) are still alive:\n", leaked.len());
+        for resource in &leaked {
+            let _ = writeln!(
+                report,
+                "\t{:?} {} (alive for {:?})",
+                resource.kind,
+                resource.label,
+                resource.created_at.elapsed()
+            );
+            if let Some(backtrace) = &resource.backtrace {
+                let _ = writeln!(report, "\tcreated at:\n{}", backtrace);
+            }
+        }
+
+        Err(report)
+    }
+
+    pub fn live_resource_counts(&self) -> HashMap<LiveResourceKind, usize> {
+        let mut counts = HashMap::new();
+        self.live_resources().iter().for_each(|r| {
+            *counts.entry(r.kind).or_insert(0) += 1;
+        });
+        counts
+    }
+}
+
+impl Drop for LeakCheckGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+
+        if let Err(report) = self.parent.check_live_resources(&self.baseline) {
+            panic!("{}", report);
+        }
+    }
+}
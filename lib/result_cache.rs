@@ -0,0 +1,362 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    gpu_task::{GPUTask, RecordedOp},
+    kernel_assert::KernelAssertionFailed,
+    pipeline::Pipeline,
+    ComputeManager, Tensor,
+};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+// FNV-1a. Unlike `DefaultHasher` its output is fixed, so keys written to disk stay valid between
+// runs and builds.
+pub(super) struct StableHasher(u64);
+
+impl StableHasher {
+    pub(super) fn new() -> Self {
+        StableHasher(FNV_OFFSET_BASIS)
+    }
+
+    pub(super) fn write(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|b| {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        });
+    }
+
+    pub(super) fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub(super) fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub(super) fn finish(&self) -> u64 {
+        self.0
+    }
+
+    pub(super) fn hash_words(words: &[u32]) -> u64 {
+        let mut hasher = StableHasher::new();
+        words.iter().for_each(|w| hasher.write_u32(*w));
+        hasher.finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ResultCacheError {
+    /// The task reads tensors it doesn't upload, so its inputs can't be hashed
+    Uncacheable,
+    /// An output tensor isn't bound by the task
+    UnknownOutput(u32),
+    ExecutionFailure,
+    KernelAssertionFailed(KernelAssertionFailed),
+    CacheDirectoryFailure,
+    CacheLockFailure,
+}
+
+/// Whether `exec_task_cached` reused an earlier run or dispatched the task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachedExecution {
+    Hit,
+    Miss,
+}
+
+// Output data by binding, keyed by device, shader, ops and uploaded inputs
+type CachedOutputs = Vec<(u32, Vec<f32>)>;
+
+pub(super) struct ResultCache {
+    enabled: bool,
+    directory: Option<PathBuf>,
+    entries: HashMap<u64, CachedOutputs>,
+}
+
+impl ResultCache {
+    pub(super) fn new() -> Self {
+        ResultCache {
+            enabled: false,
+            directory: None,
+            entries: HashMap::new(),
+        }
+    }
+}
+
+fn entry_path(directory: &Path, key: u64) -> PathBuf {
+    directory.join(format!("{key:016x}.bin"))
+}
+
+// Entry count, then every output's binding, length and little-endian data
+fn encode(outputs: &CachedOutputs) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend((outputs.len() as u32).to_le_bytes());
+    outputs.iter().for_each(|(binding, data)| {
+        bytes.extend(binding.to_le_bytes());
+        bytes.extend((data.len() as u64).to_le_bytes());
+        data.iter().for_each(|v| bytes.extend(v.to_le_bytes()));
+    });
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<CachedOutputs> {
+    fn take<'b>(bytes: &mut &'b [u8], n: usize) -> Option<&'b [u8]> {
+        if bytes.len() < n {
+            return None;
+        }
+        let (head, tail) = bytes.split_at(n);
+        *bytes = tail;
+        Some(head)
+    }
+
+    let mut bytes = bytes;
+    let count = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+    let mut outputs = Vec::new();
+    for _ in 0..count {
+        let binding = u32::from_le_bytes(take(&mut bytes, 4)?.try_into().ok()?);
+        let len = u64::from_le_bytes(take(&mut bytes, 8)?.try_into().ok()?) as usize;
+        let data = take(&mut bytes, len.checked_mul(4)?)?
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        outputs.push((binding, data));
+    }
+
+    bytes.is_empty().then_some(outputs)
+}
+
+fn hash_pipeline(hasher: &mut StableHasher, pipeline: &Pipeline) {
+    hasher.write_u64(pipeline.spirv_hash());
+    pipeline.specialization().iter().for_each(|(id, value)| {
+        hasher.write_u32(*id);
+        hasher.write_u32(*value);
+    });
+}
+
+fn binding_of(task: &GPUTask, tensor_id: u32) -> Option<u32> {
+    task.bindings()
+        .iter()
+        .find(|b| b.tensor_id == tensor_id)
+        .map(|b| b.binding)
+}
+
+impl ComputeManager {
+    /// Lets `exec_task_cached` reuse outputs of earlier runs with the same shader, ops and
+    /// uploaded inputs. Entries are kept in memory, and also in `directory` when given so later
+    /// runs can reuse them.
+    pub fn enable_result_cache(&self, directory: Option<&Path>) -> Result<(), ResultCacheError> {
+        if let Some(directory) = directory {
+            if let Err(e) = fs::create_dir_all(directory) {
+                log::error!(
+                    "Failed to create result cache directory {}! Error: {}",
+                    directory.display(),
+                    e
+                );
+                return Err(ResultCacheError::CacheDirectoryFailure);
+            }
+        }
+
+        match self.result_cache.lock() {
+            Ok(mut cache) => {
+                cache.enabled = true;
+                cache.directory = directory.map(Path::to_path_buf);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Failed to acquire result cache! Error: {e}");
+                Err(ResultCacheError::CacheLockFailure)
+            }
+        }
+    }
+
+    /// Drops in-memory entries. Entries on disk are kept for later runs.
+    pub fn disable_result_cache(&self) {
+        if let Ok(mut cache) = self.result_cache.lock() {
+            *cache = ResultCache::new();
+        }
+    }
+
+    /// Runs `task` and reads back `outputs`, unless the result cache holds a run with the same
+    /// inputs, in which case `outputs` are filled from it without dispatching. Runs the task
+    /// normally while the cache is disabled.
+    pub fn exec_task_cached(
+        &self,
+        task: &GPUTask,
+        mut outputs: Vec<&mut Tensor>,
+    ) -> Result<CachedExecution, ResultCacheError> {
+        let (enabled, directory) = match self.result_cache.lock() {
+            Ok(cache) => (cache.enabled, cache.directory.clone()),
+            Err(e) => {
+                log::error!("Failed to acquire result cache! Error: {e}");
+                return Err(ResultCacheError::CacheLockFailure);
+            }
+        };
+        if !enabled {
+            self.exec_uncached(task, outputs)?;
+            return Ok(CachedExecution::Miss);
+        }
+
+        let key = self.result_key(task, &outputs)?;
+        let cached = self.cached_outputs(key, directory.as_deref());
+        let hits: Option<Vec<&Vec<f32>>> = cached.as_ref().and_then(|cached| {
+            outputs
+                .iter()
+                .map(|tensor| {
+                    let binding = binding_of(task, tensor.id);
+                    cached
+                        .iter()
+                        .find(|(b, data)| Some(*b) == binding && data.len() == tensor.data().len())
+                        .map(|(_, data)| data)
+                })
+                .collect()
+        });
+        if let Some(hits) = hits {
+            outputs.iter_mut().zip(hits).for_each(|(tensor, data)| {
+                tensor
+                    .data_mut()
+                    .iter_mut()
+                    .zip(data)
+                    .for_each(|(v, cached)| *v = *cached);
+                tensor.apply_readback_transform();
+            });
+            return Ok(CachedExecution::Hit);
+        }
+
+        let outputs = self.exec_uncached(task, outputs)?;
+        let cached: CachedOutputs = outputs
+            .iter()
+            .filter_map(|t| Some((binding_of(task, t.id)?, t.data().to_vec())))
+            .collect();
+
+        if let Some(directory) = directory.as_deref() {
+            // Written aside and renamed so concurrent runs never read a partial entry
+            let path = entry_path(directory, key);
+            let partial = path.with_extension("partial");
+            if let Err(e) =
+                fs::write(&partial, encode(&cached)).and_then(|_| fs::rename(&partial, &path))
+            {
+                log::warn!(
+                    "Failed to write result cache entry {}! Error: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+        if let Ok(mut cache) = self.result_cache.lock() {
+            cache.entries.insert(key, cached);
+        }
+
+        Ok(CachedExecution::Miss)
+    }
+
+    fn exec_uncached<'t>(
+        &self,
+        task: &GPUTask,
+        mut outputs: Vec<&'t mut Tensor>,
+    ) -> Result<Vec<&'t mut Tensor>, ResultCacheError> {
+        let sync = match self.exec_task(task) {
+            Some(s) => s,
+            None => return Err(ResultCacheError::ExecutionFailure),
+        };
+        let result = self.await_task(&sync, outputs.iter_mut().map(|t| &mut **t).collect());
+        match result {
+            Ok(_) => Ok(outputs),
+            Err(e) => Err(ResultCacheError::KernelAssertionFailed(e)),
+        }
+    }
+
+    fn cached_outputs(&self, key: u64, directory: Option<&Path>) -> Option<CachedOutputs> {
+        if let Some(cached) = self
+            .result_cache
+            .lock()
+            .ok()
+            .and_then(|cache| cache.entries.get(&key).cloned())
+        {
+            return Some(cached);
+        }
+
+        let path = entry_path(directory?, key);
+        let cached = match fs::read(&path) {
+            Ok(bytes) => match decode(&bytes) {
+                Some(c) => c,
+                None => {
+                    log::warn!("Ignoring malformed result cache entry {}", path.display());
+                    return None;
+                }
+            },
+            Err(_) => return None,
+        };
+        if let Ok(mut cache) = self.result_cache.lock() {
+            cache.entries.insert(key, cached.clone());
+        }
+        Some(cached)
+    }
+
+    fn result_key(&self, task: &GPUTask, outputs: &[&mut Tensor]) -> Result<u64, ResultCacheError> {
+        if !task.device_inputs().is_empty() {
+            return Err(ResultCacheError::Uncacheable);
+        }
+
+        let mut hasher = StableHasher::new();
+        hasher.write(self.device_key().as_bytes());
+        hash_pipeline(&mut hasher, &task.pipeline);
+        task.bindings().iter().for_each(|b| {
+            hasher.write_u32(b.binding);
+            hasher.write_u64(b.size_bytes);
+        });
+
+        for op in task.ops() {
+            match op {
+                RecordedOp::LocalSyncDevice { tensor_ids } => {
+                    hasher.write_u32(0);
+                    for tensor_id in tensor_ids {
+                        match (
+                            binding_of(task, *tensor_id),
+                            task.uploaded_bytes(*tensor_id),
+                        ) {
+                            (Some(binding), Some(data)) => {
+                                hasher.write_u32(binding);
+                                hasher.write(data);
+                            }
+                            _ => return Err(ResultCacheError::Uncacheable),
+                        }
+                    }
+                }
+                RecordedOp::PipelineDispatch {
+                    work_group,
+                    pipeline,
+                } => {
+                    hasher.write_u32(1);
+                    hasher.write_u32(work_group.x);
+                    hasher.write_u32(work_group.y);
+                    hasher.write_u32(work_group.z);
+                    if *pipeline != 0 {
+                        hash_pipeline(&mut hasher, task.dispatch_pipeline(*pipeline));
+                    }
+                }
+                RecordedOp::DeviceSyncLocal { tensor_ids } => {
+                    hasher.write_u32(2);
+                    tensor_ids
+                        .iter()
+                        .filter_map(|id| binding_of(task, *id))
+                        .for_each(|b| hasher.write_u32(b));
+                }
+                // Ownership transfers don't change what the task computes
+                RecordedOp::ReleaseOwnership { .. } | RecordedOp::AcquireOwnership { .. } => (),
+            }
+        }
+
+        for tensor in outputs {
+            match binding_of(task, tensor.id) {
+                Some(binding) => hasher.write_u32(binding),
+                None => return Err(ResultCacheError::UnknownOutput(tensor.id)),
+            }
+        }
+
+        Ok(hasher.finish())
+    }
+}
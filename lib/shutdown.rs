@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{object_budget::VulkanObjectKind, ComputeManager};
+
+/// What was still outstanding when `ComputeManager::shutdown` returned
+#[derive(Debug, Clone)]
+pub struct ShutdownReport {
+    /// Submissions still running on the device at the deadline
+    pub unretired_submissions: usize,
+    /// Fences the submission thread still watches, for submissions that haven't finished
+    pub pending_fences: usize,
+    /// Objects still alive after the drain, e.g. tasks and pipelines the caller holds
+    pub live_objects: HashMap<VulkanObjectKind, usize>,
+    pub elapsed: Duration,
+}
+
+impl ShutdownReport {
+    /// Whether everything submitted finished before the deadline
+    pub fn drained(&self) -> bool {
+        self.unretired_submissions == 0 && self.pending_fences == 0
+    }
+}
+
+impl ComputeManager {
+    /// Stops accepting submissions, waits up to `timeout` for the ones in flight and then
+    /// destroys fences whose destruction was deferred. Registered pipelines and tasks are
+    /// released once everything has drained, so the manager can drop. Submitting afterwards
+    /// fails, while tasks that were submitted can still be awaited.
+    pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let start = Instant::now();
+
+        self.submission_thread.close();
+        let unretired_submissions = self.submission_thread.drain(Some(start + timeout));
+        let pending_fences = self.submission_thread.flush();
+
+        if unretired_submissions == 0 {
+            self.clear_registry();
+        } else {
+            log::error!(
+                "{} submissions were still running after {:?}!",
+                unretired_submissions,
+                timeout
+            );
+        }
+
+        let report = ShutdownReport {
+            unretired_submissions,
+            pending_fences,
+            live_objects: self.live_object_counts(),
+            elapsed: start.elapsed(),
+        };
+        let mut alive: Vec<String> = report
+            .live_objects
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(kind, count)| format!("{count} {kind:?}"))
+            .collect();
+        if !alive.is_empty() {
+            alive.sort();
+            log::warn!("Still alive after shutdown: {}", alive.join(", "));
+        }
+        report
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::{HashMap, HashSet};
+
+use ash::vk::DescriptorType;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const HEADER_WORDS: usize = 5;
+
+const OP_NAME: u32 = 5;
+const OP_MEMBER_NAME: u32 = 6;
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_SPEC_CONSTANT: u32 = 50;
+const OP_SPEC_CONSTANT_COMPOSITE: u32 = 51;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_EXECUTION_MODE_ID: u32 = 331;
+
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+const EXECUTION_MODE_LOCAL_SIZE_ID: u32 = 38;
+const DECORATION_SPEC_ID: u32 = 1;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_NON_WRITABLE: u32 = 24;
+const DECORATION_NON_READABLE: u32 = 25;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const BUILT_IN_WORKGROUP_SIZE: u32 = 25;
+const DIM_BUFFER: u32 = 5;
+const IMAGE_SAMPLED_STORAGE: u32 = 2;
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_WORKGROUP: u32 = 4;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+#[derive(Debug, Clone)]
+enum SpirvType {
+    Scalar { bytes: u64 },
+    // Vectors and matrices
+    Composite { element: u32, count: u32 },
+    // The length is the id of a constant, which may be a spec constant
+    Array { element: u32, length: u32 },
+    Struct { members: Vec<u32> },
+}
+
+// A resource variable the shader declares, which the pipeline layout must provide
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct DescriptorBinding {
+    pub(super) set: u32,
+    pub(super) binding: u32,
+    pub(super) descriptor_type: DescriptorType,
+}
+
+// Facts about a compute shader read straight from its SPIR-V
+#[derive(Debug, Clone, Default)]
+pub(super) struct ShaderReflection {
+    pub(super) local_size: Option<[u32; 3]>,
+    // Sorted by set and binding
+    pub(super) descriptor_bindings: Vec<DescriptorBinding>,
+    // Names of the descriptors by set and binding, for modules that kept their debug names
+    pub(super) binding_names: HashMap<(u32, u32), String>,
+    // Set and binding of the storage buffers the shader declares `readonly`
+    pub(super) read_only_bindings: HashSet<(u32, u32)>,
+    // Set and binding of the storage buffers the shader declares `writeonly`
+    pub(super) write_only_bindings: HashSet<(u32, u32)>,
+    pub(super) push_constants: bool,
+    local_size_ids: Option<[u32; 3]>,
+    // Scalar constants by result id, with spec constants at their default values
+    constants: HashMap<u32, u32>,
+    // Result ids of spec constants by their SpecId
+    spec_constants: HashMap<u32, u32>,
+    types: HashMap<u32, SpirvType>,
+    workgroup_variables: Vec<u32>,
+}
+
+impl ShaderReflection {
+    pub(super) fn local_invocations(&self) -> Option<u64> {
+        self.local_size
+            .map(|[x, y, z]| x as u64 * y as u64 * z as u64)
+    }
+
+    // Overrides spec constant defaults with `(spec id, value)` pairs
+    pub(super) fn specialize(&mut self, values: &[(u32, u32)]) {
+        values.iter().for_each(|(spec_id, value)| {
+            if let Some(id) = self.spec_constants.get(spec_id) {
+                self.constants.insert(*id, *value);
+            }
+        });
+
+        if let Some([x, y, z]) = self.local_size_ids {
+            if let (Some(x), Some(y), Some(z)) = (
+                self.constants.get(&x),
+                self.constants.get(&y),
+                self.constants.get(&z),
+            ) {
+                self.local_size = Some([*x, *y, *z]);
+            }
+        }
+    }
+
+    // Members are counted without padding, so this is a lower bound for what the driver reserves
+    pub(super) fn shared_memory_bytes(&self) -> u64 {
+        self.workgroup_variables
+            .iter()
+            .map(|t| self.type_size(*t, 0))
+            .sum()
+    }
+
+    fn type_size(&self, type_id: u32, depth: u32) -> u64 {
+        // Well-formed modules can't nest types this deep; this only guards against cycles
+        if depth > 64 {
+            return 0;
+        }
+
+        match self.types.get(&type_id) {
+            Some(SpirvType::Scalar { bytes }) => *bytes,
+            Some(SpirvType::Composite { element, count }) => {
+                self.type_size(*element, depth + 1) * *count as u64
+            }
+            Some(SpirvType::Array { element, length }) => {
+                let length = self.constants.get(length).copied().unwrap_or(0);
+                self.type_size(*element, depth + 1) * length as u64
+            }
+            Some(SpirvType::Struct { members }) => {
+                members.iter().map(|m| self.type_size(*m, depth + 1)).sum()
+            }
+            None => 0,
+        }
+    }
+}
+
+// Decodes a nul-terminated literal string packed four bytes to a word
+fn literal_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .take_while(|b| *b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Yields (opcode, operands) for every instruction after the header
+fn instructions(spirv: &[u32]) -> impl Iterator<Item = (u32, &[u32])> {
+    let mut offset = HEADER_WORDS;
+    std::iter::from_fn(move || {
+        let word = *spirv.get(offset)?;
+        let word_count = (word >> 16) as usize;
+        if word_count == 0 || offset + word_count > spirv.len() {
+            return None;
+        }
+
+        let operands = &spirv[offset + 1..offset + word_count];
+        offset += word_count;
+        Some((word & 0xffff, operands))
+    })
+}
+
+// Unknown or malformed modules reflect as empty rather than failing; the driver still validates them
+pub(super) fn reflect(spirv: &[u32]) -> ShaderReflection {
+    let mut reflection = ShaderReflection::default();
+    if spirv.len() < HEADER_WORDS || spirv[0] != SPIRV_MAGIC {
+        return reflection;
+    }
+
+    let mut spec_ids: HashMap<u32, u32> = HashMap::new();
+    let mut workgroup_size_id = None;
+    let mut composites: HashMap<u32, [u32; 3]> = HashMap::new();
+    // Pointee types by pointer type id
+    let mut pointers: HashMap<u32, u32> = HashMap::new();
+    // Descriptor types of images and samplers, and element types of runtime arrays
+    let mut opaque_types: HashMap<u32, DescriptorType> = HashMap::new();
+    let mut runtime_arrays: HashMap<u32, u32> = HashMap::new();
+    let mut buffer_blocks: Vec<u32> = Vec::new();
+    let mut sets: HashMap<u32, u32> = HashMap::new();
+    let mut bindings: HashMap<u32, u32> = HashMap::new();
+    // (variable, pointee type, storage class) of every variable that may be a descriptor
+    let mut resources: Vec<(u32, u32, u32)> = Vec::new();
+    let mut names: HashMap<u32, String> = HashMap::new();
+    let mut member_names: HashMap<(u32, u32), String> = HashMap::new();
+    // Variables decorated NonWritable, and the NonWritable members of every struct
+    let mut non_writable: Vec<u32> = Vec::new();
+    let mut non_writable_members: HashMap<u32, HashSet<u32>> = HashMap::new();
+    // The same for NonReadable
+    let mut non_readable: Vec<u32> = Vec::new();
+    let mut non_readable_members: HashMap<u32, HashSet<u32>> = HashMap::new();
+
+    for (opcode, operands) in instructions(spirv) {
+        match opcode {
+            OP_NAME if operands.len() >= 2 => {
+                names.insert(operands[0], literal_string(&operands[1..]));
+            }
+            OP_MEMBER_NAME if operands.len() >= 3 => {
+                member_names.insert((operands[0], operands[1]), literal_string(&operands[2..]));
+            }
+            OP_EXECUTION_MODE
+                if operands.len() >= 5 && operands[1] == EXECUTION_MODE_LOCAL_SIZE =>
+            {
+                reflection.local_size = Some([operands[2], operands[3], operands[4]]);
+            }
+            OP_EXECUTION_MODE_ID
+                if operands.len() >= 5 && operands[1] == EXECUTION_MODE_LOCAL_SIZE_ID =>
+            {
+                reflection.local_size_ids = Some([operands[2], operands[3], operands[4]]);
+            }
+            OP_DECORATE if operands.len() >= 3 && operands[1] == DECORATION_SPEC_ID => {
+                spec_ids.insert(operands[0], operands[2]);
+            }
+            OP_DECORATE
+                if operands.len() >= 3
+                    && operands[1] == DECORATION_BUILT_IN
+                    && operands[2] == BUILT_IN_WORKGROUP_SIZE =>
+            {
+                workgroup_size_id = Some(operands[0]);
+            }
+            OP_DECORATE if operands.len() >= 2 && operands[1] == DECORATION_BUFFER_BLOCK => {
+                buffer_blocks.push(operands[0]);
+            }
+            OP_DECORATE if operands.len() >= 3 && operands[1] == DECORATION_BINDING => {
+                bindings.insert(operands[0], operands[2]);
+            }
+            OP_DECORATE if operands.len() >= 3 && operands[1] == DECORATION_DESCRIPTOR_SET => {
+                sets.insert(operands[0], operands[2]);
+            }
+            OP_DECORATE if operands.len() >= 2 && operands[1] == DECORATION_NON_WRITABLE => {
+                non_writable.push(operands[0]);
+            }
+            OP_MEMBER_DECORATE if operands.len() >= 3 && operands[2] == DECORATION_NON_WRITABLE => {
+                non_writable_members
+                    .entry(operands[0])
+                    .or_default()
+                    .insert(operands[1]);
+            }
+            OP_DECORATE if operands.len() >= 2 && operands[1] == DECORATION_NON_READABLE => {
+                non_readable.push(operands[0]);
+            }
+            OP_MEMBER_DECORATE if operands.len() >= 3 && operands[2] == DECORATION_NON_READABLE => {
+                non_readable_members
+                    .entry(operands[0])
+                    .or_default()
+                    .insert(operands[1]);
+            }
+            OP_TYPE_BOOL if !operands.is_empty() => {
+                reflection
+                    .types
+                    .insert(operands[0], SpirvType::Scalar { bytes: 4 });
+            }
+            OP_TYPE_INT | OP_TYPE_FLOAT if operands.len() >= 2 => {
+                reflection.types.insert(
+                    operands[0],
+                    SpirvType::Scalar {
+                        bytes: operands[1] as u64 / 8,
+                    },
+                );
+            }
+            OP_TYPE_VECTOR | OP_TYPE_MATRIX if operands.len() >= 3 => {
+                reflection.types.insert(
+                    operands[0],
+                    SpirvType::Composite {
+                        element: operands[1],
+                        count: operands[2],
+                    },
+                );
+            }
+            OP_TYPE_ARRAY if operands.len() >= 3 => {
+                reflection.types.insert(
+                    operands[0],
+                    SpirvType::Array {
+                        element: operands[1],
+                        length: operands[2],
+                    },
+                );
+            }
+            OP_TYPE_IMAGE if operands.len() >= 7 => {
+                let storage = operands[6] == IMAGE_SAMPLED_STORAGE;
+                let descriptor_type = match (operands[2] == DIM_BUFFER, storage) {
+                    (true, true) => DescriptorType::STORAGE_TEXEL_BUFFER,
+                    (true, false) => DescriptorType::UNIFORM_TEXEL_BUFFER,
+                    (false, true) => DescriptorType::STORAGE_IMAGE,
+                    (false, false) => DescriptorType::SAMPLED_IMAGE,
+                };
+                opaque_types.insert(operands[0], descriptor_type);
+            }
+            OP_TYPE_SAMPLER if !operands.is_empty() => {
+                opaque_types.insert(operands[0], DescriptorType::SAMPLER);
+            }
+            OP_TYPE_SAMPLED_IMAGE if !operands.is_empty() => {
+                opaque_types.insert(operands[0], DescriptorType::COMBINED_IMAGE_SAMPLER);
+            }
+            OP_TYPE_RUNTIME_ARRAY if operands.len() >= 2 => {
+                runtime_arrays.insert(operands[0], operands[1]);
+            }
+            OP_TYPE_STRUCT if !operands.is_empty() => {
+                reflection.types.insert(
+                    operands[0],
+                    SpirvType::Struct {
+                        members: operands[1..].to_vec(),
+                    },
+                );
+            }
+            OP_TYPE_POINTER if operands.len() >= 3 => {
+                pointers.insert(operands[0], operands[2]);
+            }
+            OP_CONSTANT | OP_SPEC_CONSTANT if operands.len() >= 3 => {
+                reflection.constants.insert(operands[1], operands[2]);
+            }
+            OP_SPEC_CONSTANT_COMPOSITE if operands.len() >= 5 => {
+                composites.insert(operands[1], [operands[2], operands[3], operands[4]]);
+            }
+            OP_VARIABLE if operands.len() >= 3 && operands[2] == STORAGE_CLASS_WORKGROUP => {
+                if let Some(pointee) = pointers.get(&operands[0]) {
+                    reflection.workgroup_variables.push(*pointee);
+                }
+            }
+            OP_VARIABLE if operands.len() >= 3 && operands[2] == STORAGE_CLASS_PUSH_CONSTANT => {
+                reflection.push_constants = true;
+            }
+            OP_VARIABLE
+                if operands.len() >= 3
+                    && [
+                        STORAGE_CLASS_UNIFORM_CONSTANT,
+                        STORAGE_CLASS_UNIFORM,
+                        STORAGE_CLASS_STORAGE_BUFFER,
+                    ]
+                    .contains(&operands[2]) =>
+            {
+                if let Some(pointee) = pointers.get(&operands[0]) {
+                    resources.push((operands[1], *pointee, operands[2]));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    reflection.spec_constants = spec_ids
+        .into_iter()
+        .map(|(id, spec_id)| (spec_id, id))
+        .collect();
+
+    // A WorkgroupSize built-in takes precedence over the LocalSize execution mode
+    if let Some(ids) = workgroup_size_id.and_then(|id| composites.get(&id)) {
+        reflection.local_size_ids = Some(*ids);
+    }
+    reflection.specialize(&[]);
+
+    // Arrays of descriptors take the type of their elements
+    let element_of = |mut type_id: u32| {
+        for _ in 0..64 {
+            type_id = match (reflection.types.get(&type_id), runtime_arrays.get(&type_id)) {
+                (Some(SpirvType::Array { element, .. }), _) | (_, Some(element)) => *element,
+                _ => break,
+            };
+        }
+        type_id
+    };
+    let mut binding_names: HashMap<(u32, u32), String> = HashMap::new();
+    let mut read_only_bindings: HashSet<(u32, u32)> = HashSet::new();
+    let mut write_only_bindings: HashSet<(u32, u32)> = HashSet::new();
+    let mut descriptor_bindings: Vec<DescriptorBinding> = resources
+        .iter()
+        .filter_map(|(variable, pointee, storage_class)| {
+            let binding = *bindings.get(variable)?;
+            let element = element_of(*pointee);
+            let descriptor_type = match *storage_class {
+                STORAGE_CLASS_STORAGE_BUFFER => DescriptorType::STORAGE_BUFFER,
+                // Storage buffers from before SPIR-V 1.3 are uniform blocks decorated BufferBlock
+                STORAGE_CLASS_UNIFORM if buffer_blocks.contains(&element) => {
+                    DescriptorType::STORAGE_BUFFER
+                }
+                STORAGE_CLASS_UNIFORM => DescriptorType::UNIFORM_BUFFER,
+                _ => *opaque_types.get(&element)?,
+            };
+            let set = sets.get(variable).copied().unwrap_or(0);
+
+            // Blocks without an instance name go by their only member, or else their block name
+            let name = names
+                .get(variable)
+                .filter(|n| !n.is_empty())
+                .or_else(|| match reflection.types.get(&element) {
+                    Some(SpirvType::Struct { members }) if members.len() == 1 => {
+                        member_names.get(&(element, 0))
+                    }
+                    _ => None,
+                })
+                .or_else(|| names.get(&element))
+                .filter(|n| !n.is_empty());
+            if let Some(name) = name {
+                binding_names.insert((set, binding), name.clone());
+            }
+
+            // glslang marks every member of a `readonly` block, newer versions also the variable
+            let whole_block = |variables: &Vec<u32>, members: &HashMap<u32, HashSet<u32>>| {
+                variables.contains(variable)
+                    || match (reflection.types.get(&element), members.get(&element)) {
+                        (Some(SpirvType::Struct { members }), Some(decorated)) => {
+                            (0..members.len() as u32).all(|m| decorated.contains(&m))
+                        }
+                        _ => false,
+                    }
+            };
+            if descriptor_type == DescriptorType::STORAGE_BUFFER {
+                if whole_block(&non_writable, &non_writable_members) {
+                    read_only_bindings.insert((set, binding));
+                }
+                if whole_block(&non_readable, &non_readable_members) {
+                    write_only_bindings.insert((set, binding));
+                }
+            }
+
+            Some(DescriptorBinding {
+                set,
+                binding,
+                descriptor_type,
+            })
+        })
+        .collect();
+    descriptor_bindings.sort_by_key(|b| (b.set, b.binding));
+    reflection.descriptor_bindings = descriptor_bindings;
+    reflection.binding_names = binding_names;
+    reflection.read_only_bindings = read_only_bindings;
+    reflection.write_only_bindings = write_only_bindings;
+
+    reflection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(opcode: u32, operands: &[u32]) -> Vec<u32> {
+        let mut words = vec![((operands.len() as u32 + 1) << 16) | opcode];
+        words.extend(operands);
+        words
+    }
+
+    fn string_words(s: &str) -> Vec<u32> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(bytes.len() / 4 * 4 + 4, 0);
+        bytes
+            .chunks(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+    }
+
+    fn module(instructions: &[Vec<u32>]) -> Vec<u32> {
+        let mut words = vec![SPIRV_MAGIC, 0x0001_0300, 0, 64, 0];
+        instructions.iter().for_each(|i| words.extend(i));
+        words
+    }
+
+    // A readonly buffer `data` at binding 1, an unnamed writeonly buffer at binding 0, a 16 float
+    // shared array and push constants
+    fn well_formed() -> Vec<u32> {
+        let mut name = vec![10];
+        name.extend(string_words("data"));
+        module(&[
+            op(OP_NAME, &name),
+            op(OP_EXECUTION_MODE, &[1, EXECUTION_MODE_LOCAL_SIZE, 64, 1, 1]),
+            op(OP_DECORATE, &[10, DECORATION_DESCRIPTOR_SET, 0]),
+            op(OP_DECORATE, &[10, DECORATION_BINDING, 1]),
+            op(OP_MEMBER_DECORATE, &[5, 0, DECORATION_NON_WRITABLE]),
+            op(OP_DECORATE, &[11, DECORATION_BINDING, 0]),
+            op(OP_DECORATE, &[11, DECORATION_NON_READABLE]),
+            op(OP_TYPE_FLOAT, &[2, 32]),
+            op(OP_TYPE_RUNTIME_ARRAY, &[3, 2]),
+            op(OP_TYPE_STRUCT, &[5, 3]),
+            op(OP_TYPE_POINTER, &[6, STORAGE_CLASS_STORAGE_BUFFER, 5]),
+            op(OP_TYPE_STRUCT, &[7, 3]),
+            op(OP_TYPE_POINTER, &[8, STORAGE_CLASS_STORAGE_BUFFER, 7]),
+            op(OP_TYPE_INT, &[20, 32, 0]),
+            op(OP_CONSTANT, &[20, 21, 16]),
+            op(OP_TYPE_ARRAY, &[22, 2, 21]),
+            op(OP_TYPE_POINTER, &[23, STORAGE_CLASS_WORKGROUP, 22]),
+            op(OP_TYPE_POINTER, &[25, STORAGE_CLASS_PUSH_CONSTANT, 5]),
+            op(OP_VARIABLE, &[6, 10, STORAGE_CLASS_STORAGE_BUFFER]),
+            op(OP_VARIABLE, &[8, 11, STORAGE_CLASS_STORAGE_BUFFER]),
+            op(OP_VARIABLE, &[23, 24, STORAGE_CLASS_WORKGROUP]),
+            op(OP_VARIABLE, &[25, 26, STORAGE_CLASS_PUSH_CONSTANT]),
+        ])
+    }
+
+    fn storage_buffer(binding: u32) -> DescriptorBinding {
+        DescriptorBinding {
+            set: 0,
+            binding,
+            descriptor_type: DescriptorType::STORAGE_BUFFER,
+        }
+    }
+
+    #[test]
+    fn reflects_well_formed_module() {
+        let reflection = reflect(&well_formed());
+
+        assert_eq!(reflection.local_size, Some([64, 1, 1]));
+        assert_eq!(reflection.local_invocations(), Some(64));
+        assert_eq!(
+            reflection.descriptor_bindings,
+            vec![storage_buffer(0), storage_buffer(1)]
+        );
+        assert_eq!(
+            reflection.binding_names.get(&(0, 1)).map(|n| n.as_str()),
+            Some("data")
+        );
+        assert!(!reflection.binding_names.contains_key(&(0, 0)));
+        assert!(reflection.read_only_bindings.contains(&(0, 1)));
+        assert!(!reflection.read_only_bindings.contains(&(0, 0)));
+        assert!(reflection.write_only_bindings.contains(&(0, 0)));
+        assert!(!reflection.write_only_bindings.contains(&(0, 1)));
+        assert_eq!(reflection.shared_memory_bytes(), 64);
+        assert!(reflection.push_constants);
+    }
+
+    #[test]
+    fn specializes_workgroup_size() {
+        let mut reflection = reflect(&module(&[
+            op(OP_EXECUTION_MODE, &[1, EXECUTION_MODE_LOCAL_SIZE, 64, 1, 1]),
+            op(OP_DECORATE, &[30, DECORATION_SPEC_ID, 0]),
+            op(
+                OP_DECORATE,
+                &[31, DECORATION_BUILT_IN, BUILT_IN_WORKGROUP_SIZE],
+            ),
+            op(OP_TYPE_INT, &[20, 32, 0]),
+            op(OP_SPEC_CONSTANT, &[20, 30, 8]),
+            op(OP_CONSTANT, &[20, 32, 1]),
+            op(OP_SPEC_CONSTANT_COMPOSITE, &[21, 31, 30, 32, 32]),
+        ]));
+
+        assert_eq!(reflection.local_size, Some([8, 1, 1]));
+        reflection.specialize(&[(0, 32), (7, 2)]);
+        assert_eq!(reflection.local_size, Some([32, 1, 1]));
+    }
+
+    #[test]
+    fn reflects_invalid_headers_as_empty() {
+        let mut wrong_magic = well_formed();
+        wrong_magic[0] = 0x0203_0723;
+
+        for spirv in [&[][..], &well_formed()[..HEADER_WORDS - 1], &wrong_magic] {
+            let reflection = reflect(spirv);
+            assert_eq!(reflection.local_size, None);
+            assert!(reflection.descriptor_bindings.is_empty());
+        }
+    }
+
+    #[test]
+    fn stops_at_truncated_instructions() {
+        let spirv = well_formed();
+        for len in 0..spirv.len() {
+            reflect(&spirv[..len]);
+        }
+
+        // Cutting into the last variable drops it and leaves the rest
+        let reflection = reflect(&spirv[..spirv.len() - 1]);
+        assert!(!reflection.push_constants);
+        assert_eq!(reflection.descriptor_bindings.len(), 2);
+    }
+
+    #[test]
+    fn stops_at_corrupt_word_counts() {
+        let mut zero_count = well_formed();
+        zero_count[HEADER_WORDS] &= 0xffff;
+        let reflection = reflect(&zero_count);
+        assert_eq!(reflection.local_size, None);
+        assert!(reflection.descriptor_bindings.is_empty());
+
+        let mut past_end = well_formed();
+        past_end[HEADER_WORDS] |= 0xffff << 16;
+        assert!(reflect(&past_end).descriptor_bindings.is_empty());
+
+        // Operands too short for their opcode are skipped
+        let reflection = reflect(&module(&[
+            op(OP_EXECUTION_MODE, &[1, EXECUTION_MODE_LOCAL_SIZE, 64]),
+            op(OP_DECORATE, &[10]),
+            op(OP_VARIABLE, &[6]),
+        ]));
+        assert_eq!(reflection.local_size, None);
+        assert!(reflection.descriptor_bindings.is_empty());
+    }
+
+    #[test]
+    fn survives_cyclic_types() {
+        let reflection = reflect(&module(&[
+            op(OP_TYPE_STRUCT, &[40, 40]),
+            op(OP_TYPE_POINTER, &[41, STORAGE_CLASS_WORKGROUP, 40]),
+            op(OP_VARIABLE, &[41, 42, STORAGE_CLASS_WORKGROUP]),
+        ]));
+        assert_eq!(reflection.shared_memory_bytes(), 0);
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ash::{prelude::VkResult, vk};
+
+use super::{allocation_strategy::Buffer, device::DeviceInfo};
+
+#[derive(Debug, Clone, Copy)]
+pub enum StagingError {
+    TensorNotBound(u32),
+    NoStagingBuffer(u32),
+    NoReadbackBuffer(u32),
+    SizeMismatch(u32),
+    // The readback changed since the last `invalidate`
+    NotInvalidated(u32),
+    RangeOutOfBounds(u32),
+    FlushFailure,
+    InvalidateFailure,
+}
+
+// Counts host writes to a tensor's staging buffer and device writes to its readback buffer, and
+// how many of each the host has made visible to the other side
+#[derive(Default)]
+pub(super) struct StagingGenerations {
+    staging: AtomicU64,
+    flushed: AtomicU64,
+    readback: AtomicU64,
+    invalidated: AtomicU64,
+}
+
+impl StagingGenerations {
+    pub(super) fn note_staging_write(&self) -> u64 {
+        self.staging.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    pub(super) fn note_readback_write(&self) -> u64 {
+        self.readback.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    pub(super) fn staging(&self) -> u64 {
+        self.staging.load(Ordering::Acquire)
+    }
+
+    pub(super) fn readback(&self) -> u64 {
+        self.readback.load(Ordering::Acquire)
+    }
+
+    pub(super) fn needs_flush(&self) -> bool {
+        self.flushed.load(Ordering::Acquire) != self.staging()
+    }
+
+    pub(super) fn is_invalidated(&self) -> bool {
+        self.invalidated.load(Ordering::Acquire) == self.readback()
+    }
+
+    pub(super) fn mark_flushed(&self, generation: u64) {
+        self.flushed.fetch_max(generation, Ordering::AcqRel);
+    }
+
+    pub(super) fn mark_invalidated(&self, generation: u64) {
+        self.invalidated.fetch_max(generation, Ordering::AcqRel);
+    }
+}
+
+// gpu-allocator only hands out host-coherent memory for staging and readback today, where these
+// are cheap no-ops for the driver. They're still issued so the sync points hold on any memory type.
+// Imported host pages are always coherent and never mapped through Vulkan, so they're skipped.
+pub(super) fn flush_buffer(device_info: &DeviceInfo, buffer: &Buffer) -> VkResult<()> {
+    if buffer.host_import.is_some() {
+        return Ok(());
+    }
+    let range = mapped_range(device_info, buffer);
+    unsafe { device_info.device.flush_mapped_memory_ranges(&[range]) }
+}
+
+pub(super) fn invalidate_buffer(device_info: &DeviceInfo, buffer: &Buffer) -> VkResult<()> {
+    if buffer.host_import.is_some() {
+        return Ok(());
+    }
+    let range = mapped_range(device_info, buffer);
+    unsafe { device_info.device.invalidate_mapped_memory_ranges(&[range]) }
+}
+
+// Like `invalidate_buffer`, but only for `size` bytes from `offset` in the buffer
+pub(super) fn invalidate_buffer_range(
+    device_info: &DeviceInfo,
+    buffer: &Buffer,
+    offset: u64,
+    size: u64,
+) -> VkResult<()> {
+    if buffer.host_import.is_some() {
+        return Ok(());
+    }
+    let range = mapped_window(device_info, buffer, offset, size);
+    unsafe { device_info.device.invalidate_mapped_memory_ranges(&[range]) }
+}
+
+// Ranges must be aligned to `nonCoherentAtomSize`. Sub-allocations share their memory block, so
+// the range is widened to the atom boundaries around the allocation.
+fn mapped_range(device_info: &DeviceInfo, buffer: &Buffer) -> vk::MappedMemoryRange {
+    mapped_window(device_info, buffer, 0, buffer.allocation.size())
+}
+
+// Dedicated allocations own their memory, so a window reaching their end can run to the end of
+// the memory instead of an atom boundary past it
+fn mapped_window(
+    device_info: &DeviceInfo,
+    buffer: &Buffer,
+    offset: u64,
+    size: u64,
+) -> vk::MappedMemoryRange {
+    let allocation = &buffer.allocation;
+    let builder = vk::MappedMemoryRange::builder().memory(unsafe { allocation.memory() });
+
+    let atom = device_info.limits.non_coherent_atom_size.max(1);
+    let start = (allocation.offset() + offset) / atom * atom;
+    if allocation.is_dedicated() && offset + size >= allocation.size() {
+        return builder.offset(start).size(vk::WHOLE_SIZE).build();
+    }
+    let end = (allocation.offset() + offset + size).div_ceil(atom) * atom;
+    builder.offset(start).size(end - start).build()
+}
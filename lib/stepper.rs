@@ -0,0 +1,275 @@
+use std::sync::Arc;
+
+use ash::vk::{CommandBuffer, CommandBufferUsageFlags, Fence};
+
+use super::{
+    command_buffer_util,
+    gpu_task::{GPUTask, RecordedOp},
+    resource_state::ResourceStates,
+    staging::StagingError,
+    ComputeManager, Tensor,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum StepperError {
+    CommandBufferRecordingFailure,
+    TaskSubmissionFailure,
+    TaskExecutionFailure,
+    TensorNotBound(u32),
+    ReadbackNotEnabled(u32),
+}
+
+// The first step runs the task as recorded. Later steps only repeat its dispatches, so state stays
+// on the device between steps.
+pub struct Stepper {
+    task: GPUTask,
+    step_command_buffer: CommandBuffer,
+    readback_command_buffer: CommandBuffer,
+    readback_interval: u32,
+    steps_run: u64,
+
+    parent: Arc<ComputeManager>,
+}
+
+// Bounds how many steps go into a single queue submission when no readback is due
+const MAX_STEPS_PER_SUBMIT: u64 = 256;
+
+impl ComputeManager {
+    /// A `readback_interval` of 0 disables periodic readback; use `Stepper::readback` instead.
+    pub fn stepper(
+        self: Arc<Self>,
+        task: GPUTask,
+        readback_interval: u32,
+    ) -> Result<Stepper, StepperError> {
+        let mut stepper = Stepper {
+            task,
+            step_command_buffer: CommandBuffer::null(),
+            readback_command_buffer: CommandBuffer::null(),
+            readback_interval,
+            steps_run: 0,
+            parent: self,
+        };
+
+        stepper.step_command_buffer = stepper.record_step()?;
+        stepper.readback_command_buffer = stepper.record_readback()?;
+
+        Ok(stepper)
+    }
+}
+
+impl Stepper {
+    fn record_step(&self) -> Result<CommandBuffer, StepperError> {
+        let command_buffer = self
+            .task
+            .begin_command_buffer(CommandBufferUsageFlags::SIMULTANEOUS_USE)
+            .map_err(|_| StepperError::CommandBufferRecordingFailure)?;
+
+        // Each step starts from unknown buffer state, which orders it after the previous step's
+        // dispatches and the task's initial upload
+        let mut states = ResourceStates::new();
+        self.task.ops().iter().for_each(|op| {
+            if let RecordedOp::PipelineDispatch {
+                work_group,
+                pipeline,
+            } = op
+            {
+                self.task.record_pipeline_dispatch(
+                    command_buffer,
+                    *work_group,
+                    *pipeline,
+                    &mut states,
+                );
+            }
+        });
+
+        self.end(command_buffer)
+    }
+
+    fn record_readback(&self) -> Result<CommandBuffer, StepperError> {
+        let command_buffer = self
+            .task
+            .begin_command_buffer(CommandBufferUsageFlags::SIMULTANEOUS_USE)
+            .map_err(|_| StepperError::CommandBufferRecordingFailure)?;
+
+        let tensor_ids: Vec<u32> = self
+            .task
+            .bindings()
+            .iter()
+            .filter(|b| b.readback_enabled)
+            .map(|b| b.tensor_id)
+            .collect();
+        self.task
+            .record_device_sync_local(command_buffer, &tensor_ids, &mut ResourceStates::new());
+
+        self.end(command_buffer)
+    }
+
+    fn end(&self, command_buffer: CommandBuffer) -> Result<CommandBuffer, StepperError> {
+        match self.task.end_command_buffer(command_buffer) {
+            Ok(_) => Ok(command_buffer),
+            Err(_) => {
+                self.free(command_buffer);
+                Err(StepperError::CommandBufferRecordingFailure)
+            }
+        }
+    }
+
+    fn free(&self, command_buffer: CommandBuffer) {
+        command_buffer_util::free_command_buffers(&self.parent.device_info, &[command_buffer]);
+    }
+
+    fn validate_outputs(&self, outputs: &[&mut Tensor]) -> Result<(), StepperError> {
+        for output in outputs {
+            match self
+                .task
+                .bindings()
+                .iter()
+                .find(|b| b.tensor_id == output.id())
+            {
+                Some(b) if b.readback_enabled => (),
+                Some(_) => return Err(StepperError::ReadbackNotEnabled(output.id())),
+                None => return Err(StepperError::TensorNotBound(output.id())),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn submit(&self, command_buffers: &[CommandBuffer]) -> Result<Fence, StepperError> {
+        match self.parent.submission_thread.submit(command_buffers) {
+            Ok(f) => Ok(f),
+            Err(e) => {
+                log::error!("Failed to submit command buffer! Error: {}", e);
+                Err(StepperError::TaskSubmissionFailure)
+            }
+        }
+    }
+
+    fn wait(&self, fences: &mut Vec<Fence>) -> Result<(), StepperError> {
+        if fences.is_empty() {
+            return Ok(());
+        }
+
+        let device = &self.parent.device_info.device;
+        let result = unsafe { device.wait_for_fences(fences, true, u64::MAX) };
+        fences.drain(..).for_each(|fence| {
+            self.parent.submission_thread.destroy_fence(fence);
+        });
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!("Failed to wait for stepper submission! Error: {}", e);
+                Err(StepperError::TaskExecutionFailure)
+            }
+        }
+    }
+
+    /// The host only waits on the device when a readback is due and once all steps are submitted.
+    /// `on_readback` receives the total number of steps run so far.
+    pub fn run<F>(
+        &mut self,
+        steps: u64,
+        outputs: &mut [&mut Tensor],
+        mut on_readback: F,
+    ) -> Result<(), StepperError>
+    where
+        F: FnMut(u64, &[&mut Tensor]),
+    {
+        self.validate_outputs(outputs)?;
+
+        let mut pending = Vec::new();
+        let mut remaining = steps;
+        while remaining > 0 {
+            let mut chunk = remaining.min(MAX_STEPS_PER_SUBMIT);
+            if self.readback_interval > 0 {
+                let interval = self.readback_interval as u64;
+                chunk = chunk.min(interval - self.steps_run % interval);
+            }
+
+            let mut command_buffers = Vec::with_capacity(chunk as usize + 1);
+            for i in 0..chunk {
+                command_buffers.push(if self.steps_run + i == 0 {
+                    self.task.command_buffer
+                } else {
+                    self.step_command_buffer
+                });
+            }
+
+            let readback_due = self.readback_interval > 0
+                && (self.steps_run + chunk).is_multiple_of(self.readback_interval as u64);
+            if readback_due {
+                command_buffers.push(self.readback_command_buffer);
+            }
+
+            match self.submit(&command_buffers) {
+                Ok(f) => pending.push(f),
+                Err(e) => {
+                    let _ = self.wait(&mut pending);
+                    return Err(e);
+                }
+            }
+            self.steps_run += chunk;
+            remaining -= chunk;
+
+            if readback_due {
+                self.wait(&mut pending)?;
+                self.copy_readback(outputs)?;
+                on_readback(self.steps_run, outputs);
+            }
+        }
+
+        self.wait(&mut pending)
+    }
+
+    pub fn readback(&mut self, outputs: &mut [&mut Tensor]) -> Result<(), StepperError> {
+        self.validate_outputs(outputs)?;
+
+        let mut pending = vec![self.submit(&[self.readback_command_buffer])?];
+        self.wait(&mut pending)?;
+        self.copy_readback(outputs)
+    }
+
+    fn copy_readback(&self, outputs: &mut [&mut Tensor]) -> Result<(), StepperError> {
+        self.task
+            .copy_readback(outputs.iter_mut().map(|t| &mut **t).collect())
+            .map_err(|e| match e {
+                StagingError::TensorNotBound(id) => StepperError::TensorNotBound(id),
+                StagingError::NoReadbackBuffer(id) => StepperError::ReadbackNotEnabled(id),
+                _ => StepperError::TaskExecutionFailure,
+            })
+    }
+
+    /// Picks up a shader swapped into the task's pipeline with `Pipeline::swap_shader`. Steps
+    /// already run keep their results, later ones run the new shader.
+    pub fn refresh_pipeline(&mut self) -> Result<bool, StepperError> {
+        match self.task.refresh_pipeline() {
+            Ok(true) => (),
+            Ok(false) => return Ok(false),
+            Err(_) => return Err(StepperError::CommandBufferRecordingFailure),
+        }
+
+        let step_command_buffer = self.record_step()?;
+        self.free(self.step_command_buffer);
+        self.step_command_buffer = step_command_buffer;
+
+        Ok(true)
+    }
+
+    pub fn steps_run(&self) -> u64 {
+        self.steps_run
+    }
+
+    pub fn task(&self) -> &GPUTask {
+        &self.task
+    }
+}
+
+impl Drop for Stepper {
+    fn drop(&mut self) {
+        [self.step_command_buffer, self.readback_command_buffer]
+            .into_iter()
+            .filter(|c| *c != CommandBuffer::null())
+            .for_each(|c| self.free(c));
+    }
+}
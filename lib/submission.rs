@@ -0,0 +1,746 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use ash::{
+    prelude::VkResult,
+    vk::{self, CommandBuffer, Fence, Semaphore},
+};
+
+use super::{
+    command_buffer_util, device::DeviceInfo, gpu_task::TaskPriority, init_error::InitError,
+    ComputeManager,
+};
+
+pub(super) type CompletionCallback = Box<dyn FnOnce(bool) + Send>;
+
+struct QueuedSubmission {
+    // Index into the device's compute queues
+    queue_index: usize,
+    command_buffers: Vec<CommandBuffer>,
+    signal_semaphores: Vec<Semaphore>,
+    on_complete: Option<CompletionCallback>,
+    reply: SyncSender<VkResult<Fence>>,
+}
+
+enum SubmissionRequest {
+    // With batching and the number of tasks submitted, the thread may hold the submission back
+    Submit(QueuedSubmission, Option<(SubmissionBatching, usize)>),
+    // The fence is destroyed once it signals, after any callback watching it has run
+    DestroyFence(Fence),
+    // Polls once and replies with how many fences are still watched
+    Flush(SyncSender<usize>),
+}
+
+struct WatchedFence {
+    fence: Fence,
+    on_complete: Vec<CompletionCallback>,
+    destroy: bool,
+    // Submissions counted towards the in-flight limit until it signals
+    in_flight: usize,
+}
+
+// Submissions held back until the batching window closes. The batch's fence is created when it
+// opens, so every submission gets it back straight away rather than once the batch is submitted.
+struct PendingBatch {
+    submissions: Vec<QueuedSubmission>,
+    fence: Option<Fence>,
+    task_count: usize,
+    deadline: Option<Instant>,
+}
+
+/// Caps how many submissions may run on the device at once, so a producer that submits faster
+/// than the device completes can't pile up work and the memory it holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InFlightLimit {
+    Unlimited,
+    /// Submitting blocks until an earlier submission finishes
+    Block(usize),
+    /// Submitting fails while the limit is reached
+    Error(usize),
+}
+
+/// Coalesces task submissions made close together into one queue submission, which saves the
+/// driver overhead of submitting many small tasks one by one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubmissionBatching {
+    /// How long the first submission of a batch waits for more
+    pub window: Duration,
+    /// Submits as soon as a batch holds this many tasks
+    pub max_tasks: usize,
+}
+
+// Yields the fence of a submission sent to the thread, once it has been made
+pub(super) struct PendingSubmission(Receiver<VkResult<Fence>>);
+
+struct InFlightState {
+    count: usize,
+    limit: InFlightLimit,
+    // Set once the manager shuts down, after which nothing new is submitted
+    closed: bool,
+}
+
+// Shared with the thread, which releases a submission's slot once its fence signals
+struct InFlight {
+    state: Mutex<InFlightState>,
+    released: Condvar,
+}
+
+// How long the thread sleeps between fence polls while submissions are in flight or fences await
+// destruction
+const FENCE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// Every queue submission goes through this thread, so callers never contend on the queue
+pub(super) struct SubmissionThread {
+    sender: Option<Sender<SubmissionRequest>>,
+    handle: Option<JoinHandle<()>>,
+    turns: Mutex<TurnState>,
+    turn_released: Condvar,
+    in_flight: Arc<InFlight>,
+    batching: Mutex<Option<SubmissionBatching>>,
+}
+
+struct TurnState {
+    active: bool,
+    // Callers waiting for a turn, indexed by priority
+    waiting: [u32; 3],
+}
+
+// Held while a caller prepares and submits work that has to reach the queue in order
+pub(super) struct SubmissionTurn<'a> {
+    parent: &'a SubmissionThread,
+}
+
+impl SubmissionThread {
+    pub(super) fn spawn(device_info: DeviceInfo) -> Result<Self, InitError> {
+        let (sender, receiver) = mpsc::channel();
+        let in_flight = Arc::new(InFlight {
+            state: Mutex::new(InFlightState {
+                count: 0,
+                limit: InFlightLimit::Unlimited,
+                closed: false,
+            }),
+            released: Condvar::new(),
+        });
+
+        let thread_in_flight = in_flight.clone();
+        let handle = match thread::Builder::new()
+            .name("gauss-submission".to_string())
+            .spawn(move || run(device_info, receiver, thread_in_flight))
+        {
+            Ok(h) => h,
+            Err(e) => {
+                log::error!("Failed to spawn submission thread! Error: {}", e);
+                return Err(InitError::SubmissionThreadCreationFailure);
+            }
+        };
+
+        Ok(SubmissionThread {
+            sender: Some(sender),
+            handle: Some(handle),
+            turns: Mutex::new(TurnState {
+                active: false,
+                waiting: [0; 3],
+            }),
+            turn_released: Condvar::new(),
+            in_flight,
+            batching: Mutex::new(None),
+        })
+    }
+
+    // Blocks until no turn is active and nobody with a higher priority is waiting, so pending
+    // high priority work goes ahead of pending low priority work
+    pub(super) fn wait_turn(&self, priority: TaskPriority) -> SubmissionTurn<'_> {
+        let priority = priority as usize;
+        let mut turns = self.turns.lock().unwrap_or_else(PoisonError::into_inner);
+
+        turns.waiting[priority] += 1;
+        while turns.active || turns.waiting[priority + 1..].iter().any(|w| *w > 0) {
+            turns = self
+                .turn_released
+                .wait(turns)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        turns.waiting[priority] -= 1;
+        turns.active = true;
+
+        SubmissionTurn { parent: self }
+    }
+
+    pub(super) fn submit(&self, command_buffers: &[CommandBuffer]) -> VkResult<Fence> {
+        self.submit_with_callback(command_buffers, None)
+    }
+
+    // `on_complete` runs on the submission thread once the returned fence signals
+    pub(super) fn submit_with_callback(
+        &self,
+        command_buffers: &[CommandBuffer],
+        on_complete: Option<CompletionCallback>,
+    ) -> VkResult<Fence> {
+        self.submit_signalling(0, command_buffers, &[], on_complete)
+    }
+
+    pub(super) fn submit_to(
+        &self,
+        queue_index: usize,
+        command_buffers: &[CommandBuffer],
+        on_complete: Option<CompletionCallback>,
+    ) -> VkResult<Fence> {
+        self.submit_signalling(queue_index, command_buffers, &[], on_complete)
+    }
+
+    // `signal_semaphores` are signalled once the command buffers finish
+    pub(super) fn submit_signalling(
+        &self,
+        queue_index: usize,
+        command_buffers: &[CommandBuffer],
+        signal_semaphores: &[Semaphore],
+        on_complete: Option<CompletionCallback>,
+    ) -> VkResult<Fence> {
+        self.send_submission(
+            queue_index,
+            command_buffers,
+            signal_semaphores,
+            on_complete,
+            None,
+        )?
+        .wait()
+    }
+
+    // Submits `task_count` tasks to the first queue, batched with others when batching is enabled.
+    // A batch shares one fence, so it must be handed back through `destroy_fence`, never destroyed
+    // directly. Batched submissions get the fence as soon as they join the batch; it signals once
+    // the batch has been submitted and finished. The caller needn't hold its turn while waiting,
+    // as the thread keeps the order in which submissions were sent.
+    pub(super) fn submit_batchable(
+        &self,
+        command_buffers: &[CommandBuffer],
+        signal_semaphores: &[Semaphore],
+        task_count: usize,
+        on_complete: Option<CompletionCallback>,
+    ) -> VkResult<PendingSubmission> {
+        let batching = *self.batching.lock().unwrap_or_else(PoisonError::into_inner);
+        self.send_submission(
+            0,
+            command_buffers,
+            signal_semaphores,
+            on_complete,
+            batching.map(|b| (b, task_count)),
+        )
+    }
+
+    fn send_submission(
+        &self,
+        queue_index: usize,
+        command_buffers: &[CommandBuffer],
+        signal_semaphores: &[Semaphore],
+        on_complete: Option<CompletionCallback>,
+        batching: Option<(SubmissionBatching, usize)>,
+    ) -> VkResult<PendingSubmission> {
+        self.reserve_in_flight()?;
+
+        let (reply, response) = mpsc::sync_channel(1);
+        let submission = QueuedSubmission {
+            queue_index,
+            command_buffers: command_buffers.to_vec(),
+            signal_semaphores: signal_semaphores.to_vec(),
+            on_complete,
+            reply,
+        };
+
+        match self
+            .sender
+            .as_ref()
+            .map(|s| s.send(SubmissionRequest::Submit(submission, batching)))
+        {
+            Some(Ok(_)) => Ok(PendingSubmission(response)),
+            _ => {
+                log::error!("Submission thread is not running!");
+                self.in_flight.release(1);
+                Err(vk::Result::ERROR_DEVICE_LOST)
+            }
+        }
+    }
+
+    // Takes a slot for a submission, which the thread gives back once the submission finishes or
+    // fails to submit
+    fn reserve_in_flight(&self) -> VkResult<()> {
+        let mut state = self
+            .in_flight
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        loop {
+            if state.closed {
+                log::error!("Refusing submission, the manager is shutting down!");
+                return Err(vk::Result::ERROR_DEVICE_LOST);
+            }
+            match state.limit {
+                InFlightLimit::Block(limit) if state.count >= limit.max(1) => {
+                    state = self
+                        .in_flight
+                        .released
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                InFlightLimit::Error(limit) if state.count >= limit => {
+                    log::error!(
+                        "Refusing submission, {} submissions are already in flight!",
+                        state.count
+                    );
+                    return Err(vk::Result::ERROR_TOO_MANY_OBJECTS);
+                }
+                _ => break,
+            }
+        }
+        state.count += 1;
+
+        Ok(())
+    }
+
+    pub(super) fn destroy_fence(&self, fence: Fence) {
+        if let Some(sender) = self.sender.as_ref() {
+            if sender.send(SubmissionRequest::DestroyFence(fence)).is_ok() {
+                return;
+            }
+        }
+
+        log::error!("Submission thread is not running! Leaking fence.");
+    }
+
+    // Refuses new submissions, failing those blocked on the in-flight limit
+    pub(super) fn close(&self) {
+        self.in_flight_state().closed = true;
+        self.in_flight.released.notify_all();
+    }
+
+    // Waits until nothing is in flight or `deadline` passes, and returns what's still in flight.
+    // Without a deadline, waits as long as it takes.
+    pub(super) fn drain(&self, deadline: Option<Instant>) -> usize {
+        let mut state = self.in_flight_state();
+        while state.count > 0 {
+            state = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        break;
+                    }
+                    self.in_flight
+                        .released
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .in_flight
+                    .released
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        state.count
+    }
+
+    // Has the thread handle every request sent so far, including deferred fence destruction, and
+    // returns how many fences it's still watching
+    pub(super) fn flush(&self) -> usize {
+        let (reply, response) = mpsc::sync_channel(1);
+        match self
+            .sender
+            .as_ref()
+            .map(|s| s.send(SubmissionRequest::Flush(reply)))
+        {
+            Some(Ok(_)) => response.recv().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    // Waits for all watched fences, runs their callbacks and stops the thread
+    pub(super) fn shutdown(&mut self) {
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                log::error!("Submission thread panicked!");
+            }
+        }
+    }
+}
+
+impl PendingSubmission {
+    pub(super) fn wait(self) -> VkResult<Fence> {
+        match self.0.recv() {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("Submission thread did not reply! Error: {}", e);
+                Err(vk::Result::ERROR_DEVICE_LOST)
+            }
+        }
+    }
+}
+
+impl InFlight {
+    fn release(&self, submissions: usize) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.count = state.count.saturating_sub(submissions);
+        self.released.notify_all();
+    }
+}
+
+impl PendingBatch {
+    // Replies to the submission with the batch's fence, which is created if the batch is empty.
+    // Returns whether the submission joined the batch.
+    fn push(
+        &mut self,
+        device_info: &DeviceInfo,
+        submission: QueuedSubmission,
+        batching: SubmissionBatching,
+        tasks: usize,
+        shared_fences: &mut HashMap<Fence, usize>,
+        in_flight: &InFlight,
+    ) -> bool {
+        let fence = match self.fence {
+            Some(f) => f,
+            None => match command_buffer_util::create_fence(device_info) {
+                Ok(fence) => {
+                    self.fence = Some(fence);
+                    self.deadline = Some(Instant::now() + batching.window);
+                    fence
+                }
+                Err(e) => {
+                    log::error!("Failed to create batch fence! Error: {}", e);
+                    in_flight.release(1);
+                    if let Some(on_complete) = submission.on_complete {
+                        on_complete(false);
+                    }
+                    let _ = submission.reply.send(Err(e));
+                    return false;
+                }
+            },
+        };
+
+        // Every submission of the batch hands the fence back once
+        *shared_fences.entry(fence).or_insert(0) += 1;
+        let _ = submission.reply.send(Ok(fence));
+        self.submissions.push(submission);
+        self.task_count += tasks;
+        true
+    }
+
+    fn take(&mut self) -> (Vec<QueuedSubmission>, Option<Fence>) {
+        self.task_count = 0;
+        self.deadline = None;
+        (std::mem::take(&mut self.submissions), self.fence.take())
+    }
+}
+
+impl ComputeManager {
+    /// Applies to every queue submission, including transfers and stepper runs. Lowering the limit
+    /// doesn't affect submissions already in flight. Defaults to `Unlimited`.
+    pub fn set_in_flight_limit(&self, limit: InFlightLimit) {
+        let in_flight = &self.submission_thread.in_flight;
+        let mut state = in_flight
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.limit = limit;
+        in_flight.released.notify_all();
+    }
+
+    pub fn in_flight_limit(&self) -> InFlightLimit {
+        self.submission_thread.in_flight_state().limit
+    }
+
+    /// Submissions that haven't finished on the device yet
+    pub fn in_flight_submissions(&self) -> usize {
+        self.submission_thread.in_flight_state().count
+    }
+
+    /// Batches task submissions, trading up to `window` of latency per task for fewer queue
+    /// submissions. Only applies to tasks, not transfers or stepper runs. `None`, the default,
+    /// submits every task as soon as it's executed.
+    pub fn set_submission_batching(&self, batching: Option<SubmissionBatching>) {
+        *self
+            .submission_thread
+            .batching
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = batching;
+    }
+
+    pub fn submission_batching(&self) -> Option<SubmissionBatching> {
+        *self
+            .submission_thread
+            .batching
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Submits task batches still waiting for their window to close, and destroys fences and
+    /// runs completion callbacks of submissions that have finished. Doesn't wait for the device.
+    /// Returns how many fences are still waited on, which is 0 once everything has finished.
+    pub fn flush(&self) -> usize {
+        self.submission_thread.flush()
+    }
+
+    /// Blocks until every submission made so far has finished on the device, including batched
+    /// ones, and their completion callbacks have run and deferred fences are destroyed. Other
+    /// threads can keep submitting meanwhile, which delays the return; stop them first for a
+    /// clean point to snapshot from or shut down at.
+    pub fn wait_idle(&self) {
+        self.submission_thread.flush();
+        self.submission_thread.drain(None);
+        // Submissions are released before their callbacks run, so have the thread catch up
+        self.submission_thread.flush();
+    }
+}
+
+impl SubmissionThread {
+    fn in_flight_state(&self) -> std::sync::MutexGuard<'_, InFlightState> {
+        self.in_flight
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for SubmissionTurn<'_> {
+    fn drop(&mut self) {
+        let mut turns = self
+            .parent
+            .turns
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        turns.active = false;
+        self.parent.turn_released.notify_all();
+    }
+}
+
+fn run(device_info: DeviceInfo, receiver: Receiver<SubmissionRequest>, in_flight: Arc<InFlight>) {
+    let mut watched: Vec<WatchedFence> = Vec::new();
+    // How many submissions still have to hand back each fence shared by a batch
+    let mut shared_fences: HashMap<Fence, usize> = HashMap::new();
+    let mut batch = PendingBatch {
+        submissions: Vec::new(),
+        fence: None,
+        task_count: 0,
+        deadline: None,
+    };
+
+    loop {
+        let until_deadline = batch
+            .deadline
+            .map(|d| d.saturating_duration_since(Instant::now()));
+        let request = match (until_deadline, watched.is_empty()) {
+            (None, true) => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            (None, false) => receiver.recv_timeout(FENCE_POLL_INTERVAL),
+            (Some(t), true) => receiver.recv_timeout(t),
+            (Some(t), false) => receiver.recv_timeout(t.min(FENCE_POLL_INTERVAL)),
+        };
+
+        let submit = |(submissions, fence), watched: &mut _, shared_fences: &mut _| {
+            submit_queued(
+                &device_info,
+                submissions,
+                fence,
+                watched,
+                shared_fences,
+                &in_flight,
+            )
+        };
+        match request {
+            Ok(SubmissionRequest::Submit(submission, Some((batching, tasks)))) => {
+                let joined = batch.push(
+                    &device_info,
+                    submission,
+                    batching,
+                    tasks,
+                    &mut shared_fences,
+                    &in_flight,
+                );
+                if joined && batch.task_count >= batching.max_tasks {
+                    submit(batch.take(), &mut watched, &mut shared_fences);
+                }
+            }
+            Ok(SubmissionRequest::Submit(submission, None)) => {
+                // Anything held back goes first, so the queue sees submissions in order
+                submit(batch.take(), &mut watched, &mut shared_fences);
+                submit((vec![submission], None), &mut watched, &mut shared_fences);
+            }
+            Ok(SubmissionRequest::DestroyFence(fence)) => match shared_fences.get_mut(&fence) {
+                // A shared fence is destroyed once every submission of its batch handed it back
+                Some(owners) if *owners > 1 => *owners -= 1,
+                _ => {
+                    shared_fences.remove(&fence);
+                    match watched.iter_mut().find(|w| w.fence == fence) {
+                        Some(w) => w.destroy = true,
+                        None => watched.push(WatchedFence {
+                            fence,
+                            on_complete: Vec::new(),
+                            destroy: true,
+                            in_flight: 0,
+                        }),
+                    }
+                }
+            },
+            Ok(SubmissionRequest::Flush(reply)) => {
+                submit(batch.take(), &mut watched, &mut shared_fences);
+                poll_fences(&device_info, &mut watched, &in_flight, false);
+                let _ = reply.send(watched.len());
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                submit(batch.take(), &mut watched, &mut shared_fences);
+                break;
+            }
+        }
+
+        if batch.deadline.is_some_and(|d| d <= Instant::now()) {
+            submit(batch.take(), &mut watched, &mut shared_fences);
+        }
+        poll_fences(&device_info, &mut watched, &in_flight, false);
+    }
+
+    poll_fences(&device_info, &mut watched, &in_flight, true);
+}
+
+// Makes the submissions in one call to the first submission's queue. They share the fence, which
+// every submission gets back. A batch brings the fence it was already replied with.
+fn submit_queued(
+    device_info: &DeviceInfo,
+    submissions: Vec<QueuedSubmission>,
+    batch_fence: Option<Fence>,
+    watched: &mut Vec<WatchedFence>,
+    shared_fences: &mut HashMap<Fence, usize>,
+    in_flight: &InFlight,
+) {
+    let count = submissions.len();
+    let queue = match submissions.first() {
+        Some(s) => device_info.compute_queues.get(s.queue_index).copied(),
+        None => return,
+    };
+    let batches: Vec<(&[CommandBuffer], &[Semaphore])> = submissions
+        .iter()
+        .map(|s| (&s.command_buffers[..], &s.signal_semaphores[..]))
+        .collect();
+    let result = match (queue, batch_fence) {
+        (Some(queue), Some(fence)) => command_buffer_util::submit_command_buffers_with_fence(
+            device_info,
+            queue,
+            &batches,
+            fence,
+        )
+        .map(|_| fence),
+        (Some(queue), None) => {
+            command_buffer_util::submit_command_buffers(device_info, queue, &batches)
+        }
+        (None, _) => Err(vk::Result::ERROR_UNKNOWN),
+    };
+
+    let mut on_complete = Vec::new();
+    let mut replies = Vec::with_capacity(count);
+    submissions.into_iter().for_each(|s| {
+        on_complete.extend(s.on_complete);
+        replies.push(s.reply);
+    });
+    match (result, batch_fence) {
+        (Ok(fence), _) => {
+            if count > 1 && batch_fence.is_none() {
+                shared_fences.insert(fence, count);
+            }
+            watch_fence(watched, fence, on_complete, count);
+        }
+        (Err(e), Some(fence)) => {
+            // The batch already handed its fence out, so it's signalled empty to not leave anyone
+            // waiting on it forever
+            log::error!("Failed to submit task batch! Error: {}", e);
+            in_flight.release(count);
+            on_complete.into_iter().for_each(|c| c(false));
+            if let Some(queue) = queue {
+                if let Err(e) = unsafe { device_info.device.queue_submit(queue, &[], fence) } {
+                    log::error!("Failed to signal fence of failed batch! Error: {}", e);
+                }
+            }
+            watch_fence(watched, fence, Vec::new(), 0);
+        }
+        (Err(_), None) => {
+            in_flight.release(count);
+            on_complete.into_iter().for_each(|c| c(false));
+        }
+    }
+    // Batched submissions were replied to as they joined the batch
+    if batch_fence.is_none() {
+        replies.into_iter().for_each(|reply| {
+            let _ = reply.send(result);
+        });
+    }
+}
+
+// A fence handed back before its batch was submitted is already watched for destruction
+fn watch_fence(
+    watched: &mut Vec<WatchedFence>,
+    fence: Fence,
+    on_complete: Vec<CompletionCallback>,
+    in_flight: usize,
+) {
+    match watched.iter_mut().find(|w| w.fence == fence) {
+        Some(w) => {
+            w.on_complete.extend(on_complete);
+            w.in_flight += in_flight;
+        }
+        None => watched.push(WatchedFence {
+            fence,
+            on_complete,
+            destroy: false,
+            in_flight,
+        }),
+    }
+}
+
+fn poll_fences(
+    device_info: &DeviceInfo,
+    watched: &mut Vec<WatchedFence>,
+    in_flight: &InFlight,
+    wait: bool,
+) {
+    let device = &device_info.device;
+
+    watched.retain_mut(|w| {
+        let status = unsafe {
+            if wait {
+                device
+                    .wait_for_fences(&[w.fence], true, u64::MAX)
+                    .map(|_| true)
+            } else {
+                device.get_fence_status(w.fence)
+            }
+        };
+
+        let completed = match status {
+            Ok(false) => return true,
+            Ok(true) => true,
+            Err(e) => {
+                log::error!("Failed to query task fence! Error: {}", e);
+                false
+            }
+        };
+
+        if w.in_flight > 0 {
+            in_flight.release(w.in_flight);
+        }
+        w.on_complete
+            .drain(..)
+            .for_each(|on_complete| on_complete(completed));
+
+        // A fence still owned by a sync primitive comes back through `destroy_fence` once awaited
+        if w.destroy || wait {
+            command_buffer_util::destroy_fence(device_info, w.fence);
+        }
+        false
+    });
+}
@@ -0,0 +1,174 @@
+use std::{sync::Arc, time::Duration};
+
+use super::{
+    benchmark::{BenchmarkError, Phase},
+    pipeline::{PipelineCreateError, ShaderSource},
+    ComputeManager, Tensor, WorkGroupSize,
+};
+
+/// Spec constant values and dispatch size of one run in a sweep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SweepPoint {
+    /// `(constant_id, value)` pairs
+    pub specialization: Vec<(u32, u32)>,
+    pub work_group: WorkGroupSize,
+}
+
+/// Values to sweep, run in every combination. Pipelines have no push constants, so scalar
+/// parameters are swept as spec constants.
+#[derive(Debug, Clone, Default)]
+pub struct SweepGrid {
+    spec_constants: Vec<(u32, Vec<u32>)>,
+    work_groups: Vec<WorkGroupSize>,
+}
+
+#[derive(Debug, Clone)]
+pub enum SweepError {
+    PipelineCreationFailure(PipelineCreateError),
+    BenchmarkFailure(BenchmarkError),
+}
+
+#[derive(Debug, Clone)]
+pub struct SweepMeasurement {
+    /// Host wall-clock average per iteration, including upload, recording and readback
+    pub time: Duration,
+    /// The output tensors' data after the last iteration, in order
+    pub outputs: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SweepResult {
+    pub point: SweepPoint,
+    pub measurement: Result<SweepMeasurement, SweepError>,
+}
+
+impl SweepGrid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sweeps the spec constant `constant_id` over `values`
+    pub fn spec_constant(mut self, constant_id: u32, values: Vec<u32>) -> Self {
+        self.spec_constants.retain(|(id, _)| *id != constant_id);
+        self.spec_constants.push((constant_id, values));
+        self
+    }
+
+    pub fn work_groups(mut self, work_groups: Vec<WorkGroupSize>) -> Self {
+        self.work_groups = work_groups;
+        self
+    }
+
+    /// Every combination, with the dispatch size varying fastest so runs sharing a pipeline are
+    /// adjacent
+    pub fn points(&self) -> Vec<SweepPoint> {
+        let specializations = self.spec_constants.iter().fold(
+            vec![Vec::new()],
+            |specializations: Vec<Vec<(u32, u32)>>, (id, values)| {
+                specializations
+                    .iter()
+                    .flat_map(|s| {
+                        values.iter().map(move |v| {
+                            let mut s = s.clone();
+                            s.push((*id, *v));
+                            s
+                        })
+                    })
+                    .collect()
+            },
+        );
+
+        specializations
+            .iter()
+            .flat_map(|specialization| {
+                self.work_groups.iter().map(move |work_group| SweepPoint {
+                    specialization: specialization.clone(),
+                    work_group: *work_group,
+                })
+            })
+            .collect()
+    }
+}
+
+impl ComputeManager {
+    /// Runs the shader at every point of `grid`, timing `iterations` runs after a warm-up. Bindings
+    /// are `inputs` followed by `outputs`, which must have readback enabled. A failing point, e.g.
+    /// one whose spec constants need too much shared memory, doesn't stop the sweep.
+    pub fn sweep(
+        self: Arc<Self>,
+        source: ShaderSource,
+        name: &str,
+        grid: &SweepGrid,
+        inputs: Vec<&Tensor>,
+        mut outputs: Vec<&mut Tensor>,
+        iterations: u32,
+    ) -> Vec<SweepResult> {
+        let iterations = iterations.max(1);
+        let n_tensors = (inputs.len() + outputs.len()) as u32;
+        let mut pipeline = None;
+
+        grid.points()
+            .into_iter()
+            .map(|point| {
+                let pipeline = match &pipeline {
+                    Some((specialization, p)) if *specialization == point.specialization => {
+                        Ok(Arc::clone(p))
+                    }
+                    _ => self
+                        .program_from_source(source, name)
+                        .and_then(|program| {
+                            self.clone().build_specialized_pipeline(
+                                program,
+                                n_tensors,
+                                &point.specialization,
+                            )
+                        })
+                        .map(|p| {
+                            let p = Arc::new(p);
+                            pipeline = Some((point.specialization.clone(), p.clone()));
+                            p
+                        }),
+                };
+
+                let measurement = match pipeline {
+                    Ok(pipeline) => self
+                        .clone()
+                        .time_phase(
+                            Phase::Full,
+                            &pipeline,
+                            &inputs,
+                            &mut outputs,
+                            point.work_group,
+                            1,
+                        )
+                        .and_then(|_| {
+                            self.clone().time_phase(
+                                Phase::Full,
+                                &pipeline,
+                                &inputs,
+                                &mut outputs,
+                                point.work_group,
+                                iterations,
+                            )
+                        })
+                        .map(|time| SweepMeasurement {
+                            time,
+                            outputs: outputs.iter().map(|t| t.data().to_vec()).collect(),
+                        })
+                        .map_err(SweepError::BenchmarkFailure),
+                    Err(e) => Err(SweepError::PipelineCreationFailure(e)),
+                };
+                if let Err(e) = &measurement {
+                    log::warn!(
+                        "Sweep of \"{}\" failed at {:?}! Error: {:?}",
+                        name,
+                        point,
+                        e
+                    );
+                }
+
+                SweepResult { point, measurement }
+            })
+            .collect()
+    }
+}
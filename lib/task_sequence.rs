@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use super::{gpu_task::GPUTask, staging::StagingError, ComputeManager, Tensor};
+
+#[derive(Debug, Clone, Copy)]
+pub enum TaskSequenceError {
+    TaskSubmissionFailure,
+    TaskExecutionFailure,
+    TaskNotCompleted,
+    TensorNotBound(u32),
+    ReadbackNotEnabled(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostAction {
+    Continue,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceOutcome {
+    Completed,
+    /// `step` is the index of the host step that stopped the sequence
+    Stopped {
+        step: usize,
+    },
+}
+
+type HostStep<'a> = Box<dyn FnMut(&SequenceContext) -> HostAction + 'a>;
+
+enum SequenceStep<'a> {
+    Task(&'a GPUTask),
+    Host(HostStep<'a>),
+}
+
+// Consecutive tasks are submitted as one batch. A host step waits on the batch before it,
+// so the device is idle while it runs.
+pub struct TaskSequence<'a> {
+    steps: Vec<SequenceStep<'a>>,
+
+    parent: Arc<ComputeManager>,
+}
+
+// Handed to host steps so they can read back results of the tasks that ran before them
+pub struct SequenceContext<'s> {
+    completed: &'s [&'s GPUTask],
+}
+
+impl ComputeManager {
+    pub fn new_sequence<'a>(self: Arc<Self>) -> TaskSequence<'a> {
+        TaskSequence {
+            steps: Vec::new(),
+            parent: self,
+        }
+    }
+}
+
+impl<'a> TaskSequence<'a> {
+    pub fn then_task(mut self, task: &'a GPUTask) -> Self {
+        self.steps.push(SequenceStep::Task(task));
+        self
+    }
+
+    pub fn then_host<F>(mut self, host_step: F) -> Self
+    where
+        F: FnMut(&SequenceContext) -> HostAction + 'a,
+    {
+        self.steps.push(SequenceStep::Host(Box::new(host_step)));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn run(&mut self) -> Result<SequenceOutcome, TaskSequenceError> {
+        let mut completed: Vec<&GPUTask> = Vec::new();
+        let mut batch: Vec<&GPUTask> = Vec::new();
+
+        for (index, step) in self.steps.iter_mut().enumerate() {
+            match step {
+                SequenceStep::Task(task) => {
+                    // A command buffer can't be pending twice, so flush before repeating a task
+                    if batch.iter().any(|t| std::ptr::eq(*t, *task)) {
+                        flush(&self.parent, &mut batch, &mut completed)?;
+                    }
+                    batch.push(task);
+                }
+                SequenceStep::Host(host_step) => {
+                    flush(&self.parent, &mut batch, &mut completed)?;
+
+                    let context = SequenceContext {
+                        completed: &completed,
+                    };
+                    if host_step(&context) == HostAction::Stop {
+                        return Ok(SequenceOutcome::Stopped { step: index });
+                    }
+                }
+            }
+        }
+
+        flush(&self.parent, &mut batch, &mut completed)?;
+
+        Ok(SequenceOutcome::Completed)
+    }
+}
+
+fn flush<'a>(
+    manager: &ComputeManager,
+    batch: &mut Vec<&'a GPUTask>,
+    completed: &mut Vec<&'a GPUTask>,
+) -> Result<(), TaskSequenceError> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    let submission = match manager.submit_tracked(batch, &[], None) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Failed to submit task sequence segment! Error: {}", e);
+            return Err(TaskSequenceError::TaskSubmissionFailure);
+        }
+    };
+
+    let result = unsafe {
+        manager
+            .device_info
+            .device
+            .wait_for_fences(&[submission.fence], true, u64::MAX)
+    };
+    manager.submission_thread.destroy_fence(submission.fence);
+    manager.free_prologues(&submission.prologues);
+    if let Err(e) = result {
+        log::error!("Failed to wait for task sequence segment! Error: {}", e);
+        return Err(TaskSequenceError::TaskExecutionFailure);
+    }
+
+    batch.drain(..).for_each(|task| {
+        if !completed.iter().any(|t| std::ptr::eq(*t, task)) {
+            completed.push(task);
+        }
+    });
+
+    Ok(())
+}
+
+impl<'s> SequenceContext<'s> {
+    pub fn completed_tasks(&self) -> usize {
+        self.completed.len()
+    }
+
+    /// `task` must have run earlier in the sequence and recorded a device-to-local sync of the tensors.
+    pub fn readback(
+        &self,
+        task: &GPUTask,
+        tensors: Vec<&mut Tensor>,
+    ) -> Result<(), TaskSequenceError> {
+        if !self.completed.iter().any(|t| std::ptr::eq(*t, task)) {
+            return Err(TaskSequenceError::TaskNotCompleted);
+        }
+
+        for tensor in &tensors {
+            match task.bindings().iter().find(|b| b.tensor_id == tensor.id()) {
+                Some(b) if b.readback_enabled => (),
+                Some(_) => return Err(TaskSequenceError::ReadbackNotEnabled(tensor.id())),
+                None => return Err(TaskSequenceError::TensorNotBound(tensor.id())),
+            }
+        }
+
+        task.copy_readback(tensors).map_err(|e| match e {
+            StagingError::TensorNotBound(id) => TaskSequenceError::TensorNotBound(id),
+            StagingError::NoReadbackBuffer(id) => TaskSequenceError::ReadbackNotEnabled(id),
+            _ => TaskSequenceError::TaskExecutionFailure,
+        })
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{gpu_task::GPUTask, kernel_assert::KernelAssertionFailed, ComputeManager, Tensor};
+
+/// One reading of the device's clocks, temperature and power. Sources leave out what they can't
+/// read.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TelemetrySample {
+    /// Time since the task was submitted, filled in by the sampler
+    pub elapsed: Duration,
+    pub graphics_clock_mhz: Option<u32>,
+    pub memory_clock_mhz: Option<u32>,
+    pub temperature_celsius: Option<f32>,
+    pub power_watts: Option<f32>,
+}
+
+/// Reads device telemetry, e.g. from a vendor library. `NvmlTelemetry` and `AmdGpuTelemetry` are
+/// built in behind the `nvml` and `amdgpu-sysfs` features.
+pub trait TelemetrySource: Send {
+    fn sample(&mut self) -> TelemetrySample;
+}
+
+/// Telemetry sampled from a task's submission until it was awaited
+#[derive(Debug, Clone)]
+pub struct TaskProfile {
+    pub shader: String,
+    pub wall_time: Duration,
+    pub samples: Vec<TelemetrySample>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TelemetryError {
+    NoSource,
+    TaskSubmissionFailure,
+    KernelAssertionFailed(KernelAssertionFailed),
+}
+
+pub(super) struct Telemetry {
+    source: Box<dyn TelemetrySource>,
+    interval: Duration,
+}
+
+impl TaskProfile {
+    pub fn average_power_watts(&self) -> Option<f32> {
+        let power: Vec<f32> = self.samples.iter().filter_map(|s| s.power_watts).collect();
+        (!power.is_empty()).then(|| power.iter().sum::<f32>() / power.len() as f32)
+    }
+
+    /// Integrates power over the samples, so it's only as accurate as the sampling interval
+    pub fn energy_joules(&self) -> Option<f32> {
+        let power: Vec<(Duration, f32)> = self
+            .samples
+            .iter()
+            .filter_map(|s| Some((s.elapsed, s.power_watts?)))
+            .collect();
+        if power.len() < 2 {
+            return self
+                .average_power_watts()
+                .map(|p| p * self.wall_time.as_secs_f32());
+        }
+
+        Some(
+            power
+                .windows(2)
+                .map(|w| (w[1].0 - w[0].0).as_secs_f32() * (w[0].1 + w[1].1) / 2.0)
+                .sum(),
+        )
+    }
+
+    pub fn peak_temperature_celsius(&self) -> Option<f32> {
+        self.samples
+            .iter()
+            .filter_map(|s| s.temperature_celsius)
+            .reduce(f32::max)
+    }
+}
+
+impl ComputeManager {
+    /// Sets where `exec_task_profiled` reads telemetry from, sampling every `interval`. `None`
+    /// removes the source.
+    pub fn set_telemetry_source(
+        &self,
+        source: Option<Box<dyn TelemetrySource>>,
+        interval: Duration,
+    ) {
+        match self.telemetry.lock() {
+            Ok(mut telemetry) => *telemetry = source.map(|source| Telemetry { source, interval }),
+            Err(e) => log::error!("Failed to acquire telemetry source! Error: {e}"),
+        }
+    }
+
+    /// Runs `task` like `exec_task` and `await_task` while sampling the telemetry source, once
+    /// at submission, every interval while the task runs and once after it finishes. Samples are
+    /// of the whole device, so other work running alongside the task shows up in them too.
+    pub fn exec_task_profiled(
+        &self,
+        task: &GPUTask,
+        sync_tensors: Vec<&mut Tensor>,
+    ) -> Result<TaskProfile, TelemetryError> {
+        // Held for the whole run so profiled tasks never share a source
+        let mut telemetry = match self.telemetry.lock() {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!("Failed to acquire telemetry source! Error: {e}");
+                return Err(TelemetryError::NoSource);
+            }
+        };
+        let Telemetry { source, interval } = match telemetry.as_mut() {
+            Some(t) => t,
+            None => return Err(TelemetryError::NoSource),
+        };
+        let interval = *interval;
+
+        let start = Instant::now();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (samples, result) = thread::scope(|scope| {
+            let sampler = scope.spawn(move || {
+                let mut samples = Vec::new();
+                loop {
+                    samples.push(TelemetrySample {
+                        elapsed: start.elapsed(),
+                        ..source.sample()
+                    });
+                    match stopped.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => (),
+                        _ => break,
+                    }
+                }
+                samples.push(TelemetrySample {
+                    elapsed: start.elapsed(),
+                    ..source.sample()
+                });
+                samples
+            });
+
+            let result = match self.exec_task(task) {
+                Some(sync) => self
+                    .await_task(&sync, sync_tensors)
+                    .map_err(TelemetryError::KernelAssertionFailed),
+                None => Err(TelemetryError::TaskSubmissionFailure),
+            };
+            let _ = stop.send(());
+
+            (sampler.join().unwrap_or_default(), result)
+        });
+        let wall_time = start.elapsed();
+        result?;
+
+        Ok(TaskProfile {
+            shader: task.pipeline.shader_name().to_string(),
+            wall_time,
+            samples,
+        })
+    }
+}
+
+/// Samples an NVIDIA device through NVML
+#[cfg(feature = "nvml")]
+pub struct NvmlTelemetry {
+    nvml: nvml_wrapper::Nvml,
+    index: u32,
+}
+
+#[cfg(feature = "nvml")]
+impl NvmlTelemetry {
+    /// `index` is NVML's device index, which needn't match the Vulkan device order
+    pub fn new(index: u32) -> Option<Self> {
+        let nvml = match nvml_wrapper::Nvml::init() {
+            Ok(n) => n,
+            Err(e) => {
+                log::error!("Failed to initialize NVML! Error: {}", e);
+                return None;
+            }
+        };
+        if let Err(e) = nvml.device_by_index(index) {
+            log::error!("Failed to find NVML device {}! Error: {}", index, e);
+            return None;
+        }
+
+        Some(NvmlTelemetry { nvml, index })
+    }
+}
+
+#[cfg(feature = "nvml")]
+impl TelemetrySource for NvmlTelemetry {
+    fn sample(&mut self) -> TelemetrySample {
+        use nvml_wrapper::enum_wrappers::device::{Clock, TemperatureSensor};
+
+        let device = match self.nvml.device_by_index(self.index) {
+            Ok(d) => d,
+            Err(_) => return TelemetrySample::default(),
+        };
+        TelemetrySample {
+            elapsed: Duration::ZERO,
+            graphics_clock_mhz: device.clock_info(Clock::Graphics).ok(),
+            memory_clock_mhz: device.clock_info(Clock::Memory).ok(),
+            temperature_celsius: device
+                .temperature(TemperatureSensor::Gpu)
+                .ok()
+                .map(|t| t as f32),
+            power_watts: device.power_usage().ok().map(|mw| mw as f32 / 1000.0),
+        }
+    }
+}
+
+/// Samples an AMD device through the amdgpu driver's hwmon files in sysfs
+#[cfg(feature = "amdgpu-sysfs")]
+pub struct AmdGpuTelemetry {
+    hwmon: std::path::PathBuf,
+}
+
+#[cfg(feature = "amdgpu-sysfs")]
+impl AmdGpuTelemetry {
+    /// `card` is the DRM card number, as in `/sys/class/drm/card0`
+    pub fn new(card: u32) -> Option<Self> {
+        let dir = format!("/sys/class/drm/card{card}/device/hwmon");
+        let hwmon = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(Result::ok).map(|e| e.path()).next(),
+            Err(e) => {
+                log::error!("Failed to read {}! Error: {}", dir, e);
+                return None;
+            }
+        };
+
+        match hwmon {
+            Some(hwmon) => Some(AmdGpuTelemetry { hwmon }),
+            None => {
+                log::error!("Card {} exposes no hwmon directory", card);
+                None
+            }
+        }
+    }
+
+    fn read(&self, file: &str) -> Option<u64> {
+        std::fs::read_to_string(self.hwmon.join(file))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+#[cfg(feature = "amdgpu-sysfs")]
+impl TelemetrySource for AmdGpuTelemetry {
+    // Clocks are in Hz, temperature in millidegrees and power in microwatts. Newer kernels only
+    // report instantaneous power.
+    fn sample(&mut self) -> TelemetrySample {
+        TelemetrySample {
+            elapsed: Duration::ZERO,
+            graphics_clock_mhz: self.read("freq1_input").map(|hz| (hz / 1_000_000) as u32),
+            memory_clock_mhz: self.read("freq2_input").map(|hz| (hz / 1_000_000) as u32),
+            temperature_celsius: self.read("temp1_input").map(|t| t as f32 / 1000.0),
+            power_watts: self
+                .read("power1_average")
+                .or_else(|| self.read("power1_input"))
+                .map(|uw| uw as f32 / 1_000_000.0),
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::{env, fs, path::Path, sync::Arc};
+
+use ndarray::Array1;
+
+use super::{
+    gpu_task::GPUTaskRecordingDiagnostic, kernel_assert::KernelAssertionFailed,
+    pipeline::PipelineCreateError, ComputeManager, ShaderSource, Tensor, WorkGroupSize,
+};
+
+// Set to re-record golden files instead of comparing against them
+const UPDATE_GOLDEN_VAR: &str = "GAUSS_UPDATE_GOLDEN";
+const GOLDEN_HEADER: &str = "# gauss golden v1";
+
+// Most failures are one wrong formula, so listing every element would only bury the first
+const MAX_REPORTED_MISMATCHES: usize = 8;
+
+#[derive(Debug, Clone)]
+pub enum KernelTestError {
+    PipelineCreationFailure(PipelineCreateError),
+    TaskRecordingFailure(Vec<GPUTaskRecordingDiagnostic>),
+    TaskSubmissionFailure,
+    KernelAssertionFailed(KernelAssertionFailed),
+    OutputMismatch(Vec<OutputMismatch>),
+    GoldenReadFailure,
+    GoldenWriteFailure,
+    GoldenMalformed { line: usize },
+    GoldenShapeMismatch,
+    GoldenMismatch(Vec<FloatComparison>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputMismatch {
+    pub output: usize,
+    pub index: usize,
+    pub expected: f32,
+    pub actual: f32,
+}
+
+/// An element passes if it's within `abs` of the expected value, within `rel` of it relative to
+/// the expected magnitude, or at most `ulps` representable floats away from it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub abs: f32,
+    pub rel: f32,
+    pub ulps: u32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            abs: 1e-5,
+            rel: 1e-4,
+            ulps: 4,
+        }
+    }
+}
+
+impl Tolerance {
+    fn accepts(&self, expected: f32, actual: f32) -> bool {
+        if expected.is_nan() {
+            return actual.is_nan();
+        }
+        let error = (expected - actual).abs();
+        error <= self.abs
+            || error <= self.rel * expected.abs()
+            || ulp_distance(expected, actual) <= self.ulps
+    }
+}
+
+// Floats are ordered like sign-magnitude integers, so mapping them onto a monotonic integer line
+// makes the distance the count of floats between them
+fn ulp_distance(a: f32, b: f32) -> u32 {
+    if a.is_nan() || b.is_nan() {
+        return u32::MAX;
+    }
+    let ordered = |f: f32| {
+        let bits = f.to_bits() as i64;
+        if bits & 0x8000_0000 != 0 {
+            0x8000_0000 - bits
+        } else {
+            bits
+        }
+    };
+    (ordered(a) - ordered(b))
+        .unsigned_abs()
+        .min(u32::MAX as u64) as u32
+}
+
+/// How one output compared against its golden values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloatComparison {
+    pub output: usize,
+    pub elements: usize,
+    pub mismatched: usize,
+    /// Index of the element with the largest absolute error
+    pub worst_index: usize,
+    pub max_abs_error: f32,
+    pub max_rel_error: f32,
+    pub max_ulps: u32,
+}
+
+pub fn compare_floats(
+    output: usize,
+    expected: &[f32],
+    actual: &[f32],
+    tolerance: Tolerance,
+) -> FloatComparison {
+    let mut comparison = FloatComparison {
+        output,
+        elements: expected.len(),
+        mismatched: 0,
+        worst_index: 0,
+        max_abs_error: 0.0,
+        max_rel_error: 0.0,
+        max_ulps: 0,
+    };
+
+    expected
+        .iter()
+        .zip(actual.iter())
+        .enumerate()
+        .for_each(|(i, (e, a))| {
+            if !tolerance.accepts(*e, *a) {
+                comparison.mismatched += 1;
+            }
+            if e.is_nan() && a.is_nan() {
+                return;
+            }
+
+            let abs_error = (e - a).abs();
+            if abs_error > comparison.max_abs_error || abs_error.is_nan() {
+                comparison.max_abs_error = abs_error;
+                comparison.worst_index = i;
+            }
+            if e.abs() > 0.0 {
+                comparison.max_rel_error = comparison.max_rel_error.max(abs_error / e.abs());
+            }
+            comparison.max_ulps = comparison.max_ulps.max(ulp_distance(*e, *a));
+        });
+
+    comparison
+}
+
+/// Whether `check_golden` compared against the golden file or wrote it
+#[derive(Debug, Clone, PartialEq)]
+pub enum GoldenOutcome {
+    Recorded,
+    Matched(Vec<FloatComparison>),
+}
+
+// Recorded outputs with the device and tolerance they were recorded with
+struct GoldenFile {
+    device: String,
+    tolerance: Tolerance,
+    outputs: Vec<Vec<f32>>,
+}
+
+impl GoldenFile {
+    // Values are stored as their bits so they round-trip exactly, followed by a readable copy
+    fn serialize(&self) -> String {
+        let mut contents = format!(
+            "{GOLDEN_HEADER}\ndevice\t{}\ntolerance\t{}\t{}\t{}\n",
+            self.device, self.tolerance.abs, self.tolerance.rel, self.tolerance.ulps
+        );
+        self.outputs.iter().enumerate().for_each(|(i, output)| {
+            contents += &format!("output\t{}\t{}\n", i, output.len());
+            output
+                .iter()
+                .for_each(|v| contents += &format!("{:08x}\t{}\n", v.to_bits(), v));
+        });
+        contents
+    }
+
+    fn parse(contents: &str) -> Result<Self, KernelTestError> {
+        let mut lines = contents.lines().enumerate();
+        let mut next = || {
+            lines
+                .next()
+                .map(|(i, l)| (i + 1, l.split('\t').collect::<Vec<&str>>()))
+        };
+        let malformed = |line| KernelTestError::GoldenMalformed { line };
+
+        match next() {
+            Some((_, fields)) if fields == [GOLDEN_HEADER] => (),
+            _ => return Err(malformed(1)),
+        }
+        let device = match next() {
+            Some((_, fields)) if fields.len() == 2 && fields[0] == "device" => fields[1],
+            other => return Err(malformed(other.map_or(2, |(i, _)| i))),
+        };
+        let tolerance = match next() {
+            Some((i, fields)) if fields.len() == 4 && fields[0] == "tolerance" => {
+                match (fields[1].parse(), fields[2].parse(), fields[3].parse()) {
+                    (Ok(abs), Ok(rel), Ok(ulps)) => Tolerance { abs, rel, ulps },
+                    _ => return Err(malformed(i)),
+                }
+            }
+            other => return Err(malformed(other.map_or(3, |(i, _)| i))),
+        };
+
+        let mut outputs = Vec::new();
+        while let Some((i, fields)) = next() {
+            let len: usize = match fields.as_slice() {
+                ["output", index, len] if index.parse() == Ok(outputs.len()) => {
+                    len.parse().map_err(|_| malformed(i))?
+                }
+                _ => return Err(malformed(i)),
+            };
+
+            let mut output = Vec::new();
+            for _ in 0..len {
+                let (i, fields) = next().ok_or(malformed(i + output.len() + 1))?;
+                let bits = fields
+                    .first()
+                    .and_then(|b| u32::from_str_radix(b, 16).ok())
+                    .ok_or(malformed(i))?;
+                output.push(f32::from_bits(bits));
+            }
+            outputs.push(output);
+        }
+
+        Ok(GoldenFile {
+            device: device.to_string(),
+            tolerance,
+            outputs,
+        })
+    }
+}
+
+/// Bindings are the inputs followed by the outputs, in the order they were added
+pub struct KernelTest<'a> {
+    source: ShaderSource<'a>,
+    name: String,
+    inputs: Vec<Vec<f32>>,
+    output_lens: Vec<usize>,
+    // `None` for outputs that are only checked against golden files
+    expected_outputs: Vec<Option<Vec<f32>>>,
+    work_group: Option<WorkGroupSize>,
+    tolerance: Tolerance,
+}
+
+impl<'a> KernelTest<'a> {
+    pub fn new(source: ShaderSource<'a>, name: &str) -> Self {
+        KernelTest {
+            source,
+            name: name.to_string(),
+            inputs: Vec::new(),
+            output_lens: Vec::new(),
+            expected_outputs: Vec::new(),
+            work_group: None,
+            tolerance: Tolerance::default(),
+        }
+    }
+
+    pub fn input(mut self, data: Vec<f32>) -> Self {
+        self.inputs.push(data);
+        self
+    }
+
+    /// The output tensor has the same length as `expected` and starts zeroed
+    pub fn expect_output(mut self, expected: Vec<f32>) -> Self {
+        self.output_lens.push(expected.len());
+        self.expected_outputs.push(Some(expected));
+        self
+    }
+
+    /// A zeroed output of `len` elements with no expected values, for `check_golden`
+    pub fn output(mut self, len: usize) -> Self {
+        self.output_lens.push(len);
+        self.expected_outputs.push(None);
+        self
+    }
+
+    /// Defaults to enough work groups of the shader's local size to cover the longest tensor
+    pub fn work_group(mut self, work_group: WorkGroupSize) -> Self {
+        self.work_group = Some(work_group);
+        self
+    }
+
+    pub fn tolerance(mut self, tolerance: Tolerance) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Runs the kernel and returns its outputs without checking them
+    pub fn run(&self, gpu: &Arc<ComputeManager>) -> Result<Vec<Vec<f32>>, KernelTestError> {
+        let pipeline = gpu
+            .clone()
+            .get_or_build_pipeline(
+                self.source,
+                &self.name,
+                (self.inputs.len() + self.output_lens.len()) as u32,
+            )
+            .map_err(KernelTestError::PipelineCreationFailure)?;
+
+        let inputs: Vec<Tensor> = self
+            .inputs
+            .iter()
+            .map(|data| gpu.create_tensor(Array1::from(data.clone()), false))
+            .collect();
+        let mut outputs: Vec<Tensor> = self
+            .output_lens
+            .iter()
+            .map(|len| gpu.create_tensor(Array1::zeros(*len), true))
+            .collect();
+
+        let work_group = self.work_group.unwrap_or_else(|| {
+            let local_x = pipeline.local_size().map_or(1, |[x, _, _]| x.max(1));
+            let longest = inputs
+                .iter()
+                .chain(outputs.iter())
+                .map(|t| t.data().len() as u32)
+                .max()
+                .unwrap_or(0);
+            WorkGroupSize {
+                x: longest.div_ceil(local_x).max(1),
+                y: 1,
+                z: 1,
+            }
+        });
+
+        {
+            let bindings: Vec<&Tensor> = inputs.iter().chain(outputs.iter()).collect();
+            let task = gpu
+                .clone()
+                .new_task(&pipeline, bindings.clone())
+                .op_local_sync_device(bindings)
+                .op_pipeline_dispatch(work_group)
+                .op_device_sync_local(outputs.iter().collect())
+                .finalize()
+                .map_err(KernelTestError::TaskRecordingFailure)?;
+
+            let sync = match gpu.exec_task(&task) {
+                Some(s) => s,
+                None => return Err(KernelTestError::TaskSubmissionFailure),
+            };
+            gpu.await_task(&sync, outputs.iter_mut().collect())
+                .map_err(KernelTestError::KernelAssertionFailed)?;
+        }
+
+        Ok(outputs.iter().map(|t| t.data().to_vec()).collect())
+    }
+
+    /// Runs the kernel and compares every output element against the expected values
+    pub fn check(&self, gpu: &Arc<ComputeManager>) -> Result<(), KernelTestError> {
+        let outputs = self.run(gpu)?;
+
+        let mismatches: Vec<OutputMismatch> = self
+            .expected_outputs
+            .iter()
+            .zip(outputs.iter())
+            .enumerate()
+            .filter_map(|(output, (expected, actual))| Some((output, (expected.as_ref()?, actual))))
+            .flat_map(|(output, (expected, actual))| {
+                expected
+                    .iter()
+                    .zip(actual.iter())
+                    .enumerate()
+                    .filter(|(_, (e, a))| !self.tolerance.accepts(**e, **a))
+                    .map(move |(index, (e, a))| OutputMismatch {
+                        output,
+                        index,
+                        expected: *e,
+                        actual: *a,
+                    })
+            })
+            .collect();
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(KernelTestError::OutputMismatch(mismatches))
+        }
+    }
+
+    /// Like `check`, but panics with a readable report. Meant to be called from `#[test]`s.
+    pub fn assert(&self, gpu: &Arc<ComputeManager>) {
+        let mismatches = match self.check(gpu) {
+            Ok(_) => return,
+            Err(KernelTestError::OutputMismatch(m)) => m,
+            Err(e) => panic!("Kernel \"{}\" failed to run: {:?}", self.name, e),
+        };
+
+        let report: String = mismatches
+            .iter()
+            .take(MAX_REPORTED_MISMATCHES)
+            .map(|m| {
+                format!(
+                    "\n  output {}[{}]: expected {}, got {}",
+                    m.output, m.index, m.expected, m.actual
+                )
+            })
+            .collect();
+        panic!(
+            "Kernel \"{}\" produced {} mismatched elements:{}",
+            self.name,
+            mismatches.len(),
+            report
+        );
+    }
+
+    /// Compares the outputs against the golden file at `path` with the tolerance it was recorded
+    /// with. Records the file instead if it doesn't exist yet or `GAUSS_UPDATE_GOLDEN` is set.
+    pub fn check_golden(
+        &self,
+        gpu: &Arc<ComputeManager>,
+        path: &Path,
+    ) -> Result<GoldenOutcome, KernelTestError> {
+        let outputs = self.run(gpu)?;
+
+        let update = env::var_os(UPDATE_GOLDEN_VAR).is_some();
+        let contents = match fs::read_to_string(path) {
+            Ok(c) if !update => Some(c),
+            Ok(_) => None,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                log::error!(
+                    "Failed to read golden file {}! Error: {}",
+                    path.display(),
+                    e
+                );
+                return Err(KernelTestError::GoldenReadFailure);
+            }
+        };
+
+        let contents = match contents {
+            Some(c) => c,
+            None => {
+                let golden = GoldenFile {
+                    device: gpu.device_key(),
+                    tolerance: self.tolerance,
+                    outputs,
+                };
+                if let Err(e) = fs::write(path, golden.serialize()) {
+                    log::error!(
+                        "Failed to write golden file {}! Error: {}",
+                        path.display(),
+                        e
+                    );
+                    return Err(KernelTestError::GoldenWriteFailure);
+                }
+                log::info!("Recorded golden file {}", path.display());
+                return Ok(GoldenOutcome::Recorded);
+            }
+        };
+
+        let golden = GoldenFile::parse(&contents)?;
+        if golden.outputs.len() != outputs.len()
+            || golden
+                .outputs
+                .iter()
+                .zip(outputs.iter())
+                .any(|(g, o)| g.len() != o.len())
+        {
+            return Err(KernelTestError::GoldenShapeMismatch);
+        }
+        if golden.device != gpu.device_key() {
+            log::warn!(
+                "Golden file {} was recorded on device {}, comparing on {}",
+                path.display(),
+                golden.device,
+                gpu.device_key()
+            );
+        }
+
+        let comparisons: Vec<FloatComparison> = golden
+            .outputs
+            .iter()
+            .zip(outputs.iter())
+            .enumerate()
+            .map(|(i, (g, o))| compare_floats(i, g, o, golden.tolerance))
+            .collect();
+        if comparisons.iter().any(|c| c.mismatched > 0) {
+            Err(KernelTestError::GoldenMismatch(comparisons))
+        } else {
+            Ok(GoldenOutcome::Matched(comparisons))
+        }
+    }
+
+    /// Like `check_golden`, but panics with the comparison report
+    pub fn assert_golden(&self, gpu: &Arc<ComputeManager>, path: &Path) {
+        let comparisons = match self.check_golden(gpu, path) {
+            Ok(_) => return,
+            Err(KernelTestError::GoldenMismatch(c)) => c,
+            Err(e) => panic!("Kernel \"{}\" golden check failed: {:?}", self.name, e),
+        };
+
+        let report: String = comparisons
+            .iter()
+            .filter(|c| c.mismatched > 0)
+            .map(|c| {
+                format!(
+                    "\n  output {}: {}/{} mismatched, worst at [{}], max abs {}, max rel {}, max {} ulps",
+                    c.output,
+                    c.mismatched,
+                    c.elements,
+                    c.worst_index,
+                    c.max_abs_error,
+                    c.max_rel_error,
+                    c.max_ulps
+                )
+            })
+            .collect();
+        panic!(
+            "Kernel \"{}\" no longer matches {}:{}",
+            self.name,
+            path.display(),
+            report
+        );
+    }
+}
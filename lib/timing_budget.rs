@@ -0,0 +1,210 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use ash::{
+    prelude::VkResult,
+    vk::{self, CommandBuffer, PipelineStageFlags, QueryPool, QueryResultFlags, QueryType},
+};
+
+use super::{device::DeviceInfo, ComputeManager};
+
+const DEFAULT_BUDGET_FACTOR: f64 = 1.5;
+
+// Oldest warnings are dropped once this many are waiting to be taken
+const MAX_QUEUED_WARNINGS: usize = 1024;
+
+/// A budgeted op that ran longer than its expected duration times the budget factor
+#[derive(Debug, Clone)]
+pub struct BudgetWarning {
+    pub shader: String,
+    pub op_index: usize,
+    pub expected: Duration,
+    pub measured: Duration,
+    pub factor: f64,
+}
+
+pub(super) struct TimingBudgets {
+    factor: Mutex<f64>,
+    warnings: Mutex<VecDeque<BudgetWarning>>,
+}
+
+impl TimingBudgets {
+    pub(super) fn new() -> Self {
+        TimingBudgets {
+            factor: Mutex::new(DEFAULT_BUDGET_FACTOR),
+            warnings: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+// A pair of timestamps around every budgeted op of one task
+pub(super) struct OpTimers {
+    query_pool: QueryPool,
+    // Op index and expected duration, in query pair order
+    budgets: Vec<(usize, Duration)>,
+    timestamp_period: f32,
+    submissions: AtomicU64,
+    checked_submissions: AtomicU64,
+}
+
+impl OpTimers {
+    // `None` when the device can't time compute work
+    pub(super) fn new(
+        device_info: &DeviceInfo,
+        budgets: &[(usize, Duration)],
+    ) -> VkResult<Option<Self>> {
+        if budgets.is_empty() {
+            return Ok(None);
+        }
+        if device_info.limits.timestamp_compute_and_graphics == vk::FALSE {
+            log::warn!("Device doesn't support compute timestamps, op budgets are ignored");
+            return Ok(None);
+        }
+
+        let create_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(QueryType::TIMESTAMP)
+            .query_count(budgets.len() as u32 * 2);
+        let query_pool = unsafe { device_info.device.create_query_pool(&create_info, None)? };
+
+        Ok(Some(OpTimers {
+            query_pool,
+            budgets: budgets.to_vec(),
+            timestamp_period: device_info.limits.timestamp_period,
+            submissions: AtomicU64::new(0),
+            checked_submissions: AtomicU64::new(0),
+        }))
+    }
+
+    pub(super) fn cmd_reset(&self, device_info: &DeviceInfo, command_buffer: CommandBuffer) {
+        unsafe {
+            device_info.device.cmd_reset_query_pool(
+                command_buffer,
+                self.query_pool,
+                0,
+                self.budgets.len() as u32 * 2,
+            );
+        }
+    }
+
+    // Timestamps wait for all earlier commands, so the pair brackets just the op
+    pub(super) fn cmd_timestamp(
+        &self,
+        device_info: &DeviceInfo,
+        command_buffer: CommandBuffer,
+        op_index: usize,
+        end: bool,
+    ) {
+        if let Some(pair) = self.budgets.iter().position(|(i, _)| *i == op_index) {
+            unsafe {
+                device_info.device.cmd_write_timestamp(
+                    command_buffer,
+                    PipelineStageFlags::ALL_COMMANDS,
+                    self.query_pool,
+                    pair as u32 * 2 + end as u32,
+                );
+            }
+        }
+    }
+
+    pub(super) fn note_submission(&self) {
+        self.submissions.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Reports ops of the last finished submission that went over budget. Each submission is only
+    // checked once, however often its results are read.
+    pub(super) fn check(&self, parent: &ComputeManager, shader: &str) {
+        let submissions = self.submissions.load(Ordering::Acquire);
+        if self.checked_submissions.swap(submissions, Ordering::AcqRel) == submissions {
+            return;
+        }
+
+        let mut timestamps = vec![0_u64; self.budgets.len() * 2];
+        let result = unsafe {
+            parent.device_info.device.get_query_pool_results(
+                self.query_pool,
+                0,
+                timestamps.len() as u32,
+                &mut timestamps,
+                QueryResultFlags::TYPE_64,
+            )
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to read op timestamps! Error: {}", e);
+            return;
+        }
+
+        let factor = parent.budget_factor();
+        self.budgets
+            .iter()
+            .zip(timestamps.chunks_exact(2))
+            .for_each(|((op_index, expected), pair)| {
+                let ticks = pair[1].saturating_sub(pair[0]);
+                let measured =
+                    Duration::from_nanos((ticks as f64 * self.timestamp_period as f64) as u64);
+                if measured.as_secs_f64() > expected.as_secs_f64() * factor {
+                    parent.report_budget_warning(BudgetWarning {
+                        shader: shader.to_string(),
+                        op_index: *op_index,
+                        expected: *expected,
+                        measured,
+                        factor,
+                    });
+                }
+            });
+    }
+
+    pub(super) fn destroy(&self, device_info: &DeviceInfo) {
+        unsafe {
+            device_info.device.destroy_query_pool(self.query_pool, None);
+        }
+    }
+}
+
+impl ComputeManager {
+    /// Sets how far past its expected duration a budgeted op may run before it's reported.
+    /// Defaults to 1.5.
+    pub fn set_budget_factor(&self, factor: f64) {
+        if let Ok(mut f) = self.timing_budgets.factor.lock() {
+            *f = factor;
+        }
+    }
+
+    pub fn budget_factor(&self) -> f64 {
+        match self.timing_budgets.factor.lock() {
+            Ok(f) => *f,
+            Err(_) => DEFAULT_BUDGET_FACTOR,
+        }
+    }
+
+    /// Drains budget warnings reported since the last call, oldest first
+    pub fn take_budget_warnings(&self) -> Vec<BudgetWarning> {
+        match self.timing_budgets.warnings.lock() {
+            Ok(mut warnings) => warnings.drain(..).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn report_budget_warning(&self, warning: BudgetWarning) {
+        log::warn!(
+            "Op {} of shader \"{}\" took {:?}, over its {:?} budget by more than {}x",
+            warning.op_index,
+            warning.shader,
+            warning.measured,
+            warning.expected,
+            warning.factor
+        );
+
+        if let Ok(mut warnings) = self.timing_budgets.warnings.lock() {
+            if warnings.len() == MAX_QUEUED_WARNINGS {
+                warnings.pop_front();
+            }
+            warnings.push_back(warning);
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::{fmt::Write, fs, path::Path};
+
+use super::{
+    gpu_task::{GPUTask, RecordedOp},
+    ComputeManager, QueueRole, Tensor, WorkGroupSize,
+};
+
+const TRACE_HEADER: &str = "gauss-trace 1";
+
+/// What a task ran and the tensor contents it produced, for comparing runs across machines
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskTrace {
+    /// `ComputeManager::device_key` of the device it was captured on
+    pub device: String,
+    /// Name and SPIR-V hash of every pipeline the task dispatches, its own first
+    pub pipelines: Vec<(String, u64)>,
+    pub specialization: Vec<(u32, u32)>,
+    /// The task's ops, with tensor ids replaced by the bindings of the tensors, which stay the
+    /// same between runs
+    pub ops: Vec<RecordedOp>,
+    /// Captured tensor contents by binding
+    pub tensors: Vec<(u32, Vec<f32>)>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TraceError {
+    TensorNotBound(u32),
+    Io(String),
+    /// The line of the trace file that couldn't be read
+    Malformed(usize),
+}
+
+/// Values differ when they're further apart than both the absolute and the relative tolerance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceTolerance {
+    pub absolute: f32,
+    pub relative: f32,
+}
+
+impl Default for TraceTolerance {
+    fn default() -> Self {
+        TraceTolerance {
+            absolute: 1e-6,
+            relative: 1e-5,
+        }
+    }
+}
+
+/// Where a trace differs from the one it's compared against. Fields named `expected` hold the
+/// values of the trace `diff` was called on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceDifference {
+    PipelineCount {
+        expected: usize,
+        found: usize,
+    },
+    Shader {
+        pipeline: usize,
+        expected: (String, u64),
+        found: (String, u64),
+    },
+    Specialization {
+        expected: Vec<(u32, u32)>,
+        found: Vec<(u32, u32)>,
+    },
+    OpCount {
+        expected: usize,
+        found: usize,
+    },
+    DispatchSize {
+        op_index: usize,
+        expected: WorkGroupSize,
+        found: WorkGroupSize,
+    },
+    Op {
+        op_index: usize,
+        expected: RecordedOp,
+        found: RecordedOp,
+    },
+    /// Captured in only one of the traces
+    UnmatchedTensor {
+        binding: u32,
+    },
+    TensorLength {
+        binding: u32,
+        expected: usize,
+        found: usize,
+    },
+    TensorContents {
+        binding: u32,
+        mismatched: usize,
+        first_index: usize,
+        max_abs_error: f32,
+    },
+}
+
+impl TraceTolerance {
+    fn matches(&self, expected: f32, found: f32) -> bool {
+        if expected.is_nan() || found.is_nan() {
+            return expected.is_nan() && found.is_nan();
+        }
+        let error = (expected - found).abs();
+        error <= self.absolute || error <= self.relative * expected.abs()
+    }
+}
+
+impl ComputeManager {
+    /// Captures `task` along with the current contents of `tensors`, which must be bound by it.
+    /// Run the task and read the tensors back first to capture its results.
+    pub fn capture_trace(
+        &self,
+        task: &GPUTask,
+        tensors: &[&Tensor],
+    ) -> Result<TaskTrace, TraceError> {
+        let binding_of = |tensor_id: &u32| {
+            task.bindings()
+                .iter()
+                .find(|b| b.tensor_id == *tensor_id)
+                .map(|b| b.binding)
+                .unwrap_or(u32::MAX)
+        };
+        let bindings = |tensor_ids: &Vec<u32>| tensor_ids.iter().map(binding_of).collect();
+
+        let ops: Vec<RecordedOp> = task
+            .ops()
+            .iter()
+            .map(|op| match op {
+                RecordedOp::LocalSyncDevice { tensor_ids } => RecordedOp::LocalSyncDevice {
+                    tensor_ids: bindings(tensor_ids),
+                },
+                RecordedOp::PipelineDispatch { .. } => op.clone(),
+                RecordedOp::DeviceSyncLocal { tensor_ids } => RecordedOp::DeviceSyncLocal {
+                    tensor_ids: bindings(tensor_ids),
+                },
+                RecordedOp::ReleaseOwnership { tensor_ids, to } => RecordedOp::ReleaseOwnership {
+                    tensor_ids: bindings(tensor_ids),
+                    to: *to,
+                },
+                RecordedOp::AcquireOwnership { tensor_ids, from } => RecordedOp::AcquireOwnership {
+                    tensor_ids: bindings(tensor_ids),
+                    from: *from,
+                },
+            })
+            .collect();
+
+        let pipeline_count = task
+            .ops()
+            .iter()
+            .filter_map(|op| match op {
+                RecordedOp::PipelineDispatch { pipeline, .. } => Some(*pipeline),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let pipelines = (0..pipeline_count)
+            .map(|i| {
+                let pipeline = task.dispatch_pipeline(i);
+                (pipeline.shader_name().to_string(), pipeline.spirv_hash())
+            })
+            .collect();
+
+        let mut captured = Vec::with_capacity(tensors.len());
+        for tensor in tensors {
+            match task.bindings().iter().find(|b| b.tensor_id == tensor.id) {
+                Some(b) => captured.push((b.binding, tensor.data().to_vec())),
+                None => return Err(TraceError::TensorNotBound(tensor.id)),
+            }
+        }
+        captured.sort_by_key(|(binding, _)| *binding);
+
+        Ok(TaskTrace {
+            device: self.device_key(),
+            pipelines,
+            specialization: task.pipeline.specialization().to_vec(),
+            ops,
+            tensors: captured,
+        })
+    }
+}
+
+fn role_name(role: QueueRole) -> &'static str {
+    match role {
+        QueueRole::Compute => "compute",
+        QueueRole::Transfer => "transfer",
+    }
+}
+
+fn parse_role(name: &str) -> Option<QueueRole> {
+    match name {
+        "compute" => Some(QueueRole::Compute),
+        "transfer" => Some(QueueRole::Transfer),
+        _ => None,
+    }
+}
+
+fn join_bindings(bindings: &[u32]) -> String {
+    bindings
+        .iter()
+        .map(|b| b.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn parse_bindings(field: &str) -> Option<Vec<u32>> {
+    if field.is_empty() {
+        return Some(Vec::new());
+    }
+    field.split(',').map(|b| b.parse().ok()).collect()
+}
+
+impl TaskTrace {
+    /// Writes the trace as text, with tensor values as exact bit patterns
+    pub fn save(&self, path: &Path) -> Result<(), TraceError> {
+        let mut text = format!("{TRACE_HEADER}\ndevice\t{}\n", self.device);
+        self.pipelines.iter().for_each(|(name, hash)| {
+            let _ = writeln!(text, "pipeline\t{hash:016x}\t{name}");
+        });
+        self.specialization.iter().for_each(|(id, value)| {
+            let _ = writeln!(text, "spec\t{id}\t{value}");
+        });
+        self.ops.iter().for_each(|op| {
+            let _ = match op {
+                RecordedOp::LocalSyncDevice { tensor_ids } => {
+                    writeln!(text, "op\tupload\t{}", join_bindings(tensor_ids))
+                }
+                RecordedOp::PipelineDispatch {
+                    work_group,
+                    pipeline,
+                } => writeln!(
+                    text,
+                    "op\tdispatch\t{}\t{}\t{}\t{}",
+                    work_group.x, work_group.y, work_group.z, pipeline
+                ),
+                RecordedOp::DeviceSyncLocal { tensor_ids } => {
+                    writeln!(text, "op\treadback\t{}", join_bindings(tensor_ids))
+                }
+                RecordedOp::ReleaseOwnership { tensor_ids, to } => writeln!(
+                    text,
+                    "op\trelease\t{}\t{}",
+                    join_bindings(tensor_ids),
+                    role_name(*to)
+                ),
+                RecordedOp::AcquireOwnership { tensor_ids, from } => writeln!(
+                    text,
+                    "op\tacquire\t{}\t{}",
+                    join_bindings(tensor_ids),
+                    role_name(*from)
+                ),
+            };
+        });
+        self.tensors.iter().for_each(|(binding, data)| {
+            let words: Vec<String> = data
+                .iter()
+                .map(|v| format!("{:08x}", v.to_bits()))
+                .collect();
+            let _ = writeln!(text, "tensor\t{}\t{}", binding, words.join(" "));
+        });
+
+        fs::write(path, text).map_err(|e| {
+            log::error!("Failed to write trace {}! Error: {}", path.display(), e);
+            TraceError::Io(e.to_string())
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<TaskTrace, TraceError> {
+        let text = fs::read_to_string(path).map_err(|e| TraceError::Io(e.to_string()))?;
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, l)| l) != Some(TRACE_HEADER) {
+            return Err(TraceError::Malformed(1));
+        }
+
+        let mut trace = TaskTrace {
+            device: String::new(),
+            pipelines: Vec::new(),
+            specialization: Vec::new(),
+            ops: Vec::new(),
+            tensors: Vec::new(),
+        };
+        for (index, line) in lines {
+            if line.is_empty() {
+                continue;
+            }
+            if trace.parse_line(line).is_none() {
+                return Err(TraceError::Malformed(index + 1));
+            }
+        }
+
+        Ok(trace)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Option<()> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["device", device] => self.device = device.to_string(),
+            ["pipeline", hash, name] => self
+                .pipelines
+                .push((name.to_string(), u64::from_str_radix(hash, 16).ok()?)),
+            ["spec", id, value] => self
+                .specialization
+                .push((id.parse().ok()?, value.parse().ok()?)),
+            ["op", "upload", bindings] => self.ops.push(RecordedOp::LocalSyncDevice {
+                tensor_ids: parse_bindings(bindings)?,
+            }),
+            ["op", "dispatch", x, y, z, pipeline] => self.ops.push(RecordedOp::PipelineDispatch {
+                work_group: WorkGroupSize {
+                    x: x.parse().ok()?,
+                    y: y.parse().ok()?,
+                    z: z.parse().ok()?,
+                },
+                pipeline: pipeline.parse().ok()?,
+            }),
+            ["op", "readback", bindings] => self.ops.push(RecordedOp::DeviceSyncLocal {
+                tensor_ids: parse_bindings(bindings)?,
+            }),
+            ["op", "release", bindings, role] => self.ops.push(RecordedOp::ReleaseOwnership {
+                tensor_ids: parse_bindings(bindings)?,
+                to: parse_role(role)?,
+            }),
+            ["op", "acquire", bindings, role] => self.ops.push(RecordedOp::AcquireOwnership {
+                tensor_ids: parse_bindings(bindings)?,
+                from: parse_role(role)?,
+            }),
+            ["tensor", binding, words] => {
+                let data = words
+                    .split(' ')
+                    .filter(|w| !w.is_empty())
+                    .map(|w| u32::from_str_radix(w, 16).ok().map(f32::from_bits))
+                    .collect::<Option<Vec<f32>>>()?;
+                self.tensors.push((binding.parse().ok()?, data));
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Compares `other` against this trace. Devices aren't compared, as traces from different
+    /// machines are what this is for.
+    pub fn diff(&self, other: &TaskTrace, tolerance: TraceTolerance) -> Vec<TraceDifference> {
+        let mut differences = Vec::new();
+
+        if self.pipelines.len() != other.pipelines.len() {
+            differences.push(TraceDifference::PipelineCount {
+                expected: self.pipelines.len(),
+                found: other.pipelines.len(),
+            });
+        }
+        self.pipelines
+            .iter()
+            .zip(&other.pipelines)
+            .enumerate()
+            .filter(|(_, (expected, found))| expected.1 != found.1)
+            .for_each(|(pipeline, (expected, found))| {
+                differences.push(TraceDifference::Shader {
+                    pipeline,
+                    expected: expected.clone(),
+                    found: found.clone(),
+                })
+            });
+        if self.specialization != other.specialization {
+            differences.push(TraceDifference::Specialization {
+                expected: self.specialization.clone(),
+                found: other.specialization.clone(),
+            });
+        }
+
+        if self.ops.len() != other.ops.len() {
+            differences.push(TraceDifference::OpCount {
+                expected: self.ops.len(),
+                found: other.ops.len(),
+            });
+        }
+        self.ops
+            .iter()
+            .zip(&other.ops)
+            .enumerate()
+            .filter(|(_, (expected, found))| expected != found)
+            .for_each(|(op_index, (expected, found))| {
+                differences.push(match (expected, found) {
+                    (
+                        RecordedOp::PipelineDispatch {
+                            work_group: expected,
+                            pipeline: p,
+                        },
+                        RecordedOp::PipelineDispatch {
+                            work_group: found,
+                            pipeline: q,
+                        },
+                    ) if p == q => TraceDifference::DispatchSize {
+                        op_index,
+                        expected: *expected,
+                        found: *found,
+                    },
+                    _ => TraceDifference::Op {
+                        op_index,
+                        expected: expected.clone(),
+                        found: found.clone(),
+                    },
+                })
+            });
+
+        let unmatched = |a: &TaskTrace, b: &TaskTrace| -> Vec<u32> {
+            a.tensors
+                .iter()
+                .filter(|(binding, _)| !b.tensors.iter().any(|(other, _)| other == binding))
+                .map(|(binding, _)| *binding)
+                .collect()
+        };
+        unmatched(self, other)
+            .into_iter()
+            .chain(unmatched(other, self))
+            .for_each(|binding| differences.push(TraceDifference::UnmatchedTensor { binding }));
+
+        for (binding, expected) in &self.tensors {
+            let found = match other.tensors.iter().find(|(b, _)| b == binding) {
+                Some((_, data)) => data,
+                None => continue,
+            };
+            if expected.len() != found.len() {
+                differences.push(TraceDifference::TensorLength {
+                    binding: *binding,
+                    expected: expected.len(),
+                    found: found.len(),
+                });
+                continue;
+            }
+
+            let mismatches: Vec<(usize, f32)> = expected
+                .iter()
+                .zip(found)
+                .enumerate()
+                .filter(|(_, (e, f))| !tolerance.matches(**e, **f))
+                .map(|(i, (e, f))| (i, (e - f).abs()))
+                .collect();
+            if let Some((first_index, _)) = mismatches.first() {
+                differences.push(TraceDifference::TensorContents {
+                    binding: *binding,
+                    mismatched: mismatches.len(),
+                    first_index: *first_index,
+                    max_abs_error: mismatches
+                        .iter()
+                        .map(|(_, e)| *e)
+                        .filter(|e| !e.is_nan())
+                        .fold(0.0, f32::max),
+                });
+            }
+        }
+
+        differences
+    }
+}
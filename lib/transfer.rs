@@ -0,0 +1,340 @@
+use ash::vk::{
+    self, AccessFlags, BufferCopy, BufferUsageFlags, CommandBuffer, CommandBufferUsageFlags,
+    PipelineStageFlags,
+};
+
+use super::{
+    allocation_strategy::{Buffer, HostMemoryLocation},
+    barrier::{self, Barrier},
+    command_buffer_util,
+    gpu_task::{free_buffer, GPUTask, TaskPriority},
+    host_staging::HostStagingHints,
+    resource_state::ResourceStates,
+    staging, ComputeManager, Tensor,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum TransferError {
+    TensorNotOnDevice(u32),
+    SizeMismatch(u32),
+    BufferAllocationFailure,
+    FlushFailure,
+    InvalidateFailure,
+    CommandBufferRecordingFailure,
+    TaskSubmissionFailure,
+    TaskExecutionFailure,
+    HazardTrackerLockFailure,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TransferDirection {
+    Upload,
+    Download,
+}
+
+impl ComputeManager {
+    /// Overwrites the latest device contents of `tensor`, as left by the last task that wrote it,
+    /// with its host data. Later tasks that read the tensor on the device see the new contents.
+    pub fn upload(&self, tensor: &Tensor) -> Result<(), TransferError> {
+        let size = tensor.data().len() as u64 * 4;
+        self.transfer(
+            tensor.id(),
+            size,
+            TransferDirection::Upload,
+            tensor.staging_location,
+            tensor.staging_hints,
+            |mapped| unsafe {
+                mapped.copy_from(tensor.data().as_ptr() as *const u8, size as usize);
+            },
+        )
+    }
+
+    /// Reads the latest device contents of `tensor` into its host data without running a task.
+    pub fn download(&self, tensor: &mut Tensor) -> Result<(), TransferError> {
+        let size = tensor.data().len() as u64 * 4;
+        let location = tensor.readback_location;
+        let destination = tensor.data_mut().as_mut_ptr() as *mut u8;
+        self.transfer(
+            tensor.id(),
+            size,
+            TransferDirection::Download,
+            location,
+            HostStagingHints::default(),
+            |mapped| unsafe {
+                destination.copy_from(mapped, size as usize);
+            },
+        )?;
+        tensor.apply_readback_transform();
+
+        Ok(())
+    }
+
+    // Uploads `data` into a tensor's buffer of a task that hasn't run yet, at byte `offset` into the
+    // tensor. Lets a task's input be filled piece by piece without a host copy of all of it.
+    pub(super) fn upload_to_task(
+        &self,
+        task: &GPUTask,
+        tensor_id: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), TransferError> {
+        let size = data.len() as u64;
+        let (buffer, base) = match (task.device_range(tensor_id), task.buffer_size(tensor_id)) {
+            (Some(r), Some(s)) if offset.checked_add(size).is_some_and(|e| e <= s) => r,
+            (Some(_), Some(_)) => return Err(TransferError::SizeMismatch(tensor_id)),
+            _ => return Err(TransferError::TensorNotOnDevice(tensor_id)),
+        };
+        if size == 0 {
+            return Ok(());
+        }
+
+        let _turn = self.submission_thread.wait_turn(TaskPriority::Normal);
+        self.copy_through_staging(
+            (buffer, base + offset),
+            size,
+            TransferDirection::Upload,
+            HostMemoryLocation::CpuToGpu,
+            HostStagingHints::default(),
+            |mapped| unsafe {
+                mapped.copy_from(data.as_ptr(), data.len());
+            },
+        )
+    }
+
+    // Copies between the tensor's device buffer and a temporary host-visible buffer. The
+    // submission takes a turn like any task and holds the hazard tracker until it finishes, so the
+    // device buffer can't be replaced or freed underneath it.
+    fn transfer<F>(
+        &self,
+        tensor_id: u32,
+        size: u64,
+        direction: TransferDirection,
+        location: HostMemoryLocation,
+        hints: HostStagingHints,
+        host_copy: F,
+    ) -> Result<(), TransferError>
+    where
+        F: FnOnce(*mut u8),
+    {
+        let _turn = self.submission_thread.wait_turn(TaskPriority::Normal);
+        let mut hazard_tracker = match self.hazard_tracker.lock() {
+            Ok(h) => h,
+            Err(e) => {
+                log::error!("Failed to acquire hazard tracker! Error: {e}");
+                return Err(TransferError::HazardTrackerLockFailure);
+            }
+        };
+
+        let device_range = match hazard_tracker.device_location(tensor_id) {
+            Some((buffer, offset, device_size)) if device_size == size => (buffer, offset),
+            Some(_) => return Err(TransferError::SizeMismatch(tensor_id)),
+            None => return Err(TransferError::TensorNotOnDevice(tensor_id)),
+        };
+        if size == 0 {
+            return Ok(());
+        }
+
+        self.copy_through_staging(device_range, size, direction, location, hints, host_copy)
+            .map(|_| {
+                if direction == TransferDirection::Upload {
+                    hazard_tracker.note_transfer_write(tensor_id);
+                }
+            })
+    }
+
+    fn copy_through_staging<F>(
+        &self,
+        device_range: (vk::Buffer, u64),
+        size: u64,
+        direction: TransferDirection,
+        location: HostMemoryLocation,
+        hints: HostStagingHints,
+        host_copy: F,
+    ) -> Result<(), TransferError>
+    where
+        F: FnOnce(*mut u8),
+    {
+        let staging_buffer = match self.allocator.write() {
+            Ok(mut allocator) => match allocator.allocate_buffer_with_hints(
+                &self.device_info,
+                size,
+                match direction {
+                    TransferDirection::Upload => BufferUsageFlags::TRANSFER_SRC,
+                    TransferDirection::Download => BufferUsageFlags::TRANSFER_DST,
+                },
+                location.memory_location(),
+                hints,
+                "transfer_staging_alloc",
+                self.device_info.queue_indices.compute_queue.unwrap(),
+            ) {
+                Ok(b) => b,
+                Err(e) => {
+                    log::error!("Failed to allocate buffer! Error: {:?}", e);
+                    return Err(TransferError::BufferAllocationFailure);
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to acquire allocator! Error: {e}");
+                return Err(TransferError::BufferAllocationFailure);
+            }
+        };
+        let mapped = staging_buffer.mapped_ptr().unwrap().as_ptr() as *mut u8;
+
+        // The staging memory may not be host-coherent
+        let result = match direction {
+            TransferDirection::Upload => {
+                host_copy(mapped);
+                match staging::flush_buffer(&self.device_info, &staging_buffer) {
+                    Ok(_) => self.submit_transfer(device_range, &staging_buffer, size, direction),
+                    Err(e) => {
+                        log::error!("Failed to flush staging buffer! Error: {}", e);
+                        Err(TransferError::FlushFailure)
+                    }
+                }
+            }
+            TransferDirection::Download => self
+                .submit_transfer(device_range, &staging_buffer, size, direction)
+                .and_then(|_| {
+                    match staging::invalidate_buffer(&self.device_info, &staging_buffer) {
+                        Ok(_) => {
+                            host_copy(mapped);
+                            Ok(())
+                        }
+                        Err(e) => {
+                            log::error!("Failed to invalidate staging buffer! Error: {}", e);
+                            Err(TransferError::InvalidateFailure)
+                        }
+                    }
+                }),
+        };
+
+        match self.allocator.write() {
+            Ok(mut allocator) => free_buffer(&self.device_info, &mut allocator, staging_buffer),
+            Err(e) => log::error!("Failed to acquire allocator! Leaking buffer. Error: {e}"),
+        }
+
+        result
+    }
+
+    fn submit_transfer(
+        &self,
+        device_range: (vk::Buffer, u64),
+        staging_buffer: &Buffer,
+        size: u64,
+        direction: TransferDirection,
+    ) -> Result<(), TransferError> {
+        let device = &self.device_info.device;
+        let command_buffer = match command_buffer_util::allocate_command_buffer(&self.device_info) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to allocate command buffer! Error: {}", e);
+                return Err(TransferError::CommandBufferRecordingFailure);
+            }
+        };
+
+        let result = self.record_transfer(
+            command_buffer,
+            device_range,
+            staging_buffer,
+            size,
+            direction,
+        );
+        let result = result.and_then(|_| {
+            let fence = match self.submission_thread.submit(&[command_buffer]) {
+                Ok(f) => f,
+                Err(e) => {
+                    log::error!("Failed to submit command buffer! Error: {}", e);
+                    return Err(TransferError::TaskSubmissionFailure);
+                }
+            };
+
+            let result = unsafe { device.wait_for_fences(&[fence], true, u64::MAX) };
+            self.submission_thread.destroy_fence(fence);
+            match result {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    log::error!("Failed to wait for transfer! Error: {}", e);
+                    Err(TransferError::TaskExecutionFailure)
+                }
+            }
+        });
+
+        command_buffer_util::free_command_buffers(&self.device_info, &[command_buffer]);
+
+        result
+    }
+
+    fn record_transfer(
+        &self,
+        command_buffer: CommandBuffer,
+        device_range: (vk::Buffer, u64),
+        staging_buffer: &Buffer,
+        size: u64,
+        direction: TransferDirection,
+    ) -> Result<(), TransferError> {
+        let device = &self.device_info.device;
+
+        if let Err(e) = command_buffer_util::begin_command_buffer_recording(
+            device,
+            command_buffer,
+            CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        ) {
+            log::error!("Failed to begin command buffer recording! Error: {}", e);
+            return Err(TransferError::CommandBufferRecordingFailure);
+        }
+
+        let ((source, src_offset), (destination, dst_offset)) = match direction {
+            TransferDirection::Upload => ((staging_buffer.buffer, 0), device_range),
+            TransferDirection::Download => (device_range, (staging_buffer.buffer, 0)),
+        };
+
+        // The device buffer starts out unknown, so earlier submissions still using it are waited on
+        let mut states = ResourceStates::new();
+        let barriers: Vec<Barrier> = states
+            .access(
+                source,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_READ,
+            )
+            .into_iter()
+            .chain(states.access(
+                destination,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_WRITE,
+            ))
+            .collect();
+        barrier::cmd_barriers(&self.device_info, command_buffer, &barriers);
+
+        unsafe {
+            device.cmd_copy_buffer(
+                command_buffer,
+                source,
+                destination,
+                &[BufferCopy {
+                    src_offset,
+                    dst_offset,
+                    size,
+                }],
+            );
+        }
+
+        if direction == TransferDirection::Download {
+            let host_barriers: Vec<Barrier> = states
+                .access(
+                    destination,
+                    PipelineStageFlags::HOST,
+                    AccessFlags::HOST_READ,
+                )
+                .into_iter()
+                .collect();
+            barrier::cmd_barriers(&self.device_info, command_buffer, &host_barriers);
+        }
+
+        if let Err(e) = unsafe { device.end_command_buffer(command_buffer) } {
+            log::error!("Failed to end command buffer recording! Error: {}", e);
+            return Err(TransferError::CommandBufferRecordingFailure);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::VecDeque,
+    ffi::{CStr, CString},
+    slice,
+    sync::Mutex,
+};
+
+use ash::vk::{self, CommandBuffer, DebugUtilsLabelEXT, DebugUtilsObjectNameInfoEXT, Handle};
+
+use super::{gpu_task::GPUTaskOpKind, ComputeManager};
+
+// Objects gauss names are tagged with their shader, and op labels with the op's index, so
+// validation messages can be traced back to the kernel that caused them
+const OBJECT_NAME_PREFIX: &str = "gauss:";
+const OP_LABEL_PREFIX: &str = "gauss-op:";
+
+// Oldest messages are dropped once this many are waiting to be taken
+const MAX_QUEUED_MESSAGES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationSeverity {
+    Verbose,
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationMessage {
+    pub severity: ValidationSeverity,
+    pub message_id: String,
+    pub message: String,
+    /// Shader of the pipeline or task the message refers to
+    pub shader: Option<String>,
+    /// Index of the task op that was recording or executing
+    pub op_index: Option<u32>,
+}
+
+pub(super) struct ValidationQueue {
+    messages: Mutex<VecDeque<ValidationMessage>>,
+}
+
+impl ValidationQueue {
+    pub(super) fn new() -> Self {
+        ValidationQueue {
+            messages: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(super) fn push(&self, message: ValidationMessage) {
+        if let Ok(mut messages) = self.messages.lock() {
+            if messages.len() == MAX_QUEUED_MESSAGES {
+                messages.pop_front();
+            }
+            messages.push_back(message);
+        }
+    }
+}
+
+// Reads the shader and op index back out of the names and labels the layer reports
+pub(super) unsafe fn attribute(
+    callback_data: &vk::DebugUtilsMessengerCallbackDataEXT,
+) -> (Option<String>, Option<u32>) {
+    let objects = if callback_data.p_objects.is_null() {
+        &[][..]
+    } else {
+        slice::from_raw_parts(callback_data.p_objects, callback_data.object_count as usize)
+    };
+    let shader = objects
+        .iter()
+        .filter(|o| !o.p_object_name.is_null())
+        .find_map(|o| {
+            CStr::from_ptr(o.p_object_name)
+                .to_string_lossy()
+                .strip_prefix(OBJECT_NAME_PREFIX)
+                .map(str::to_string)
+        });
+
+    let labels = if callback_data.p_cmd_buf_labels.is_null() {
+        &[][..]
+    } else {
+        slice::from_raw_parts(
+            callback_data.p_cmd_buf_labels,
+            callback_data.cmd_buf_label_count as usize,
+        )
+    };
+    // Innermost label last
+    let op_index = labels
+        .iter()
+        .rev()
+        .filter(|l| !l.p_label_name.is_null())
+        .find_map(|l| {
+            let label = CStr::from_ptr(l.p_label_name).to_string_lossy();
+            label
+                .strip_prefix(OP_LABEL_PREFIX)?
+                .split(':')
+                .next()?
+                .parse()
+                .ok()
+        });
+
+    (shader, op_index)
+}
+
+impl ComputeManager {
+    /// Drains validation messages reported since the last call, oldest first. Only filled when
+    /// validation is enabled.
+    pub fn take_validation_messages(&self) -> Vec<ValidationMessage> {
+        match self.instance_info.validation_queue.messages.lock() {
+            Ok(mut messages) => messages.drain(..).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub(super) fn name_object<H: Handle>(&self, handle: H, shader_name: &str) {
+        let debug_utils = match self.instance_info.debug_utils_loader.as_ref() {
+            Some(d) => d,
+            None => return,
+        };
+        let name = match CString::new(format!("{OBJECT_NAME_PREFIX}{shader_name}")) {
+            Ok(n) => n,
+            Err(_) => return,
+        };
+
+        let name_info = DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(H::TYPE)
+            .object_handle(handle.as_raw())
+            .object_name(&name);
+        unsafe {
+            let _ = debug_utils
+                .set_debug_utils_object_name(self.device_info.device.handle(), &name_info);
+        }
+    }
+
+    pub(super) fn begin_op_label(
+        &self,
+        command_buffer: CommandBuffer,
+        op_index: usize,
+        op_kind: GPUTaskOpKind,
+    ) {
+        let debug_utils = match self.instance_info.debug_utils_loader.as_ref() {
+            Some(d) => d,
+            None => return,
+        };
+        let name = CString::new(format!("{OP_LABEL_PREFIX}{op_index}:{op_kind:?}")).unwrap();
+
+        let label = DebugUtilsLabelEXT::builder().label_name(&name);
+        unsafe { debug_utils.cmd_begin_debug_utils_label(command_buffer, &label) };
+    }
+
+    pub(super) fn end_op_label(&self, command_buffer: CommandBuffer) {
+        if let Some(debug_utils) = self.instance_info.debug_utils_loader.as_ref() {
+            unsafe { debug_utils.cmd_end_debug_utils_label(command_buffer) };
+        }
+    }
+}
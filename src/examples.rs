@@ -0,0 +1,609 @@
+use std::{process::ExitCode, sync::Arc};
+
+use gauss::{
+    compute_init, testing::Tolerance, AllocatorLogConfig, ComputeManager, CustomOp, LogConfig,
+    Reduction, ReductionOrder, ShaderSource, ValidationLayerLogConfig, WorkGroupSize,
+};
+use indoc::indoc;
+use ndarray::prelude::*;
+
+const USAGE: &str = indoc! {"
+    Usage: gauss-examples [OPTIONS] [DEMO...]
+
+    Runs gauss demos on the best available device and checks their results on the host. Runs
+    every demo when none are named.
+
+    Options:
+        --list          List the demos and exit
+        --size N        Elements per tensor [default: 65536]
+        --validation    Enable the Khronos validation layer
+        -h, --help      Print this help
+"};
+
+const LOCAL_SIZE_X: u32 = 64;
+
+// Each demo goes through a different part of the API and returns a summary of what it checked
+struct Demo {
+    name: &'static str,
+    description: &'static str,
+    run: fn(&Arc<ComputeManager>, usize) -> Result<String, String>,
+}
+
+const DEMOS: [Demo; 6] = [
+    Demo {
+        name: "saxpy",
+        description: "A task recorded by hand: upload, dispatch and readback",
+        run: saxpy,
+    },
+    Demo {
+        name: "parallel",
+        description: "Independent tasks spread over every compute queue by the executor",
+        run: parallel,
+    },
+    Demo {
+        name: "reduction",
+        description: "Built-in reductions in both combination orders",
+        run: reduction,
+    },
+    Demo {
+        name: "matmul",
+        description: "A 2D dispatch sized by specialization constants",
+        run: matmul,
+    },
+    Demo {
+        name: "blur",
+        description: "An image blur as a custom op, checked against its host reference",
+        run: blur,
+    },
+    Demo {
+        name: "simulation",
+        description: "A stepper advancing oscillators on the device with periodic readback",
+        run: simulation,
+    },
+];
+
+struct Options {
+    list: bool,
+    size: usize,
+    demos: Vec<String>,
+    validation: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        list: false,
+        size: 1 << 16,
+        demos: Vec::new(),
+        validation: false,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+        match arg.as_str() {
+            "--list" => options.list = true,
+            "--validation" => options.validation = true,
+            "--size" => {
+                options.size = value("--size")?
+                    .parse()
+                    .map_err(|e| format!("Invalid --size: {e}"))?
+            }
+            "-h" | "--help" => return Err(String::new()),
+            other if other.starts_with('-') => return Err(format!("Unknown argument \"{other}\"")),
+            other => options.demos.push(other.to_string()),
+        }
+    }
+
+    if options.size == 0 {
+        return Err("--size must be at least 1".to_string());
+    }
+    if let Some(unknown) = options
+        .demos
+        .iter()
+        .find(|name| !DEMOS.iter().any(|demo| demo.name == name.as_str()))
+    {
+        return Err(format!("Unknown demo \"{unknown}\""));
+    }
+
+    Ok(options)
+}
+
+fn dispatch_size(invocations: usize) -> WorkGroupSize {
+    WorkGroupSize {
+        x: (invocations as u32).div_ceil(LOCAL_SIZE_X),
+        y: 1,
+        z: 1,
+    }
+}
+
+fn max_abs_error(expected: &[f32], actual: &[f32]) -> f32 {
+    expected
+        .iter()
+        .zip(actual)
+        .map(|(e, a)| (e - a).abs())
+        .fold(0.0, f32::max)
+}
+
+const SAXPY_SHADER: &str = indoc! {"
+    #version 450
+    layout (local_size_x = 64) in;
+    layout(set = 0, binding = 0) buffer buf_x   { float x[]; };
+    layout(set = 0, binding = 1) buffer buf_y   { float y[]; };
+    layout(set = 0, binding = 2) buffer buf_out { float out_a[]; };
+    void main() {
+        uint i = gl_GlobalInvocationID.x;
+        if (i >= out_a.length()) return;
+        out_a[i] = 2.0 * x[i] + y[i];
+    }
+"};
+
+fn saxpy(gpu: &Arc<ComputeManager>, size: usize) -> Result<String, String> {
+    let pipeline = gpu
+        .clone()
+        .get_or_build_pipeline(ShaderSource::Glsl(SAXPY_SHADER), "saxpy", 3)
+        .map_err(|e| format!("{e:?}"))?;
+
+    let x = gpu.create_tensor(Array1::from_shape_fn(size, |i| i as f32 * 0.5), false);
+    let y = gpu.create_tensor(Array1::from_shape_fn(size, |i| (i % 7) as f32), false);
+    let mut out = gpu.create_tensor(Array1::zeros(size), true);
+
+    let task = gpu
+        .clone()
+        .new_task(&pipeline, vec![&x, &y, &out])
+        .op_local_sync_device(vec![&x, &y])
+        .op_pipeline_dispatch(dispatch_size(size))
+        .op_device_sync_local(vec![&out])
+        .finalize()
+        .map_err(|e| format!("{e:?}"))?;
+
+    let running_task = gpu
+        .exec_task(&task)
+        .ok_or("Failed to submit task".to_string())?;
+    gpu.await_task(&running_task, vec![&mut out])
+        .map_err(|e| format!("{e:?}"))?;
+
+    let expected = x.data() * 2.0 + y.data();
+    let error = max_abs_error(&expected.to_vec(), &out.data().to_vec());
+    if error > 0.0 {
+        return Err(format!("Output is off by up to {error:e}"));
+    }
+
+    Ok(format!("{size} elements match the host exactly"))
+}
+
+fn parallel(gpu: &Arc<ComputeManager>, size: usize) -> Result<String, String> {
+    let pipeline = gpu
+        .clone()
+        .get_or_build_pipeline(ShaderSource::Glsl(SAXPY_SHADER), "saxpy", 3)
+        .map_err(|e| format!("{e:?}"))?;
+
+    // Each task gets its own inputs, so none depends on another's results
+    let inputs: Vec<_> = (0..4)
+        .map(|t| {
+            let x = Array1::from_shape_fn(size, |i| (i + t) as f32 * 0.25);
+            let y = Array1::from_shape_fn(size, |i| ((i * t) % 5) as f32);
+            (gpu.create_tensor(x, false), gpu.create_tensor(y, false))
+        })
+        .collect();
+    let outputs: Vec<_> = inputs
+        .iter()
+        .map(|_| gpu.create_tensor(Array1::zeros(size), true))
+        .collect();
+    let tasks = inputs
+        .iter()
+        .zip(&outputs)
+        .map(|((x, y), out)| {
+            gpu.clone()
+                .new_task(&pipeline, vec![x, y, out])
+                .op_local_sync_device(vec![x, y])
+                .op_pipeline_dispatch(dispatch_size(size))
+                .op_device_sync_local(vec![out])
+                .finalize()
+                .map_err(|e| format!("{e:?}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let report = gpu
+        .execute_parallel(&tasks.iter().collect::<Vec<_>>())
+        .map_err(|e| format!("{e:?}"))?;
+
+    for (((x, y), out), task) in inputs.iter().zip(&outputs).zip(&tasks) {
+        let expected = x.data() * 2.0 + y.data();
+        let actual = out.peek(task, 0..size).map_err(|e| format!("{e:?}"))?;
+        let error = max_abs_error(&expected.to_vec(), &actual);
+        if error > 0.0 {
+            return Err(format!("Output is off by up to {error:e}"));
+        }
+    }
+
+    Ok(format!(
+        "{} tasks match the host exactly, run per queue: {:?}",
+        tasks.len(),
+        report.tasks_per_queue
+    ))
+}
+
+fn reduction(gpu: &Arc<ComputeManager>, size: usize) -> Result<String, String> {
+    let data = Array1::from_shape_fn(size, |i| ((i * 7919) % 1000) as f32 * 0.001);
+    let input = gpu.create_tensor(data.clone(), false);
+    let mut output = gpu.create_tensor(Array1::zeros(1), true);
+
+    let mut run = |reduction, order| -> Result<f32, String> {
+        gpu.clone()
+            .reduce(reduction, order, &input, &mut output)
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(output.data()[0])
+    };
+
+    let first = run(Reduction::Sum, ReductionOrder::Deterministic)?;
+    let second = run(Reduction::Sum, ReductionOrder::Deterministic)?;
+    if first.to_bits() != second.to_bits() {
+        return Err(format!(
+            "Deterministic sums differ between runs: {first} and {second}"
+        ));
+    }
+    let unordered = run(Reduction::Sum, ReductionOrder::Unordered)?;
+    let max = run(Reduction::Max, ReductionOrder::Unordered)?;
+
+    // Summed in f64 so the host reference doesn't carry its own rounding error
+    let expected = data.iter().map(|&v| v as f64).sum::<f64>();
+    let tolerance = expected.abs() * 1e-4 + 1e-3;
+    for (label, sum) in [("deterministic", first), ("unordered", unordered)] {
+        if (sum as f64 - expected).abs() > tolerance {
+            return Err(format!("The {label} sum is {sum}, expected {expected}"));
+        }
+    }
+    let expected_max = data.fold(f32::MIN, |a, &b| a.max(b));
+    if max != expected_max {
+        return Err(format!("The max is {max}, expected {expected_max}"));
+    }
+
+    Ok(format!(
+        "sum {first} is stable across runs, unordered sum {unordered}, max {max}"
+    ))
+}
+
+const MATMUL_SHADER: &str = indoc! {"
+    #version 450
+    layout (local_size_x = 8, local_size_y = 8) in;
+    layout (constant_id = 0) const uint M = 1;
+    layout (constant_id = 1) const uint N = 1;
+    layout (constant_id = 2) const uint K = 1;
+    layout(set = 0, binding = 0) buffer buf_a   { float a[]; };
+    layout(set = 0, binding = 1) buffer buf_b   { float b[]; };
+    layout(set = 0, binding = 2) buffer buf_out { float out_c[]; };
+    void main() {
+        uint row = gl_GlobalInvocationID.y;
+        uint col = gl_GlobalInvocationID.x;
+        if (row >= M || col >= N) return;
+        float sum = 0.0;
+        for (uint k = 0; k < K; k++) {
+            sum += a[row * K + k] * b[k * N + col];
+        }
+        out_c[row * N + col] = sum;
+    }
+"};
+
+fn matmul(gpu: &Arc<ComputeManager>, _size: usize) -> Result<String, String> {
+    let (m, n, k) = (96, 80, 64);
+
+    let program = gpu
+        .compile_program(MATMUL_SHADER, "matmul", true)
+        .map_err(|e| format!("{e:?}"))?;
+    let pipeline = Arc::new(
+        gpu.clone()
+            .build_specialized_pipeline(program, 3, &[(0, m), (1, n), (2, k)])
+            .map_err(|e| format!("{e:?}"))?,
+    );
+
+    let (m, n, k) = (m as usize, n as usize, k as usize);
+    let a = Array2::from_shape_fn((m, k), |(r, c)| ((r + 2 * c) % 5) as f32 - 2.0);
+    let b = Array2::from_shape_fn((k, n), |(r, c)| ((3 * r + c) % 7) as f32 * 0.25);
+    let a_tensor = gpu.create_tensor(Array1::from_iter(a.iter().copied()), false);
+    let b_tensor = gpu.create_tensor(Array1::from_iter(b.iter().copied()), false);
+    let mut out = gpu.create_tensor(Array1::zeros(m * n), true);
+
+    let task = gpu
+        .clone()
+        .new_task(&pipeline, vec![&a_tensor, &b_tensor, &out])
+        .op_local_sync_device(vec![&a_tensor, &b_tensor])
+        .op_pipeline_dispatch(WorkGroupSize {
+            x: (n as u32).div_ceil(8),
+            y: (m as u32).div_ceil(8),
+            z: 1,
+        })
+        .op_device_sync_local(vec![&out])
+        .finalize()
+        .map_err(|e| format!("{e:?}"))?;
+
+    let running_task = gpu
+        .exec_task(&task)
+        .ok_or("Failed to submit task".to_string())?;
+    gpu.await_task(&running_task, vec![&mut out])
+        .map_err(|e| format!("{e:?}"))?;
+
+    // Every product and partial sum is a small multiple of 0.25, so the result is exact
+    let expected: Vec<f32> = a.dot(&b).iter().copied().collect();
+    let error = max_abs_error(&expected, &out.data().to_vec());
+    if error > 0.0 {
+        return Err(format!("Output is off by up to {error:e}"));
+    }
+
+    Ok(format!("{m}x{k} by {k}x{n} matches the host exactly"))
+}
+
+// 3x3 box blur with clamped edges over a row-major single channel image
+struct BoxBlur {
+    width: usize,
+    height: usize,
+    source: String,
+}
+
+impl BoxBlur {
+    fn new(width: usize, height: usize) -> Self {
+        let source = format!(
+            indoc! {"
+                #version 450
+                layout (local_size_x = 8, local_size_y = 8) in;
+                layout(set = 0, binding = 0) buffer buf_in  {{ float image[]; }};
+                layout(set = 0, binding = 1) buffer buf_out {{ float blurred[]; }};
+                const int WIDTH = {};
+                const int HEIGHT = {};
+                void main() {{
+                    int x = int(gl_GlobalInvocationID.x);
+                    int y = int(gl_GlobalInvocationID.y);
+                    if (x >= WIDTH || y >= HEIGHT) return;
+                    float sum = 0.0;
+                    for (int dy = -1; dy <= 1; dy++) {{
+                        for (int dx = -1; dx <= 1; dx++) {{
+                            int sx = clamp(x + dx, 0, WIDTH - 1);
+                            int sy = clamp(y + dy, 0, HEIGHT - 1);
+                            sum += image[sy * WIDTH + sx];
+                        }}
+                    }}
+                    blurred[y * WIDTH + x] = sum / 9.0;
+                }}
+            "},
+            width, height
+        );
+
+        BoxBlur {
+            width,
+            height,
+            source,
+        }
+    }
+}
+
+impl CustomOp for BoxBlur {
+    fn name(&self) -> &str {
+        "box_blur"
+    }
+
+    fn source(&self) -> ShaderSource<'_> {
+        ShaderSource::Glsl(&self.source)
+    }
+
+    fn input_count(&self) -> u32 {
+        1
+    }
+
+    fn output_count(&self) -> u32 {
+        1
+    }
+
+    fn output_lens(&self, input_lens: &[usize]) -> Vec<usize> {
+        input_lens.to_vec()
+    }
+
+    fn work_group(&self, _input_lens: &[usize]) -> WorkGroupSize {
+        WorkGroupSize {
+            x: (self.width as u32).div_ceil(8),
+            y: (self.height as u32).div_ceil(8),
+            z: 1,
+        }
+    }
+
+    fn reference(&self, inputs: &[&[f32]]) -> Option<Vec<Vec<f32>>> {
+        let image = inputs[0];
+        let clamp = |v: isize, len: usize| v.clamp(0, len as isize - 1) as usize;
+
+        let blurred = (0..self.width * self.height)
+            .map(|i| {
+                let (x, y) = ((i % self.width) as isize, (i / self.width) as isize);
+                let mut sum = 0.0;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        sum += image
+                            [clamp(y + dy, self.height) * self.width + clamp(x + dx, self.width)];
+                    }
+                }
+                sum / 9.0
+            })
+            .collect();
+        Some(vec![blurred])
+    }
+}
+
+fn blur(gpu: &Arc<ComputeManager>, size: usize) -> Result<String, String> {
+    let width = (size as f64).sqrt().ceil() as usize;
+    let height = size.div_ceil(width);
+    let op = BoxBlur::new(width, height);
+
+    // A checkerboard with a gradient, so both edges and flat regions get blurred
+    let image: Vec<f32> = (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            ((x / 4 + y / 4) % 2) as f32 + x as f32 / width as f32
+        })
+        .collect();
+
+    let comparisons = gpu
+        .clone()
+        .check_custom_op(&op, vec![image], Tolerance::default())
+        .map_err(|e| format!("{e:?}"))?;
+
+    Ok(format!(
+        "{width}x{height} image within tolerance, max error {:e}",
+        comparisons[0].max_abs_error
+    ))
+}
+
+const SIMULATION_SHADER: &str = indoc! {"
+    #version 450
+    layout (local_size_x = 64) in;
+    layout(set = 0, binding = 0) buffer buf_pos { float pos[]; };
+    layout(set = 0, binding = 1) buffer buf_vel { float vel[]; };
+    const float DT = 0.01;
+    void main() {
+        uint i = gl_GlobalInvocationID.x;
+        if (i >= pos.length()) return;
+        float stiffness = 1.0 + float(i % 16);
+        vel[i] -= stiffness * pos[i] * DT;
+        pos[i] += vel[i] * DT;
+    }
+"};
+
+const SIMULATION_STEPS: u64 = 1000;
+const SIMULATION_READBACK_INTERVAL: u32 = 250;
+
+// The same step as the shader, for the host to follow along
+fn simulation_step(pos: &mut [f32], vel: &mut [f32]) {
+    const DT: f32 = 0.01;
+    pos.iter_mut()
+        .zip(vel.iter_mut())
+        .enumerate()
+        .for_each(|(i, (p, v))| {
+            let stiffness = 1.0 + (i % 16) as f32;
+            *v -= stiffness * *p * DT;
+            *p += *v * DT;
+        });
+}
+
+fn simulation(gpu: &Arc<ComputeManager>, size: usize) -> Result<String, String> {
+    let pipeline = gpu
+        .clone()
+        .get_or_build_pipeline(ShaderSource::Glsl(SIMULATION_SHADER), "oscillators", 2)
+        .map_err(|e| format!("{e:?}"))?;
+
+    let initial_pos: Vec<f32> = (0..size).map(|i| ((i % 11) as f32 - 5.0) * 0.1).collect();
+    let mut pos = gpu.create_tensor(Array1::from(initial_pos.clone()), true);
+    let mut vel = gpu.create_tensor(Array1::zeros(size), true);
+
+    let task = gpu
+        .clone()
+        .new_task(&pipeline, vec![&pos, &vel])
+        .op_local_sync_device(vec![&pos, &vel])
+        .op_pipeline_dispatch(dispatch_size(size))
+        .op_device_sync_local(vec![&pos, &vel])
+        .finalize()
+        .map_err(|e| format!("{e:?}"))?;
+    let mut stepper = gpu
+        .clone()
+        .stepper(task, SIMULATION_READBACK_INTERVAL)
+        .map_err(|e| format!("{e:?}"))?;
+
+    // The host runs the same steps as each readback comes in. Rounding can differ where the
+    // device fuses multiplies and adds, so positions are compared with a tolerance.
+    let mut host_pos = initial_pos;
+    let mut host_vel = vec![0.0; size];
+    let mut host_steps = 0;
+    let mut worst_error = 0.0f32;
+    stepper
+        .run(
+            SIMULATION_STEPS,
+            &mut [&mut pos, &mut vel],
+            |steps, tensors| {
+                while host_steps < steps {
+                    simulation_step(&mut host_pos, &mut host_vel);
+                    host_steps += 1;
+                }
+                let error = max_abs_error(&host_pos, &tensors[0].data().to_vec());
+                worst_error = worst_error.max(error);
+            },
+        )
+        .map_err(|e| format!("{e:?}"))?;
+
+    if worst_error > 1e-3 {
+        return Err(format!(
+            "Positions drifted from the host by up to {worst_error:e}"
+        ));
+    }
+
+    Ok(format!(
+        "{SIMULATION_STEPS} steps of {size} oscillators, max drift {worst_error:e}"
+    ))
+}
+
+pub fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(o) => o,
+        Err(e) if e.is_empty() => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if options.list {
+        DEMOS
+            .iter()
+            .for_each(|demo| println!("{:<12} {}", demo.name, demo.description));
+        return ExitCode::SUCCESS;
+    }
+
+    let compute_manager = match compute_init(LogConfig {
+        validation_config: options.validation.then_some(ValidationLayerLogConfig {
+            log_errors: true,
+            log_warnings: true,
+            log_verbose_info: false,
+        }),
+        allocator_config: Some(AllocatorLogConfig {
+            log_memory_information: false,
+            log_leaks_on_shutdown: true,
+            store_stack_traces: false,
+            log_allocations: false,
+            log_frees: false,
+            log_stack_traces: false,
+        }),
+        track_live_resources: false,
+    }) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to initialize gauss: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let device_name = compute_manager
+        .devices()
+        .into_iter()
+        .find(|d| d.selected)
+        .map(|d| d.name)
+        .unwrap_or("unknown".to_string());
+    println!("Device: {device_name}");
+
+    // Every selected demo runs even after a failure, so one run shows everything broken on a device
+    let mut failed = 0;
+    for demo in DEMOS
+        .iter()
+        .filter(|demo| options.demos.is_empty() || options.demos.iter().any(|n| n == demo.name))
+    {
+        match (demo.run)(&compute_manager, options.size) {
+            Ok(summary) => println!("{:<12} ok      {summary}", demo.name),
+            Err(e) => {
+                println!("{:<12} FAILED  {e}", demo.name);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        eprintln!("{failed} demo(s) failed");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
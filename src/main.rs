@@ -1,68 +1,466 @@
-use std::sync::Arc;
+use std::{process::ExitCode, sync::Arc};
 
-use gauss::{compute_init, AllocatorLogConfig, LogConfig, ValidationLayerLogConfig, WorkGroupSize};
+use gauss::{
+    compute_init, AllocatorLogConfig, ComparisonReport, ComputeManager, DeviceSummary, LogConfig,
+    ValidationLayerLogConfig, WorkGroupSize,
+};
 use indoc::indoc;
 use ndarray::prelude::*;
 
-pub fn main() {
-    let compute_manager = compute_init(LogConfig {
-        validation_config: Some(ValidationLayerLogConfig {
+const USAGE: &str = indoc! {"
+    Usage: gauss-bench [OPTIONS]
+
+    Runs the gauss op suite on the best available device and reports per-phase timings.
+
+    Options:
+        --list-devices     List the devices gauss can see and exit
+        --format FORMAT    Output format: table, json or csv [default: table]
+        --size N           Elements per tensor [default: 1048576]
+        --iterations N     Timed iterations per op [default: 20]
+        --ops A,B,...      Only run the named ops
+        --validation       Enable the Khronos validation layer
+        -h, --help         Print this help
+"};
+
+const LOCAL_SIZE_X: u32 = 64;
+
+// Every op binds its inputs followed by one output
+struct BenchOp {
+    name: &'static str,
+    shader: &'static str,
+    input_count: usize,
+    cpu_reference: fn(&[&Array1<f32>]) -> Array1<f32>,
+}
+
+const OPS: [BenchOp; 4] = [
+    BenchOp {
+        name: "copy",
+        shader: indoc! {"
+            #version 450
+            layout (local_size_x = 64) in;
+            layout(set = 0, binding = 0) buffer buf_a   { float a[]; };
+            layout(set = 0, binding = 1) buffer buf_out { float out_a[]; };
+            void main() {
+                uint i = gl_GlobalInvocationID.x;
+                if (i >= out_a.length()) return;
+                out_a[i] = a[i];
+            }
+        "},
+        input_count: 1,
+        cpu_reference: |inputs| inputs[0].clone(),
+    },
+    BenchOp {
+        name: "square",
+        shader: indoc! {"
+            #version 450
+            layout (local_size_x = 64) in;
+            layout(set = 0, binding = 0) buffer buf_a   { float a[]; };
+            layout(set = 0, binding = 1) buffer buf_out { float out_a[]; };
+            void main() {
+                uint i = gl_GlobalInvocationID.x;
+                if (i >= out_a.length()) return;
+                out_a[i] = a[i] * a[i];
+            }
+        "},
+        input_count: 1,
+        cpu_reference: |inputs| inputs[0] * inputs[0],
+    },
+    BenchOp {
+        name: "saxpy",
+        shader: indoc! {"
+            #version 450
+            layout (local_size_x = 64) in;
+            layout(set = 0, binding = 0) buffer buf_x   { float x[]; };
+            layout(set = 0, binding = 1) buffer buf_y   { float y[]; };
+            layout(set = 0, binding = 2) buffer buf_out { float out_a[]; };
+            void main() {
+                uint i = gl_GlobalInvocationID.x;
+                if (i >= out_a.length()) return;
+                out_a[i] = 2.0 * x[i] + y[i];
+            }
+        "},
+        input_count: 2,
+        cpu_reference: |inputs| inputs[0] * 2.0 + inputs[1],
+    },
+    BenchOp {
+        name: "polynomial",
+        shader: indoc! {"
+            #version 450
+            layout (local_size_x = 64) in;
+            layout(set = 0, binding = 0) buffer buf_a   { float a[]; };
+            layout(set = 0, binding = 1) buffer buf_out { float out_a[]; };
+            void main() {
+                uint i = gl_GlobalInvocationID.x;
+                if (i >= out_a.length()) return;
+                float x = a[i];
+                out_a[i] = ((0.5 * x + 1.5) * x - 2.0) * x + 0.25;
+            }
+        "},
+        input_count: 1,
+        cpu_reference: |inputs| inputs[0].mapv(|x| ((0.5 * x + 1.5) * x - 2.0) * x + 0.25),
+    },
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+struct Options {
+    list_devices: bool,
+    format: OutputFormat,
+    size: usize,
+    iterations: u32,
+    ops: Option<Vec<String>>,
+    validation: bool,
+}
+
+struct BenchResult {
+    op: &'static str,
+    size: usize,
+    report: ComparisonReport,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        list_devices: false,
+        format: OutputFormat::Table,
+        size: 1 << 20,
+        iterations: 20,
+        ops: None,
+        validation: false,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+        match arg.as_str() {
+            "--list-devices" => options.list_devices = true,
+            "--validation" => options.validation = true,
+            "--format" => {
+                options.format = match value("--format")?.as_str() {
+                    "table" => OutputFormat::Table,
+                    "json" => OutputFormat::Json,
+                    "csv" => OutputFormat::Csv,
+                    other => return Err(format!("Unknown format \"{other}\"")),
+                }
+            }
+            "--size" => {
+                options.size = value("--size")?
+                    .parse()
+                    .map_err(|e| format!("Invalid --size: {e}"))?
+            }
+            "--iterations" => {
+                options.iterations = value("--iterations")?
+                    .parse()
+                    .map_err(|e| format!("Invalid --iterations: {e}"))?
+            }
+            "--ops" => {
+                options.ops = Some(
+                    value("--ops")?
+                        .split(',')
+                        .map(|s| s.trim().to_string())
+                        .collect(),
+                )
+            }
+            "-h" | "--help" => return Err(String::new()),
+            other => return Err(format!("Unknown argument \"{other}\"")),
+        }
+    }
+
+    if options.size == 0 {
+        return Err("--size must be at least 1".to_string());
+    }
+    if let Some(unknown) = options
+        .ops
+        .iter()
+        .flatten()
+        .find(|name| !OPS.iter().any(|op| op.name == name.as_str()))
+    {
+        return Err(format!("Unknown op \"{unknown}\""));
+    }
+
+    Ok(options)
+}
+
+fn run_op(
+    compute_manager: &Arc<ComputeManager>,
+    op: &BenchOp,
+    options: &Options,
+) -> Result<ComparisonReport, String> {
+    let program = compute_manager
+        .compile_program(op.shader, op.name, true)
+        .map_err(|e| format!("{e:?}"))?;
+    let pipeline = Arc::new(
+        compute_manager
+            .clone()
+            .build_pipeline(program, op.input_count as u32 + 1)
+            .map_err(|e| format!("{e:?}"))?,
+    );
+
+    let inputs: Vec<_> = (0..op.input_count)
+        .map(|i| {
+            let data = Array1::from_shape_fn(options.size, |j| ((j + i) % 97) as f32 * 0.125);
+            compute_manager.create_tensor(data, false)
+        })
+        .collect();
+    let mut output = compute_manager.create_tensor(Array1::zeros(options.size), true);
+
+    let work_group = WorkGroupSize {
+        x: (options.size as u32).div_ceil(LOCAL_SIZE_X),
+        y: 1,
+        z: 1,
+    };
+
+    compute_manager
+        .clone()
+        .compare_with_cpu(
+            &pipeline,
+            inputs.iter().collect(),
+            vec![&mut output],
+            work_group,
+            options.iterations,
+            |inputs| vec![(op.cpu_reference)(inputs)],
+        )
+        .map_err(|e| format!("{e:?}"))
+}
+
+fn micros(duration: std::time::Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+// Bytes moved per second, in GB/s
+fn bandwidth(bytes: usize, duration: std::time::Duration) -> f64 {
+    bytes as f64 / duration.as_secs_f64().max(f64::EPSILON) / 1e9
+}
+
+fn json_string(s: &str) -> String {
+    let escaped: String = s
+        .chars()
+        .flat_map(|c| match c {
+            '"' => vec!['\\', '"'],
+            '\\' => vec!['\\', '\\'],
+            c if c.is_control() => format!("\\u{:04x}", c as u32).chars().collect(),
+            c => vec![c],
+        })
+        .collect();
+    format!("\"{escaped}\"")
+}
+
+fn print_devices(devices: &[DeviceSummary], format: OutputFormat) {
+    match format {
+        OutputFormat::Json => {
+            let entries: Vec<String> = devices
+                .iter()
+                .map(|d| {
+                    format!(
+                        "{{\"name\":{},\"type\":{},\"vendor_id\":{},\"device_id\":{},\"api_version\":\"{}.{}.{}\",\"usable\":{},\"selected\":{}}}",
+                        json_string(&d.name),
+                        json_string(&format!("{:?}", d.device_type)),
+                        d.vendor_id,
+                        d.device_id,
+                        d.api_version.0,
+                        d.api_version.1,
+                        d.api_version.2,
+                        d.score.is_some(),
+                        d.selected
+                    )
+                })
+                .collect();
+            println!("{{\"devices\":[{}]}}", entries.join(","));
+        }
+        OutputFormat::Csv => {
+            println!("name,type,vendor_id,device_id,api_version,usable,selected");
+            devices.iter().for_each(|d| {
+                println!(
+                    "\"{}\",{:?},{},{},{}.{}.{},{},{}",
+                    d.name.replace('"', "\"\""),
+                    d.device_type,
+                    d.vendor_id,
+                    d.device_id,
+                    d.api_version.0,
+                    d.api_version.1,
+                    d.api_version.2,
+                    d.score.is_some(),
+                    d.selected
+                )
+            });
+        }
+        OutputFormat::Table => devices.iter().for_each(|d| {
+            println!(
+                "{} {} ({:?}, {:04x}:{:04x}, Vulkan {}.{}.{}){}",
+                if d.selected { "*" } else { " " },
+                d.name,
+                d.device_type,
+                d.vendor_id,
+                d.device_id,
+                d.api_version.0,
+                d.api_version.1,
+                d.api_version.2,
+                if d.score.is_none() {
+                    " [no compute queue]"
+                } else {
+                    ""
+                }
+            )
+        }),
+    }
+}
+
+fn print_results(device: Option<&DeviceSummary>, results: &[BenchResult], format: OutputFormat) {
+    let device_name = device.map(|d| d.name.as_str()).unwrap_or("unknown");
+
+    match format {
+        OutputFormat::Json => {
+            let entries: Vec<String> = results
+                .iter()
+                .map(|r| {
+                    let bytes = r.size * 4;
+                    format!(
+                        "{{\"op\":{},\"size\":{},\"iterations\":{},\"cpu_us\":{:.3},\"gpu_us\":{:.3},\"overhead_us\":{:.3},\"upload_us\":{:.3},\"dispatch_us\":{:.3},\"readback_us\":{:.3},\"upload_gbps\":{:.3},\"readback_gbps\":{:.3},\"speedup\":{:.3},\"max_abs_error\":{:e}}}",
+                        json_string(r.op),
+                        r.size,
+                        r.report.iterations,
+                        micros(r.report.cpu_time),
+                        micros(r.report.gpu_time),
+                        micros(r.report.task_overhead_time),
+                        micros(r.report.upload_time),
+                        micros(r.report.dispatch_time),
+                        micros(r.report.readback_time),
+                        bandwidth(bytes, r.report.upload_time),
+                        bandwidth(bytes, r.report.readback_time),
+                        r.report.speedup,
+                        r.report.max_abs_error
+                    )
+                })
+                .collect();
+            println!(
+                "{{\"device\":{},\"results\":[{}]}}",
+                json_string(device_name),
+                entries.join(",")
+            );
+        }
+        OutputFormat::Csv => {
+            println!("device,op,size,iterations,cpu_us,gpu_us,overhead_us,upload_us,dispatch_us,readback_us,upload_gbps,readback_gbps,speedup,max_abs_error");
+            results.iter().for_each(|r| {
+                let bytes = r.size * 4;
+                println!(
+                    "\"{}\",{},{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:e}",
+                    device_name.replace('"', "\"\""),
+                    r.op,
+                    r.size,
+                    r.report.iterations,
+                    micros(r.report.cpu_time),
+                    micros(r.report.gpu_time),
+                    micros(r.report.task_overhead_time),
+                    micros(r.report.upload_time),
+                    micros(r.report.dispatch_time),
+                    micros(r.report.readback_time),
+                    bandwidth(bytes, r.report.upload_time),
+                    bandwidth(bytes, r.report.readback_time),
+                    r.report.speedup,
+                    r.report.max_abs_error
+                )
+            });
+        }
+        OutputFormat::Table => {
+            println!("Device: {device_name}");
+            println!(
+                "{:<12} {:>10} {:>12} {:>12} {:>12} {:>12} {:>12} {:>9} {:>11}",
+                "op",
+                "size",
+                "gpu us",
+                "upload us",
+                "dispatch us",
+                "readback us",
+                "cpu us",
+                "speedup",
+                "max error"
+            );
+            results.iter().for_each(|r| {
+                println!(
+                    "{:<12} {:>10} {:>12.1} {:>12.1} {:>12.1} {:>12.1} {:>12.1} {:>8.2}x {:>11.2e}",
+                    r.op,
+                    r.size,
+                    micros(r.report.gpu_time),
+                    micros(r.report.upload_time),
+                    micros(r.report.dispatch_time),
+                    micros(r.report.readback_time),
+                    micros(r.report.cpu_time),
+                    r.report.speedup,
+                    r.report.max_abs_error
+                )
+            });
+        }
+    }
+}
+
+pub fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(o) => o,
+        Err(e) if e.is_empty() => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let compute_manager = match compute_init(LogConfig {
+        validation_config: options.validation.then_some(ValidationLayerLogConfig {
             log_errors: true,
             log_warnings: true,
-            log_verbose_info: true,
+            log_verbose_info: false,
         }),
         allocator_config: Some(AllocatorLogConfig {
-            log_memory_information: true,
+            log_memory_information: false,
             log_leaks_on_shutdown: true,
             store_stack_traces: false,
             log_allocations: false,
             log_frees: false,
             log_stack_traces: false,
         }),
-    })
-    .unwrap();
-
-    let shader = indoc! {"
-        #version 450
-        
-        layout (local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
-
-        layout(set = 0, binding = 0) buffer buf_in  {  float in_a[];  };
-        layout(set = 0, binding = 1) buffer buf_out {  float out_a[]; };
-
-        void main() {
-            uint index = gl_GlobalInvocationID.x;
-            out_a[index] = in_a[index] * in_a[index];
+        track_live_resources: false,
+    }) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to initialize gauss: {e:?}");
+            return ExitCode::FAILURE;
         }
-    "};
+    };
 
-    let tensor_in = compute_manager.create_tensor(array![1.0, 2.0, 3.0, 4.0, 5.0], false);
-    let mut tensor_out = compute_manager.create_tensor(array![5.0, 4.0, 3.0, 2.0, 1.0], true);
+    let devices = compute_manager.devices();
+    if options.list_devices {
+        print_devices(&devices, options.format);
+        return ExitCode::SUCCESS;
+    }
 
-    let pipeline = compute_manager
-        .clone()
-        .build_pipeline(
-            compute_manager
-                .compile_program(shader, "basic_compute", true)
-                .unwrap(),
-            2,
-        )
-        .unwrap();
-
-    let task = compute_manager
-        .clone()
-        .new_task(&pipeline, vec![&tensor_in, &tensor_out])
-        .op_local_sync_device(vec![&tensor_in, &tensor_out])
-        .op_pipeline_dispatch(WorkGroupSize { x: 5, y: 1, z: 1 })
-        .op_device_sync_local(vec![&tensor_out])
-        .finalize()
-        .unwrap();
-
-    let running_task = compute_manager.exec_task(&task).unwrap();
+    let mut results = Vec::new();
+    for op in OPS.iter().filter(|op| match options.ops.as_ref() {
+        Some(names) => names.iter().any(|n| n == op.name),
+        None => true,
+    }) {
+        match run_op(&compute_manager, op, &options) {
+            Ok(report) => results.push(BenchResult {
+                op: op.name,
+                size: options.size,
+                report,
+            }),
+            Err(e) => {
+                eprintln!("Op \"{}\" failed: {e}", op.name);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
 
-    log::trace!("Strong RefCount: {}", Arc::strong_count(&compute_manager));
-    log::trace!("Weak RefCount: {}", Arc::weak_count(&compute_manager));
+    print_results(
+        devices.iter().find(|d| d.selected),
+        &results,
+        options.format,
+    );
 
-    compute_manager.await_task(&running_task, vec![&mut tensor_out]);
-    println!("Data: {}", tensor_out.data());
+    ExitCode::SUCCESS
 }